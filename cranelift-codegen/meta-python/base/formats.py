@@ -10,6 +10,7 @@ from cdsl.formats import InstructionFormat
 from cdsl.operands import VALUE, VARIABLE_ARGS
 from .immediates import imm64, uimm8, uimm32, ieee32, ieee64, offset32
 from .immediates import boolean, intcc, floatcc, memflags, regunit, trapcode
+from .immediates import atomic_rmw_op
 from . import entities
 from .entities import ebb, sig_ref, func_ref, stack_slot, heap, table
 
@@ -64,6 +65,12 @@ LoadComplex = InstructionFormat(memflags, VARIABLE_ARGS, offset32)
 Store = InstructionFormat(memflags, VALUE, VALUE, offset32)
 StoreComplex = InstructionFormat(memflags, VALUE, VARIABLE_ARGS, offset32)
 
+# Atomic memory accesses are controlled by the type of the value operands, not
+# by the address.
+AtomicRmw = InstructionFormat(
+        memflags, atomic_rmw_op, VALUE, VALUE, typevar_operand=1)
+AtomicCas = InstructionFormat(memflags, VALUE, VALUE, VALUE, typevar_operand=2)
+
 StackLoad = InstructionFormat(stack_slot, offset32)
 StackStore = InstructionFormat(VALUE, stack_slot, offset32)
 
@@ -100,6 +100,24 @@ memflags = ImmediateKind(
         'Memory operation flags',
         default_member='flags', rust_type='ir::MemFlags')
 
+#: The operation performed by an atomic read-modify-write instruction.
+#:
+#: This enumerated operand kind is used for the :clif:inst:`atomic_rmw`
+#: instruction and corresponds to the `ir::AtomicRmwOp` Rust type.
+atomic_rmw_op = ImmediateKind(
+        'atomic_rmw_op',
+        'An atomic read-modify-write operation.',
+        default_member='op',
+        rust_type='ir::AtomicRmwOp',
+        values={
+            'add': 'Add',
+            'sub': 'Sub',
+            'and': 'And',
+            'or': 'Or',
+            'xor': 'Xor',
+            'xchg': 'Xchg',
+        })
+
 #: A register unit in the current target ISA.
 regunit = ImmediateKind(
         'regunit',
@@ -11,7 +11,7 @@ from cdsl.instructions import Instruction, InstructionGroup
 from base.types import f32, f64, b1, iflags, fflags
 from base.immediates import imm64, uimm8, uimm32, ieee32, ieee64, offset32
 from base.immediates import boolean, intcc, floatcc, memflags, regunit
-from base.immediates import trapcode
+from base.immediates import trapcode, atomic_rmw_op
 from base import entities
 from cdsl.ti import WiderOrEq
 import base.formats  # noqa
@@ -509,6 +509,62 @@ istore32_complex = Instruction(
         """,
         ins=(MemFlags, x, args, Offset), can_store=True)
 
+AtomicMem = TypeVar(
+        'AtomicMem', 'An integer type that can be accessed atomically',
+        ints=True)
+x = Operand('x', AtomicMem, doc='Value to be stored')
+a = Operand('a', AtomicMem, doc='Value loaded')
+e = Operand('e', AtomicMem, doc='Expected value')
+AtomicOp = Operand('AtomicOp', atomic_rmw_op)
+
+atomic_load = Instruction(
+        'atomic_load', r"""
+        Atomically load from memory at ``p + Offset``.
+
+        The load is sequentially consistent with all other atomic memory
+        accesses. The effective address must be naturally aligned for the
+        loaded type.
+        """,
+        ins=(MemFlags, p, Offset), outs=a, can_load=True,
+        other_side_effects=True)
+
+atomic_store = Instruction(
+        'atomic_store', r"""
+        Atomically store ``x`` to memory at ``p + Offset``.
+
+        The store is sequentially consistent with all other atomic memory
+        accesses. The effective address must be naturally aligned for the
+        stored type.
+        """,
+        ins=(MemFlags, x, p, Offset), can_store=True, other_side_effects=True)
+
+atomic_rmw = Instruction(
+        'atomic_rmw', r"""
+        Atomically read, modify and write memory at ``p``.
+
+        Load the value at ``p``, combine it with ``x`` using ``AtomicOp`` and
+        store the result back to ``p`` as a single sequentially consistent
+        operation. The ``xchg`` operation stores ``x`` unchanged. Returns
+        the value originally loaded from ``p``.
+
+        The address must be naturally aligned for the accessed type.
+        """,
+        ins=(MemFlags, AtomicOp, p, x), outs=a, can_load=True, can_store=True,
+        other_side_effects=True)
+
+atomic_cas = Instruction(
+        'atomic_cas', r"""
+        Atomically compare and swap memory at ``p``.
+
+        Load the value at ``p`` and, if it is equal to ``e``, store ``x`` in
+        its place as a single sequentially consistent operation. Returns the
+        value originally loaded from ``p`` whether or not the store happened.
+
+        The address must be naturally aligned for the accessed type.
+        """,
+        ins=(MemFlags, p, e, x), outs=a, can_load=True, can_store=True,
+        other_side_effects=True)
+
 x = Operand('x', Mem, doc='Value to be stored')
 a = Operand('a', Mem, doc='Value loaded')
 Offset = Operand('Offset', offset32, 'In-bounds offset into stack slot')
@@ -94,6 +94,13 @@ expand.custom_legalize(insts.f64const, 'expand_fconst')
 expand.custom_legalize(insts.stack_load, 'expand_stack_load')
 expand.custom_legalize(insts.stack_store, 'expand_stack_store')
 
+# Custom expansions for atomic memory accesses in terms of `atomic_rmw` with
+# `add` and `xchg`, and `atomic_cas`. Narrow atomics can't be widened without
+# affecting the neighboring bytes, so the same expansions are used for them.
+for group in [expand, widen]:
+    group.custom_legalize(insts.atomic_store, 'expand_atomic_store')
+    group.custom_legalize(insts.atomic_rmw, 'expand_atomic_rmw')
+
 x = Var('x')
 y = Var('y')
 z = Var('z')
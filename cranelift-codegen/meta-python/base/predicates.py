@@ -35,6 +35,18 @@ class IsColocatedData(FieldPredicate):
             UnaryGlobalValue.global_value, 'is_colocated_data', ('func',))
 
 
+class IsTlsData(FieldPredicate):
+    """
+    An instruction predicate that checks the referenced data object is
+    thread-local.
+    """
+
+    def __init__(self):
+        # type: () -> None
+        super(IsTlsData, self).__init__(
+            UnaryGlobalValue.global_value, 'is_tls_data', ('func',))
+
+
 class LengthEquals(FieldPredicate):
     def __init__(self, iform, num):
         # type: (InstructionFormat, int) -> None
@@ -105,6 +105,20 @@ allones_funcaddrs = BoolSetting(
         Emit not-yet-relocated function addresses as all-ones bit patterns.
         """)
 
+#
+# Frame layout options.
+#
+omit_frame_pointer = BoolSetting(
+        """
+        Omit the frame pointer in functions using the System V calling
+        convention on x86-64.
+
+        The frame pointer register becomes available to the register allocator
+        and stack slots are addressed relative to the stack pointer. This is
+        only appropriate when stack unwinding doesn't rely on a frame pointer
+        chain, for example because unwind information is emitted separately.
+        """)
+
 #
 # Stack probing options.
 #
@@ -121,12 +135,22 @@ probestack_func_adjusts_sp = BoolSetting(
         itself.
         """)
 
+probestack_strategy = EnumSetting(
+        """
+        The strategy used for stack probes:
+
+        - outline: Call the probestack function.
+        - inline: Emit an inline sequence probing each page of the new stack
+          frame. This doesn't require a probestack function.
+        """,
+        'outline', 'inline')
+
 probestack_size_log2 = NumSetting(
         """
         The log2 of the size of the stack guard region.
 
         Stack frames larger than this size will have stack overflow checked
-        by calling the probestack function.
+        by stack probes, probing every page of this size.
 
         The default is 12, which translates to a size of 4096.
         """,
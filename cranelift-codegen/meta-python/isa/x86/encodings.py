@@ -3,12 +3,15 @@ x86 Encodings.
 """
 from __future__ import absolute_import
 from cdsl.predicates import IsZero32BitFloat, IsZero64BitFloat
-from cdsl.predicates import IsUnsignedInt, Not, And
-from base.predicates import IsColocatedFunc, IsColocatedData, LengthEquals
+from cdsl.predicates import IsUnsignedInt, IsEqual, Not, And
+from base.predicates import IsColocatedFunc, IsColocatedData, IsTlsData
+from base.predicates import LengthEquals
 from base import instructions as base
 from base import types
 from base.formats import UnaryIeee32, UnaryIeee64, UnaryImm
 from base.formats import FuncAddr, Call, LoadComplex, StoreComplex
+from base.formats import AtomicRmw
+from base.immediates import atomic_rmw_op
 from .defs import X86_64, X86_32
 from . import recipes as r
 from . import settings as cfg
@@ -19,10 +22,11 @@ from base.settings import allones_funcaddrs, is_pic
 from .settings import use_sse41
 
 try:
-    from typing import TYPE_CHECKING, Any  # noqa
+    from typing import TYPE_CHECKING, Any, Sequence  # noqa
     if TYPE_CHECKING:
         from cdsl.instructions import MaybeBoundInst  # noqa
         from cdsl.predicates import FieldPredicate # noqa
+        from cdsl.predicates import PredNode # noqa
 except ImportError:
     pass
 
@@ -326,6 +330,47 @@ for ty in [types.b1, types.i8, types.i16]:
     enc_both(base.fill.bind(ty), r.fillSib32, 0x8b)
     enc_both(base.regfill.bind(ty), r.regfill32, 0x8b)
 
+#
+# Atomic memory accesses.
+#
+
+# Aligned loads are atomic, and they are sequentially consistent as long as
+# atomic stores use a locked instruction, so atomic loads are plain loads.
+for recipe in [r.ld, r.ldDisp8, r.ldDisp32]:
+    enc_i32_i64_ld_st(base.atomic_load, True, recipe, 0x8b)
+    enc_both(base.atomic_load.i16.any, recipe, 0x0f, 0xb7)
+    enc_both(base.atomic_load.i8.any, recipe, 0x0f, 0xb6)
+
+
+def enc_atomic(inst, recipe, recipe_abcd, op8, op, instp=None):
+    # type: (MaybeBoundInst, r.TailRecipe, r.TailRecipe, Sequence[int], Sequence[int], PredNode) -> None  # noqa
+    """
+    Add encodings for an atomic memory instruction operating on all integer
+    types. Byte operations use the `op8` opcode, and wider operations use
+    `op`.
+    """
+    X86_32.enc(inst.i8.any, *recipe_abcd(*op8), instp=instp)
+    enc_x86_64_instp(inst.i8.any, recipe_abcd, instp, *op8)
+    enc_both_instp(inst.i16.any, recipe, instp, 0x66, *op)
+    X86_32.enc(inst.i32.any, *recipe(*op), instp=instp)
+    enc_x86_64_instp(inst.i32.any, recipe, instp, *op)
+    X86_64.enc(inst.i64.any, *recipe.rex(*op, w=1), instp=instp)
+
+
+# Only exchanges and additions have single-instruction encodings. The other
+# read-modify-write operations are legalized in terms of these or of
+# `atomic_cas`.
+enc_atomic(
+        base.atomic_rmw, r.xchg_m, r.xchg_m_abcd, (0x86,), (0x87,),
+        instp=IsEqual(AtomicRmw.op, atomic_rmw_op.xchg))
+enc_atomic(
+        base.atomic_rmw, r.lock_rmw, r.lock_rmw_abcd,
+        (0x0f, 0xc0), (0x0f, 0xc1),
+        instp=IsEqual(AtomicRmw.op, atomic_rmw_op.add))
+enc_atomic(
+        base.atomic_cas, r.lock_cmpxchg, r.lock_cmpxchg_abcd,
+        (0x0f, 0xb0), (0x0f, 0xb1))
+
 # Push and Pop
 X86_32.enc(x86.push.i32, *r.pushq(0x50))
 enc_x86_64(x86.push.i64, r.pushq, 0x50)
@@ -430,20 +475,27 @@ X86_64.enc(base.func_addr.i64, *r.got_fnaddr8.rex(0x8b, w=1),
 # Global addresses.
 #
 
+# Thread-local, both PIC and non-PIC. Use the ELF initial-exec TLS model.
+X86_64.enc(base.symbol_value.i64, *r.got_tls_gvaddr8.rex(0x8b, w=1),
+           instp=IsTlsData())
+
 # Non-PIC
 X86_32.enc(base.symbol_value.i32, *r.gvaddr4(0xb8),
-           isap=Not(is_pic))
+           isap=Not(is_pic),
+           instp=Not(IsTlsData()))
 X86_64.enc(base.symbol_value.i64, *r.gvaddr8.rex(0xb8, w=1),
-           isap=Not(is_pic))
+           isap=Not(is_pic),
+           instp=Not(IsTlsData()))
 
 # PIC, colocated
 X86_64.enc(base.symbol_value.i64, *r.pcrel_gvaddr8.rex(0x8d, w=1),
            isap=is_pic,
-           instp=IsColocatedData())
+           instp=And(IsColocatedData(), Not(IsTlsData())))
 
 # PIC, non-colocated
 X86_64.enc(base.symbol_value.i64, *r.got_gvaddr8.rex(0x8b, w=1),
-           isap=is_pic)
+           isap=is_pic,
+           instp=Not(IsTlsData()))
 
 #
 # Stack addresses.
@@ -517,7 +569,9 @@ X86_64.enc(base.jump_table_entry.i64.any.any, *r.jt_entry.rex(0x63, w=1))
 X86_32.enc(base.jump_table_entry.i32.any.any, *r.jt_entry(0x8b))
 
 X86_64.enc(base.jump_table_base.i64, *r.jt_base.rex(0x8d, w=1))
-X86_32.enc(base.jump_table_base.i32, *r.jt_base(0x8d))
+X86_32.enc(base.jump_table_base.i32, *r.jt_base_abs4(0x8d),
+           isap=Not(is_pic))
+X86_32.enc(base.jump_table_base.i32, *r.jt_base_pic4(0x8d), isap=is_pic)
 
 enc_x86_64(base.indirect_jump_table_br.i64, r.indirect_jmp, 0xff, rrr=4)
 X86_32.enc(base.indirect_jump_table_br.i32, *r.indirect_jmp(0xff, rrr=4))
@@ -18,6 +18,7 @@ from base.formats import BranchTableEntry, BranchTableBase, IndirectJump
 from base.formats import Ternary, FuncAddr, UnaryGlobalValue
 from base.formats import RegMove, RegSpill, RegFill, CopySpecial
 from base.formats import LoadComplex, StoreComplex
+from base.formats import AtomicRmw, AtomicCas
 from base.formats import StackLoad
 from .registers import GPR, ABCD, FPR
 from .registers import GPR8, FPR8, FLAG
@@ -537,6 +538,7 @@ r_id = TailRecipe(
 # XX /n id with 32-bit immediate sign-extended. UnaryImm version.
 u_id = TailRecipe(
         'u_id', UnaryImm, base_size=5, ins=(), outs=GPR,
+        clobbers_flags=False,
         instp=IsSignedInt(UnaryImm.imm, 32),
         emit='''
         PUT_OP(bits, rex1(out_reg0), sink);
@@ -548,6 +550,7 @@ u_id = TailRecipe(
 # XX+rd id unary with 32-bit immediate. Note no recipe predicate.
 pu_id = TailRecipe(
         'pu_id', UnaryImm, base_size=4, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         // The destination register is encoded in the low bits of the opcode.
         // No ModR/M.
@@ -559,6 +562,7 @@ pu_id = TailRecipe(
 # XX+rd id unary with bool immediate. Note no recipe predicate.
 pu_id_bool = TailRecipe(
         'pu_id_bool', UnaryBool, base_size=4, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         // The destination register is encoded in the low bits of the opcode.
         // No ModR/M.
@@ -570,6 +574,7 @@ pu_id_bool = TailRecipe(
 # XX+rd iq unary with 64-bit immediate.
 pu_iq = TailRecipe(
         'pu_iq', UnaryImm, base_size=8, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits | (out_reg0 & 7), rex1(out_reg0), sink);
         let imm: i64 = imm.into();
@@ -579,6 +584,7 @@ pu_iq = TailRecipe(
 # XX /n Unary with floating point 32-bit immediate equal to zero.
 f32imm_z = TailRecipe(
     'f32imm_z', UnaryIeee32, base_size=1, ins=(), outs=FPR,
+    clobbers_flags=False,
     instp=IsZero32BitFloat(UnaryIeee32.imm),
     emit='''
         PUT_OP(bits, rex2(out_reg0, out_reg0), sink);
@@ -588,6 +594,7 @@ f32imm_z = TailRecipe(
 # XX /n Unary with floating point 64-bit immediate equal to zero.
 f64imm_z = TailRecipe(
     'f64imm_z', UnaryIeee64, base_size=1, ins=(), outs=FPR,
+    clobbers_flags=False,
     instp=IsZero64BitFloat(UnaryIeee64.imm),
     emit='''
         PUT_OP(bits, rex2(out_reg0, out_reg0), sink);
@@ -647,6 +654,7 @@ adjustsp_id = TailRecipe(
 # XX+rd id with Abs4 function relocation.
 fnaddr4 = TailRecipe(
         'fnaddr4', FuncAddr, base_size=4, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits | (out_reg0 & 7), rex1(out_reg0), sink);
         sink.reloc_external(Reloc::Abs4,
@@ -658,6 +666,7 @@ fnaddr4 = TailRecipe(
 # XX+rd iq with Abs8 function relocation.
 fnaddr8 = TailRecipe(
         'fnaddr8', FuncAddr, base_size=8, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits | (out_reg0 & 7), rex1(out_reg0), sink);
         sink.reloc_external(Reloc::Abs8,
@@ -669,6 +678,7 @@ fnaddr8 = TailRecipe(
 # Similar to fnaddr4, but writes !0 (this is used by BaldrMonkey).
 allones_fnaddr4 = TailRecipe(
         'allones_fnaddr4', FuncAddr, base_size=4, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits | (out_reg0 & 7), rex1(out_reg0), sink);
         sink.reloc_external(Reloc::Abs4,
@@ -681,6 +691,7 @@ allones_fnaddr4 = TailRecipe(
 # Similar to fnaddr8, but writes !0 (this is used by BaldrMonkey).
 allones_fnaddr8 = TailRecipe(
         'allones_fnaddr8', FuncAddr, base_size=8, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits | (out_reg0 & 7), rex1(out_reg0), sink);
         sink.reloc_external(Reloc::Abs8,
@@ -692,6 +703,7 @@ allones_fnaddr8 = TailRecipe(
 
 pcrel_fnaddr8 = TailRecipe(
         'pcrel_fnaddr8', FuncAddr, base_size=5, ins=(), outs=GPR,
+        clobbers_flags=False,
         # rex2 gets passed 0 for r/m register because the upper bit of
         # r/m doesnt get decoded when in rip-relative addressing mode.
         emit='''
@@ -707,6 +719,7 @@ pcrel_fnaddr8 = TailRecipe(
 
 got_fnaddr8 = TailRecipe(
         'got_fnaddr8', FuncAddr, base_size=5, ins=(), outs=GPR,
+        clobbers_flags=False,
         # rex2 gets passed 0 for r/m register because the upper bit of
         # r/m doesnt get decoded when in rip-relative addressing mode.
         emit='''
@@ -724,6 +737,7 @@ got_fnaddr8 = TailRecipe(
 # XX+rd id with Abs4 globalsym relocation.
 gvaddr4 = TailRecipe(
         'gvaddr4', UnaryGlobalValue, base_size=4, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits | (out_reg0 & 7), rex1(out_reg0), sink);
         sink.reloc_external(Reloc::Abs4,
@@ -735,6 +749,7 @@ gvaddr4 = TailRecipe(
 # XX+rd iq with Abs8 globalsym relocation.
 gvaddr8 = TailRecipe(
         'gvaddr8', UnaryGlobalValue, base_size=8, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits | (out_reg0 & 7), rex1(out_reg0), sink);
         sink.reloc_external(Reloc::Abs8,
@@ -746,6 +761,7 @@ gvaddr8 = TailRecipe(
 # XX+rd iq with PCRel4 globalsym relocation.
 pcrel_gvaddr8 = TailRecipe(
         'pcrel_gvaddr8', UnaryGlobalValue, base_size=5, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits, rex2(0, out_reg0), sink);
         modrm_rm(5, out_reg0, sink);
@@ -760,6 +776,7 @@ pcrel_gvaddr8 = TailRecipe(
 # XX+rd iq with Abs8 globalsym relocation.
 got_gvaddr8 = TailRecipe(
         'got_gvaddr8', UnaryGlobalValue, base_size=5, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         PUT_OP(bits, rex2(0, out_reg0), sink);
         modrm_rm(5, out_reg0, sink);
@@ -771,6 +788,29 @@ got_gvaddr8 = TailRecipe(
         sink.put4(0);
         ''')
 
+# XX+rd iq with GOTTPOff4 globalsym relocation, followed by an add of the
+# thread pointer: mov r, [rip + sym@GOTTPOFF]; add r, fs:[0].
+got_tls_gvaddr8 = TailRecipe(
+        'got_tls_gvaddr8', UnaryGlobalValue, base_size=14, ins=(), outs=GPR,
+        clobbers_flags=True,
+        emit='''
+        PUT_OP(bits, rex2(0, out_reg0), sink);
+        modrm_rm(5, out_reg0, sink);
+        // The addend adjusts for the difference between the end of the
+        // instruction and the beginning of the immediate field.
+        sink.reloc_external(Reloc::X86GOTTPOff4,
+                            &func.global_values[global_value].symbol_name(),
+                            -4);
+        sink.put4(0);
+        // The thread pointer is the first word of the thread control block,
+        // addressed by the FS segment.
+        sink.put1(0x64);
+        put_rexop1(0x03 | (bits & 0x8000), rex2(0, out_reg0), sink);
+        modrm_sib(out_reg0, sink);
+        sib_noindex(0b101, sink);
+        sink.put4(0);
+        ''')
+
 #
 # Stack addresses.
 #
@@ -779,6 +819,7 @@ got_gvaddr8 = TailRecipe(
 
 spaddr4_id = TailRecipe(
         'spaddr4_id', StackLoad, base_size=6, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         let sp = StackRef::sp(stack_slot, &func.stack_slots);
         let base = stk_base(sp.base);
@@ -791,6 +832,7 @@ spaddr4_id = TailRecipe(
 
 spaddr8_id = TailRecipe(
         'spaddr8_id', StackLoad, base_size=6, ins=(), outs=GPR,
+        clobbers_flags=False,
         emit='''
         let sp = StackRef::sp(stack_slot, &func.stack_slots);
         let base = stk_base(sp.base);
@@ -1538,6 +1580,155 @@ fregfill32 = TailRecipe(
         sink.put4(src.offset as u32);
         ''')
 
+#
+# Atomic memory accesses
+#
+# Atomic loads use the plain load recipes since aligned x86 loads are atomic.
+# The read-modify-write instructions below only address memory through a
+# register with no offset, which is all the atomic instruction formats have.
+#
+
+# XX /r exchange of a register with memory. XCHG with a memory operand is
+# implicitly locked. The old memory value is returned in the input register.
+xchg_m = TailRecipe(
+        'xchg_m', AtomicRmw, base_size=1, ins=(GPR, GPR), outs=1,
+        clobbers_flags=False,
+        compute_size="size_plus_maybe_sib_or_offset_for_in_reg_0",
+        emit='''
+        if !flags.notrap() {
+            sink.trap(TrapCode::HeapOutOfBounds, func.srclocs[inst]);
+        }
+        PUT_OP(bits, rex2(in_reg0, in_reg1), sink);
+        if needs_sib_byte(in_reg0) {
+            modrm_sib(in_reg1, sink);
+            sib_noindex(in_reg0, sink);
+        } else if needs_offset(in_reg0) {
+            modrm_disp8(in_reg0, in_reg1, sink);
+            sink.put1(0);
+        } else {
+            modrm_rm(in_reg0, in_reg1, sink);
+        }
+        ''')
+
+# Like xchg_m, but only ABCD registers are allowed for the value. This is for
+# byte exchanges with no REX.
+xchg_m_abcd = TailRecipe(
+        'xchg_m_abcd', AtomicRmw, base_size=1, ins=(GPR, ABCD), outs=1,
+        when_prefixed=xchg_m,
+        clobbers_flags=False,
+        compute_size="size_plus_maybe_sib_or_offset_for_in_reg_0",
+        emit='''
+        if !flags.notrap() {
+            sink.trap(TrapCode::HeapOutOfBounds, func.srclocs[inst]);
+        }
+        PUT_OP(bits, rex2(in_reg0, in_reg1), sink);
+        if needs_sib_byte(in_reg0) {
+            modrm_sib(in_reg1, sink);
+            sib_noindex(in_reg0, sink);
+        } else if needs_offset(in_reg0) {
+            modrm_disp8(in_reg0, in_reg1, sink);
+            sink.put1(0);
+        } else {
+            modrm_rm(in_reg0, in_reg1, sink);
+        }
+        ''')
+
+# LOCK XX /r read-modify-write of memory, such as XADD, which returns the old
+# memory value in the input register.
+lock_rmw = TailRecipe(
+        'lock_rmw', AtomicRmw, base_size=2, ins=(GPR, GPR), outs=1,
+        compute_size="size_plus_maybe_sib_or_offset_for_in_reg_0",
+        emit='''
+        if !flags.notrap() {
+            sink.trap(TrapCode::HeapOutOfBounds, func.srclocs[inst]);
+        }
+        // LOCK prefix.
+        sink.put1(0xf0);
+        PUT_OP(bits, rex2(in_reg0, in_reg1), sink);
+        if needs_sib_byte(in_reg0) {
+            modrm_sib(in_reg1, sink);
+            sib_noindex(in_reg0, sink);
+        } else if needs_offset(in_reg0) {
+            modrm_disp8(in_reg0, in_reg1, sink);
+            sink.put1(0);
+        } else {
+            modrm_rm(in_reg0, in_reg1, sink);
+        }
+        ''')
+
+# Like lock_rmw, but only ABCD registers are allowed for the value. This is
+# for byte operations with no REX.
+lock_rmw_abcd = TailRecipe(
+        'lock_rmw_abcd', AtomicRmw, base_size=2, ins=(GPR, ABCD), outs=1,
+        when_prefixed=lock_rmw,
+        compute_size="size_plus_maybe_sib_or_offset_for_in_reg_0",
+        emit='''
+        if !flags.notrap() {
+            sink.trap(TrapCode::HeapOutOfBounds, func.srclocs[inst]);
+        }
+        // LOCK prefix.
+        sink.put1(0xf0);
+        PUT_OP(bits, rex2(in_reg0, in_reg1), sink);
+        if needs_sib_byte(in_reg0) {
+            modrm_sib(in_reg1, sink);
+            sib_noindex(in_reg0, sink);
+        } else if needs_offset(in_reg0) {
+            modrm_disp8(in_reg0, in_reg1, sink);
+            sink.put1(0);
+        } else {
+            modrm_rm(in_reg0, in_reg1, sink);
+        }
+        ''')
+
+# LOCK CMPXCHG: compare %rax with memory and store the replacement if they are
+# equal. The old memory value is returned in %rax either way.
+lock_cmpxchg = TailRecipe(
+        'lock_cmpxchg', AtomicCas, base_size=2,
+        ins=(GPR, GPR.rax, GPR), outs=(GPR.rax),
+        compute_size="size_plus_maybe_sib_or_offset_for_in_reg_0",
+        emit='''
+        if !flags.notrap() {
+            sink.trap(TrapCode::HeapOutOfBounds, func.srclocs[inst]);
+        }
+        // LOCK prefix.
+        sink.put1(0xf0);
+        PUT_OP(bits, rex2(in_reg0, in_reg2), sink);
+        if needs_sib_byte(in_reg0) {
+            modrm_sib(in_reg2, sink);
+            sib_noindex(in_reg0, sink);
+        } else if needs_offset(in_reg0) {
+            modrm_disp8(in_reg0, in_reg2, sink);
+            sink.put1(0);
+        } else {
+            modrm_rm(in_reg0, in_reg2, sink);
+        }
+        ''')
+
+# Like lock_cmpxchg, but only ABCD registers are allowed for the replacement.
+# This is for byte operations with no REX.
+lock_cmpxchg_abcd = TailRecipe(
+        'lock_cmpxchg_abcd', AtomicCas, base_size=2,
+        ins=(GPR, GPR.rax, ABCD), outs=(GPR.rax),
+        when_prefixed=lock_cmpxchg,
+        compute_size="size_plus_maybe_sib_or_offset_for_in_reg_0",
+        emit='''
+        if !flags.notrap() {
+            sink.trap(TrapCode::HeapOutOfBounds, func.srclocs[inst]);
+        }
+        // LOCK prefix.
+        sink.put1(0xf0);
+        PUT_OP(bits, rex2(in_reg0, in_reg2), sink);
+        if needs_sib_byte(in_reg0) {
+            modrm_sib(in_reg2, sink);
+            sib_noindex(in_reg0, sink);
+        } else if needs_offset(in_reg0) {
+            modrm_disp8(in_reg0, in_reg2, sink);
+            sink.put1(0);
+        } else {
+            modrm_rm(in_reg0, in_reg2, sink);
+        }
+        ''')
+
 #
 # Call/return
 #
@@ -1677,6 +1868,37 @@ jt_base = TailRecipe(
         jt_disp4(table, func, sink);
         ''')
 
+# Like jt_base, but for 32-bit mode, where the RIP-relative addressing form
+# is an absolute 32-bit address, so the jump table needs a relocation.
+jt_base_abs4 = TailRecipe(
+        'jt_base_abs4', BranchTableBase, base_size=5, ins=(), outs=(GPR),
+        clobbers_flags=False,
+        emit='''
+        PUT_OP(bits, rex2(0, out_reg0), sink);
+        modrm_riprel(out_reg0, sink);
+        sink.reloc_jt(Reloc::Abs4, table);
+        sink.put4(0);
+        ''')
+
+# Like jt_base, but for 32-bit PIC, where there is no PC-relative addressing.
+# The address of the next instruction is pushed by a call and popped into the
+# output register, which is then offset to the jump table with a lea. No reloc
+# is needed as the jump table is emitted directly after the function body.
+jt_base_pic4 = TailRecipe(
+        'jt_base_pic4', BranchTableBase, base_size=11, ins=(), outs=(GPR),
+        clobbers_flags=False,
+        emit='''
+        // call 0
+        sink.put1(0xe8);
+        sink.put4(0);
+        let base = sink.offset();
+        // pop out_reg0
+        sink.put1(0x58 | (out_reg0 as u8 & 7));
+        PUT_OP(bits, rex2(out_reg0, out_reg0), sink);
+        modrm_disp32(out_reg0, out_reg0, sink);
+        sink.put4(func.jt_offsets[table].wrapping_sub(base));
+        ''')
+
 #
 # Test flags and set a register.
 #
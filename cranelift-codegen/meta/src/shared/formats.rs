@@ -14,6 +14,7 @@ pub fn define(immediates: &OperandKinds, entities: &OperandKinds) -> FormatRegis
     let memflags = immediates.by_name("memflags");
     let offset32 = immediates.by_name("offset32");
     let trapcode = immediates.by_name("trapcode");
+    let atomic_rmw_op = immediates.by_name("atomic_rmw_op");
     let regunit = immediates.by_name("regunit");
 
     // Shorthands for entities.
@@ -132,6 +133,25 @@ pub fn define(immediates: &OperandKinds, entities: &OperandKinds) -> FormatRegis
             .varargs()
             .imm(offset32),
     );
+
+    // Atomic memory accesses are controlled by the type of the value operands, not by the
+    // address.
+    registry.insert(
+        Builder::new("AtomicRmw")
+            .imm(memflags)
+            .imm(atomic_rmw_op)
+            .value()
+            .value()
+            .typevar_operand(1),
+    );
+    registry.insert(
+        Builder::new("AtomicCas")
+            .imm(memflags)
+            .value()
+            .value()
+            .value()
+            .typevar_operand(2),
+    );
     registry.insert(Builder::new("StackLoad").imm(stack_slot).imm(offset32));
     registry.insert(
         Builder::new("StackStore")
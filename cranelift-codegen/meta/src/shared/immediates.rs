@@ -118,6 +118,23 @@ pub fn define() -> Vec<OperandKind> {
         .finish();
     kinds.push(memflags);
 
+    // The operation performed by an atomic read-modify-write instruction.
+    // This enumerated operand kind is used for the `atomic_rmw` instruction and corresponds to
+    // the `ir::AtomicRmwOp` Rust type.
+    let mut atomic_rmw_op_values = HashMap::new();
+    atomic_rmw_op_values.insert("add", "Add");
+    atomic_rmw_op_values.insert("sub", "Sub");
+    atomic_rmw_op_values.insert("and", "And");
+    atomic_rmw_op_values.insert("or", "Or");
+    atomic_rmw_op_values.insert("xor", "Xor");
+    atomic_rmw_op_values.insert("xchg", "Xchg");
+    let atomic_rmw_op = Builder::new_enum("atomic_rmw_op", atomic_rmw_op_values)
+        .doc("An atomic read-modify-write operation.")
+        .default_member("op")
+        .rust_type("ir::AtomicRmwOp")
+        .finish();
+    kinds.push(atomic_rmw_op);
+
     // A register unit in the current target ISA.
     let regunit = Builder::new_imm("regunit")
         .doc("A register unit in the target ISA")
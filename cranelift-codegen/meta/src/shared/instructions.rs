@@ -19,6 +19,7 @@ pub fn define(
     let intcc = immediates.by_name("intcc");
     let floatcc = immediates.by_name("floatcc");
     let trapcode = immediates.by_name("trapcode");
+    let atomic_rmw_op = immediates.by_name("atomic_rmw_op");
     let uimm8 = immediates.by_name("uimm8");
     let uimm32 = immediates.by_name("uimm32");
     let imm64 = immediates.by_name("imm64");
@@ -897,6 +898,94 @@ pub fn define(
         .finish(format_registry),
     );
 
+    let AtomicMem = &TypeVar::new(
+        "AtomicMem",
+        "An integer type that can be accessed atomically",
+        TypeSetBuilder::new().ints(Interval::All).finish(),
+    );
+    let x = &operand_doc("x", AtomicMem, "Value to be stored");
+    let a = &operand_doc("a", AtomicMem, "Value loaded");
+    let e = &operand_doc("e", AtomicMem, "Expected value");
+    let AtomicOp = &operand("AtomicOp", atomic_rmw_op);
+
+    ig.push(
+        Inst::new(
+            "atomic_load",
+            r#"
+        Atomically load from memory at ``p + Offset``.
+
+        The load is sequentially consistent with all other atomic memory
+        accesses. The effective address must be naturally aligned for the
+        loaded type.
+        "#,
+        )
+        .operands_in(vec![MemFlags, p, Offset])
+        .operands_out(vec![a])
+        .can_load(true)
+        .other_side_effects(true)
+        .finish(format_registry),
+    );
+
+    ig.push(
+        Inst::new(
+            "atomic_store",
+            r#"
+        Atomically store ``x`` to memory at ``p + Offset``.
+
+        The store is sequentially consistent with all other atomic memory
+        accesses. The effective address must be naturally aligned for the
+        stored type.
+        "#,
+        )
+        .operands_in(vec![MemFlags, x, p, Offset])
+        .can_store(true)
+        .other_side_effects(true)
+        .finish(format_registry),
+    );
+
+    ig.push(
+        Inst::new(
+            "atomic_rmw",
+            r#"
+        Atomically read, modify and write memory at ``p``.
+
+        Load the value at ``p``, combine it with ``x`` using ``AtomicOp`` and
+        store the result back to ``p`` as a single sequentially consistent
+        operation. The ``xchg`` operation stores ``x`` unchanged. Returns
+        the value originally loaded from ``p``.
+
+        The address must be naturally aligned for the accessed type.
+        "#,
+        )
+        .operands_in(vec![MemFlags, AtomicOp, p, x])
+        .operands_out(vec![a])
+        .can_load(true)
+        .can_store(true)
+        .other_side_effects(true)
+        .finish(format_registry),
+    );
+
+    ig.push(
+        Inst::new(
+            "atomic_cas",
+            r#"
+        Atomically compare and swap memory at ``p``.
+
+        Load the value at ``p`` and, if it is equal to ``e``, store ``x`` in
+        its place as a single sequentially consistent operation. Returns the
+        value originally loaded from ``p`` whether or not the store happened.
+
+        The address must be naturally aligned for the accessed type.
+        "#,
+        )
+        .operands_in(vec![MemFlags, p, e, x])
+        .operands_out(vec![a])
+        .can_load(true)
+        .can_store(true)
+        .other_side_effects(true)
+        .finish(format_registry),
+    );
+
     let x = &operand_doc("x", Mem, "Value to be stored");
     let a = &operand_doc("a", Mem, "Value loaded");
     let Offset = &operand_doc("Offset", offset32, "In-bounds offset into stack slot");
@@ -119,6 +119,22 @@ pub fn define() -> SettingGroup {
         false,
     );
 
+    // Frame layout options.
+
+    settings.add_bool(
+        "omit_frame_pointer",
+        r#"
+            Omit the frame pointer in functions using the System V calling
+            convention on x86-64.
+
+            The frame pointer register becomes available to the register allocator
+            and stack slots are addressed relative to the stack pointer. This is
+            only appropriate when stack unwinding doesn't rely on a frame pointer
+            chain, for example because unwind information is emitted separately.
+            "#,
+        false,
+    );
+
     // Stack probing options.
 
     settings.add_bool(
@@ -139,13 +155,25 @@ pub fn define() -> SettingGroup {
         false,
     );
 
+    settings.add_enum(
+        "probestack_strategy",
+        r#"
+            The strategy used for stack probes:
+
+            - outline: Call the probestack function.
+            - inline: Emit an inline sequence probing each page of the new stack
+              frame. This doesn't require a probestack function.
+            "#,
+        vec!["outline", "inline"],
+    );
+
     settings.add_num(
         "probestack_size_log2",
         r#"
             The log2 of the size of the stack guard region.
 
             Stack frames larger than this size will have stack overflow checked
-            by calling the probestack function.
+            by stack probes, probing every page of this size.
 
             The default is 12, which translates to a size of 4096.
             "#,
@@ -39,10 +39,22 @@ pub enum Reloc {
     X86CallPLTRel4,
     /// x86 GOT PC-relative 4-byte
     X86GOTPCRel4,
+    /// x86 PC-relative 4-byte address of the GOT
+    X86GOTPC4,
+    /// x86 GOT PC-relative 4-byte offset of a thread-local symbol from the thread pointer
+    X86GOTTPOff4,
     /// Arm32 call target
     Arm32Call,
     /// Arm64 call target
     Arm64Call,
+    /// Arm64 PC-relative page address, for `adrp`
+    Arm64AdrPrelPgHi21,
+    /// Arm64 low 12 bits of an address, for `add`
+    Arm64AddAbsLo12Nc,
+    /// Arm64 PC-relative page address of a GOT entry, for `adrp`
+    Arm64AdrGotPage21,
+    /// Arm64 low 12 bits of the address of a GOT entry, for `ldr`
+    Arm64Ld64GotLo12Nc,
     /// RISC-V call target
     RiscvCall,
 }
@@ -58,7 +70,13 @@ impl fmt::Display for Reloc {
             Reloc::X86CallPCRel4 => write!(f, "CallPCRel4"),
             Reloc::X86CallPLTRel4 => write!(f, "CallPLTRel4"),
             Reloc::X86GOTPCRel4 => write!(f, "GOTPCRel4"),
+            Reloc::X86GOTPC4 => write!(f, "GOTPC4"),
+            Reloc::X86GOTTPOff4 => write!(f, "GOTTPOff4"),
             Reloc::Arm32Call | Reloc::Arm64Call | Reloc::RiscvCall => write!(f, "Call"),
+            Reloc::Arm64AdrPrelPgHi21 => write!(f, "AdrPrelPgHi21"),
+            Reloc::Arm64AddAbsLo12Nc => write!(f, "AddAbsLo12Nc"),
+            Reloc::Arm64AdrGotPage21 => write!(f, "AdrGotPage21"),
+            Reloc::Arm64Ld64GotLo12Nc => write!(f, "Ld64GotLo12Nc"),
         }
     }
 }
@@ -18,6 +18,7 @@ use crate::flowgraph::ControlFlowGraph;
 use crate::ir::Function;
 use crate::isa::TargetIsa;
 use crate::legalize_function;
+use crate::legalizer::check_tls_symbols;
 use crate::licm::do_licm;
 use crate::loop_analysis::LoopAnalysis;
 use crate::nan_canonicalization::do_nan_canonicalization;
@@ -242,6 +243,7 @@ impl Context {
         // TODO: Avoid doing this when legalization doesn't actually mutate the CFG.
         self.domtree.clear();
         self.loop_analysis.clear();
+        check_tls_symbols(&self.func, isa)?;
         legalize_function(&mut self.func, &mut self.cfg, isa);
         self.verify_if(isa)
     }
@@ -310,7 +312,13 @@ impl Context {
 
     /// Insert prologue and epilogues after computing the stack frame layout.
     pub fn prologue_epilogue(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
+        let num_ebbs = self.func.dfg.num_ebbs();
         isa.prologue_epilogue(&mut self.func)?;
+        // The prologue can split the entry EBB, for example around an inline stack probe loop.
+        if self.func.dfg.num_ebbs() != num_ebbs {
+            self.compute_cfg();
+            self.compute_domtree();
+        }
         self.verify_if(isa)?;
         self.verify_locations_if(isa)?;
         Ok(())
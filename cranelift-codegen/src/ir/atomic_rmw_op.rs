@@ -0,0 +1,67 @@
+//! Operations performed by atomic read-modify-write instructions.
+
+use core::fmt::{self, Display, Formatter};
+use core::str::FromStr;
+
+/// The operation an `atomic_rmw` instruction combines the loaded value and its operand with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum AtomicRmwOp {
+    /// Wrapping integer addition.
+    Add,
+    /// Wrapping integer subtraction.
+    Sub,
+    /// Bitwise and.
+    And,
+    /// Bitwise or.
+    Or,
+    /// Bitwise exclusive or.
+    Xor,
+    /// Store the operand, discarding the loaded value.
+    Xchg,
+}
+
+impl Display for AtomicRmwOp {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use self::AtomicRmwOp::*;
+        f.write_str(match *self {
+            Add => "add",
+            Sub => "sub",
+            And => "and",
+            Or => "or",
+            Xor => "xor",
+            Xchg => "xchg",
+        })
+    }
+}
+
+impl FromStr for AtomicRmwOp {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::AtomicRmwOp::*;
+        match s {
+            "add" => Ok(Add),
+            "sub" => Ok(Sub),
+            "and" => Ok(And),
+            "or" => Ok(Or),
+            "xor" => Ok(Xor),
+            "xchg" => Ok(Xchg),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn display() {
+        use self::AtomicRmwOp::*;
+        for &op in &[Add, Sub, And, Or, Xor, Xchg] {
+            assert_eq!(op.to_string().parse(), Ok(op));
+        }
+        assert_eq!("bogus".parse::<AtomicRmwOp>(), Err(()));
+    }
+}
@@ -30,12 +30,22 @@ pub struct Function {
     /// Signature of this function.
     pub signature: Signature,
 
+    /// The original signature of this function, if its legalized `signature` returns values
+    /// through a struct-return buffer because they don't fit in registers.
+    pub old_signature: Option<Signature>,
+
     /// Stack slots allocated in this function.
     pub stack_slots: StackSlots,
 
     /// Global values referenced.
     pub global_values: PrimaryMap<ir::GlobalValue, ir::GlobalValueData>,
 
+    /// Global value holding the lowest address the stack may grow down to, if any.
+    ///
+    /// When this is set, the prologue checks that the new stack frame doesn't cross the limit,
+    /// and traps with `TrapCode::StackOverflow` otherwise.
+    pub stack_limit: Option<ir::GlobalValue>,
+
     /// Heaps referenced.
     pub heaps: PrimaryMap<ir::Heap, ir::HeapData>,
 
@@ -81,8 +91,10 @@ impl Function {
         Self {
             name,
             signature: sig,
+            old_signature: None,
             stack_slots: StackSlots::new(),
             global_values: PrimaryMap::new(),
+            stack_limit: None,
             heaps: PrimaryMap::new(),
             tables: PrimaryMap::new(),
             jump_tables: PrimaryMap::new(),
@@ -99,8 +111,10 @@ impl Function {
     /// Clear all data structures in this function.
     pub fn clear(&mut self) {
         self.signature.clear(CallConv::Fast);
+        self.old_signature = None;
         self.stack_slots.clear();
         self.global_values.clear();
+        self.stack_limit = None;
         self.heaps.clear();
         self.tables.clear();
         self.jump_tables.clear();
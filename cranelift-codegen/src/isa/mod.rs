@@ -59,10 +59,11 @@ use crate::flowgraph;
 use crate::ir;
 use crate::isa::enc_tables::Encodings;
 use crate::regalloc;
-use crate::result::CodegenResult;
+use crate::result::{CodegenError, CodegenResult};
 use crate::settings;
 use crate::settings::SetResult;
 use crate::timing;
+use crate::verifier::{VerifierError, VerifierErrors};
 use core::fmt;
 use failure_derive::Fail;
 use std::boxed::Box;
@@ -342,6 +343,16 @@ pub trait TargetIsa: fmt::Display + Sync {
 
         let word_size = StackSize::from(self.pointer_bytes());
 
+        // Stack limit checks are only implemented in the x86 prologue.
+        if let Some(gv) = func.stack_limit {
+            return Err(CodegenError::Verifier(VerifierErrors(vec![
+                VerifierError {
+                    location: gv.into(),
+                    message: format!("stack limits are not supported on {}", self.name()),
+                },
+            ])));
+        }
+
         // Account for the SpiderMonkey standard prologue pushes.
         if func.signature.call_conv == CallConv::Baldrdash {
             let bytes = StackSize::from(self.flags().baldrdash_prologue_words()) * word_size;
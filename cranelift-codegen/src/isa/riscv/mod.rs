@@ -126,8 +126,9 @@ impl TargetIsa for Isa {
 #[cfg(test)]
 mod tests {
     use crate::ir::{immediates, types};
-    use crate::ir::{Function, InstructionData, Opcode};
+    use crate::ir::{Function, GlobalValueData, InstructionData, Opcode};
     use crate::isa;
+    use crate::result::CodegenError;
     use crate::settings::{self, Configurable};
     use core::str::FromStr;
     use std::string::{String, ToString};
@@ -272,6 +273,25 @@ mod tests {
             "R#10c"
         );
     }
+
+    #[test]
+    fn stack_limit_unsupported() {
+        let shared_flags = settings::Flags::new(settings::builder());
+        let isa = isa::lookup(triple!("riscv64"))
+            .unwrap()
+            .finish(shared_flags);
+
+        let mut func = Function::new();
+        let limit = func.create_global_value(GlobalValueData::VMContext);
+        func.stack_limit = Some(limit);
+
+        match isa.prologue_epilogue(&mut func) {
+            Err(CodegenError::Verifier(errors)) => {
+                assert!(errors.0[0].message.contains("not supported"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
 }
 
 impl fmt::Display for Isa {
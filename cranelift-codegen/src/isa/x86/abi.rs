@@ -3,7 +3,11 @@
 use super::registers::{FPR, GPR, RU};
 use crate::abi::{legalize_args, ArgAction, ArgAssigner, ValueConversion};
 use crate::cursor::{Cursor, CursorPosition, EncCursor};
+use crate::dominator_tree::DominatorTree;
+use crate::entity::EntitySet;
+use crate::flowgraph::ControlFlowGraph;
 use crate::ir;
+use crate::ir::entities::AnyEntity;
 use crate::ir::immediates::Imm64;
 use crate::ir::stackslot::{StackOffset, StackSize};
 use crate::ir::{
@@ -11,10 +15,15 @@ use crate::ir::{
     ValueLoc,
 };
 use crate::isa::{CallConv, RegClass, RegUnit, TargetIsa};
+use crate::loop_analysis::LoopAnalysis;
 use crate::regalloc::RegisterSet;
-use crate::result::CodegenResult;
+use crate::result::{CodegenError, CodegenResult};
+use crate::settings::{self as shared_settings, OptLevel, ProbestackStrategy};
 use crate::stack_layout::layout_stack;
+use crate::verifier::{VerifierError, VerifierErrors};
 use core::i32;
+use std::string::String;
+use std::vec::Vec;
 use target_lexicon::{PointerWidth, Triple};
 
 /// Argument registers for x86-64
@@ -182,10 +191,16 @@ pub fn regclass_for_abi_type(ty: ir::Type) -> RegClass {
 }
 
 /// Get the set of allocatable registers for `func`.
-pub fn allocatable_registers(_func: &ir::Function, triple: &Triple) -> RegisterSet {
+pub fn allocatable_registers(
+    func: &ir::Function,
+    triple: &Triple,
+    flags: &shared_settings::Flags,
+) -> RegisterSet {
     let mut regs = RegisterSet::new();
     regs.take(GPR, RU::rsp as RegUnit);
-    regs.take(GPR, RU::rbp as RegUnit);
+    if !omits_frame_pointer(flags, triple, func.signature.call_conv) {
+        regs.take(GPR, RU::rbp as RegUnit);
+    }
 
     // 32-bit arch only has 8 registers.
     if triple.pointer_width().unwrap() != PointerWidth::U64 {
@@ -198,6 +213,24 @@ pub fn allocatable_registers(_func: &ir::Function, triple: &Triple) -> RegisterS
     regs
 }
 
+/// Does a function with the `call_conv` calling convention omit the frame pointer?
+///
+/// When the frame pointer is omitted, `%rbp` is an ordinary callee-saved register and stack slots
+/// are addressed relative to `%rsp`, which they always are anyway.
+fn omits_frame_pointer(
+    flags: &shared_settings::Flags,
+    triple: &Triple,
+    call_conv: CallConv,
+) -> bool {
+    if !flags.omit_frame_pointer() || triple.pointer_width().unwrap() != PointerWidth::U64 {
+        return false;
+    }
+    match call_conv {
+        CallConv::Fast | CallConv::Cold | CallConv::SystemV => true,
+        _ => false,
+    }
+}
+
 /// Get the set of callee-saved registers.
 fn callee_saved_gprs(isa: &TargetIsa, call_conv: CallConv) -> &'static [RU] {
     match isa.triple().pointer_width().unwrap() {
@@ -232,6 +265,9 @@ fn callee_saved_gprs_used(isa: &TargetIsa, func: &ir::Function) -> RegisterSet {
     for reg in callee_saved_gprs(isa, func.signature.call_conv) {
         all_callee_saved.free(GPR, *reg as RegUnit);
     }
+    if omits_frame_pointer(isa.flags(), isa.triple(), func.signature.call_conv) {
+        all_callee_saved.free(GPR, RU::rbp as RegUnit);
+    }
 
     let mut used = RegisterSet::empty();
     for value_loc in func.locations.values() {
@@ -287,6 +323,14 @@ fn baldrdash_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Code
         "baldrdash does not expect cranelift to emit stack probes"
     );
 
+    // SpiderMonkey emits its own prologue, so there is nowhere to check the stack limit.
+    if let Some(gv) = func.stack_limit {
+        return Err(abi_error(
+            gv,
+            "stack limits are not supported with the baldrdash calling convention".into(),
+        ));
+    }
+
     // Baldrdash on 32-bit x86 always aligns its stack pointer to 16 bytes.
     let stack_align = 16;
     let word_size = StackSize::from(isa.pointer_bytes());
@@ -364,11 +408,11 @@ fn fastcall_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
     // Set up the cursor and insert the prologue
     let entry_ebb = func.layout.entry_block().expect("missing entry block");
     let mut pos = EncCursor::new(func, isa).at_first_insertion_point(entry_ebb);
-    insert_common_prologue(&mut pos, local_stack_size, reg_type, &csrs, isa);
+    insert_common_prologue(&mut pos, local_stack_size, reg_type, true, &csrs, isa)?;
 
     // Reset the cursor and insert the epilogue
     let mut pos = pos.at_position(CursorPosition::Nowhere);
-    insert_common_epilogues(&mut pos, local_stack_size, reg_type, &csrs);
+    insert_common_epilogues(&mut pos, local_stack_size, reg_type, true, &csrs, None);
 
     Ok(())
 }
@@ -384,14 +428,37 @@ fn system_v_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
 
     let csrs = callee_saved_gprs_used(isa, func);
 
+    // Functions with an early exit path don't need to save the callee-saved registers on that
+    // path. Try to move the saves and restores to where the registers are actually used. The
+    // shrink-wrapped registers are stored in spill slots instead of being pushed.
+    let shrink_wrap = if isa.flags().opt_level() != OptLevel::Fastest {
+        shrink_wrap_csrs(func, &csrs)
+    } else {
+        None
+    };
+    let (pushed_csrs, csr_slots) = match shrink_wrap {
+        Some(_) => {
+            let slots = csrs
+                .iter(GPR)
+                .map(|reg| (reg, func.stack_slots.make_spill_slot(reg_type)))
+                .collect::<Vec<_>>();
+            (RegisterSet::empty(), slots)
+        }
+        None => (csrs.clone(), Vec::new()),
+    };
+
+    // Without a frame pointer, `%rbp` is just another callee-saved register.
+    let frame_pointer = !omits_frame_pointer(isa.flags(), isa.triple(), func.signature.call_conv);
+
     // The reserved stack area is composed of:
-    //   return address + frame pointer + all callee-saved registers
+    //   return address + frame pointer (if any) + all pushed callee-saved registers
     //
     // Pushing the return address is an implicit function of the `call`
     // instruction. Each of the others we will then push explicitly. Then we
     // will adjust the stack pointer to make room for the rest of the required
     // space for this frame.
-    let csr_stack_size = ((csrs.iter(GPR).len() + 2) * word_size) as i32;
+    let csr_stack_size =
+        ((pushed_csrs.iter(GPR).len() + 1 + frame_pointer as usize) * word_size) as i32;
     func.create_stack_slot(ir::StackSlotData {
         kind: ir::StackSlotKind::IncomingArg,
         size: csr_stack_size as u32,
@@ -399,16 +466,28 @@ fn system_v_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
     });
 
     let total_stack_size = layout_stack(&mut func.stack_slots, stack_align)? as i32;
-    let local_stack_size = i64::from(total_stack_size - csr_stack_size);
+    let mut local_stack_size = i64::from(total_stack_size - csr_stack_size);
+
+    // Without a frame pointer push, the stack pointer is misaligned on entry. A leaf function
+    // without stack slots never depends on its alignment, so don't bother padding it. The frame
+    // then only holds the pushed registers, and the incoming arguments are addressed relative to
+    // the stack pointer accordingly.
+    if !frame_pointer && is_leaf_without_stack_slots(func) {
+        local_stack_size = 0;
+        let total_stack_size = layout_stack(&mut func.stack_slots, word_size as StackSize)?;
+        debug_assert_eq!(total_stack_size, csr_stack_size as StackSize);
+    }
 
     // Add CSRs to function signature
-    let fp_arg = ir::AbiParam::special_reg(
-        reg_type,
-        ir::ArgumentPurpose::FramePointer,
-        RU::rbp as RegUnit,
-    );
-    func.signature.params.push(fp_arg);
-    func.signature.returns.push(fp_arg);
+    if frame_pointer {
+        let fp_arg = ir::AbiParam::special_reg(
+            reg_type,
+            ir::ArgumentPurpose::FramePointer,
+            RU::rbp as RegUnit,
+        );
+        func.signature.params.push(fp_arg);
+        func.signature.returns.push(fp_arg);
+    }
 
     for csr in csrs.iter(GPR) {
         let csr_arg = ir::AbiParam::special_reg(reg_type, ir::ArgumentPurpose::CalleeSaved, csr);
@@ -419,47 +498,272 @@ fn system_v_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
     // Set up the cursor and insert the prologue
     let entry_ebb = func.layout.entry_block().expect("missing entry block");
     let mut pos = EncCursor::new(func, isa).at_first_insertion_point(entry_ebb);
-    insert_common_prologue(&mut pos, local_stack_size, reg_type, &csrs, isa);
+    insert_common_prologue(
+        &mut pos,
+        local_stack_size,
+        reg_type,
+        frame_pointer,
+        &pushed_csrs,
+        isa,
+    )?;
+
+    // Save the shrink-wrapped registers away from the prologue.
+    let saved_csrs = match shrink_wrap {
+        Some(ref sw) => insert_shrink_wrapped_saves(&mut pos, reg_type, sw, &csr_slots),
+        None => Vec::new(),
+    };
 
     // Reset the cursor and insert the epilogue
     let mut pos = pos.at_position(CursorPosition::Nowhere);
-    insert_common_epilogues(&mut pos, local_stack_size, reg_type, &csrs);
+    insert_common_epilogues(
+        &mut pos,
+        local_stack_size,
+        reg_type,
+        frame_pointer,
+        &pushed_csrs,
+        shrink_wrap.as_ref().map(|sw| (sw, &saved_csrs[..])),
+    );
 
     Ok(())
 }
 
+/// Does `func` make no calls and use no stack slots other than its incoming arguments?
+fn is_leaf_without_stack_slots(func: &ir::Function) -> bool {
+    func.stack_slots
+        .values()
+        .all(|ss| ss.kind == ir::StackSlotKind::IncomingArg)
+        && func.layout.ebbs().all(|ebb| {
+            func.layout
+                .ebb_insts(ebb)
+                .all(|inst| !func.dfg[inst].opcode().is_call())
+        })
+}
+
+/// Placement of callee-saved register saves and restores around the part of a function that
+/// uses them, instead of in the prologue and epilogues.
+struct ShrinkWrap {
+    /// The EBB where the callee-saved registers are saved.
+    save_ebb: ir::Ebb,
+
+    /// EBBs ending in a return that is dominated by `save_ebb`. The callee-saved registers must be
+    /// restored before these returns. No other returns are reachable from `save_ebb`.
+    restore_ebbs: Vec<ir::Ebb>,
+}
+
+/// A callee-saved register that has been saved by shrink-wrapping.
+struct SavedCsr {
+    /// The saved register.
+    reg: RegUnit,
+
+    /// The incoming value of the register, as passed to the entry block.
+    incoming: ir::Value,
+
+    /// The value spilled to the register's save slot in the save EBB.
+    saved: ir::Value,
+}
+
+/// Compute a shrink-wrapping placement for the callee-saved registers in `csrs`.
+///
+/// The registers are saved at the top of the nearest common dominator of all EBBs that use them,
+/// and they are restored before the returns dominated by that EBB. This requires that the save
+/// EBB can't execute more than once, and that no other returns are reachable from it.
+///
+/// Returns `None` when the registers would be saved in the entry block anyway, when every return
+/// would need a restore, or when the placement isn't possible. The saves and restores should then
+/// go in the prologue and epilogues.
+fn shrink_wrap_csrs(func: &ir::Function, csrs: &RegisterSet) -> Option<ShrinkWrap> {
+    if csrs.iter(GPR).len() == 0 {
+        return None;
+    }
+
+    let layout = &func.layout;
+    let entry = layout.entry_block()?;
+    let cfg = ControlFlowGraph::with_function(func);
+    let domtree = DominatorTree::with_function(func, &cfg);
+    let idom_ebb = |ebb| {
+        domtree
+            .idom(ebb)
+            .and_then(|inst| layout.inst_ebb(inst))
+            .expect("reachable EBB must have an immediate dominator")
+    };
+
+    // Find the nearest common dominator of all the EBBs using a callee-saved register.
+    let mut save_ebb: Option<ir::Ebb> = None;
+    for ebb in layout.ebbs() {
+        if !domtree.is_reachable(ebb) || !ebb_uses_regs(func, ebb, csrs) {
+            continue;
+        }
+        let mut dom = save_ebb.unwrap_or(ebb);
+        while !domtree.dominates(dom, ebb, layout) {
+            dom = idom_ebb(dom);
+        }
+        save_ebb = Some(dom);
+    }
+    let mut save_ebb = save_ebb?;
+
+    // Saving the registers a second time would overwrite the saved values with clobbered ones, so
+    // hoist the save point out of any loops.
+    let mut loop_analysis = LoopAnalysis::new();
+    loop_analysis.compute(func, &cfg, &domtree);
+    while let Some(lp) = loop_analysis
+        .loops()
+        .find(|&lp| loop_analysis.is_in_loop(save_ebb, lp))
+    {
+        let header = loop_analysis.loop_header(lp);
+        if header == entry {
+            return None;
+        }
+        save_ebb = idom_ebb(header);
+    }
+
+    if save_ebb == entry {
+        return None;
+    }
+
+    // Find all the EBBs reachable from the save point.
+    let mut reachable = EntitySet::new();
+    let mut worklist = vec![save_ebb];
+    while let Some(ebb) = worklist.pop() {
+        if reachable.insert(ebb) {
+            worklist.extend(cfg.succ_iter(ebb));
+        }
+    }
+
+    let mut restore_ebbs = Vec::new();
+    let mut early_exit = false;
+    for ebb in layout.ebbs() {
+        let is_return = layout
+            .last_inst(ebb)
+            .map_or(false, |inst| func.dfg[inst].opcode().is_return());
+        if !is_return {
+            continue;
+        }
+        if domtree.dominates(save_ebb, ebb, layout) {
+            restore_ebbs.push(ebb);
+        } else if reachable.contains(ebb) {
+            // This return could be reached both with and without saving the registers.
+            return None;
+        } else if domtree.is_reachable(ebb) {
+            early_exit = true;
+        }
+    }
+
+    if !early_exit {
+        return None;
+    }
+
+    Some(ShrinkWrap {
+        save_ebb,
+        restore_ebbs,
+    })
+}
+
+/// Does `ebb` read or write any of the registers in `regs`?
+fn ebb_uses_regs(func: &ir::Function, ebb: ir::Ebb, regs: &RegisterSet) -> bool {
+    let in_regs = |value: &ir::Value| match func.locations[*value] {
+        ValueLoc::Reg(ru) => regs.is_avail(GPR, ru),
+        _ => false,
+    };
+
+    if func.dfg.ebb_params(ebb).iter().any(in_regs) {
+        return true;
+    }
+
+    for inst in func.layout.ebb_insts(ebb) {
+        if func.dfg.inst_args(inst).iter().any(in_regs)
+            || func.dfg.inst_results(inst).iter().any(in_regs)
+        {
+            return true;
+        }
+
+        // Diversions are not reflected in `func.locations`.
+        match func.dfg[inst] {
+            ir::instructions::InstructionData::RegMove { dst, .. }
+            | ir::instructions::InstructionData::RegFill { dst, .. } => {
+                if regs.is_avail(GPR, dst) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    false
+}
+
+/// Add entry block parameters for the shrink-wrapped callee-saved registers, and save them to
+/// their stack slots at the top of the save EBB.
+fn insert_shrink_wrapped_saves(
+    pos: &mut EncCursor,
+    reg_type: ir::types::Type,
+    shrink_wrap: &ShrinkWrap,
+    slots: &[(RegUnit, ir::StackSlot)],
+) -> Vec<SavedCsr> {
+    let entry_ebb = pos.func.layout.entry_block().expect("missing entry block");
+    pos.goto_first_insertion_point(shrink_wrap.save_ebb);
+
+    let mut saved_csrs = Vec::with_capacity(slots.len());
+    for &(reg, slot) in slots {
+        let incoming = pos.func.dfg.append_ebb_param(entry_ebb, reg_type);
+        pos.func.locations[incoming] = ir::ValueLoc::Reg(reg);
+
+        let saved = pos.ins().spill(incoming);
+        pos.func.locations[saved] = ir::ValueLoc::Stack(slot);
+
+        saved_csrs.push(SavedCsr {
+            reg,
+            incoming,
+            saved,
+        });
+    }
+    saved_csrs
+}
+
 /// Insert the prologue for a given function.
 /// This is used by common calling conventions such as System V.
+///
+/// The frame pointer is only set up if `frame_pointer` is true.
 fn insert_common_prologue(
     pos: &mut EncCursor,
     stack_size: i64,
     reg_type: ir::types::Type,
+    frame_pointer: bool,
     csrs: &RegisterSet,
     isa: &TargetIsa,
-) {
-    if stack_size > 0 {
-        // Check if there is a special stack limit parameter. If so insert stack check.
-        if let Some(stack_limit_arg) = pos.func.special_param(ArgumentPurpose::StackLimit) {
-            // Total stack size is the size of all stack area used by the function, including
-            // pushed CSRs, frame pointer.
-            // Also, the size of a return address, implicitly pushed by a x86 `call` instruction,
-            // also should be accounted for.
-            // TODO: Check if the function body actually contains a `call` instruction.
-            let word_size = isa.pointer_bytes();
-            let total_stack_size = (csrs.iter(GPR).len() + 1 + 1) as i64 * word_size as i64;
-
-            insert_stack_check(pos, total_stack_size, stack_limit_arg);
-        }
+) -> CodegenResult<()> {
+    // Check if there is a special stack limit parameter or a stack limit global value. If so
+    // insert stack check. This is done even without a local frame, since the pushed registers
+    // still use the stack, and frameless recursive functions could overflow it otherwise.
+    let stack_limit = match pos.func.special_param(ArgumentPurpose::StackLimit) {
+        Some(stack_limit_arg) => Some(stack_limit_arg),
+        None => match pos.func.stack_limit {
+            Some(gv) => Some(insert_global_value_load(pos, gv, RU::rax as RegUnit)?),
+            None => None,
+        },
+    };
+    if let Some(stack_limit) = stack_limit {
+        // Total stack size is the size of all stack area used by the function, including
+        // the local frame, pushed CSRs and frame pointer.
+        // Also, the size of a return address, implicitly pushed by a x86 `call` instruction,
+        // also should be accounted for.
+        // TODO: Check if the function body actually contains a `call` instruction.
+        let word_size = isa.pointer_bytes();
+        let pushed_size =
+            (csrs.iter(GPR).len() + 1 + frame_pointer as usize) as i64 * word_size as i64;
+
+        insert_stack_check(pos, stack_size + pushed_size, stack_limit);
     }
 
     // Append param to entry EBB
     let ebb = pos.current_ebb().expect("missing ebb under cursor");
-    let fp = pos.func.dfg.append_ebb_param(ebb, reg_type);
-    pos.func.locations[fp] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
+    if frame_pointer {
+        let fp = pos.func.dfg.append_ebb_param(ebb, reg_type);
+        pos.func.locations[fp] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
 
-    pos.ins().x86_push(fp);
-    pos.ins()
-        .copy_special(RU::rsp as RegUnit, RU::rbp as RegUnit);
+        pos.ins().x86_push(fp);
+        pos.ins()
+            .copy_special(RU::rsp as RegUnit, RU::rbp as RegUnit);
+    }
 
     for reg in csrs.iter(GPR) {
         // Append param to entry EBB
@@ -474,9 +778,11 @@ fn insert_common_prologue(
 
     // Allocate stack frame storage.
     if stack_size > 0 {
-        if isa.flags().probestack_enabled()
-            && stack_size > (1 << isa.flags().probestack_size_log2())
-        {
+        let probestack = isa.flags().probestack_enabled()
+            && stack_size > (1 << isa.flags().probestack_size_log2());
+        if probestack && isa.flags().probestack_strategy() == ProbestackStrategy::Inline {
+            insert_inline_stack_probes(pos, stack_size, reg_type, isa)?;
+        } else if probestack {
             // Emit a stack probe.
             let rax = RU::rax as RegUnit;
             let rax_val = ir::ValueLoc::Reg(rax);
@@ -516,6 +822,179 @@ fn insert_common_prologue(
             pos.ins().adjust_sp_down_imm(Imm64::new(stack_size));
         }
     }
+    Ok(())
+}
+
+/// Frames needing more page probes than this use a loop instead of an unrolled sequence.
+const INLINE_PROBESTACK_UNROLL_LIMIT: i64 = 4;
+
+/// Registers that the inline stack probes may clobber. They are caller-saved and aren't used to
+/// pass arguments under any of the calling conventions using the common prologue, but special
+/// purpose parameters can still be assigned to them.
+static PROBESTACK_SCRATCH_GPRS: [RU; 3] = [RU::r11, RU::r10, RU::rax];
+
+/// Allocate `stack_size` bytes of stack frame storage, probing each page of the new frame in
+/// order so that a guard page of `1 << probestack_size_log2` bytes is always hit.
+///
+/// For each page, the stack pointer is moved down by all but one word of the page, and a word is
+/// pushed. This moves the stack pointer down by a whole page, and probes the word it now points
+/// to. The part of the frame smaller than a page is allocated and probed the same way, so no
+/// part of the new frame is ever more than a page below the last probe. When there are too many
+/// pages to unroll the probes, this splits the current EBB around a loop. The cursor is left
+/// where the prologue continues.
+fn insert_inline_stack_probes(
+    pos: &mut EncCursor,
+    stack_size: i64,
+    reg_type: ir::types::Type,
+    isa: &TargetIsa,
+) -> CodegenResult<()> {
+    let page_size = 1i64 << isa.flags().probestack_size_log2();
+    let word_size = i64::from(isa.pointer_bytes());
+    let probe_count = stack_size / page_size;
+    let remainder = stack_size - probe_count * page_size;
+    debug_assert!(remainder % word_size == 0);
+
+    // The pushed value is never read, but the loop also keeps its counter in the scratch
+    // register.
+    let scratch = ir::ValueLoc::Reg(probestack_scratch_reg(pos.func)?);
+
+    if probe_count <= INLINE_PROBESTACK_UNROLL_LIMIT {
+        let probe = pos.ins().iconst(reg_type, 0);
+        pos.func.locations[probe] = scratch;
+        for _ in 0..probe_count {
+            pos.ins()
+                .adjust_sp_down_imm(Imm64::new(page_size - word_size));
+            pos.ins().x86_push(probe);
+        }
+        if remainder > 0 {
+            insert_remainder_probe(pos, remainder, word_size, probe);
+        }
+    } else {
+        // The loop counts down the remaining pages in the scratch register, and pushes the
+        // counter itself as the probe.
+        let count = pos.ins().iconst(reg_type, probe_count);
+        pos.func.locations[count] = scratch;
+
+        let loop_ebb = pos.func.dfg.make_ebb();
+        let body_ebb = pos.func.dfg.make_ebb();
+        let entry_ebb = pos.current_ebb().expect("missing ebb under cursor");
+        let first_inst = pos.current_inst().expect("missing terminator in entry ebb");
+        pos.func.layout.split_ebb(body_ebb, first_inst);
+        pos.func.layout.insert_ebb(loop_ebb, body_ebb);
+
+        pos.goto_bottom(entry_ebb);
+        pos.ins().jump(loop_ebb, &[count]);
+
+        pos.goto_bottom(loop_ebb);
+        let remaining = pos.func.dfg.append_ebb_param(loop_ebb, reg_type);
+        pos.func.locations[remaining] = scratch;
+        pos.ins()
+            .adjust_sp_down_imm(Imm64::new(page_size - word_size));
+        pos.ins().x86_push(remaining);
+        let next = pos.ins().iadd_imm(remaining, -1);
+        pos.func.locations[next] = scratch;
+        pos.ins().brnz(next, loop_ebb, &[next]);
+        if remainder > 0 {
+            insert_remainder_probe(pos, remainder, word_size, next);
+        }
+        pos.ins().jump(body_ebb, &[]);
+
+        pos.goto_first_insertion_point(body_ebb);
+    }
+    Ok(())
+}
+
+/// Allocate and probe the `remainder` bytes of the frame which are smaller than a page, by
+/// pushing `probe`.
+fn insert_remainder_probe(pos: &mut EncCursor, remainder: i64, word_size: i64, probe: ir::Value) {
+    if remainder > word_size {
+        pos.ins()
+            .adjust_sp_down_imm(Imm64::new(remainder - word_size));
+    }
+    pos.ins().x86_push(probe);
+}
+
+/// Find a register the inline stack probes can clobber, which doesn't hold any of the
+/// parameters of `func`.
+fn probestack_scratch_reg(func: &ir::Function) -> CodegenResult<RegUnit> {
+    let entry = func.layout.entry_block().expect("missing entry block");
+    let params = func.dfg.ebb_params(entry);
+    PROBESTACK_SCRATCH_GPRS
+        .iter()
+        .map(|&reg| reg as RegUnit)
+        .find(|&reg| {
+            params
+                .iter()
+                .all(|&param| func.locations[param] != ir::ValueLoc::Reg(reg))
+        })
+        .ok_or_else(|| {
+            abi_error(
+                entry,
+                "no free scratch register for the inline stack probes".into(),
+            )
+        })
+}
+
+/// Report an ABI problem in the function being compiled as a verifier error at `location`.
+fn abi_error<E: Into<AnyEntity>>(location: E, message: String) -> CodegenError {
+    CodegenError::Verifier(VerifierErrors(vec![VerifierError {
+        location: location.into(),
+        message,
+    }]))
+}
+
+/// Compute the value of the global value `gv` at the top of the prologue, into `scratch`.
+///
+/// Only global values derived from the VM context by loads and additions are supported, since
+/// the rest of the function's values haven't been computed yet.
+fn insert_global_value_load(
+    pos: &mut EncCursor,
+    gv: ir::GlobalValue,
+    scratch: RegUnit,
+) -> CodegenResult<ir::Value> {
+    match pos.func.global_values[gv] {
+        ir::GlobalValueData::VMContext => {
+            let vmctx = match pos.func.special_param(ArgumentPurpose::VMContext) {
+                Some(vmctx) => vmctx,
+                None => {
+                    return Err(abi_error(
+                        gv,
+                        "the stack limit needs a vmctx parameter".into(),
+                    ))
+                }
+            };
+            match pos.func.locations[vmctx] {
+                ir::ValueLoc::Reg(_) => Ok(vmctx),
+                _ => Err(abi_error(
+                    gv,
+                    "vmctx for the stack limit must be passed in a register".into(),
+                )),
+            }
+        }
+        ir::GlobalValueData::Load {
+            base,
+            offset,
+            global_type,
+            ..
+        } => {
+            let base = insert_global_value_load(pos, base, scratch)?;
+            let value = pos
+                .ins()
+                .load(global_type, ir::MemFlags::trusted(), base, offset);
+            pos.func.locations[value] = ir::ValueLoc::Reg(scratch);
+            Ok(value)
+        }
+        ir::GlobalValueData::IAddImm { base, offset, .. } => {
+            let base = insert_global_value_load(pos, base, scratch)?;
+            let value = pos.ins().iadd_imm(base, offset);
+            pos.func.locations[value] = ir::ValueLoc::Reg(scratch);
+            Ok(value)
+        }
+        ref other => Err(abi_error(
+            gv,
+            format!("unsupported stack limit global value: {}", other),
+        )),
+    }
 }
 
 /// Insert a check that generates a trap if the stack pointer goes
@@ -542,17 +1021,42 @@ fn insert_stack_check(pos: &mut EncCursor, stack_size: i64, stack_limit_arg: ir:
 }
 
 /// Find all `return` instructions and insert epilogues before them.
+///
+/// If some callee-saved registers were shrink-wrapped, they are restored before the returns that
+/// need it, and passed through unchanged to the others.
 fn insert_common_epilogues(
     pos: &mut EncCursor,
     stack_size: i64,
     reg_type: ir::types::Type,
+    frame_pointer: bool,
     csrs: &RegisterSet,
+    shrink_wrap: Option<(&ShrinkWrap, &[SavedCsr])>,
 ) {
     while let Some(ebb) = pos.next_ebb() {
         pos.goto_last_inst(ebb);
         if let Some(inst) = pos.current_inst() {
             if pos.func.dfg[inst].opcode().is_return() {
-                insert_common_epilogue(inst, stack_size, pos, reg_type, csrs);
+                // Restore the shrink-wrapped registers while the stack slots are still
+                // addressable.
+                let mut csr_rets = Vec::new();
+                if let Some((sw, saved_csrs)) = shrink_wrap {
+                    let restore = sw.restore_ebbs.contains(&ebb);
+                    for csr in saved_csrs {
+                        if restore {
+                            let csr_ret = pos.ins().fill(csr.saved);
+                            pos.func.locations[csr_ret] = ir::ValueLoc::Reg(csr.reg);
+                            csr_rets.push(csr_ret);
+                        } else {
+                            csr_rets.push(csr.incoming);
+                        }
+                    }
+                }
+
+                insert_common_epilogue(inst, stack_size, pos, reg_type, frame_pointer, csrs);
+
+                for csr_ret in csr_rets {
+                    pos.func.dfg.append_inst_arg(inst, csr_ret);
+                }
             }
         }
     }
@@ -565,6 +1069,7 @@ fn insert_common_epilogue(
     stack_size: i64,
     pos: &mut EncCursor,
     reg_type: ir::types::Type,
+    frame_pointer: bool,
     csrs: &RegisterSet,
 ) {
     if stack_size > 0 {
@@ -573,11 +1078,13 @@ fn insert_common_epilogue(
 
     // Pop all the callee-saved registers, stepping backward each time to
     // preserve the correct order.
-    let fp_ret = pos.ins().x86_pop(reg_type);
-    pos.prev_inst();
+    if frame_pointer {
+        let fp_ret = pos.ins().x86_pop(reg_type);
+        pos.prev_inst();
 
-    pos.func.locations[fp_ret] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
-    pos.func.dfg.append_inst_arg(inst, fp_ret);
+        pos.func.locations[fp_ret] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
+        pos.func.dfg.append_inst_arg(inst, fp_ret);
+    }
 
     for reg in csrs.iter(GPR) {
         let csr_ret = pos.ins().x86_pop(reg_type);
@@ -587,3 +1094,122 @@ fn insert_common_epilogue(
         pos.func.dfg.append_inst_arg(inst, csr_ret);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cursor::{Cursor, FuncCursor};
+    use crate::ir::immediates::{Imm64, Offset32};
+    use crate::ir::{
+        types, AbiParam, ArgumentPurpose, ExternalName, Function, GlobalValueData, InstBuilder,
+        Signature,
+    };
+    use crate::isa::{self, CallConv};
+    use crate::result::CodegenError;
+    use crate::settings::{self, Configurable};
+    use crate::Context;
+    use core::str::FromStr;
+    use target_lexicon::triple;
+
+    /// Compile an empty function whose stack limit is `limit`, which may refer to the
+    /// function's `vmctx` parameter as `gv0`.
+    fn compile_with_limit(call_conv: CallConv, limit: GlobalValueData) -> Result<(), CodegenError> {
+        let mut flags = settings::builder();
+        // Baldrdash does not expect Cranelift to emit stack probes.
+        flags.set("probestack_enabled", "false").unwrap();
+        let isa = isa::lookup(triple!("x86_64"))
+            .unwrap()
+            .finish(settings::Flags::new(flags));
+
+        let mut sig = Signature::new(call_conv);
+        sig.params
+            .push(AbiParam::special(types::I64, ArgumentPurpose::VMContext));
+        let mut func = Function::with_name_signature(ExternalName::testcase("limit"), sig);
+        func.create_global_value(GlobalValueData::VMContext);
+        func.stack_limit = Some(func.create_global_value(limit));
+
+        let ebb = func.dfg.make_ebb();
+        func.dfg.append_ebb_param(ebb, types::I64);
+        let mut pos = FuncCursor::new(&mut func);
+        pos.insert_ebb(ebb);
+        pos.ins().return_(&[]);
+
+        Context::for_function(func).compile(&*isa).map(|_| ())
+    }
+
+    fn vmctx_limit() -> GlobalValueData {
+        GlobalValueData::Load {
+            base: crate::ir::GlobalValue::with_number(0).unwrap(),
+            offset: Offset32::new(8),
+            global_type: types::I64,
+            readonly: true,
+        }
+    }
+
+    #[test]
+    fn stack_limit_from_vmctx() {
+        assert!(compile_with_limit(CallConv::SystemV, vmctx_limit()).is_ok());
+    }
+
+    #[test]
+    fn unsupported_stack_limit_kind() {
+        let limit = GlobalValueData::Symbol {
+            name: ExternalName::testcase("limit"),
+            offset: Imm64::new(0),
+            colocated: false,
+            tls: false,
+        };
+        match compile_with_limit(CallConv::SystemV, limit) {
+            Err(CodegenError::Verifier(errors)) => {
+                assert!(errors.0[0].message.contains("unsupported stack limit"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frameless_leaf_stack_args() {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "best").unwrap();
+        flags.enable("omit_frame_pointer").unwrap();
+        let isa = isa::lookup(triple!("x86_64"))
+            .unwrap()
+            .finish(settings::Flags::new(flags));
+
+        // fn(a, b, c, d, e, f, g) -> g, whose last parameter is passed on the stack.
+        let mut sig = Signature::new(CallConv::SystemV);
+        sig.params = vec![AbiParam::new(types::I64); 7];
+        sig.returns.push(AbiParam::new(types::I64));
+        let mut func = Function::with_name_signature(ExternalName::testcase("args"), sig);
+        let ebb = func.dfg.make_ebb();
+        for _ in 0..7 {
+            func.dfg.append_ebb_param(ebb, types::I64);
+        }
+        let last = func.dfg.ebb_params(ebb)[6];
+        let mut pos = FuncCursor::new(&mut func);
+        pos.insert_ebb(ebb);
+        pos.ins().return_(&[last]);
+
+        let mut ctx = Context::for_function(func);
+        ctx.compile(&*isa).unwrap();
+
+        // Only the return address is on the stack, so the argument is right above it.
+        let func = &ctx.func;
+        assert_eq!(func.stack_slots.frame_size, Some(8));
+        let arg = func
+            .stack_slots
+            .values()
+            .find(|ss| ss.offset == Some(0))
+            .expect("missing incoming argument slot");
+        assert_eq!(arg.size, 8);
+    }
+
+    #[test]
+    fn baldrdash_stack_limit() {
+        match compile_with_limit(CallConv::Baldrdash, vmctx_limit()) {
+            Err(CodegenError::Verifier(errors)) => {
+                assert!(errors.0[0].message.contains("baldrdash"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -114,7 +114,7 @@ impl TargetIsa for Isa {
     }
 
     fn allocatable_registers(&self, func: &ir::Function) -> regalloc::RegisterSet {
-        abi::allocatable_registers(func, &self.triple)
+        abi::allocatable_registers(func, &self.triple, &self.shared_flags)
     }
 
     #[cfg(feature = "testing_hooks")]
@@ -16,6 +16,11 @@
 //!
 //! Between the two phases, preamble signatures and call/return arguments don't match. This
 //! intermediate state doesn't type check.
+//!
+//! When a signature has more return values than the ABI can return in registers, the legalized
+//! signature takes a `sret` pointer to a buffer instead. The return instructions store the return
+//! values into that buffer, and the callers allocate it on their stack and load the return values
+//! from it after the call.
 
 use crate::abi::{legalize_abi_value, ValueConversion};
 use crate::cursor::{Cursor, FuncCursor};
@@ -23,7 +28,7 @@ use crate::flowgraph::ControlFlowGraph;
 use crate::ir::instructions::CallInfo;
 use crate::ir::{
     AbiParam, ArgumentLoc, ArgumentPurpose, DataFlowGraph, Ebb, Function, Inst, InstBuilder,
-    SigRef, Signature, Type, Value, ValueLoc,
+    MemFlags, SigRef, Signature, StackSlotData, StackSlotKind, Type, Value, ValueLoc,
 };
 use crate::isa::TargetIsa;
 use crate::legalizer::split::{isplit, vsplit};
@@ -36,9 +41,9 @@ use std::vec::Vec;
 /// change the entry block arguments, calls, or return instructions, so this can leave the function
 /// in a state with type discrepancies.
 pub fn legalize_signatures(func: &mut Function, isa: &TargetIsa) {
-    legalize_signature(&mut func.signature, true, isa);
-    for sig_data in func.dfg.signatures.values_mut() {
-        legalize_signature(sig_data, false, isa);
+    func.old_signature = legalize_signature(&mut func.signature, true, isa);
+    for (sig_ref, sig_data) in func.dfg.signatures.iter_mut() {
+        func.dfg.old_signatures[sig_ref] = legalize_signature(sig_data, false, isa);
     }
 
     if let Some(entry) = func.layout.entry_block() {
@@ -50,14 +55,63 @@ pub fn legalize_signatures(func: &mut Function, isa: &TargetIsa) {
 /// Legalize the libcall signature, which we may generate on the fly after
 /// `legalize_signatures` has been called.
 pub fn legalize_libcall_signature(signature: &mut Signature, isa: &TargetIsa) {
-    legalize_signature(signature, false, isa);
+    isa.legalize_signature(signature, false);
 }
 
 /// Legalize the given signature.
 ///
 /// `current` is true if this is the signature for the current function.
-fn legalize_signature(signature: &mut Signature, current: bool, isa: &TargetIsa) {
+///
+/// If the return values don't all fit in registers, the signature is rewritten to take a `sret`
+/// pointer to a buffer holding the return values, and return that pointer instead. The original
+/// signature is returned in that case.
+fn legalize_signature(
+    signature: &mut Signature,
+    current: bool,
+    isa: &TargetIsa,
+) -> Option<Signature> {
+    let original = signature.clone();
+    isa.legalize_signature(signature, current);
+
+    // Return values explicitly assigned to the stack are left alone.
+    if original
+        .returns
+        .iter()
+        .any(|ret| ret.location.is_assigned())
+        || !signature.returns.iter().any(|ret| ret.location.is_stack())
+    {
+        return None;
+    }
+
+    debug_assert!(
+        original
+            .special_param_index(ArgumentPurpose::StructReturn)
+            .is_none(),
+        "Can't return values on the stack with an existing sret parameter: {}",
+        original
+    );
+    let sret = AbiParam::special(isa.pointer_type(), ArgumentPurpose::StructReturn);
+    *signature = original.clone();
+    signature.params.insert(0, sret);
+    signature.returns = vec![sret];
     isa.legalize_signature(signature, current);
+    Some(original)
+}
+
+/// Compute the offset of each value in a struct-return buffer holding values of the given types.
+///
+/// Every value is naturally aligned. Returns the offsets along with the size of the buffer.
+fn sret_layout<I: Iterator<Item = Type>>(types: I) -> (Vec<i32>, u32) {
+    let mut size = 0;
+    let offsets = types
+        .map(|ty| {
+            let bytes = ty.bytes();
+            let offset = (size + bytes - 1) / bytes * bytes;
+            size = offset + bytes;
+            offset as i32
+        })
+        .collect();
+    (offsets, size)
 }
 
 /// Legalize the entry block parameters after `func`'s signature has been legalized.
@@ -86,6 +140,17 @@ fn legalize_entry_params(func: &mut Function, entry: Ebb) {
     // Process the EBB parameters one at a time, possibly replacing one argument with multiple new
     // ones. We do this by detaching the entry EBB parameters first.
     let ebb_params = pos.func.dfg.detach_ebb_params(entry);
+
+    // A `sret` parameter added for the return values is the first parameter, and has no
+    // counterpart among the original EBB parameters.
+    if pos.func.old_signature.is_some() {
+        let sret = pos.func.signature.params[0];
+        debug_assert_eq!(sret.purpose, ArgumentPurpose::StructReturn);
+        pos.func.dfg.append_ebb_param(entry, sret.value_type);
+        has_sret = true;
+        abi_arg += 1;
+    }
+
     let mut old_arg = 0;
     while let Some(arg) = ebb_params.get(old_arg, &pos.func.dfg.value_lists) {
         old_arg += 1;
@@ -528,6 +593,11 @@ pub fn handle_call_abi(mut inst: Inst, func: &mut Function, cfg: &ControlFlowGra
         Err(s) => s,
     };
 
+    // Return values passed through a struct-return buffer are loaded from it after the call.
+    if pos.func.dfg.old_signatures[sig_ref].is_some() {
+        inst = legalize_sret_call(pos, sig_ref, inst);
+    }
+
     // OK, we need to fix the call arguments to match the ABI signature.
     let abi_args = pos.func.dfg.signatures[sig_ref].params.len();
     legalize_inst_arguments(pos, cfg, abi_args, |func, abi_arg| {
@@ -556,6 +626,62 @@ pub fn handle_call_abi(mut inst: Inst, func: &mut Function, cfg: &ControlFlowGra
     true
 }
 
+/// Rewrite the call instruction at `pos` to pass its return values through a struct-return
+/// buffer allocated in a new stack slot.
+///
+/// The buffer address is passed as the `sret` argument, and the original call results are loaded
+/// from the buffer after the call. The cursor is left pointing at the call instruction.
+fn legalize_sret_call(pos: &mut FuncCursor, sig_ref: SigRef, call: Inst) -> Inst {
+    let sret = pos.func.dfg.signatures[sig_ref].params[0];
+    debug_assert_eq!(sret.purpose, ArgumentPurpose::StructReturn);
+
+    let results = pos.func.dfg.detach_results(call);
+    let results = results.as_slice(&pos.func.dfg.value_lists).to_vec();
+    let (offsets, size) = sret_layout(results.iter().map(|&res| pos.func.dfg.value_type(res)));
+
+    let ss = pos
+        .func
+        .create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, size));
+    let addr = pos.ins().stack_addr(sret.value_type, ss, 0);
+
+    // The `sret` argument goes first, after any fixed arguments like the callee address of an
+    // indirect call.
+    let num_fixed_values = pos.func.dfg[call]
+        .opcode()
+        .constraints()
+        .num_fixed_value_arguments();
+    let mut vlist = pos.func.dfg[call].take_value_list().unwrap();
+    vlist.insert(num_fixed_values, addr, &mut pos.func.dfg.value_lists);
+    pos.func.dfg[call].put_value_list(vlist);
+    pos.func.dfg.append_result(call, sret.value_type);
+
+    pos.goto_after_inst(call);
+    for (res, offset) in results.into_iter().zip(offsets) {
+        let ty = pos.func.dfg.value_type(res);
+        pos.ins()
+            .with_result(res)
+            .load(ty, MemFlags::trusted(), addr, offset);
+    }
+    pos.goto_inst(call);
+    call
+}
+
+/// Store the values returned by the return instruction `inst` into the struct-return buffer of
+/// the current function, and remove them from the instruction.
+fn legalize_sret_return(pos: &mut FuncCursor, inst: Inst) {
+    let entry = pos.func.layout.entry_block().unwrap();
+    let sret = pos.func.dfg.ebb_params(entry)[0];
+
+    let mut vlist = pos.func.dfg[inst].take_value_list().unwrap();
+    let args = vlist.as_slice(&pos.func.dfg.value_lists).to_vec();
+    let (offsets, _) = sret_layout(args.iter().map(|&arg| pos.func.dfg.value_type(arg)));
+    for (arg, offset) in args.into_iter().zip(offsets) {
+        pos.ins().store(MemFlags::trusted(), arg, sret, offset);
+    }
+    vlist.clear(&mut pos.func.dfg.value_lists);
+    pos.func.dfg[inst].put_value_list(vlist);
+}
+
 /// Insert ABI conversion code before and after the return instruction at `inst`.
 ///
 /// Return `true` if any instructions were inserted.
@@ -583,6 +709,11 @@ pub fn handle_return_abi(inst: Inst, func: &mut Function, cfg: &ControlFlowGraph
     let pos = &mut FuncCursor::new(func).at_inst(inst);
     pos.use_srcloc(inst);
 
+    // Return values passed through a struct-return buffer are stored before returning.
+    if pos.func.old_signature.is_some() {
+        legalize_sret_return(pos, inst);
+    }
+
     legalize_inst_arguments(pos, cfg, abi_args, |func, abi_arg| {
         func.signature.returns[abi_arg]
     });
@@ -7,6 +7,10 @@ use crate::cursor::{Cursor, FuncCursor};
 use crate::flowgraph::ControlFlowGraph;
 use crate::ir::{self, InstBuilder};
 use crate::isa::TargetIsa;
+use crate::result::{CodegenError, CodegenResult};
+use crate::verifier::{VerifierError, VerifierErrors};
+use std::vec::Vec;
+use target_lexicon::{Architecture, BinaryFormat};
 
 /// Expand a `global_value` instruction according to the definition of the global value.
 pub fn expand_global_value(
@@ -127,3 +131,31 @@ fn symbol(inst: ir::Inst, func: &mut ir::Function, gv: ir::GlobalValue, isa: &Ta
     let ptr_ty = isa.pointer_type();
     func.dfg.replace(inst).symbol_value(ptr_ty, gv);
 }
+
+/// Check that `isa` can address the thread-local symbols used by `func`.
+///
+/// Thread-local symbols are only supported with the ELF initial-exec TLS model on x86-64. On other
+/// targets, their `symbol_value` instructions would be left without an encoding.
+pub fn check_tls_symbols(func: &ir::Function, isa: &TargetIsa) -> CodegenResult<()> {
+    let triple = isa.triple();
+    if triple.architecture == Architecture::X86_64 && triple.binary_format == BinaryFormat::Elf {
+        return Ok(());
+    }
+    let errors: Vec<VerifierError> = func
+        .global_values
+        .iter()
+        .filter(|(_, data)| match data {
+            ir::GlobalValueData::Symbol { tls: true, .. } => true,
+            _ => false,
+        })
+        .map(|(gv, _)| VerifierError {
+            location: gv.into(),
+            message: format!("thread-local symbols are not supported on {}", triple),
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(CodegenError::Verifier(VerifierErrors(errors)))
+    }
+}
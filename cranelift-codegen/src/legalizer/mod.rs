@@ -30,6 +30,7 @@ mod split;
 mod table;
 
 use self::call::expand_call;
+pub use self::globalvalue::check_tls_symbols;
 use self::globalvalue::expand_global_value;
 use self::heap::expand_heap_addr;
 use self::libcall::expand_as_libcall;
@@ -438,3 +439,118 @@ fn expand_stack_store(
     mflags.set_aligned();
     pos.func.dfg.replace(inst).store(mflags, val, addr, 0);
 }
+
+/// Expand an atomic store as an exchange whose result is ignored.
+fn expand_atomic_store(
+    inst: ir::Inst,
+    func: &mut ir::Function,
+    _cfg: &mut ControlFlowGraph,
+    _isa: &TargetIsa,
+) {
+    let (flags, val, addr, offset) = match func.dfg[inst] {
+        ir::InstructionData::Store {
+            opcode: ir::Opcode::AtomicStore,
+            flags,
+            args,
+            offset,
+        } => (flags, args[0], args[1], offset),
+        _ => panic!(
+            "Expected atomic_store: {}",
+            func.dfg.display_inst(inst, None)
+        ),
+    };
+
+    let mut pos = FuncCursor::new(func).at_inst(inst);
+    pos.use_srcloc(inst);
+
+    let offset: i64 = offset.into();
+    let addr = if offset == 0 {
+        addr
+    } else {
+        pos.ins().iadd_imm(addr, offset)
+    };
+    pos.func
+        .dfg
+        .replace(inst)
+        .atomic_rmw(flags, ir::AtomicRmwOp::Xchg, addr, val);
+}
+
+/// Expand an atomic read-modify-write operation.
+///
+/// Subtraction is an addition of the negated operand. The remaining operations are expanded to a
+/// loop which retries an `atomic_cas` until memory didn't change between loading the old value and
+/// storing the new one.
+fn expand_atomic_rmw(
+    inst: ir::Inst,
+    func: &mut ir::Function,
+    cfg: &mut ControlFlowGraph,
+    _isa: &TargetIsa,
+) {
+    use crate::ir::condcodes::IntCC;
+
+    let (flags, op, addr, arg) = match func.dfg[inst] {
+        ir::InstructionData::AtomicRmw {
+            opcode: ir::Opcode::AtomicRmw,
+            flags,
+            op,
+            args,
+        } => (flags, op, args[0], args[1]),
+        _ => panic!("Expected atomic_rmw: {}", func.dfg.display_inst(inst, None)),
+    };
+
+    if op == ir::AtomicRmwOp::Sub {
+        let mut pos = FuncCursor::new(func).at_inst(inst);
+        pos.use_srcloc(inst);
+        let neg = pos.ins().irsub_imm(arg, 0);
+        pos.func
+            .dfg
+            .replace(inst)
+            .atomic_rmw(flags, ir::AtomicRmwOp::Add, addr, neg);
+        return;
+    }
+
+    // Replace `result = atomic_rmw op addr, arg` with:
+    //
+    //     old0 = atomic_load addr
+    //     jump retry_ebb(old0)
+    //   retry_ebb(old):
+    //     new = op old, arg
+    //     cur = atomic_cas addr, old, new
+    //     same = icmp eq cur, old
+    //     brz same, retry_ebb(cur)
+    //     jump done_ebb(cur)
+    //   done_ebb(result):
+    let old_ebb = func.layout.pp_ebb(inst);
+    let result = func.dfg.first_result(inst);
+    let ty = func.dfg.value_type(result);
+    func.dfg.clear_results(inst);
+    let retry_ebb = func.dfg.make_ebb();
+    let old = func.dfg.append_ebb_param(retry_ebb, ty);
+    let done_ebb = func.dfg.make_ebb();
+    func.dfg.attach_ebb_param(done_ebb, result);
+
+    let mut pos = FuncCursor::new(func).at_inst(inst);
+    pos.use_srcloc(inst);
+    let old0 = pos.ins().atomic_load(ty, flags, addr, 0);
+    pos.func.dfg.replace(inst).jump(retry_ebb, &[old0]);
+    pos.next_inst();
+    pos.insert_ebb(retry_ebb);
+
+    let new = match op {
+        ir::AtomicRmwOp::Add => pos.ins().iadd(old, arg),
+        ir::AtomicRmwOp::And => pos.ins().band(old, arg),
+        ir::AtomicRmwOp::Or => pos.ins().bor(old, arg),
+        ir::AtomicRmwOp::Xor => pos.ins().bxor(old, arg),
+        ir::AtomicRmwOp::Xchg => arg,
+        ir::AtomicRmwOp::Sub => unreachable!(),
+    };
+    let cur = pos.ins().atomic_cas(flags, addr, old, new);
+    let same = pos.ins().icmp(IntCC::Equal, cur, old);
+    pos.ins().brz(same, retry_ebb, &[cur]);
+    pos.ins().jump(done_ebb, &[cur]);
+    pos.insert_ebb(done_ebb);
+
+    cfg.recompute_ebb(pos.func, old_ebb);
+    cfg.recompute_ebb(pos.func, retry_ebb);
+    cfg.recompute_ebb(pos.func, done_ebb);
+}
@@ -303,6 +303,8 @@ fn optimize_complex_addresses(pos: &mut EncCursor, inst: Inst, isa: &TargetIsa)
                         info.offset,
                     );
                 }
+                // Atomic accesses don't have complex addressing forms.
+                Opcode::AtomicLoad | Opcode::AtomicStore => return,
                 _ => panic!("Unsupported load or store opcode"),
             },
             InstructionData::BinaryImm {
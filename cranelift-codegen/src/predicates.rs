@@ -60,6 +60,14 @@ pub fn is_colocated_data(global_value: ir::GlobalValue, func: &ir::Function) ->
     }
 }
 
+#[allow(dead_code)]
+pub fn is_tls_data(global_value: ir::GlobalValue, func: &ir::Function) -> bool {
+    match func.global_values[global_value] {
+        ir::GlobalValueData::Symbol { tls, .. } => tls,
+        _ => panic!("is_tls_data only makes sense for data with symbolic addresses"),
+    }
+}
+
 #[allow(dead_code)]
 pub fn has_length_of(value_list: &ir::ValueList, num: usize, func: &ir::Function) -> bool {
     value_list.len(&func.dfg.value_lists) == num
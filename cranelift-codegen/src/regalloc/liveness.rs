@@ -382,6 +382,15 @@ impl Liveness {
         mem::replace(&mut lr.affinity, Affinity::Stack)
     }
 
+    /// Mark the spilled `value` as rematerializable.
+    ///
+    /// The value must already have `Stack` affinity.
+    pub fn set_remat(&mut self, value: Value) {
+        let lr = self.ranges.get_mut(value).expect("Value has no live range");
+        debug_assert!(lr.affinity.is_stack(), "{} is not spilled", value);
+        lr.remat = true;
+    }
+
     /// Compute the live ranges of all SSA values used in `func`.
     /// This clears out any existing analysis stored in this data structure.
     pub fn compute(&mut self, isa: &TargetIsa, func: &mut Function, cfg: &ControlFlowGraph) {
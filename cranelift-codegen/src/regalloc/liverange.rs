@@ -155,6 +155,13 @@ pub struct GenLiveRange<PO: ProgramOrder> {
     /// The preferred register allocation for this value.
     pub affinity: Affinity,
 
+    /// This value has been spilled, but it can be recomputed by repeating its defining
+    /// instruction instead of being filled from a spill slot.
+    ///
+    /// The spilling pass sets this flag for cheap definitions like constants, and the reload pass
+    /// rematerializes the value at each register use.
+    pub remat: bool,
+
     /// The instruction or EBB header where this value is defined.
     def_begin: ProgramPoint,
 
@@ -223,6 +230,7 @@ impl<PO: ProgramOrder> GenLiveRange<PO> {
         Self {
             value,
             affinity,
+            remat: false,
             def_begin: def,
             def_end: def,
             liveins: bforest::Map::new(),
@@ -8,11 +8,15 @@
 //! The secondary responsibility of the reload pass is to reuse values in registers as much as
 //! possible to minimize the number of `fill` instructions needed. This must not cause the register
 //! pressure limits to be exceeded.
+//!
+//! Spilled values that the spilling pass tagged as rematerializable don't have a stack slot.
+//! Their defining instruction is removed, and a copy of it is inserted before each use instead of
+//! a `fill`.
 
 use crate::cursor::{Cursor, EncCursor};
 use crate::dominator_tree::DominatorTree;
 use crate::entity::{SparseMap, SparseMapValue};
-use crate::ir::{AbiParam, ArgumentLoc, InstBuilder};
+use crate::ir::{AbiParam, ArgumentLoc, InstBuilder, InstBuilderBase};
 use crate::ir::{Ebb, Function, Inst, InstructionData, Opcode, Value};
 use crate::isa::RegClass;
 use crate::isa::{ConstraintKind, EncInfo, Encoding, RecipeConstraints, TargetIsa};
@@ -126,7 +130,15 @@ impl<'a> Context<'a> {
 
         // visit_ebb_header() places us at the first interesting instruction in the EBB.
         while let Some(inst) = self.cur.current_inst() {
-            if !self.cur.func.dfg[inst].opcode().is_ghost() {
+            if self.is_remat_def(inst) {
+                // All uses of this value will be rewritten to use a rematerialized copy, so the
+                // original definition is no longer needed.
+                debug!(
+                    "Removing rematerialized def {}",
+                    self.cur.display_inst(inst)
+                );
+                self.cur.remove_inst();
+            } else if !self.cur.func.dfg[inst].opcode().is_ghost() {
                 // This instruction either has an encoding or has ABI constraints, so visit it to
                 // insert spills and fills as needed.
                 let encoding = self.cur.func.encodings[inst];
@@ -301,7 +313,11 @@ impl<'a> Context<'a> {
                 continue;
             }
 
-            let reg = self.cur.ins().fill(cand.value);
+            let reg = if self.liveness[cand.value].remat {
+                insert_remat(&mut self.cur, cand.value)
+            } else {
+                self.cur.ins().fill(cand.value)
+            };
             let fill = self.cur.built_inst();
 
             self.reloads.insert(ReloadedValue {
@@ -334,15 +350,31 @@ impl<'a> Context<'a> {
     // Reload the current candidates for the given copy `inst`.
     //
     // As an optimization, replace a copy instruction where the argument has been spilled with
-    // a fill instruction.
+    // a fill instruction, or with a copy of the defining instruction for rematerializable values.
     fn reload_copy_candidates(&mut self, inst: Inst) {
         // Copy instructions can only have one argument.
         debug_assert!(self.candidates.is_empty() || self.candidates.len() == 1);
 
         if let Some(cand) = self.candidates.pop() {
-            self.cur.func.dfg.replace(inst).fill(cand.value);
-            let ok = self.cur.func.update_encoding(inst, self.cur.isa).is_ok();
-            debug_assert!(ok);
+            if self.liveness[cand.value].remat {
+                let def = self.cur.func.dfg.value_def(cand.value).unwrap_inst();
+                let data = self.cur.func.dfg[def].clone();
+                let ctrl_type = self.cur.func.dfg.ctrl_typevar(def);
+                self.cur.func.dfg.replace(inst).build(data, ctrl_type);
+                self.cur.func.encodings[inst] = self.cur.func.encodings[def];
+            } else {
+                self.cur.func.dfg.replace(inst).fill(cand.value);
+                let ok = self.cur.func.update_encoding(inst, self.cur.isa).is_ok();
+                debug_assert!(ok);
+            }
+        }
+    }
+
+    /// Is `inst` the definition of a value that will be rematerialized at its uses?
+    fn is_remat_def(&self, inst: Inst) -> bool {
+        match *self.cur.func.dfg.inst_results(inst) {
+            [value] => self.liveness.get(value).map_or(false, |lr| lr.remat),
+            _ => false,
         }
     }
 
@@ -436,3 +468,18 @@ fn handle_abi_args(
         }
     }
 }
+
+/// Insert a copy of the instruction defining the rematerializable `value` at the current position.
+///
+/// Returns the new register value.
+fn insert_remat(pos: &mut EncCursor, value: Value) -> Value {
+    let def = pos.func.dfg.value_def(value).unwrap_inst();
+    let data = pos.func.dfg[def].clone();
+    let ctrl_type = pos.func.dfg.ctrl_typevar(def);
+    let (inst, dfg) = pos.ins().build(data, ctrl_type);
+    let reg = dfg.first_result(inst);
+
+    // Keep the original encoding. The spilling pass checked that it doesn't clobber the flags.
+    pos.func.encodings[inst] = pos.func.encodings[def];
+    reg
+}
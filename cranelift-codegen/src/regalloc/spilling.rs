@@ -14,10 +14,16 @@
 //! 2. When the same value is used more than once by an instruction, the operand constraints must
 //!    be compatible. Otherwise, the value must be copied into a new register for some of the
 //!    operands.
+//!
+//! Values that are cheap to recompute, like constants, are not assigned a spill slot when they are
+//! spilled. Instead, their live range is tagged as rematerializable, and the reload pass repeats
+//! the defining instruction at each use instead of inserting a `fill`.
 
 use crate::cursor::{Cursor, EncCursor};
 use crate::dominator_tree::DominatorTree;
-use crate::ir::{ArgumentLoc, Ebb, Function, Inst, InstBuilder, SigRef, Value, ValueLoc};
+use crate::ir::{
+    ArgumentLoc, Ebb, Function, Inst, InstBuilder, Opcode, SigRef, Value, ValueDef, ValueLoc,
+};
 use crate::isa::registers::{RegClass, RegClassIndex, RegClassMask, RegUnit};
 use crate::isa::{ConstraintKind, EncInfo, RecipeConstraints, RegInfo, TargetIsa};
 use crate::regalloc::affinity::Affinity;
@@ -535,6 +541,16 @@ impl<'a> Context<'a> {
             panic!("Cannot spill {} that was already on the stack", value);
         }
 
+        // Cheap values don't need a spill slot. The reload pass will recompute them at each use.
+        if self.is_rematerializable(value) {
+            debug!(
+                "Rematerializing {} instead of assigning a spill slot",
+                value
+            );
+            self.liveness.set_remat(value);
+            return;
+        }
+
         // Assign a spill slot for the whole virtual register.
         let ss = self
             .cur
@@ -547,6 +563,46 @@ impl<'a> Context<'a> {
         }
     }
 
+    /// Can the spilled `value` be recomputed at its uses instead of being stored in a spill slot?
+    ///
+    /// This is the case when `value` is defined by a cheap instruction with no value arguments,
+    /// so it can be repeated anywhere the value is live. The value must be alone in its virtual
+    /// register since the other members of a congruence class expect to share a stack slot. The
+    /// defining instruction can't clobber the CPU flags since the copies will be inserted without
+    /// regard to live flags values.
+    fn is_rematerializable(&self, value: Value) -> bool {
+        let inst = match self.cur.func.dfg.value_def(value) {
+            ValueDef::Result(inst, _) => inst,
+            ValueDef::Param(_, _) => return false,
+        };
+
+        match self.cur.func.dfg[inst].opcode() {
+            Opcode::Iconst
+            | Opcode::F32const
+            | Opcode::F64const
+            | Opcode::Bconst
+            | Opcode::FuncAddr
+            | Opcode::SymbolValue
+            | Opcode::StackAddr => {}
+            _ => return false,
+        }
+
+        if self.virtregs.congruence_class(&value).len() != 1 {
+            return false;
+        }
+
+        match self
+            .encinfo
+            .operand_constraints(self.cur.func.encodings[inst])
+        {
+            Some(constraints) => {
+                !constraints.fixed_outs
+                    && !(constraints.clobbers_flags && self.cur.isa.uses_cpu_flags())
+            }
+            None => false,
+        }
+    }
+
     /// Process any pending spills in the `self.spills` vector.
     ///
     /// It is assumed that spills are removed from the pressure tracker immediately, see
@@ -389,8 +389,10 @@ mod tests {
              enable_atomics = true\n\
              baldrdash_prologue_words = 0\n\
              allones_funcaddrs = false\n\
+             omit_frame_pointer = false\n\
              probestack_enabled = true\n\
              probestack_func_adjusts_sp = false\n\
+             probestack_strategy = \"outline\"\n\
              probestack_size_log2 = 12\n\
              jump_tables_enabled = true\n"
         );
@@ -374,6 +374,30 @@ impl<'a> Verifier<'a> {
         Ok(())
     }
 
+    fn verify_stack_limit(&self, errors: &mut VerifierErrors) -> VerifierStepResult<()> {
+        if let Some(gv) = self.func.stack_limit {
+            if !self.func.global_values.is_valid(gv) {
+                return nonfatal!(errors, gv, "invalid stack limit global value {}", gv);
+            }
+
+            if let Some(isa) = self.isa {
+                let pointer_type = isa.pointer_type();
+                let limit_type = self.func.global_values[gv].global_type(isa);
+                if limit_type != pointer_type {
+                    report!(
+                        errors,
+                        gv,
+                        "stack limit has type {}, which is not the pointer type {}",
+                        limit_type,
+                        pointer_type
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn verify_heaps(&self, errors: &mut VerifierErrors) -> VerifierStepResult<()> {
         if let Some(isa) = self.isa {
             for (heap, heap_data) in &self.func.heaps {
@@ -671,6 +695,8 @@ impl<'a> Verifier<'a> {
             | IntSelect { .. }
             | Load { .. }
             | Store { .. }
+            | AtomicRmw { .. }
+            | AtomicCas { .. }
             | RegMove { .. }
             | CopySpecial { .. }
             | Trap { .. }
@@ -1677,6 +1703,7 @@ impl<'a> Verifier<'a> {
 
     pub fn run(&self, errors: &mut VerifierErrors) -> VerifierStepResult<()> {
         self.verify_global_values(errors)?;
+        self.verify_stack_limit(errors)?;
         self.verify_heaps(errors)?;
         self.verify_tables(errors)?;
         self.typecheck_entry_block_params(errors)?;
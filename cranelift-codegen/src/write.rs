@@ -97,6 +97,11 @@ pub trait FuncWriter {
             self.write_entity_definition(w, func, jt.into(), jt_data)?;
         }
 
+        if let Some(gv) = func.stack_limit {
+            any = true;
+            writeln!(w, "    stack_limit = {}", gv)?;
+        }
+
         Ok(any)
     }
 
@@ -551,6 +556,12 @@ pub fn write_operands(
             offset,
             ..
         } => write!(w, "{} {}, {}{}", flags, args[0], args[1], offset),
+        AtomicRmw {
+            flags, op, args, ..
+        } => write!(w, "{} {} {}, {}", flags, op, args[0], args[1]),
+        AtomicCas { flags, args, .. } => {
+            write!(w, "{} {}, {}, {}", flags, args[0], args[1], args[2])
+        }
         StoreComplex {
             flags,
             ref args,
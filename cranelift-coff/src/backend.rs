@@ -0,0 +1,499 @@
+//! Defines `CoffBackend`.
+
+use crate::container;
+use cranelift_codegen::binemit::{Addend, CodeOffset, NullTrapSink, Reloc, RelocSink};
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_codegen::{self, binemit, ir};
+use cranelift_module::{
+    Backend, DataContext, DataDescription, Init, Linkage, ModuleError, ModuleNamespace,
+    ModuleResult,
+};
+use goblin::pe::section_table::{
+    IMAGE_SCN_CNT_CODE, IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_CNT_UNINITIALIZED_DATA,
+    IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE,
+};
+use goblin::pe::symbol::{
+    IMAGE_COMDAT_SELECT_ANY, IMAGE_SYM_CLASS_EXTERNAL, IMAGE_SYM_CLASS_STATIC,
+};
+use std::fs::File;
+use std::io::{self, Write};
+use target_lexicon::{Architecture, BinaryFormat, Triple};
+
+/// The alignment of functions in the `.text` section.
+const FUNCTION_ALIGNMENT: u64 = 16;
+
+/// A builder for `CoffBackend`.
+pub struct CoffBuilder {
+    isa: Box<TargetIsa>,
+    name: String,
+    libcall_names: Box<Fn(ir::LibCall) -> String>,
+}
+
+impl CoffBuilder {
+    /// Create a new `CoffBuilder` using the given Cranelift target, that
+    /// can be passed to
+    /// [`Module::new`](cranelift_module/struct.Module.html#method.new].
+    ///
+    /// COFF output requires an x86-64 TargetIsa with a COFF binary format, such as
+    /// `x86_64-pc-windows-msvc`. COFF has no GOT, so the TargetIsa must not have PIC enabled.
+    ///
+    /// The `libcall_names` function provides a way to translate `cranelift_codegen`'s `ir::LibCall`
+    /// enum to symbols. LibCalls are inserted in the IR as part of the legalization for certain
+    /// floating point instructions, and for stack probes. If you don't know what to use for this
+    /// argument, use `CoffBuilder::default_libcall_names()`.
+    pub fn new(
+        isa: Box<TargetIsa>,
+        name: String,
+        libcall_names: Box<Fn(ir::LibCall) -> String>,
+    ) -> ModuleResult<Self> {
+        if isa.triple().architecture != Architecture::X86_64 {
+            return Err(ModuleError::Backend(format!(
+                "coff doesn't support architecture {} yet",
+                isa.triple().architecture
+            )));
+        }
+        if isa.triple().binary_format != BinaryFormat::Coff {
+            return Err(ModuleError::Backend(format!(
+                "coff requires a coff target, not {}",
+                isa.triple()
+            )));
+        }
+        if isa.flags().is_pic() {
+            return Err(ModuleError::Backend(
+                "coff requires TargetIsa not be PIC".to_owned(),
+            ));
+        }
+        Ok(Self {
+            isa,
+            name,
+            libcall_names,
+        })
+    }
+
+    /// Default names for `ir::LibCall`s. A function by this name is imported into the object as
+    /// part of the translation of a `ir::ExternalName::LibCall` variant.
+    pub fn default_libcall_names() -> Box<Fn(ir::LibCall) -> String> {
+        Box::new(move |libcall| match libcall {
+            ir::LibCall::Probestack => "__cranelift_probestack".to_owned(),
+            ir::LibCall::CeilF32 => "ceilf".to_owned(),
+            ir::LibCall::CeilF64 => "ceil".to_owned(),
+            ir::LibCall::FloorF32 => "floorf".to_owned(),
+            ir::LibCall::FloorF64 => "floor".to_owned(),
+            ir::LibCall::TruncF32 => "truncf".to_owned(),
+            ir::LibCall::TruncF64 => "trunc".to_owned(),
+            ir::LibCall::NearestF32 => "nearbyintf".to_owned(),
+            ir::LibCall::NearestF64 => "nearbyint".to_owned(),
+            ir::LibCall::Memcpy => "memcpy".to_owned(),
+            ir::LibCall::Memset => "memset".to_owned(),
+            ir::LibCall::Memmove => "memmove".to_owned(),
+        })
+    }
+}
+
+/// A `CoffBackend` implements `Backend` and emits ".obj" files in the COFF format.
+///
+/// Functions are placed in `.text`, and data objects in `.data`, `.rdata` or `.bss` depending
+/// on whether they're writable and initialized, unless a custom section is requested.
+///
+/// COFF has no weak definitions outside of COMDAT sections, so `Linkage::Preemptible`
+/// definitions are each placed in a COMDAT section of their own, and the linker keeps any one of
+/// the definitions of the symbol. As with LLVM, they can only be overridden by other COMDAT
+/// definitions; a plain definition in another object is a duplicate symbol error.
+///
+/// No unwind information is emitted in `.pdata` and `.xdata`, since Cranelift doesn't describe
+/// the prologues it generates. Exceptions and debuggers can therefore only unwind through leaf
+/// functions without a frame.
+///
+/// See the `CoffBuilder` for a convenient way to construct `CoffBackend` instances.
+pub struct CoffBackend {
+    isa: Box<TargetIsa>,
+    name: String,
+    object: container::Object,
+    libcall_names: Box<Fn(ir::LibCall) -> String>,
+}
+
+pub struct CoffCompiledFunction {}
+
+pub struct CoffCompiledData {}
+
+impl Backend for CoffBackend {
+    type Builder = CoffBuilder;
+
+    type CompiledFunction = CoffCompiledFunction;
+    type CompiledData = CoffCompiledData;
+
+    // There's no need to return individual artifacts; we're writing them into
+    // the output file instead.
+    type FinalizedFunction = ();
+    type FinalizedData = ();
+
+    /// The returned value here provides functions for emitting object files
+    /// to memory and files.
+    type Product = CoffProduct;
+
+    /// Create a new `CoffBackend` using the given Cranelift target.
+    fn new(builder: CoffBuilder) -> Self {
+        Self {
+            isa: builder.isa,
+            name: builder.name,
+            object: container::Object::new(),
+            libcall_names: builder.libcall_names,
+        }
+    }
+
+    fn isa(&self) -> &TargetIsa {
+        &*self.isa
+    }
+
+    fn declare_function(&mut self, name: &str, linkage: Linkage) {
+        let (storage_class, selection) = translate_linkage(linkage);
+        self.object.declare(name, true, storage_class, selection);
+    }
+
+    fn declare_data(&mut self, name: &str, linkage: Linkage, _writable: bool) {
+        let (storage_class, selection) = translate_linkage(linkage);
+        self.object.declare(name, false, storage_class, selection);
+    }
+
+    fn define_function(
+        &mut self,
+        name: &str,
+        ctx: &cranelift_codegen::Context,
+        namespace: &ModuleNamespace<Self>,
+        code_size: u32,
+    ) -> ModuleResult<CoffCompiledFunction> {
+        let mut code: Vec<u8> = vec![0; code_size as usize];
+        let symbol = self.object.symbol(name);
+
+        let mut reloc_sink = CoffRelocSink {
+            triple: self.isa.triple().clone(),
+            object: &mut self.object,
+            symbol,
+            namespace,
+            libcall_names: &*self.libcall_names,
+            jt_offsets: &ctx.func.jt_offsets,
+            relocs: Vec::new(),
+            error: None,
+        };
+        let mut trap_sink = NullTrapSink {};
+        unsafe {
+            ctx.emit_to_memory(
+                &*self.isa,
+                code.as_mut_ptr(),
+                &mut reloc_sink,
+                &mut trap_sink,
+            )
+        };
+        if let Some(error) = reloc_sink.error {
+            return Err(ModuleError::Backend(error));
+        }
+        let relocs = reloc_sink.relocs;
+
+        for reloc in &relocs {
+            container::write_addend(&mut code, reloc.offset as usize, reloc.typ, reloc.addend)
+                .map_err(|error| ModuleError::Backend(format!("{} in {}", error, name)))?;
+        }
+        let text = self.object.symbol_section(
+            symbol,
+            ".text",
+            IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ,
+        );
+        let offset = self.object.append(text, FUNCTION_ALIGNMENT, &code);
+        self.object.define(symbol, text, offset);
+        for reloc in relocs {
+            self.object
+                .relocate(text, offset + reloc.offset, reloc.symbol, reloc.typ);
+        }
+        Ok(CoffCompiledFunction {})
+    }
+
+    fn define_data(
+        &mut self,
+        name: &str,
+        writable: bool,
+        data_ctx: &DataContext,
+        namespace: &ModuleNamespace<Self>,
+    ) -> ModuleResult<CoffCompiledData> {
+        let &DataDescription {
+            ref init,
+            ref function_decls,
+            ref data_decls,
+            ref function_relocs,
+            ref data_relocs,
+            align,
+            ref section,
+        } = data_ctx.description();
+
+        if namespace.get_data_decl_by_name(name).tls {
+            return Err(ModuleError::Backend(
+                "coff doesn't support thread-local data yet".to_owned(),
+            ));
+        }
+        let align = align.unwrap_or_else(|| u64::from(self.isa.pointer_bytes()));
+        if align > container::MAX_ALIGNMENT {
+            return Err(ModuleError::Backend(format!(
+                "coff doesn't support alignments above {} bytes",
+                container::MAX_ALIGNMENT
+            )));
+        }
+
+        let size = init.size();
+        let mut bytes = Vec::with_capacity(size);
+        let zeroed = match *init {
+            Init::Uninitialized => {
+                return Err(ModuleError::Backend(format!(
+                    "data object {} is not initialized",
+                    name
+                )));
+            }
+            Init::Zeros { .. } => {
+                bytes.resize(size, 0);
+                true
+            }
+            Init::Bytes { ref contents } => {
+                bytes.extend_from_slice(contents);
+                false
+            }
+        };
+
+        let reloc = match self.isa.pointer_bytes() {
+            4 => Reloc::Abs4,
+            8 => Reloc::Abs8,
+            bytes => {
+                return Err(ModuleError::Backend(format!(
+                    "coff doesn't support {}-byte pointers",
+                    bytes
+                )));
+            }
+        };
+        let (typ, raw_addend) =
+            container::raw_relocation(reloc, self.isa.triple()).map_err(ModuleError::Backend)?;
+        let mut relocs = Vec::with_capacity(function_relocs.len() + data_relocs.len());
+        for &(offset, id) in function_relocs {
+            let to = &namespace.get_function_decl(&function_decls[id]).name;
+            relocs.push((offset, self.object.symbol(to), 0));
+        }
+        for &(offset, id, addend) in data_relocs {
+            let to = &namespace.get_data_decl(&data_decls[id]).name;
+            relocs.push((offset, self.object.symbol(to), addend));
+        }
+        for &(offset, _, addend) in &relocs {
+            container::write_addend(&mut bytes, offset as usize, typ, addend + raw_addend)
+                .map_err(|error| ModuleError::Backend(format!("{} in {}", error, name)))?;
+        }
+
+        let (section_name, characteristics) = match *section {
+            Some(ref section) => {
+                let mut characteristics = IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ;
+                if writable {
+                    characteristics |= IMAGE_SCN_MEM_WRITE;
+                }
+                (&section[..], characteristics)
+            }
+            // Relocations need initialized contents to hold their addends.
+            None if writable && zeroed && relocs.is_empty() => (
+                ".bss",
+                IMAGE_SCN_CNT_UNINITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            ),
+            None if writable => (
+                ".data",
+                IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            ),
+            None => (
+                ".rdata",
+                IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+            ),
+        };
+        let symbol = self.object.symbol(name);
+        let section = self
+            .object
+            .symbol_section(symbol, section_name, characteristics);
+        let offset = self.object.append(section, align, &bytes);
+        self.object.define(symbol, section, offset);
+        for (at, to, _) in relocs {
+            self.object.relocate(section, offset + at, to, typ);
+        }
+        Ok(CoffCompiledData {})
+    }
+
+    fn write_data_funcaddr(
+        &mut self,
+        _data: &mut CoffCompiledData,
+        _offset: usize,
+        _what: ir::FuncRef,
+    ) -> ModuleResult<()> {
+        Err(ModuleError::Backend(
+            "coff doesn't support writing addresses into defined data".to_owned(),
+        ))
+    }
+
+    fn write_data_dataaddr(
+        &mut self,
+        _data: &mut CoffCompiledData,
+        _offset: usize,
+        _what: ir::GlobalValue,
+        _usize: binemit::Addend,
+    ) -> ModuleResult<()> {
+        Err(ModuleError::Backend(
+            "coff doesn't support writing addresses into defined data".to_owned(),
+        ))
+    }
+
+    fn finalize_function(
+        &mut self,
+        _func: &CoffCompiledFunction,
+        _namespace: &ModuleNamespace<Self>,
+    ) {
+        // Nothing to do.
+    }
+
+    fn get_finalized_function(&self, _func: &CoffCompiledFunction) {
+        // Nothing to do.
+    }
+
+    fn finalize_data(&mut self, _data: &CoffCompiledData, _namespace: &ModuleNamespace<Self>) {
+        // Nothing to do.
+    }
+
+    fn get_finalized_data(&self, _data: &CoffCompiledData) {
+        // Nothing to do.
+    }
+
+    fn publish(&mut self) {
+        // Nothing to do.
+    }
+
+    fn finish(self) -> CoffProduct {
+        CoffProduct {
+            name: self.name,
+            object: self.object,
+        }
+    }
+}
+
+/// This is the output of `Module`'s
+/// [`finish`](../cranelift_module/struct.Module.html#method.finish) function.
+/// It provides functions for writing out the object file to memory or a file.
+pub struct CoffProduct {
+    name: String,
+    object: container::Object,
+}
+
+impl CoffProduct {
+    /// Return the name of the output file. This is the name passed into `new`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Lay out the object and produce its bytes in memory.
+    pub fn emit(&self) -> Vec<u8> {
+        self.object.emit()
+    }
+
+    /// Lay out the object and write it to a file.
+    pub fn write(&self, mut sink: File) -> io::Result<()> {
+        sink.write_all(&self.emit())
+    }
+}
+
+/// Translate `linkage` to a storage class, and the COMDAT selection for the definitions which
+/// may also be present in other objects.
+fn translate_linkage(linkage: Linkage) -> (u8, Option<u8>) {
+    match linkage {
+        Linkage::Local => (IMAGE_SYM_CLASS_STATIC, None),
+        Linkage::Import | Linkage::Export => (IMAGE_SYM_CLASS_EXTERNAL, None),
+        Linkage::Preemptible => (IMAGE_SYM_CLASS_EXTERNAL, Some(IMAGE_COMDAT_SELECT_ANY)),
+    }
+}
+
+/// A relocation in the function being defined, with its addend adjusted to be implicit.
+struct CoffReloc {
+    offset: CodeOffset,
+    symbol: usize,
+    typ: u16,
+    addend: i64,
+}
+
+struct CoffRelocSink<'a> {
+    triple: Triple,
+    object: &'a mut container::Object,
+    symbol: usize,
+    namespace: &'a ModuleNamespace<'a, CoffBackend>,
+    libcall_names: &'a Fn(ir::LibCall) -> String,
+    jt_offsets: &'a ir::JumpTableOffsets,
+    relocs: Vec<CoffReloc>,
+    /// The first relocation which couldn't be recorded, since `RelocSink` can't report errors.
+    error: Option<String>,
+}
+
+impl<'a> CoffRelocSink<'a> {
+    /// Record `error`, unless an earlier relocation has already failed.
+    fn set_error(&mut self, error: String) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    /// Add a relocation to `code_offset` in the function being defined.
+    fn reloc_local(&mut self, offset: CodeOffset, reloc: Reloc, code_offset: CodeOffset) {
+        let (typ, raw_addend) = match container::raw_relocation(reloc, &self.triple) {
+            Ok(raw) => raw,
+            Err(error) => return self.set_error(error),
+        };
+        // The relocation is relative to the symbol of the function itself.
+        self.relocs.push(CoffReloc {
+            offset,
+            symbol: self.symbol,
+            typ,
+            addend: i64::from(code_offset) + raw_addend,
+        });
+    }
+}
+
+impl<'a> RelocSink for CoffRelocSink<'a> {
+    fn reloc_ebb(&mut self, offset: CodeOffset, reloc: Reloc, ebb_offset: CodeOffset) {
+        self.reloc_local(offset, reloc, ebb_offset);
+    }
+
+    fn reloc_external(
+        &mut self,
+        offset: CodeOffset,
+        reloc: Reloc,
+        name: &ir::ExternalName,
+        addend: Addend,
+    ) {
+        let symbol = match *name {
+            ir::ExternalName::User { .. } => {
+                if self.namespace.is_function(name) {
+                    self.object
+                        .symbol(&self.namespace.get_function_decl(name).name)
+                } else {
+                    self.object.symbol(&self.namespace.get_data_decl(name).name)
+                }
+            }
+            ir::ExternalName::LibCall(ref libcall) => {
+                let sym = (self.libcall_names)(*libcall);
+                self.object.import(&sym, true)
+            }
+            // Functions and data objects declared in a module always have user names.
+            ir::ExternalName::TestCase { .. } => {
+                return self.set_error(format!("invalid ExternalName {}", name));
+            }
+        };
+        let (typ, raw_addend) = match container::raw_relocation(reloc, &self.triple) {
+            Ok(raw) => raw,
+            Err(error) => return self.set_error(error),
+        };
+        self.relocs.push(CoffReloc {
+            offset,
+            symbol,
+            typ,
+            addend: addend + raw_addend,
+        });
+    }
+
+    fn reloc_jt(&mut self, offset: CodeOffset, reloc: Reloc, jt: ir::JumpTable) {
+        let jt_offset = self.jt_offsets[jt];
+        self.reloc_local(offset, reloc, jt_offset);
+    }
+}
@@ -0,0 +1,440 @@
+//! Utilities for building and writing COFF object files.
+
+use cranelift_codegen::binemit::Reloc;
+use goblin::pe::header::{COFF_MACHINE_X86_64, SIZEOF_COFF_HEADER};
+use goblin::pe::relocation::{
+    COFF_RELOCATION_SIZE, IMAGE_REL_AMD64_ADDR32, IMAGE_REL_AMD64_ADDR64, IMAGE_REL_AMD64_REL32,
+};
+use goblin::pe::section_table::{
+    IMAGE_SCN_ALIGN_1BYTES, IMAGE_SCN_CNT_UNINITIALIZED_DATA, IMAGE_SCN_LNK_COMDAT,
+    IMAGE_SCN_LNK_NRELOC_OVFL, SIZEOF_SECTION_TABLE,
+};
+use goblin::pe::symbol::{
+    COFF_SYMBOL_SIZE, IMAGE_SYM_CLASS_EXTERNAL, IMAGE_SYM_CLASS_STATIC, IMAGE_SYM_DTYPE_FUNCTION,
+    IMAGE_SYM_DTYPE_SHIFT, IMAGE_SYM_TYPE_NULL, IMAGE_SYM_UNDEFINED,
+};
+use std::collections::HashMap;
+use target_lexicon::{Architecture, Triple};
+
+/// The largest section alignment that can be expressed in the section characteristics.
+pub const MAX_ALIGNMENT: u64 = 8192;
+
+/// Translate from a Cranelift `Reloc` to a raw COFF relocation type and relocation-implied
+/// addend.
+pub fn raw_relocation(reloc: Reloc, triple: &Triple) -> Result<(u16, i64), String> {
+    match triple.architecture {
+        Architecture::X86_64 => match reloc {
+            Reloc::Abs4 => Ok((IMAGE_REL_AMD64_ADDR32, 0)),
+            Reloc::Abs8 => Ok((IMAGE_REL_AMD64_ADDR64, 0)),
+            // `IMAGE_REL_AMD64_REL32` is relative to the end of the 4-byte field, so it includes
+            // the 4-byte addend implicitly. COFF has no PLT; calls go directly to the symbol.
+            Reloc::X86PCRel4 | Reloc::X86CallPCRel4 | Reloc::X86CallPLTRel4 => {
+                Ok((IMAGE_REL_AMD64_REL32, 4))
+            }
+            _ => Err(format!("unsupported relocation for {}: {}", triple, reloc)),
+        },
+        _ => Err(format!("unsupported architecture: {}", triple)),
+    }
+}
+
+/// Return the section characteristics bits for an alignment of `align` bytes.
+pub fn align_characteristics(align: u64) -> u32 {
+    debug_assert!(align.is_power_of_two() && align <= MAX_ALIGNMENT);
+    IMAGE_SCN_ALIGN_1BYTES * (align.trailing_zeros() + 1)
+}
+
+/// A section in a COFF object.
+struct Section {
+    name: String,
+    characteristics: u32,
+    align: u64,
+    /// The contents of the section. Empty for uninitialized data.
+    data: Vec<u8>,
+    size: u64,
+    relocs: Vec<Relocation>,
+    /// The COMDAT selection of the section, if it's a COMDAT section holding a single symbol.
+    selection: Option<u8>,
+}
+
+/// A relocation in a section. COFF relocations take their addend from the relocated bytes.
+struct Relocation {
+    offset: u32,
+    symbol: usize,
+    typ: u16,
+}
+
+/// A symbol in a COFF object.
+struct Symbol {
+    name: String,
+    function: bool,
+    storage_class: u8,
+    /// The COMDAT selection of the section the symbol is defined in, for definitions which
+    /// may also be present in other objects.
+    selection: Option<u8>,
+    /// The section index and offset of the definition, if the symbol is defined.
+    definition: Option<(usize, u32)>,
+}
+
+/// An in-memory COFF object, which is laid out and serialized by `emit`.
+pub struct Object {
+    sections: Vec<Section>,
+    section_indices: HashMap<String, usize>,
+    symbols: Vec<Symbol>,
+    symbol_indices: HashMap<String, usize>,
+}
+
+impl Object {
+    /// Create a new empty object.
+    pub fn new() -> Self {
+        Self {
+            sections: Vec::new(),
+            section_indices: HashMap::new(),
+            symbols: Vec::new(),
+            symbol_indices: HashMap::new(),
+        }
+    }
+
+    /// Declare the symbol `name`, or update the storage class and COMDAT selection of an existing
+    /// declaration.
+    ///
+    /// If `selection` is set, the symbol is defined in a COMDAT section of its own, and the linker
+    /// picks one of the definitions from all the objects according to `selection`.
+    pub fn declare(
+        &mut self,
+        name: &str,
+        function: bool,
+        storage_class: u8,
+        selection: Option<u8>,
+    ) -> usize {
+        if let Some(&index) = self.symbol_indices.get(name) {
+            self.symbols[index].storage_class = storage_class;
+            self.symbols[index].selection = selection;
+            return index;
+        }
+        let index = self.symbols.len();
+        self.symbols.push(Symbol {
+            name: name.to_owned(),
+            function,
+            storage_class,
+            selection,
+            definition: None,
+        });
+        self.symbol_indices.insert(name.to_owned(), index);
+        index
+    }
+
+    /// Declare an external symbol `name` unless it's already declared.
+    pub fn import(&mut self, name: &str, function: bool) -> usize {
+        match self.symbol_indices.get(name) {
+            Some(&index) => index,
+            None => self.declare(name, function, IMAGE_SYM_CLASS_EXTERNAL, None),
+        }
+    }
+
+    /// Return the index of the declared symbol `name`.
+    pub fn symbol(&self, name: &str) -> usize {
+        *self
+            .symbol_indices
+            .get(name)
+            .unwrap_or_else(|| panic!("undeclared symbol {}", name))
+    }
+
+    /// Return the index of the section `name`, creating it with `characteristics` if it doesn't
+    /// exist yet.
+    pub fn section(&mut self, name: &str, characteristics: u32) -> usize {
+        if let Some(&index) = self.section_indices.get(name) {
+            self.sections[index].characteristics |= characteristics;
+            return index;
+        }
+        let index = self.sections.len();
+        self.sections.push(Section {
+            name: name.to_owned(),
+            characteristics,
+            align: 1,
+            data: Vec::new(),
+            size: 0,
+            relocs: Vec::new(),
+            selection: None,
+        });
+        self.section_indices.insert(name.to_owned(), index);
+        index
+    }
+
+    /// Return the index of the section to define `symbol` in. This is the section `name`, as
+    /// returned by `section`, unless the symbol is defined in a COMDAT section, in which case a
+    /// new section by that name is created for it.
+    pub fn symbol_section(&mut self, symbol: usize, name: &str, characteristics: u32) -> usize {
+        let selection = match self.symbols[symbol].selection {
+            Some(selection) => selection,
+            None => return self.section(name, characteristics),
+        };
+        let index = self.sections.len();
+        self.sections.push(Section {
+            name: name.to_owned(),
+            characteristics: characteristics | IMAGE_SCN_LNK_COMDAT,
+            align: 1,
+            data: Vec::new(),
+            size: 0,
+            relocs: Vec::new(),
+            selection: Some(selection),
+        });
+        index
+    }
+
+    /// Append `bytes` to the section `section` at an offset aligned to `align`, returning the
+    /// offset. Uninitialized sections only grow in size.
+    pub fn append(&mut self, section: usize, align: u64, bytes: &[u8]) -> u32 {
+        let section = &mut self.sections[section];
+        section.align = section.align.max(align);
+        let offset = (section.size + align - 1) & !(align - 1);
+        section.size = offset + bytes.len() as u64;
+        if section.characteristics & IMAGE_SCN_CNT_UNINITIALIZED_DATA == 0 {
+            section.data.resize(offset as usize, 0);
+            section.data.extend_from_slice(bytes);
+        } else {
+            debug_assert!(bytes.iter().all(|&b| b == 0));
+        }
+        offset as u32
+    }
+
+    /// Define the symbol `symbol` at `offset` in section `section`.
+    pub fn define(&mut self, symbol: usize, section: usize, offset: u32) {
+        debug_assert!(self.symbols[symbol].definition.is_none());
+        debug_assert_eq!(
+            self.symbols[symbol].selection,
+            self.sections[section].selection
+        );
+        self.symbols[symbol].definition = Some((section, offset));
+    }
+
+    /// Add a relocation of type `typ` to `symbol` at `offset` in section `section`.
+    pub fn relocate(&mut self, section: usize, offset: u32, symbol: usize, typ: u16) {
+        self.sections[section].relocs.push(Relocation {
+            offset,
+            symbol,
+            typ,
+        });
+    }
+
+    /// Serialize the object.
+    pub fn emit(&self) -> Vec<u8> {
+        let mut strtab = vec![0; 4];
+        let mut name_offset = |name: &str| {
+            let offset = strtab.len() as u32;
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+            offset
+        };
+
+        // Each COMDAT section has a section symbol followed by an auxiliary record at the start of
+        // the symbol table, before the symbols themselves.
+        let comdat_sections = self
+            .sections
+            .iter()
+            .filter(|section| section.selection.is_some())
+            .count();
+        let first_symbol = 2 * comdat_sections;
+        let num_symbols = first_symbol + self.symbols.len();
+
+        // Lay out the section contents and relocations after the section table.
+        let mut offset = SIZEOF_COFF_HEADER + self.sections.len() * SIZEOF_SECTION_TABLE;
+        let mut section_headers = Vec::new();
+        for section in &self.sections {
+            let data_offset = if section.data.is_empty() { 0 } else { offset };
+            offset += section.data.len();
+            // If there are more relocations than fit in 16 bits, the real count is stored in
+            // the address of an extra leading relocation.
+            let mut nrelocs = section.relocs.len();
+            let mut characteristics =
+                section.characteristics | align_characteristics(section.align);
+            if nrelocs > 0xffff {
+                characteristics |= IMAGE_SCN_LNK_NRELOC_OVFL;
+                nrelocs += 1;
+            }
+            let relocs_offset = if nrelocs == 0 { 0 } else { offset };
+            offset += nrelocs * COFF_RELOCATION_SIZE;
+
+            let mut header = Vec::with_capacity(SIZEOF_SECTION_TABLE);
+            if section.name.len() > 8 {
+                let short = format!("/{}", name_offset(&section.name));
+                header.extend_from_slice(short.as_bytes());
+            } else {
+                header.extend_from_slice(section.name.as_bytes());
+            }
+            header.resize(8, 0);
+            put_u32(&mut header, 0); // VirtualSize
+            put_u32(&mut header, 0); // VirtualAddress
+            put_u32(&mut header, section.size as u32);
+            put_u32(&mut header, data_offset as u32);
+            put_u32(&mut header, relocs_offset as u32);
+            put_u32(&mut header, 0); // PointerToLinenumbers
+            put_u16(&mut header, nrelocs.min(0xffff) as u16);
+            put_u16(&mut header, 0); // NumberOfLinenumbers
+            put_u32(&mut header, characteristics);
+            section_headers.push(header);
+        }
+
+        let mut bytes = Vec::with_capacity(offset);
+        put_u16(&mut bytes, COFF_MACHINE_X86_64);
+        put_u16(&mut bytes, self.sections.len() as u16);
+        put_u32(&mut bytes, 0); // TimeDateStamp
+        put_u32(&mut bytes, offset as u32);
+        put_u32(&mut bytes, num_symbols as u32);
+        put_u16(&mut bytes, 0); // SizeOfOptionalHeader
+        put_u16(&mut bytes, 0); // Characteristics
+        for header in section_headers {
+            bytes.extend_from_slice(&header);
+        }
+        for section in &self.sections {
+            bytes.extend_from_slice(&section.data);
+            if section.relocs.len() > 0xffff {
+                put_u32(&mut bytes, section.relocs.len() as u32 + 1);
+                put_u32(&mut bytes, 0);
+                put_u16(&mut bytes, 0);
+            }
+            for reloc in &section.relocs {
+                put_u32(&mut bytes, reloc.offset);
+                put_u32(&mut bytes, (first_symbol + reloc.symbol) as u32);
+                put_u16(&mut bytes, reloc.typ);
+            }
+        }
+        debug_assert_eq!(bytes.len(), offset);
+
+        let mut put_name = |bytes: &mut Vec<u8>, name: &str| {
+            if name.len() > 8 {
+                put_u32(bytes, 0);
+                put_u32(bytes, name_offset(name));
+            } else {
+                let mut short = [0; 8];
+                short[..name.len()].copy_from_slice(name.as_bytes());
+                bytes.extend_from_slice(&short);
+            }
+        };
+        for (index, section) in self.sections.iter().enumerate() {
+            let selection = match section.selection {
+                Some(selection) => selection,
+                None => continue,
+            };
+            put_name(&mut bytes, &section.name);
+            put_u32(&mut bytes, 0); // Value
+            put_u16(&mut bytes, (index + 1) as u16);
+            put_u16(&mut bytes, IMAGE_SYM_TYPE_NULL);
+            bytes.push(IMAGE_SYM_CLASS_STATIC);
+            bytes.push(1); // NumberOfAuxSymbols
+            put_u32(&mut bytes, section.size as u32);
+            put_u16(&mut bytes, section.relocs.len().min(0xffff) as u16);
+            put_u16(&mut bytes, 0); // NumberOfLinenumbers
+            put_u32(&mut bytes, 0); // CheckSum
+            put_u16(&mut bytes, 0); // Number
+            bytes.push(selection);
+            bytes.extend_from_slice(&[0; 3]);
+        }
+        for symbol in &self.symbols {
+            put_name(&mut bytes, &symbol.name);
+            // Undefined symbols must be external, whatever their declared linkage.
+            let (section_number, value, storage_class) = match symbol.definition {
+                Some((section, value)) => ((section + 1) as i16, value, symbol.storage_class),
+                None => (IMAGE_SYM_UNDEFINED, 0, IMAGE_SYM_CLASS_EXTERNAL),
+            };
+            let typ = if symbol.function {
+                IMAGE_SYM_DTYPE_FUNCTION << IMAGE_SYM_DTYPE_SHIFT
+            } else {
+                IMAGE_SYM_TYPE_NULL
+            };
+            put_u32(&mut bytes, value);
+            put_u16(&mut bytes, section_number as u16);
+            put_u16(&mut bytes, typ);
+            bytes.push(storage_class);
+            bytes.push(0); // NumberOfAuxSymbols
+        }
+        debug_assert_eq!(bytes.len(), offset + num_symbols * COFF_SYMBOL_SIZE);
+
+        let strtab_size = strtab.len() as u32;
+        strtab[..4].copy_from_slice(&strtab_size.to_le_bytes());
+        bytes.extend_from_slice(&strtab);
+        bytes
+    }
+}
+
+/// Write the implicit addend `addend` of a relocation of type `typ` into `bytes` at `offset`.
+///
+/// Fails if the addend doesn't fit in the relocated field.
+pub fn write_addend(bytes: &mut [u8], offset: usize, typ: u16, addend: i64) -> Result<(), String> {
+    match typ {
+        IMAGE_REL_AMD64_ADDR64 => bytes[offset..offset + 8].copy_from_slice(&addend.to_le_bytes()),
+        IMAGE_REL_AMD64_ADDR32 | IMAGE_REL_AMD64_REL32 => {
+            let addend_i32 = addend as i32;
+            if i64::from(addend_i32) != addend {
+                return Err(format!(
+                    "addend {} of the relocation at offset {} is out of range",
+                    addend, offset
+                ));
+            }
+            bytes[offset..offset + 4].copy_from_slice(&addend_i32.to_le_bytes())
+        }
+        _ => return Err(format!("unsupported coff relocation type: {}", typ)),
+    }
+    Ok(())
+}
+
+fn put_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::pe::section_table::{IMAGE_SCN_ALIGN_16BYTES, IMAGE_SCN_ALIGN_8192BYTES};
+    use std::str::FromStr;
+    use target_lexicon::triple;
+
+    #[test]
+    fn relocations() {
+        let x86_64 = triple!("x86_64-pc-windows-msvc");
+        assert_eq!(
+            raw_relocation(Reloc::Abs8, &x86_64),
+            Ok((IMAGE_REL_AMD64_ADDR64, 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::Abs4, &x86_64),
+            Ok((IMAGE_REL_AMD64_ADDR32, 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::X86PCRel4, &x86_64),
+            Ok((IMAGE_REL_AMD64_REL32, 4))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::X86CallPLTRel4, &x86_64),
+            Ok((IMAGE_REL_AMD64_REL32, 4))
+        );
+        assert!(raw_relocation(Reloc::X86GOTPCRel4, &x86_64).is_err());
+        assert!(raw_relocation(Reloc::Abs4, &triple!("i686-pc-windows-msvc")).is_err());
+    }
+
+    #[test]
+    fn addends() {
+        let mut bytes = [0; 8];
+        assert_eq!(
+            write_addend(&mut bytes, 0, IMAGE_REL_AMD64_REL32, -4),
+            Ok(())
+        );
+        assert_eq!(&bytes[..4], &(-4i32).to_le_bytes());
+        assert_eq!(
+            write_addend(&mut bytes, 0, IMAGE_REL_AMD64_ADDR64, 1 << 32),
+            Ok(())
+        );
+        assert_eq!(&bytes, &(1i64 << 32).to_le_bytes());
+        assert!(write_addend(&mut bytes, 0, IMAGE_REL_AMD64_ADDR32, 1 << 32).is_err());
+    }
+
+    #[test]
+    fn alignments() {
+        assert_eq!(align_characteristics(1), IMAGE_SCN_ALIGN_1BYTES);
+        assert_eq!(align_characteristics(16), IMAGE_SCN_ALIGN_16BYTES);
+        assert_eq!(align_characteristics(8192), IMAGE_SCN_ALIGN_8192BYTES);
+    }
+}
@@ -0,0 +1,458 @@
+use cranelift_codegen::ir::*;
+use cranelift_codegen::isa::{self, CallConv};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_coff::*;
+use cranelift_frontend::*;
+use cranelift_module::*;
+use goblin::pe::relocation::{IMAGE_REL_AMD64_ADDR64, IMAGE_REL_AMD64_REL32};
+use goblin::pe::section_table::*;
+use goblin::pe::symbol::{
+    self, IMAGE_SYM_CLASS_EXTERNAL, IMAGE_SYM_CLASS_STATIC, IMAGE_SYM_DTYPE_FUNCTION,
+    IMAGE_SYM_DTYPE_NULL,
+};
+use goblin::pe::Coff;
+use std::str::FromStr;
+use target_lexicon::triple;
+
+fn coff_module() -> Module<CoffBackend> {
+    let isa = isa::lookup(triple!("x86_64-pc-windows-msvc"))
+        .unwrap()
+        .finish(settings::Flags::new(settings::builder()));
+    let builder = CoffBuilder::new(
+        isa,
+        "test.obj".to_string(),
+        CoffBuilder::default_libcall_names(),
+    )
+    .unwrap();
+    Module::new(builder)
+}
+
+fn find_symbol(coff: &Coff, name: &str) -> (usize, symbol::Symbol) {
+    coff.symbols
+        .iter()
+        .find(|(_, _, sym)| sym.name(&coff.strings).unwrap() == name)
+        .map(|(index, _, sym)| (index, sym))
+        .unwrap_or_else(|| panic!("missing symbol {}", name))
+}
+
+fn find_section<'a>(coff: &'a Coff, name: &str) -> (usize, &'a SectionTable) {
+    coff.sections
+        .iter()
+        .enumerate()
+        .find(|&(_, section)| section.name().unwrap() == name)
+        .unwrap_or_else(|| panic!("missing section {}", name))
+}
+
+fn section_data<'a>(bytes: &'a [u8], section: &SectionTable) -> &'a [u8] {
+    let start = section.pointer_to_raw_data as usize;
+    &bytes[start..start + section.size_of_raw_data as usize]
+}
+
+fn void_signature() -> Signature {
+    Signature {
+        params: vec![],
+        returns: vec![],
+        call_conv: CallConv::WindowsFastcall,
+    }
+}
+
+/// Define `caller` as a function with no parameters that calls each of `callees`.
+fn define_call(module: &mut Module<CoffBackend>, caller: FuncId, callees: &[FuncId]) {
+    let sig = void_signature();
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, caller.as_u32()), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        for &callee in callees {
+            let callee_ref = module.declare_func_in_func(callee, bcx.func);
+            bcx.ins().call(callee_ref, &[]);
+        }
+        bcx.ins().return_(&[]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(caller, &mut ctx).unwrap();
+}
+
+#[test]
+fn pic_unsupported() {
+    let mut flag_builder = settings::builder();
+    flag_builder.enable("is_pic").unwrap();
+    let isa = isa::lookup(triple!("x86_64-pc-windows-msvc"))
+        .unwrap()
+        .finish(settings::Flags::new(flag_builder));
+    assert!(CoffBuilder::new(
+        isa,
+        "test.obj".to_string(),
+        CoffBuilder::default_libcall_names()
+    )
+    .is_err());
+}
+
+#[test]
+fn call_relocations() {
+    let mut module = coff_module();
+    let sig = void_signature();
+    let external = module
+        .declare_function("external_function", Linkage::Import, &sig)
+        .unwrap();
+    let helper = module
+        .declare_function("helper", Linkage::Local, &sig)
+        .unwrap();
+    let caller = module
+        .declare_function("caller", Linkage::Export, &sig)
+        .unwrap();
+    define_call(&mut module, helper, &[]);
+    define_call(&mut module, caller, &[helper, external]);
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit();
+    let coff = Coff::parse(&bytes).unwrap();
+    assert_eq!(coff.header.machine, goblin::pe::header::COFF_MACHINE_X86_64);
+
+    let (text_index, text) = find_section(&coff, ".text");
+    assert_eq!(
+        text.characteristics,
+        IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ | IMAGE_SCN_ALIGN_16BYTES
+    );
+
+    let (helper_index, helper_sym) = find_symbol(&coff, "helper");
+    assert_eq!(helper_sym.section_number as usize, text_index + 1);
+    assert_eq!(helper_sym.value, 0);
+    assert_eq!(helper_sym.storage_class, IMAGE_SYM_CLASS_STATIC);
+    assert_eq!(helper_sym.derived_type(), IMAGE_SYM_DTYPE_FUNCTION);
+
+    let (_, caller_sym) = find_symbol(&coff, "caller");
+    assert_eq!(caller_sym.section_number as usize, text_index + 1);
+    assert_eq!(caller_sym.value % 16, 0);
+    assert_ne!(caller_sym.value, 0);
+    assert!(caller_sym.is_function_definition());
+
+    // The name is longer than 8 bytes, so it's stored in the string table.
+    let (external_index, external_sym) = find_symbol(&coff, "external_function");
+    assert!(external_sym.name_offset().is_some());
+    assert_eq!(external_sym.section_number, symbol::IMAGE_SYM_UNDEFINED);
+    assert_eq!(external_sym.storage_class, IMAGE_SYM_CLASS_EXTERNAL);
+
+    // Colocated calls are PC-relative, and others load the absolute address of the callee.
+    let relocs: Vec<_> = text.relocations(&bytes).unwrap().collect();
+    assert_eq!(relocs.len(), 2);
+    let data = section_data(&bytes, text);
+    for reloc in &relocs {
+        assert!(reloc.virtual_address > caller_sym.value);
+        let at = reloc.virtual_address as usize;
+        if reloc.symbol_table_index as usize == helper_index {
+            assert_eq!(reloc.typ, IMAGE_REL_AMD64_REL32);
+            assert_eq!(&data[at..at + 4], &0i32.to_le_bytes());
+        } else {
+            assert_eq!(reloc.symbol_table_index as usize, external_index);
+            assert_eq!(reloc.typ, IMAGE_REL_AMD64_ADDR64);
+            assert_eq!(&data[at..at + 8], &0i64.to_le_bytes());
+        }
+    }
+}
+
+#[test]
+fn br_table() {
+    let mut module = coff_module();
+    let sig = Signature {
+        params: vec![AbiParam::new(types::I32)],
+        returns: vec![AbiParam::new(types::I32)],
+        call_conv: CallConv::WindowsFastcall,
+    };
+    let func_id = module
+        .declare_function("switch", Linkage::Export, &sig)
+        .unwrap();
+
+    // fn(x) { match x { 0 => 10, 1 => 20, 2 => 30, _ => -1 } }
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, func_id.as_u32()), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        let cases: Vec<Ebb> = (0..3).map(|_| bcx.create_ebb()).collect();
+        let default = bcx.create_ebb();
+        let mut jt_data = JumpTableData::new();
+        for &case in &cases {
+            jt_data.push_entry(case);
+        }
+        let jt = bcx.create_jump_table(jt_data);
+
+        bcx.switch_to_block(ebb);
+        bcx.append_ebb_params_for_function_params(ebb);
+        let x = bcx.ebb_params(ebb)[0];
+        bcx.ins().br_table(x, default, jt);
+        for (i, &case) in cases.iter().enumerate() {
+            bcx.switch_to_block(case);
+            let value = bcx.ins().iconst(types::I32, 10 * (i as i64 + 1));
+            bcx.ins().return_(&[value]);
+        }
+        bcx.switch_to_block(default);
+        let value = bcx.ins().iconst(types::I32, -1);
+        bcx.ins().return_(&[value]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    let code_size = module.define_function(func_id, &mut ctx).unwrap();
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit();
+    let coff = Coff::parse(&bytes).unwrap();
+    let (text_index, text) = find_section(&coff, ".text");
+    let (_, switch_sym) = find_symbol(&coff, "switch");
+
+    assert_eq!(switch_sym.section_number as usize, text_index + 1);
+    assert!(switch_sym.is_function_definition());
+    assert_eq!(text.size_of_raw_data, code_size);
+}
+
+#[test]
+fn data_sections() {
+    let mut module = coff_module();
+    let strings = module
+        .declare_data("strings", Linkage::Local, false)
+        .unwrap();
+    let table = module.declare_data("table", Linkage::Export, true).unwrap();
+    let counter = module
+        .declare_data("counter", Linkage::Export, true)
+        .unwrap();
+
+    let mut data_ctx = DataContext::new();
+    data_ctx.define(b"foo\0bar\0baz\0".to_vec().into_boxed_slice());
+    module.define_data(strings, &data_ctx).unwrap();
+
+    data_ctx.clear();
+    data_ctx.define_zeroinit(16);
+    let strings_gv = module.declare_data_in_data(strings, &mut data_ctx);
+    data_ctx.write_data_addr(0, strings_gv, 4);
+    data_ctx.write_data_addr(8, strings_gv, 8);
+    module.define_data(table, &data_ctx).unwrap();
+
+    data_ctx.clear();
+    data_ctx.define_zeroinit(4096);
+    data_ctx.set_align(64);
+    module.define_data(counter, &data_ctx).unwrap();
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit();
+    let coff = Coff::parse(&bytes).unwrap();
+
+    let (rdata_index, rdata) = find_section(&coff, ".rdata");
+    assert_eq!(
+        rdata.characteristics,
+        IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_ALIGN_8BYTES
+    );
+    assert_eq!(section_data(&bytes, rdata), b"foo\0bar\0baz\0");
+    let (strings_index, strings_sym) = find_symbol(&coff, "strings");
+    assert_eq!(strings_sym.section_number as usize, rdata_index + 1);
+    assert_eq!(strings_sym.storage_class, IMAGE_SYM_CLASS_STATIC);
+    assert_eq!(strings_sym.derived_type(), IMAGE_SYM_DTYPE_NULL);
+
+    // Zero-initialized data with relocations still needs contents for the addends.
+    let (data_index, data) = find_section(&coff, ".data");
+    assert_eq!(
+        data.characteristics,
+        IMAGE_SCN_CNT_INITIALIZED_DATA
+            | IMAGE_SCN_MEM_READ
+            | IMAGE_SCN_MEM_WRITE
+            | IMAGE_SCN_ALIGN_8BYTES
+    );
+    let (_, table_sym) = find_symbol(&coff, "table");
+    assert_eq!(table_sym.section_number as usize, data_index + 1);
+    assert_eq!(table_sym.storage_class, IMAGE_SYM_CLASS_EXTERNAL);
+    let contents = section_data(&bytes, data);
+    let relocs: Vec<_> = data.relocations(&bytes).unwrap().collect();
+    assert_eq!(relocs.len(), 2);
+    for (reloc, &(offset, addend)) in relocs.iter().zip(&[(0, 4i64), (8, 8)]) {
+        assert_eq!(reloc.virtual_address, offset);
+        assert_eq!(reloc.symbol_table_index as usize, strings_index);
+        assert_eq!(reloc.typ, IMAGE_REL_AMD64_ADDR64);
+        let at = offset as usize;
+        assert_eq!(&contents[at..at + 8], &addend.to_le_bytes());
+    }
+
+    let (bss_index, bss) = find_section(&coff, ".bss");
+    assert_eq!(
+        bss.characteristics,
+        IMAGE_SCN_CNT_UNINITIALIZED_DATA
+            | IMAGE_SCN_MEM_READ
+            | IMAGE_SCN_MEM_WRITE
+            | IMAGE_SCN_ALIGN_64BYTES
+    );
+    assert_eq!(bss.size_of_raw_data, 4096);
+    assert_eq!(bss.pointer_to_raw_data, 0);
+    let (_, counter_sym) = find_symbol(&coff, "counter");
+    assert_eq!(counter_sym.section_number as usize, bss_index + 1);
+}
+
+#[test]
+fn custom_sections() {
+    let mut module = coff_module();
+    let sig = void_signature();
+    let init = module
+        .declare_function("init", Linkage::Local, &sig)
+        .unwrap();
+    define_call(&mut module, init, &[]);
+    let init_ptr = module
+        .declare_data("init_ptr", Linkage::Local, false)
+        .unwrap();
+    let note = module.declare_data("note", Linkage::Export, true).unwrap();
+
+    // Static initializers are found by the CRT in `.CRT$XC*` sections.
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(8);
+    data_ctx.set_section(".CRT$XCU");
+    let init_ref = module.declare_func_in_data(init, &mut data_ctx);
+    data_ctx.write_function_addr(0, init_ref);
+    module.define_data(init_ptr, &data_ctx).unwrap();
+
+    data_ctx.clear();
+    data_ctx.define(b"cranelift".to_vec().into_boxed_slice());
+    data_ctx.set_section(".cranelift_note");
+    module.define_data(note, &data_ctx).unwrap();
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit();
+    let coff = Coff::parse(&bytes).unwrap();
+
+    let (crt_index, crt) = find_section(&coff, ".CRT$XCU");
+    assert_eq!(
+        crt.characteristics,
+        IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_ALIGN_8BYTES
+    );
+    let (_, init_ptr_sym) = find_symbol(&coff, "init_ptr");
+    assert_eq!(init_ptr_sym.section_number as usize, crt_index + 1);
+    let (init_index, _) = find_symbol(&coff, "init");
+    let relocs: Vec<_> = crt.relocations(&bytes).unwrap().collect();
+    assert_eq!(relocs.len(), 1);
+    assert_eq!(relocs[0].virtual_address, 0);
+    assert_eq!(relocs[0].symbol_table_index as usize, init_index);
+    assert_eq!(relocs[0].typ, IMAGE_REL_AMD64_ADDR64);
+
+    // The name is longer than 8 bytes, so it's stored in the string table.
+    let (_, note_section) = find_section(&coff, ".cranelift_note");
+    assert_eq!(note_section.name[0], b'/');
+    assert_eq!(
+        note_section.characteristics,
+        IMAGE_SCN_CNT_INITIALIZED_DATA
+            | IMAGE_SCN_MEM_READ
+            | IMAGE_SCN_MEM_WRITE
+            | IMAGE_SCN_ALIGN_8BYTES
+    );
+    assert_eq!(section_data(&bytes, note_section), b"cranelift");
+}
+
+#[test]
+fn preemptible_comdat() {
+    let mut module = coff_module();
+    let sig = void_signature();
+    let shared = module
+        .declare_function("shared", Linkage::Preemptible, &sig)
+        .unwrap();
+    let caller = module
+        .declare_function("caller", Linkage::Export, &sig)
+        .unwrap();
+    define_call(&mut module, shared, &[]);
+    define_call(&mut module, caller, &[shared]);
+    let counter = module
+        .declare_data("counter", Linkage::Preemptible, true)
+        .unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(8);
+    module.define_data(counter, &data_ctx).unwrap();
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit();
+    let coff = Coff::parse(&bytes).unwrap();
+
+    // Each preemptible definition is in a COMDAT section of its own, which starts with a section
+    // symbol describing the COMDAT selection.
+    for &(name, characteristics) in &[
+        (
+            "shared",
+            IMAGE_SCN_CNT_CODE
+                | IMAGE_SCN_MEM_EXECUTE
+                | IMAGE_SCN_MEM_READ
+                | IMAGE_SCN_ALIGN_16BYTES,
+        ),
+        (
+            "counter",
+            IMAGE_SCN_CNT_UNINITIALIZED_DATA
+                | IMAGE_SCN_MEM_READ
+                | IMAGE_SCN_MEM_WRITE
+                | IMAGE_SCN_ALIGN_8BYTES,
+        ),
+    ] {
+        let (sym_index, sym) = find_symbol(&coff, name);
+        assert_eq!(sym.storage_class, IMAGE_SYM_CLASS_EXTERNAL);
+        assert_eq!(sym.value, 0);
+        let section = &coff.sections[sym.section_number as usize - 1];
+        assert_eq!(
+            section.characteristics,
+            characteristics | IMAGE_SCN_LNK_COMDAT
+        );
+        let (section_sym_index, _, section_sym) = coff
+            .symbols
+            .iter()
+            .find(|(_, _, other)| other.section_number == sym.section_number)
+            .unwrap();
+        assert!(section_sym_index < sym_index);
+        assert_eq!(section_sym.storage_class, IMAGE_SYM_CLASS_STATIC);
+        assert_eq!(section_sym.number_of_aux_symbols, 1);
+        let aux = coff
+            .symbols
+            .aux_section_definition(section_sym_index + 1)
+            .unwrap();
+        assert_eq!(aux.length, section.size_of_raw_data);
+        assert_eq!(aux.selection, symbol::IMAGE_COMDAT_SELECT_ANY);
+    }
+
+    // Other definitions stay in the shared sections, and refer to the preemptible symbol.
+    let (_, caller_sym) = find_symbol(&coff, "caller");
+    let text = &coff.sections[caller_sym.section_number as usize - 1];
+    assert_eq!(text.name().unwrap(), ".text");
+    assert_eq!(text.characteristics & IMAGE_SCN_LNK_COMDAT, 0);
+    let (shared_index, _) = find_symbol(&coff, "shared");
+    let relocs: Vec<_> = text.relocations(&bytes).unwrap().collect();
+    assert_eq!(relocs.len(), 1);
+    assert_eq!(relocs[0].symbol_table_index as usize, shared_index);
+    assert_eq!(relocs[0].typ, IMAGE_REL_AMD64_ADDR64);
+}
+
+#[test]
+fn tls_unsupported() {
+    let mut module = coff_module();
+    let counter = module
+        .declare_tls_data("counter", Linkage::Export, true)
+        .unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(8);
+    match module.define_data(counter, &data_ctx) {
+        Err(ModuleError::Backend(_)) => {}
+        _ => panic!("expected a backend error"),
+    }
+}
+
+#[test]
+fn data_address_writes_unsupported() {
+    let mut module = coff_module();
+    let data = module.declare_data("data", Linkage::Export, true).unwrap();
+    let target = module
+        .declare_data("target", Linkage::Local, false)
+        .unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(8);
+    let target_gv = module.declare_data_in_data(target, &mut data_ctx);
+    module.define_data(data, &data_ctx).unwrap();
+
+    // Addresses can only be written into data objects through relocations.
+    match module.write_data_dataaddr(data, 0, target_gv, 0) {
+        Err(ModuleError::Backend(_)) => {}
+        _ => panic!("expected a backend error"),
+    }
+}
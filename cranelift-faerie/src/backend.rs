@@ -11,8 +11,9 @@ use cranelift_module::{
 };
 use faerie;
 use failure::Error;
+use std::collections::HashMap;
 use std::fs::File;
-use target_lexicon::Triple;
+use target_lexicon::{BinaryFormat, Triple};
 
 #[derive(Debug)]
 /// Setting to enable collection of traps. Setting this to `Enabled` in
@@ -94,6 +95,14 @@ pub struct FaerieBackend {
     artifact: faerie::Artifact,
     trap_manifest: Option<FaerieTrapManifest>,
     libcall_names: Box<Fn(ir::LibCall) -> String>,
+    /// The linkage and writability of the local and exported data objects declared in the module,
+    /// or `None` once they're defined.
+    ///
+    /// Faerie doesn't allow changing the alignment of a data object once it's declared, and the
+    /// alignment is only known from its definition. So data objects are declared to faerie as
+    /// imports until they're defined, and the ones which are still undefined when the module is
+    /// finished are declared with their actual linkage then.
+    data_linkages: HashMap<String, Option<(Linkage, bool)>>,
 }
 
 pub struct FaerieCompiledFunction {}
@@ -125,6 +134,7 @@ impl Backend for FaerieBackend {
                 FaerieTrapCollection::Disabled => None,
             },
             libcall_names: builder.libcall_names,
+            data_linkages: HashMap::new(),
         }
     }
 
@@ -139,9 +149,19 @@ impl Backend for FaerieBackend {
     }
 
     fn declare_data(&mut self, name: &str, linkage: Linkage, writable: bool) {
+        // Data objects are declared as imports until they're defined; see `data_linkages`.
         self.artifact
-            .declare(name, translate_data_linkage(linkage, writable))
+            .declare(name, faerie::Decl::data_import())
             .expect("inconsistent declarations");
+        if linkage != Linkage::Import {
+            let pending = self
+                .data_linkages
+                .entry(name.to_owned())
+                .or_insert(Some((linkage, writable)));
+            if let Some(ref mut pending) = *pending {
+                *pending = (linkage, writable);
+            }
+        }
     }
 
     fn define_function(
@@ -161,6 +181,9 @@ impl Backend for FaerieBackend {
                 name,
                 namespace,
                 libcall_names: &*self.libcall_names,
+                jt_offsets: &ctx.func.jt_offsets,
+                in_place_addends: Vec::new(),
+                error: None,
             };
 
             if let Some(ref mut trap_manifest) = self.trap_manifest {
@@ -185,6 +208,13 @@ impl Backend for FaerieBackend {
                     )
                 };
             }
+            if let Some(error) = reloc_sink.error {
+                return Err(ModuleError::Backend(error));
+            }
+            for (offset, addend) in reloc_sink.in_place_addends {
+                let at = offset as usize;
+                code[at..at + 4].copy_from_slice(&addend.to_le_bytes());
+            }
         }
 
         self.artifact
@@ -206,8 +236,29 @@ impl Backend for FaerieBackend {
             ref data_decls,
             ref function_relocs,
             ref data_relocs,
+            align,
+            ref section,
         } = data_ctx.description();
 
+        if namespace.get_data_decl_by_name(name).tls {
+            return Err(ModuleError::Backend(
+                "faerie doesn't support thread-local data yet".to_owned(),
+            ));
+        }
+        if section.is_some() {
+            return Err(ModuleError::Backend(
+                "faerie doesn't support custom sections yet".to_owned(),
+            ));
+        }
+        let (linkage, writable) = self
+            .data_linkages
+            .get_mut(name)
+            .and_then(Option::take)
+            .expect("data object is declared and not defined yet");
+        self.artifact
+            .declare(name, translate_data_linkage(linkage, writable, align))
+            .expect("inconsistent declarations");
+
         let size = init.size();
         let mut bytes = Vec::with_capacity(size);
         match *init {
@@ -233,18 +284,59 @@ impl Backend for FaerieBackend {
                 .map_err(|e| ModuleError::Backend(e.to_string()))?;
         }
         for &(offset, id, addend) in data_relocs {
-            debug_assert_eq!(
-                addend, 0,
-                "faerie doesn't support addends in data section relocations yet"
-            );
             let to = &namespace.get_data_decl(&data_decls[id]).name;
-            self.artifact
-                .link(faerie::Link {
-                    from: name,
-                    to,
-                    at: u64::from(offset),
-                })
-                .map_err(|e| ModuleError::Backend(e.to_string()))?;
+            let link = faerie::Link {
+                from: name,
+                to,
+                at: u64::from(offset),
+            };
+            // Only 64-bit Mach-O relocations can hold addends which don't fit in 32 bits.
+            let addend_i32 = addend as i32;
+            if i64::from(addend_i32) != addend
+                && (self.isa.triple().binary_format == BinaryFormat::Elf
+                    || self.isa.pointer_bytes() != 8)
+            {
+                return Err(ModuleError::Backend(format!(
+                    "addend {} of the relocation at offset {} in {} is out of range",
+                    addend, offset, name
+                )));
+            }
+            match self.isa.triple().binary_format {
+                // ELF relocations carry their addend explicitly.
+                BinaryFormat::Elf => {
+                    let reloc = match self.isa.pointer_bytes() {
+                        4 => Reloc::Abs4,
+                        8 => Reloc::Abs8,
+                        _ => unimplemented!("unsupported pointer size"),
+                    };
+                    let (raw_reloc, raw_addend) =
+                        container::raw_relocation(reloc, self.isa.triple())
+                            .map_err(ModuleError::Backend)?;
+                    debug_assert_eq!(raw_addend, 0);
+                    if container::addend_in_place(self.isa.triple()) {
+                        let at = offset as usize;
+                        bytes[at..at + 4].copy_from_slice(&addend_i32.to_le_bytes());
+                    }
+                    self.artifact.link_with(
+                        link,
+                        faerie::Reloc::Raw {
+                            reloc: raw_reloc,
+                            addend: addend_i32,
+                        },
+                    )
+                }
+                // Mach-O relocations take their addend from the relocated bytes.
+                _ => {
+                    let at = offset as usize;
+                    match self.isa.pointer_bytes() {
+                        4 => bytes[at..at + 4].copy_from_slice(&addend_i32.to_le_bytes()),
+                        8 => bytes[at..at + 8].copy_from_slice(&addend.to_le_bytes()),
+                        _ => unimplemented!("unsupported pointer size"),
+                    }
+                    self.artifact.link(link)
+                }
+            }
+            .map_err(|e| ModuleError::Backend(e.to_string()))?;
         }
 
         self.artifact
@@ -258,8 +350,10 @@ impl Backend for FaerieBackend {
         _data: &mut FaerieCompiledData,
         _offset: usize,
         _what: ir::FuncRef,
-    ) {
-        unimplemented!()
+    ) -> ModuleResult<()> {
+        Err(ModuleError::Backend(
+            "faerie doesn't support writing addresses into defined data".to_owned(),
+        ))
     }
 
     fn write_data_dataaddr(
@@ -268,8 +362,10 @@ impl Backend for FaerieBackend {
         _offset: usize,
         _what: ir::GlobalValue,
         _usize: binemit::Addend,
-    ) {
-        unimplemented!()
+    ) -> ModuleResult<()> {
+        Err(ModuleError::Backend(
+            "faerie doesn't support writing addresses into defined data".to_owned(),
+        ))
     }
 
     fn finalize_function(
@@ -296,7 +392,16 @@ impl Backend for FaerieBackend {
         // Nothing to do.
     }
 
-    fn finish(self) -> FaerieProduct {
+    fn finish(mut self) -> FaerieProduct {
+        // Declare the data objects which were never defined with their actual linkage, so that
+        // they're reported as undefined when emitting the artifact instead of becoming imports.
+        for (name, pending) in self.data_linkages {
+            if let Some((linkage, writable)) = pending {
+                self.artifact
+                    .declare(name, translate_data_linkage(linkage, writable, None))
+                    .expect("inconsistent declarations");
+            }
+        }
         FaerieProduct {
             artifact: self.artifact,
             trap_manifest: self.trap_manifest,
@@ -341,13 +446,16 @@ fn translate_function_linkage(linkage: Linkage) -> faerie::Decl {
     }
 }
 
-fn translate_data_linkage(linkage: Linkage, writable: bool) -> faerie::Decl {
-    match linkage {
-        Linkage::Import => faerie::Decl::data_import().into(),
-        Linkage::Local => faerie::Decl::data().with_writable(writable).into(),
-        Linkage::Export => faerie::Decl::data().global().with_writable(writable).into(),
-        Linkage::Preemptible => faerie::Decl::data().weak().with_writable(writable).into(),
-    }
+fn translate_data_linkage(linkage: Linkage, writable: bool, align: Option<u64>) -> faerie::Decl {
+    let decl = match linkage {
+        Linkage::Import => return faerie::Decl::data_import().into(),
+        Linkage::Local => faerie::Decl::data(),
+        Linkage::Export => faerie::Decl::data().global(),
+        Linkage::Preemptible => faerie::Decl::data().weak(),
+    };
+    decl.with_writable(writable)
+        .with_align(align.map(|align| align as usize))
+        .into()
 }
 
 struct FaerieRelocSink<'a> {
@@ -356,11 +464,61 @@ struct FaerieRelocSink<'a> {
     name: &'a str,
     namespace: &'a ModuleNamespace<'a, FaerieBackend>,
     libcall_names: &'a Fn(ir::LibCall) -> String,
+    jt_offsets: &'a ir::JumpTableOffsets,
+    /// The addends to write in the code once it is emitted, for targets using REL relocations.
+    in_place_addends: Vec<(CodeOffset, i32)>,
+    /// The first relocation which couldn't be recorded, since `RelocSink` can't report errors.
+    error: Option<String>,
+}
+
+impl<'a> FaerieRelocSink<'a> {
+    /// Record `error`, unless an earlier relocation has already failed.
+    fn set_error(&mut self, error: String) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    /// Add a relocation at `offset` to the symbol `to`, with `addend` on top of the addend
+    /// implied by `reloc`.
+    fn link(&mut self, offset: CodeOffset, reloc: Reloc, to: &str, addend: Addend) {
+        let (raw_reloc, raw_addend) = match container::raw_relocation(reloc, &self.triple) {
+            Ok(raw) => raw,
+            Err(error) => return self.set_error(error),
+        };
+        let final_addend = addend + raw_addend;
+        let addend_i32 = final_addend as i32;
+        if i64::from(addend_i32) != final_addend {
+            let error = format!(
+                "addend {} of the relocation at offset {} in {} is out of range",
+                final_addend, offset, self.name
+            );
+            return self.set_error(error);
+        }
+        if container::addend_in_place(&self.triple) {
+            self.in_place_addends.push((offset, addend_i32));
+        }
+        self.artifact
+            .link_with(
+                faerie::Link {
+                    from: self.name,
+                    to,
+                    at: u64::from(offset),
+                },
+                faerie::Reloc::Raw {
+                    reloc: raw_reloc,
+                    addend: addend_i32,
+                },
+            )
+            .expect("faerie relocation error");
+    }
 }
 
 impl<'a> RelocSink for FaerieRelocSink<'a> {
-    fn reloc_ebb(&mut self, _offset: CodeOffset, _reloc: Reloc, _ebb_offset: CodeOffset) {
-        unimplemented!();
+    fn reloc_ebb(&mut self, offset: CodeOffset, reloc: Reloc, ebb_offset: CodeOffset) {
+        // The relocation is relative to the symbol of the function itself.
+        let name = self.name;
+        self.link(offset, reloc, name, i64::from(ebb_offset));
     }
 
     fn reloc_external(
@@ -387,27 +545,12 @@ impl<'a> RelocSink for FaerieRelocSink<'a> {
             }
             _ => panic!("invalid ExternalName {}", name),
         };
-        let (raw_reloc, raw_addend) = container::raw_relocation(reloc, &self.triple);
-        // TODO: Handle overflow.
-        let final_addend = addend + raw_addend;
-        let addend_i32 = final_addend as i32;
-        debug_assert!(i64::from(addend_i32) == final_addend);
-        self.artifact
-            .link_with(
-                faerie::Link {
-                    from: self.name,
-                    to: &ref_name,
-                    at: u64::from(offset),
-                },
-                faerie::Reloc::Raw {
-                    reloc: raw_reloc,
-                    addend: addend_i32,
-                },
-            )
-            .expect("faerie relocation error");
+        self.link(offset, reloc, &ref_name, addend);
     }
 
-    fn reloc_jt(&mut self, _offset: CodeOffset, _reloc: Reloc, _jt: ir::JumpTable) {
-        unimplemented!();
+    fn reloc_jt(&mut self, offset: CodeOffset, reloc: Reloc, jt: ir::JumpTable) {
+        let name = self.name;
+        let jt_offset = self.jt_offsets[jt];
+        self.link(offset, reloc, name, i64::from(jt_offset));
     }
 }
@@ -14,52 +14,176 @@ pub enum Format {
 
 /// Translate from a Cranelift `Reloc` to a raw object-file-format-specific
 /// relocation code and relocation-implied addend.
-pub fn raw_relocation(reloc: Reloc, triple: &Triple) -> (u32, i64) {
+///
+/// Return an error if the relocation can't be represented for `triple`.
+pub fn raw_relocation(reloc: Reloc, triple: &Triple) -> Result<(u32, i64), String> {
+    let unsupported = || Err(format!("unsupported relocation for {}: {}", triple, reloc));
     match triple.binary_format {
         BinaryFormat::Elf => {
             use goblin::elf;
-            (
-                match triple.architecture {
-                    Architecture::X86_64 => {
-                        match reloc {
-                            Reloc::Abs4 => elf::reloc::R_X86_64_32,
-                            Reloc::Abs8 => elf::reloc::R_X86_64_64,
-                            Reloc::X86PCRel4 | Reloc::X86CallPCRel4 => elf::reloc::R_X86_64_PC32,
-                            // TODO: Get Cranelift to tell us when we can use
-                            // R_X86_64_GOTPCRELX/R_X86_64_REX_GOTPCRELX.
-                            Reloc::X86CallPLTRel4 => elf::reloc::R_X86_64_PLT32,
-                            Reloc::X86GOTPCRel4 => elf::reloc::R_X86_64_GOTPCREL,
-                            _ => unimplemented!(),
-                        }
+            let raw_reloc = match triple.architecture {
+                Architecture::X86_64 => {
+                    match reloc {
+                        Reloc::Abs4 => elf::reloc::R_X86_64_32,
+                        Reloc::Abs8 => elf::reloc::R_X86_64_64,
+                        Reloc::X86PCRel4 | Reloc::X86CallPCRel4 => elf::reloc::R_X86_64_PC32,
+                        // TODO: Get Cranelift to tell us when we can use
+                        // R_X86_64_GOTPCRELX/R_X86_64_REX_GOTPCRELX.
+                        Reloc::X86CallPLTRel4 => elf::reloc::R_X86_64_PLT32,
+                        Reloc::X86GOTPCRel4 => elf::reloc::R_X86_64_GOTPCREL,
+                        Reloc::X86GOTTPOff4 => elf::reloc::R_X86_64_GOTTPOFF,
+                        _ => return unsupported(),
                     }
-                    _ => unimplemented!("unsupported architecture: {}", triple),
+                }
+                Architecture::I386 | Architecture::I586 | Architecture::I686 => match reloc {
+                    Reloc::Abs4 => elf::reloc::R_386_32,
+                    Reloc::X86PCRel4 | Reloc::X86CallPCRel4 => elf::reloc::R_386_PC32,
+                    Reloc::X86CallPLTRel4 => elf::reloc::R_386_PLT32,
+                    Reloc::X86GOTPC4 => elf::reloc::R_386_GOTPC,
+                    _ => return unsupported(),
+                },
+                Architecture::Aarch64 => match reloc {
+                    Reloc::Abs4 => elf::reloc::R_AARCH64_ABS32,
+                    Reloc::Abs8 => elf::reloc::R_AARCH64_ABS64,
+                    Reloc::Arm64Call => elf::reloc::R_AARCH64_CALL26,
+                    Reloc::Arm64AdrPrelPgHi21 => elf::reloc::R_AARCH64_ADR_PREL_PG_HI21,
+                    Reloc::Arm64AddAbsLo12Nc => elf::reloc::R_AARCH64_ADD_ABS_LO12_NC,
+                    Reloc::Arm64AdrGotPage21 => elf::reloc::R_AARCH64_ADR_GOT_PAGE,
+                    Reloc::Arm64Ld64GotLo12Nc => elf::reloc::R_AARCH64_LD64_GOT_LO12_NC,
+                    _ => return unsupported(),
                 },
-                // Most ELF relocations do not include an implicit addend.
-                0,
-            )
+                _ => return Err(format!("unsupported architecture: {}", triple)),
+            };
+            // Most ELF relocations do not include an implicit addend.
+            Ok((raw_reloc, 0))
         }
         BinaryFormat::Macho => {
             use goblin::mach;
             match triple.architecture {
                 Architecture::X86_64 => {
                     match reloc {
-                        Reloc::Abs8 => (u32::from(mach::relocation::R_ABS), 0),
+                        Reloc::Abs8 => Ok((u32::from(mach::relocation::R_ABS), 0)),
                         // Mach-O doesn't need us to distinguish between PC-relative calls
                         // and PLT calls, but it does need us to distinguish between calls
                         // and non-calls. And, it includes the 4-byte addend implicitly.
-                        Reloc::X86PCRel4 => (u32::from(mach::relocation::X86_64_RELOC_SIGNED), 4),
+                        Reloc::X86PCRel4 => {
+                            Ok((u32::from(mach::relocation::X86_64_RELOC_SIGNED), 4))
+                        }
                         Reloc::X86CallPCRel4 | Reloc::X86CallPLTRel4 => {
-                            (u32::from(mach::relocation::X86_64_RELOC_BRANCH), 4)
+                            Ok((u32::from(mach::relocation::X86_64_RELOC_BRANCH), 4))
                         }
                         Reloc::X86GOTPCRel4 => {
-                            (u32::from(mach::relocation::X86_64_RELOC_GOT_LOAD), 4)
+                            Ok((u32::from(mach::relocation::X86_64_RELOC_GOT_LOAD), 4))
+                        }
+                        _ => unsupported(),
+                    }
+                }
+                Architecture::Aarch64 => {
+                    match reloc {
+                        Reloc::Abs8 => Ok((u32::from(mach::relocation::ARM64_RELOC_UNSIGNED), 0)),
+                        Reloc::Arm64Call => {
+                            Ok((u32::from(mach::relocation::ARM64_RELOC_BRANCH26), 0))
+                        }
+                        Reloc::Arm64AdrPrelPgHi21 => {
+                            Ok((u32::from(mach::relocation::ARM64_RELOC_PAGE21), 0))
+                        }
+                        Reloc::Arm64AdrGotPage21 => {
+                            Ok((u32::from(mach::relocation::ARM64_RELOC_GOT_LOAD_PAGE21), 0))
                         }
-                        _ => unimplemented!("unsupported mach-o reloc: {}", reloc),
+                        // Faerie marks every Mach-O relocation except `ARM64_RELOC_UNSIGNED` as
+                        // PC-relative, which the page offset relocations are not.
+                        Reloc::Arm64AddAbsLo12Nc | Reloc::Arm64Ld64GotLo12Nc => Err(format!(
+                            "page offset relocations are not supported in Mach-O: {}",
+                            reloc
+                        )),
+                        _ => unsupported(),
                     }
                 }
-                _ => unimplemented!("unsupported architecture: {}", triple),
+                _ => Err(format!("unsupported architecture: {}", triple)),
             }
         }
-        _ => unimplemented!("unsupported format"),
+        _ => Err(format!("unsupported format: {}", triple)),
+    }
+}
+
+/// Whether relocations for `triple` are expected to find their addend in the relocated bytes.
+///
+/// i386 ELF uses REL relocations, whose addend is stored in place, but Faerie always emits RELA
+/// relocations. Their addend is written in both places, so that the object file can be linked
+/// either way.
+pub fn addend_in_place(triple: &Triple) -> bool {
+    triple.binary_format == BinaryFormat::Elf
+        && match triple.architecture {
+            Architecture::I386 | Architecture::I586 | Architecture::I686 => true,
+            _ => false,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::{elf, mach};
+    use std::str::FromStr;
+    use target_lexicon::triple;
+
+    #[test]
+    fn elf_relocations() {
+        let i686 = triple!("i686-unknown-linux-gnu");
+        assert_eq!(
+            raw_relocation(Reloc::Abs4, &i686),
+            Ok((elf::reloc::R_386_32, 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::X86CallPCRel4, &i686),
+            Ok((elf::reloc::R_386_PC32, 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::X86CallPLTRel4, &i686),
+            Ok((elf::reloc::R_386_PLT32, 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::X86GOTPC4, &i686),
+            Ok((elf::reloc::R_386_GOTPC, 0))
+        );
+
+        let aarch64 = triple!("aarch64-unknown-linux-gnu");
+        assert_eq!(
+            raw_relocation(Reloc::Abs8, &aarch64),
+            Ok((elf::reloc::R_AARCH64_ABS64, 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::Arm64Call, &aarch64),
+            Ok((elf::reloc::R_AARCH64_CALL26, 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::Arm64AdrPrelPgHi21, &aarch64),
+            Ok((elf::reloc::R_AARCH64_ADR_PREL_PG_HI21, 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::Arm64Ld64GotLo12Nc, &aarch64),
+            Ok((elf::reloc::R_AARCH64_LD64_GOT_LO12_NC, 0))
+        );
+    }
+
+    #[test]
+    fn macho_relocations() {
+        let aarch64 = triple!("aarch64-apple-darwin");
+        assert_eq!(
+            raw_relocation(Reloc::Arm64Call, &aarch64),
+            Ok((u32::from(mach::relocation::ARM64_RELOC_BRANCH26), 0))
+        );
+        assert_eq!(
+            raw_relocation(Reloc::Arm64AdrPrelPgHi21, &aarch64),
+            Ok((u32::from(mach::relocation::ARM64_RELOC_PAGE21), 0))
+        );
+        assert!(raw_relocation(Reloc::Arm64AddAbsLo12Nc, &aarch64).is_err());
+        assert!(raw_relocation(Reloc::Arm64Ld64GotLo12Nc, &aarch64).is_err());
+    }
+
+    #[test]
+    fn in_place_addends() {
+        assert!(addend_in_place(&triple!("i686-unknown-linux-gnu")));
+        assert!(!addend_in_place(&triple!("x86_64-unknown-linux-gnu")));
+        assert!(!addend_in_place(&triple!("aarch64-apple-darwin")));
     }
 }
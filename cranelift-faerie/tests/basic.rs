@@ -0,0 +1,349 @@
+use cranelift_codegen::ir::*;
+use cranelift_codegen::isa::{self, CallConv};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_faerie::*;
+use cranelift_frontend::*;
+use cranelift_module::*;
+use goblin::elf::Elf;
+use std::str::FromStr;
+use target_lexicon::triple;
+
+fn faerie_module() -> Module<FaerieBackend> {
+    faerie_module_for(triple!("x86_64-unknown-linux-gnu"))
+}
+
+fn faerie_module_for(triple: target_lexicon::Triple) -> Module<FaerieBackend> {
+    let mut flag_builder = settings::builder();
+    flag_builder.enable("is_pic").unwrap();
+    let isa = isa::lookup(triple)
+        .unwrap()
+        .finish(settings::Flags::new(flag_builder));
+    let builder = FaerieBuilder::new(
+        isa,
+        "test.o".to_string(),
+        FaerieTrapCollection::Disabled,
+        FaerieBuilder::default_libcall_names(),
+    )
+    .unwrap();
+    Module::new(builder)
+}
+
+#[test]
+fn i686_call_relocation() {
+    let mut module = faerie_module_for(triple!("i686-unknown-linux-gnu"));
+    let sig = Signature {
+        params: vec![],
+        returns: vec![],
+        call_conv: CallConv::SystemV,
+    };
+    let callee = module
+        .declare_function("callee", Linkage::Import, &sig)
+        .unwrap();
+    let caller = module
+        .declare_function("caller", Linkage::Export, &sig)
+        .unwrap();
+
+    // fn caller() { callee() }
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, caller.as_u32()), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let callee_ref = module.declare_func_in_func(callee, &mut bcx.func);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        bcx.ins().call(callee_ref, &[]);
+        bcx.ins().return_(&[]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(caller, &mut ctx).unwrap();
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit().unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    assert_eq!(elf.header.e_machine, goblin::elf::header::EM_386);
+    assert_eq!(elf.shdr_relocs.len(), 1);
+    let (index, ref relocs) = elf.shdr_relocs[0];
+    let relocs: Vec<_> = relocs.iter().collect();
+    assert_eq!(relocs.len(), 1);
+    assert_eq!(relocs[0].r_type, goblin::elf::reloc::R_386_PC32);
+    let sym = elf.syms.get(relocs[0].r_sym).unwrap();
+    assert_eq!(elf.strtab.get(sym.st_name).unwrap().unwrap(), "callee");
+
+    // Faerie only writes RELA relocations, while i386 uses REL relocations, so the addend is
+    // found both in the relocation and in the relocated bytes.
+    let reloc_shdr = &elf.section_headers[index];
+    assert_eq!(reloc_shdr.sh_type, goblin::elf::section_header::SHT_RELA);
+    assert_eq!(relocs[0].r_addend, Some(-4));
+    let text = &elf.section_headers[reloc_shdr.sh_info as usize];
+    let at = (text.sh_offset + relocs[0].r_offset) as usize;
+    assert_eq!(&bytes[at..at + 4], &(-4i32).to_le_bytes());
+}
+
+/// Define `table`, holding the addresses of the second and third strings in `strings`.
+fn define_string_table(module: &mut Module<FaerieBackend>) {
+    let strings = module
+        .declare_data("strings", Linkage::Local, false)
+        .unwrap();
+    let table = module
+        .declare_data("table", Linkage::Export, false)
+        .unwrap();
+
+    let mut data_ctx = DataContext::new();
+    data_ctx.define(b"foo\0bar\0baz\0".to_vec().into_boxed_slice());
+    module.define_data(strings, &data_ctx).unwrap();
+
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(16);
+    let strings_gv = module.declare_data_in_data(strings, &mut data_ctx);
+    data_ctx.write_data_addr(0, strings_gv, 4);
+    data_ctx.write_data_addr(8, strings_gv, 8);
+    module.define_data(table, &data_ctx).unwrap();
+    module.finalize_definitions();
+}
+
+#[test]
+fn elf_data_relocation_addends() {
+    let mut module = faerie_module();
+    define_string_table(&mut module);
+
+    let bytes = module.finish().emit().unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    let mut relocs: Vec<_> = elf
+        .shdr_relocs
+        .iter()
+        .flat_map(|(_, relocs)| relocs.iter())
+        .collect();
+    relocs.sort_by_key(|reloc| reloc.r_offset);
+    assert_eq!(relocs.len(), 2);
+    for (reloc, &(offset, addend)) in relocs.iter().zip(&[(0, 4), (8, 8)]) {
+        assert_eq!(reloc.r_type, goblin::elf::reloc::R_X86_64_64);
+        assert_eq!(reloc.r_offset, offset);
+        assert_eq!(reloc.r_addend, Some(addend));
+        let sym = elf.syms.get(reloc.r_sym).unwrap();
+        let shdr = &elf.section_headers[sym.st_shndx];
+        assert_eq!(
+            elf.shdr_strtab.get(shdr.sh_name).unwrap().unwrap(),
+            ".rodata.strings"
+        );
+    }
+}
+
+#[test]
+fn i686_data_relocation_addends() {
+    let mut module = faerie_module_for(triple!("i686-unknown-linux-gnu"));
+    define_string_table(&mut module);
+
+    let bytes = module.finish().emit().unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    let (index, ref relocs) = elf.shdr_relocs[0];
+    let mut relocs: Vec<_> = relocs.iter().collect();
+    relocs.sort_by_key(|reloc| reloc.r_offset);
+    assert_eq!(relocs.len(), 2);
+    let table = &elf.section_headers[elf.section_headers[index].sh_info as usize];
+    for (reloc, &(offset, addend)) in relocs.iter().zip(&[(0, 4i32), (8, 8)]) {
+        assert_eq!(reloc.r_type, goblin::elf::reloc::R_386_32);
+        assert_eq!(reloc.r_offset, offset);
+        assert_eq!(reloc.r_addend, Some(i64::from(addend)));
+        let at = (table.sh_offset + offset) as usize;
+        assert_eq!(&bytes[at..at + 4], &addend.to_le_bytes());
+    }
+}
+
+#[test]
+fn elf_data_relocation_addend_out_of_range() {
+    let mut module = faerie_module();
+    let strings = module
+        .declare_data("strings", Linkage::Local, false)
+        .unwrap();
+    let table = module
+        .declare_data("table", Linkage::Export, false)
+        .unwrap();
+
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(8);
+    let strings_gv = module.declare_data_in_data(strings, &mut data_ctx);
+    data_ctx.write_data_addr(0, strings_gv, 1 << 32);
+    match module.define_data(table, &data_ctx) {
+        Err(ModuleError::Backend(_)) => {}
+        _ => panic!("expected a backend error"),
+    }
+}
+
+#[test]
+fn macho_data_relocation_addends() {
+    use goblin::mach::{self, MachO};
+
+    let mut module = faerie_module_for(triple!("x86_64-apple-darwin"));
+    define_string_table(&mut module);
+
+    let bytes = module.finish().emit().unwrap();
+    let macho = MachO::parse(&bytes, 0).unwrap();
+    let ctx = mach::parse_magic_and_ctx(&bytes, 0).unwrap().1.unwrap();
+    let table = macho
+        .symbols()
+        .map(Result::unwrap)
+        .find(|&(name, _)| name == "_table")
+        .unwrap()
+        .1;
+    let (section, data) = macho
+        .segments
+        .sections()
+        .flat_map(|sections| sections.map(Result::unwrap))
+        .find(|&(ref section, _)| section.name().unwrap() == "__data")
+        .unwrap();
+
+    // Mach-O relocations have no explicit addend; it's stored in the relocated bytes.
+    let start = (table.n_value - section.addr) as usize;
+    assert_eq!(&data[start..start + 8], &4u64.to_le_bytes());
+    assert_eq!(&data[start + 8..start + 16], &8u64.to_le_bytes());
+    let mut relocs: Vec<_> = section
+        .iter_relocations(&bytes, ctx)
+        .map(Result::unwrap)
+        .collect();
+    relocs.sort_by_key(|reloc| reloc.r_address);
+    assert_eq!(relocs.len(), 2);
+    for (reloc, &offset) in relocs.iter().zip(&[0, 8]) {
+        assert_eq!(reloc.r_type(), mach::relocation::X86_64_RELOC_UNSIGNED);
+        assert_eq!(reloc.r_address as usize, start + offset);
+        assert!(!reloc.is_pic());
+    }
+}
+
+#[test]
+fn data_alignment() {
+    let mut module = faerie_module();
+    let data_id = module
+        .declare_data("aligned", Linkage::Export, false)
+        .unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define(vec![1, 2, 3, 4].into_boxed_slice());
+    data_ctx.set_align(64);
+    module.define_data(data_id, &data_ctx).unwrap();
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit().unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    let shdr = elf
+        .section_headers
+        .iter()
+        .find(|shdr| elf.shdr_strtab.get(shdr.sh_name).unwrap().unwrap() == ".rodata.aligned")
+        .unwrap();
+    assert_eq!(shdr.sh_addralign, 64);
+    let sym = elf
+        .syms
+        .iter()
+        .find(|sym| elf.strtab.get(sym.st_name).unwrap().unwrap() == "aligned")
+        .unwrap();
+    assert_ne!(
+        sym.st_shndx,
+        goblin::elf::section_header::SHN_UNDEF as usize
+    );
+}
+
+#[test]
+fn custom_section_unsupported() {
+    let mut module = faerie_module();
+    let data_id = module.declare_data("ctors", Linkage::Local, false).unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(8);
+    data_ctx.set_section(".init_array");
+    match module.define_data(data_id, &data_ctx) {
+        Err(ModuleError::Backend(_)) => {}
+        _ => panic!("expected a backend error"),
+    }
+}
+
+#[test]
+fn tls_import() {
+    let mut module = faerie_module();
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+    let counter = module
+        .declare_tls_data("counter", Linkage::Import, true)
+        .unwrap();
+    let func_id = module
+        .declare_function("counter_addr", Linkage::Export, &sig)
+        .unwrap();
+
+    // fn counter_addr() -> *mut i64 { &counter }
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, func_id.as_u32()), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let gv = module.declare_data_in_func(counter, &mut bcx.func);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        let addr = bcx.ins().global_value(types::I64, gv);
+        bcx.ins().return_(&[addr]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(func_id, &mut ctx).unwrap();
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit().unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    let relocs: Vec<_> = elf
+        .shdr_relocs
+        .iter()
+        .flat_map(|(_, relocs)| relocs.iter())
+        .collect();
+    assert_eq!(relocs.len(), 1);
+    assert_eq!(relocs[0].r_type, goblin::elf::reloc::R_X86_64_GOTTPOFF);
+    assert_eq!(relocs[0].r_addend, Some(-4));
+    let sym = elf.syms.get(relocs[0].r_sym).unwrap();
+    assert_eq!(elf.strtab.get(sym.st_name).unwrap().unwrap(), "counter");
+}
+
+#[test]
+fn tls_import_unsupported() {
+    let mut module = faerie_module_for(triple!("i686-unknown-linux-gnu"));
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I32)],
+        call_conv: CallConv::SystemV,
+    };
+    let counter = module
+        .declare_tls_data("counter", Linkage::Import, true)
+        .unwrap();
+    let func_id = module
+        .declare_function("counter_addr", Linkage::Export, &sig)
+        .unwrap();
+
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, func_id.as_u32()), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let gv = module.declare_data_in_func(counter, &mut bcx.func);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        let addr = bcx.ins().global_value(types::I32, gv);
+        bcx.ins().return_(&[addr]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    match module.define_function(func_id, &mut ctx) {
+        Err(ModuleError::Compilation(_)) => {}
+        _ => panic!("expected a compilation error"),
+    }
+}
+
+#[test]
+fn undefined_data() {
+    let mut module = faerie_module();
+    module
+        .declare_data("missing", Linkage::Export, false)
+        .unwrap();
+    module.finalize_definitions();
+
+    // Data objects which are declared but never defined aren't turned into imports.
+    assert!(module.finish().emit().is_err());
+}
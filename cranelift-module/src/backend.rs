@@ -70,7 +70,9 @@ where
 
     /// Define a zero-initialized data object of the given size.
     ///
-    /// Data objects must be declared before being defined.
+    /// Data objects must be declared before being defined. Backends should honor the alignment
+    /// and section requested by `data_ctx`, and return an error for what they can't represent.
+    /// Whether the data object is thread-local is part of its declaration in `namespace`.
     fn define_data(
         &mut self,
         name: &str,
@@ -86,7 +88,7 @@ where
         data: &mut Self::CompiledData,
         offset: usize,
         what: ir::FuncRef,
-    );
+    ) -> ModuleResult<()>;
 
     /// Write the address of `what` plus `addend` into the data for `data` at `offset`. `data` must
     /// refer to a defined data object.
@@ -96,7 +98,7 @@ where
         offset: usize,
         what: ir::GlobalValue,
         addend: binemit::Addend,
-    );
+    ) -> ModuleResult<()>;
 
     /// Perform all outstanding relocations on the given function. This requires all `Local`
     /// and `Export` entities referenced to be defined.
@@ -123,6 +125,24 @@ where
     /// "Publish" all finalized functions and data objects to their ultimate destinations.
     fn publish(&mut self);
 
+    /// "Publish" the function `func`, which was just finalized by `Module::finalize_function`,
+    /// leaving the other definitions as they are.
+    ///
+    /// By default this publishes all finalized definitions with `publish`.
+    fn publish_function(&mut self, _func: &Self::CompiledFunction) {
+        self.publish();
+    }
+
+    /// Free the compiled artifact of a function which is being freed or redefined.
+    ///
+    /// By default this just drops it.
+    fn free_function(&mut self, _func: Self::CompiledFunction) {}
+
+    /// Free the compiled artifact of a data object which is being freed.
+    ///
+    /// By default this just drops it.
+    fn free_data(&mut self, _data: Self::CompiledData) {}
+
     /// Consume this `Backend` and return a result. Some implementations may
     /// provide additional functionality through this result.
     fn finish(self) -> Self::Product;
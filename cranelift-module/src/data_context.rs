@@ -3,7 +3,9 @@
 use cranelift_codegen::binemit::{Addend, CodeOffset};
 use cranelift_codegen::entity::PrimaryMap;
 use cranelift_codegen::ir;
+use std::borrow::ToOwned;
 use std::boxed::Box;
+use std::string::String;
 use std::vec::Vec;
 
 /// This specifies how data is to be initialized.
@@ -46,6 +48,10 @@ pub struct DataDescription {
     pub function_relocs: Vec<(CodeOffset, ir::FuncRef)>,
     /// Data addresses to write at specified offsets.
     pub data_relocs: Vec<(CodeOffset, ir::GlobalValue, Addend)>,
+    /// Alignment in bytes, if it's more than the backend's default.
+    pub align: Option<u64>,
+    /// Name of the section the data should be placed in, instead of the default one.
+    pub section: Option<String>,
 }
 
 /// This is to data objects what cranelift_codegen::Context is to functions.
@@ -63,6 +69,8 @@ impl DataContext {
                 data_decls: PrimaryMap::new(),
                 function_relocs: vec![],
                 data_relocs: vec![],
+                align: None,
+                section: None,
             },
         }
     }
@@ -74,6 +82,8 @@ impl DataContext {
         self.description.data_decls.clear();
         self.description.function_relocs.clear();
         self.description.data_relocs.clear();
+        self.description.align = None;
+        self.description.section = None;
     }
 
     /// Define a zero-initialized object with the given size.
@@ -90,6 +100,23 @@ impl DataContext {
         self.description.init = Init::Bytes { contents };
     }
 
+    /// Set the alignment of the object, in bytes. It must be a power of two.
+    pub fn set_align(&mut self, align: u64) {
+        debug_assert!(align.is_power_of_two());
+        self.description.align = Some(align);
+    }
+
+    /// Place the object in the section `section`, such as `.init_array`.
+    ///
+    /// On Mach-O, the name has the form `segment,section`, such as `__DATA,__mod_init_func`.
+    /// On COFF, it's a plain section name, such as `.CRT$XCU`.
+    ///
+    /// Backends which can't place data in custom sections, such as SimpleJIT and Faerie, fail to
+    /// define the object instead of ignoring the section.
+    pub fn set_section(&mut self, section: &str) {
+        self.description.section = Some(section.to_owned());
+    }
+
     /// Declare an external function import.
     ///
     /// Users of the `Module` API generally should call
@@ -134,6 +161,7 @@ impl DataContext {
 mod tests {
     use super::{DataContext, Init};
     use cranelift_codegen::ir;
+    use std::string::String;
 
     #[test]
     fn basic_data_context() {
@@ -145,9 +173,13 @@ mod tests {
             assert!(description.data_decls.is_empty());
             assert!(description.function_relocs.is_empty());
             assert!(description.data_relocs.is_empty());
+            assert_eq!(description.align, None);
+            assert_eq!(description.section, None);
         }
 
         data_ctx.define_zeroinit(256);
+        data_ctx.set_align(64);
+        data_ctx.set_section(".init_array");
 
         let _func_a = data_ctx.import_function(ir::ExternalName::user(0, 0));
         let func_b = data_ctx.import_function(ir::ExternalName::user(0, 1));
@@ -166,6 +198,8 @@ mod tests {
             assert_eq!(description.data_decls.len(), 2);
             assert_eq!(description.function_relocs.len(), 2);
             assert_eq!(description.data_relocs.len(), 1);
+            assert_eq!(description.align, Some(64));
+            assert_eq!(description.section, Some(String::from(".init_array")));
         }
 
         data_ctx.clear();
@@ -176,6 +210,8 @@ mod tests {
             assert!(description.data_decls.is_empty());
             assert!(description.function_relocs.is_empty());
             assert!(description.data_relocs.is_empty());
+            assert_eq!(description.align, None);
+            assert_eq!(description.section, None);
         }
 
         let contents = vec![33, 34, 35, 36];
@@ -185,6 +185,7 @@ pub struct DataDeclaration {
     pub name: String,
     pub linkage: Linkage,
     pub writable: bool,
+    pub tls: bool,
 }
 
 /// A data object belonging to a `Module`.
@@ -213,6 +214,7 @@ struct ModuleContents<B>
 where
     B: Backend,
 {
+    names: HashMap<String, FuncOrDataId>,
     functions: PrimaryMap<FuncId, ModuleFunction<B>>,
     data_objects: PrimaryMap<DataId, ModuleData<B>>,
 }
@@ -266,6 +268,14 @@ where
         &self.contents.get_data_info(name).decl
     }
 
+    /// Get the `DataDeclaration` for the data object declared to the backend as `name`.
+    pub fn get_data_decl_by_name(&self, name: &str) -> &DataDeclaration {
+        match self.contents.names.get(name) {
+            Some(&FuncOrDataId::Data(data)) => &self.contents.data_objects[data].decl,
+            _ => panic!("{} isn't a declared data object", name),
+        }
+    }
+
     /// Get the definition for the function named by `name`, along with its name
     /// and signature.
     pub fn get_function_definition(
@@ -319,10 +329,12 @@ pub struct Module<B>
 where
     B: Backend,
 {
-    names: HashMap<String, FuncOrDataId>,
     contents: ModuleContents<B>,
     functions_to_finalize: Vec<FuncId>,
     data_objects_to_finalize: Vec<DataId>,
+    /// Previous definitions of redefined functions, which are freed once the new definitions are
+    /// published.
+    functions_to_free: Vec<(FuncId, B::CompiledFunction)>,
     backend: B,
 }
 
@@ -333,12 +345,13 @@ where
     /// Create a new `Module`.
     pub fn new(backend_builder: B::Builder) -> Self {
         Self {
-            names: HashMap::new(),
             contents: ModuleContents {
+                names: HashMap::new(),
                 functions: PrimaryMap::new(),
                 data_objects: PrimaryMap::new(),
             },
             functions_to_finalize: Vec::new(),
+            functions_to_free: Vec::new(),
             data_objects_to_finalize: Vec::new(),
             backend: B::new(backend_builder),
         }
@@ -347,7 +360,7 @@ where
     /// Get the module identifier for a given name, if that name
     /// has been declared.
     pub fn get_name(&self, name: &str) -> Option<FuncOrDataId> {
-        self.names.get(name).cloned()
+        self.contents.names.get(name).cloned()
     }
 
     /// Return the target information needed by frontends to produce Cranelift IR
@@ -399,7 +412,7 @@ where
     ) -> ModuleResult<FuncId> {
         // TODO: Can we avoid allocating names so often?
         use super::hash_map::Entry::*;
-        match self.names.entry(name.to_owned()) {
+        match self.contents.names.entry(name.to_owned()) {
             Occupied(entry) => match *entry.get() {
                 FuncOrDataId::Func(id) => {
                     let existing = &mut self.contents.functions[id];
@@ -433,13 +446,41 @@ where
         name: &str,
         linkage: Linkage,
         writable: bool,
+    ) -> ModuleResult<DataId> {
+        self.declare_data_object(name, linkage, writable, false)
+    }
+
+    /// Declare a thread-local data object in this module. Each thread accesses its own instance
+    /// of it.
+    ///
+    /// All declarations of a data object must agree on whether it is thread-local. Functions can
+    /// only refer to thread-local data on x86-64 ELF targets, and none of the backends can define
+    /// it yet, so it must be imported.
+    pub fn declare_tls_data(
+        &mut self,
+        name: &str,
+        linkage: Linkage,
+        writable: bool,
+    ) -> ModuleResult<DataId> {
+        self.declare_data_object(name, linkage, writable, true)
+    }
+
+    fn declare_data_object(
+        &mut self,
+        name: &str,
+        linkage: Linkage,
+        writable: bool,
+        tls: bool,
     ) -> ModuleResult<DataId> {
         // TODO: Can we avoid allocating names so often?
         use super::hash_map::Entry::*;
-        match self.names.entry(name.to_owned()) {
+        match self.contents.names.entry(name.to_owned()) {
             Occupied(entry) => match *entry.get() {
                 FuncOrDataId::Data(id) => {
                     let existing = &mut self.contents.data_objects[id];
+                    if existing.decl.tls != tls {
+                        return Err(ModuleError::IncompatibleDeclaration(name.to_owned()));
+                    }
                     existing.merge(linkage, writable);
                     self.backend
                         .declare_data(name, existing.decl.linkage, existing.decl.writable);
@@ -456,6 +497,7 @@ where
                         name: name.to_owned(),
                         linkage,
                         writable,
+                        tls,
                     },
                     compiled: None,
                 });
@@ -491,6 +533,7 @@ where
             name: ir::ExternalName::user(1, data.as_u32()),
             offset: ir::immediates::Imm64::new(0),
             colocated,
+            tls: decl.tls,
         })
     }
 
@@ -545,6 +588,70 @@ where
         Ok(code_size)
     }
 
+    /// Define a function with an artifact produced by the backend directly, rather than by
+    /// compiling a `Context`. This is for functionality specific to a backend.
+    pub fn define_function_artifact(
+        &mut self,
+        func: FuncId,
+        compiled: B::CompiledFunction,
+    ) -> ModuleResult<()> {
+        let info = &self.contents.functions[func];
+        if info.compiled.is_some() {
+            return Err(ModuleError::DuplicateDefinition(info.decl.name.clone()));
+        }
+        if !info.decl.linkage.is_definable() {
+            return Err(ModuleError::InvalidImportDefinition(info.decl.name.clone()));
+        }
+        self.contents.functions[func].compiled = Some(compiled);
+        self.functions_to_finalize.push(func);
+        Ok(())
+    }
+
+    /// Replace the definition of a function, producing the new function body from the given
+    /// `Context`.
+    ///
+    /// The previous definition, if any, is freed with `Backend::free_function` once the new
+    /// definition is published by `finalize_definitions` or `finalize_function`, so that it can
+    /// still be called until then. Whether code referring to the function uses the new definition
+    /// depends on the backend. If the new definition fails to compile, the previous one is kept.
+    ///
+    /// Returns the size of the function's code.
+    pub fn redefine_function(
+        &mut self,
+        func: FuncId,
+        ctx: &mut Context,
+    ) -> ModuleResult<binemit::CodeOffset> {
+        let previous = self.contents.functions[func].compiled.take();
+        let was_pending = self.functions_to_finalize.iter().any(|x| *x == func);
+        self.functions_to_finalize.retain(|x| *x != func);
+        match self.define_function(func, ctx) {
+            Ok(code_size) => {
+                if let Some(previous) = previous {
+                    self.functions_to_free.push((func, previous));
+                }
+                Ok(code_size)
+            }
+            Err(e) => {
+                self.contents.functions[func].compiled = previous;
+                if was_pending {
+                    self.functions_to_finalize.push(func);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Free the definition of a function. It may be defined again later.
+    ///
+    /// The function must not be called or referred to by other definitions anymore, until it is
+    /// defined again. Does nothing if the function isn't defined.
+    pub fn free_function(&mut self, func: FuncId) {
+        self.functions_to_finalize.retain(|x| *x != func);
+        if let Some(compiled) = self.contents.functions[func].compiled.take() {
+            self.backend.free_function(compiled);
+        }
+    }
+
     /// Define a function, producing the data contents from the given `DataContext`.
     pub fn define_data(&mut self, data: DataId, data_ctx: &DataContext) -> ModuleResult<()> {
         let compiled = {
@@ -569,9 +676,25 @@ where
         Ok(())
     }
 
+    /// Free the definition of a data object. It may be defined again later.
+    ///
+    /// The data object must not be accessed or referred to by other definitions anymore, until
+    /// it is defined again. Does nothing if the data object isn't defined.
+    pub fn free_data(&mut self, data: DataId) {
+        self.data_objects_to_finalize.retain(|x| *x != data);
+        if let Some(compiled) = self.contents.data_objects[data].compiled.take() {
+            self.backend.free_data(compiled);
+        }
+    }
+
     /// Write the address of `what` into the data for `data` at `offset`. `data` must refer to a
     /// defined data object.
-    pub fn write_data_funcaddr(&mut self, data: DataId, offset: usize, what: ir::FuncRef) {
+    pub fn write_data_funcaddr(
+        &mut self,
+        data: DataId,
+        offset: usize,
+        what: ir::FuncRef,
+    ) -> ModuleResult<()> {
         let info = &mut self.contents.data_objects[data];
         debug_assert!(
             info.decl.linkage.is_definable(),
@@ -584,7 +707,7 @@ where
                 .expect("`data` must refer to a defined data object"),
             offset,
             what,
-        );
+        )
     }
 
     /// Write the address of `what` plus `addend` into the data for `data` at `offset`. `data` must
@@ -595,7 +718,7 @@ where
         offset: usize,
         what: ir::GlobalValue,
         addend: binemit::Addend,
-    ) {
+    ) -> ModuleResult<()> {
         let info = &mut self.contents.data_objects[data];
         debug_assert!(
             info.decl.linkage.is_definable(),
@@ -609,7 +732,7 @@ where
             offset,
             what,
             addend,
-        );
+        )
     }
 
     /// Finalize all functions and data objects that are defined but not yet finalized.
@@ -644,6 +767,44 @@ where
             );
         }
         self.backend.publish();
+        for (_, func) in self.functions_to_free.drain(..) {
+            self.backend.free_function(func);
+        }
+    }
+
+    /// Finalize the function `func` if it is defined but not yet finalized, leaving the other
+    /// definitions pending. The symbols referenced in its body that are declared as needing a
+    /// definition must be defined by this point.
+    ///
+    /// This is for backends which run code while the module is being built, such as to compile
+    /// functions on demand. The function is published with `Backend::publish_function`.
+    pub fn finalize_function(&mut self, func: FuncId) {
+        if !self.functions_to_finalize.iter().any(|x| *x == func) {
+            return;
+        }
+        self.functions_to_finalize.retain(|x| *x != func);
+        let info = &self.contents.functions[func];
+        debug_assert!(info.decl.linkage.is_definable());
+        let compiled = info
+            .compiled
+            .as_ref()
+            .expect("function must be compiled before it can be finalized");
+        self.backend.finalize_function(
+            compiled,
+            &ModuleNamespace::<B> {
+                contents: &self.contents,
+            },
+        );
+        self.backend.publish_function(compiled);
+
+        let (previous, pending): (Vec<_>, Vec<_>) = self
+            .functions_to_free
+            .drain(..)
+            .partition(|&(id, _)| id == func);
+        self.functions_to_free = pending;
+        for (_, previous) in previous {
+            self.backend.free_function(previous);
+        }
     }
 
     /// Return the finalized artifact from the backend, if it provides one.
@@ -679,6 +840,16 @@ where
         self.backend.isa()
     }
 
+    /// Return the backend, for functionality specific to it.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Return the backend mutably, for functionality specific to it.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
     /// Consume the module and return the resulting `Product`. Some `Backend`
     /// implementations may provide additional functionality available after
     /// a `Module` is complete.
@@ -0,0 +1,15 @@
+// Build script.
+//
+// This program is run by Cargo when building cranelift-simplejit. It compiles the C helpers used
+// to recover from traps on Unix platforms.
+
+use std::env;
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/signals.c");
+    if env::var_os("CARGO_CFG_UNIX").is_some() {
+        cc::Build::new()
+            .file("src/signals.c")
+            .compile("simplejit-signals");
+    }
+}
@@ -1,26 +1,41 @@
 //! Defines `SimpleJITBackend`.
 
-use crate::memory::Memory;
-use cranelift_codegen::binemit::{Addend, CodeOffset, NullTrapSink, Reloc, RelocSink};
-use cranelift_codegen::isa::TargetIsa;
+use crate::lazy::{compile_lazy_function, LazyFunction, LAZY_COMPILATION_TRAP};
+use crate::memory::{Memory, MemoryStats};
+use crate::traps::{register_function, unregister_range, SimpleJITTrapSink, TrapSite};
+use cranelift_codegen::binemit::{Addend, CodeOffset, Reloc, RelocSink};
+use cranelift_codegen::isa::{CallConv, TargetIsa};
 use cranelift_codegen::{self, ir, settings};
 use cranelift_module::{
-    Backend, DataContext, DataDescription, Init, Linkage, ModuleNamespace, ModuleResult,
+    Backend, DataContext, DataDescription, FuncId, Init, Linkage, Module, ModuleError,
+    ModuleNamespace, ModuleResult,
 };
 use cranelift_native;
 use libc;
+use region;
+use std::cmp;
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::io::Write;
+use std::mem;
 use std::ptr;
-use target_lexicon::PointerWidth;
+use std::sync::{Mutex, Weak};
+use target_lexicon::{Architecture, PointerWidth};
 #[cfg(windows)]
 use winapi;
 
+/// The alignment of the code of functions.
+const FUNCTION_ALIGNMENT: usize = 16;
+
+/// The alignment of data objects.
+const DATA_ALIGNMENT: usize = 16;
+
 /// A builder for `SimpleJITBackend`.
 pub struct SimpleJITBuilder {
     isa: Box<TargetIsa>,
     symbols: HashMap<String, *const u8>,
+    hotswap: bool,
+    dual_mapping: bool,
 }
 
 impl SimpleJITBuilder {
@@ -44,7 +59,12 @@ impl SimpleJITBuilder {
     pub fn with_isa(isa: Box<TargetIsa>) -> Self {
         debug_assert!(!isa.flags().is_pic(), "SimpleJIT requires non-PIC code");
         let symbols = HashMap::new();
-        Self { isa, symbols }
+        Self {
+            isa,
+            symbols,
+            hotswap: false,
+            dual_mapping: false,
+        }
     }
 
     /// Define a symbol in the internal symbol table.
@@ -82,47 +102,378 @@ impl SimpleJITBuilder {
         }
         self
     }
+
+    /// Enable or disable hot-swapping of functions. It is disabled by default.
+    ///
+    /// When it is enabled, functions are called through a trampoline which jumps to their
+    /// current definition, so that callers use the new code of a function after it is redefined
+    /// with `Module::redefine_function` and finalized. The trampoline is also what
+    /// `get_finalized_function` returns.
+    ///
+    /// When it is disabled, functions referring to a function which is redefined keep using its
+    /// old code, which is freed, so they need to be redefined as well.
+    pub fn hotswap(&mut self, enabled: bool) -> &Self {
+        self.hotswap = enabled;
+        self
+    }
+
+    /// Enable or disable dual mapping of code. It is disabled by default.
+    ///
+    /// When it is enabled, the memory holding code is mapped twice: code is emitted and
+    /// relocated through a view which is readable and writable, and executed from a view which
+    /// is readable and executable. No page of code is ever writable and executable at the same
+    /// address, and the protection of code never changes, which is required by kernels
+    /// forbidding writable memory to become executable.
+    /// Since the protection of code never changes, finalizing functions doesn't waste the end
+    /// of their last page either.
+    ///
+    /// Dual mapping is only supported on Linux. Enabling it on other platforms returns an error.
+    pub fn dual_mapping(&mut self, enabled: bool) -> Result<&Self, String> {
+        if enabled && !cfg!(target_os = "linux") {
+            return Err("dual mapping is only supported on Linux".to_string());
+        }
+        self.dual_mapping = enabled;
+        Ok(self)
+    }
 }
 
 /// A `SimpleJITBackend` implements `Backend` and emits code and data into memory where it can be
 /// directly called and accessed.
 ///
 /// See the `SimpleJITBuilder` for a convenient way to construct `SimpleJITBackend` instances.
+///
+/// The memory of code and readonly data is protected at page granularity when definitions are
+/// finalized, so the definitions made afterwards start on a new page. Finalizing after each
+/// definition wastes up to a page each time, unless code is dual-mapped, so definitions are best
+/// finalized in batches.
 pub struct SimpleJITBackend {
     isa: Box<TargetIsa>,
     symbols: HashMap<String, *const u8>,
+    hotswap: bool,
     code_memory: Memory,
     readonly_memory: Memory,
     writable_memory: Memory,
+    /// Memory for the code of trampolines, which is never freed.
+    trampoline_memory: Memory,
+    /// The trampolines of hot-swappable functions, indexed by name.
+    trampolines: HashMap<String, Trampoline>,
+    /// Trampoline slots to update with the address of new definitions when they are published.
+    pending_slots: Vec<(*mut *const u8, *const u8)>,
+    /// Memory for the code of lazily-compiled functions, which is published on its own as each
+    /// of them is compiled.
+    lazy_code_memory: Memory,
+    /// The functions declared with `declare_lazy_function`.
+    lazy_functions: Vec<Box<LazyFunction>>,
+    /// The code shared by the stubs of lazily-compiled functions and the trap they jump to when
+    /// the compilation fails, once they are needed.
+    lazy_resolver: Option<(*const u8, *const u8)>,
+    /// The name of the function being compiled by its lazy compilation stub.
+    compiling_lazily: Option<String>,
+    /// The errors of the lazily-compiled functions which failed to compile.
+    lazy_errors: Vec<(FuncId, ModuleError)>,
+    /// The module of this backend, once it is shared with `share_module`.
+    shared_module: Option<Weak<Mutex<Module<Self>>>>,
+}
+
+// The raw pointers of a `SimpleJITBackend` and of its artifacts point to the memory it owns, to
+// the symbols of the process or to the trampolines of the module, which can be used from any
+// thread. This lets a module be shared with the lazy compilation stubs called in other threads.
+unsafe impl Send for SimpleJITBackend {}
+unsafe impl Send for SimpleJITCompiledFunction {}
+unsafe impl Send for SimpleJITCompiledData {}
+
+/// The memory of the code and data emitted by a `SimpleJITBackend`, returned by `Module::finish`.
+///
+/// The memory is freed when this is dropped, so it must be kept alive as long as the code is
+/// used.
+pub struct SimpleJITProduct {
+    _code_memory: Memory,
+    _readonly_memory: Memory,
+    _writable_memory: Memory,
+    _trampoline_memory: Memory,
+    _lazy_code_memory: Memory,
+    _lazy_functions: Vec<Box<LazyFunction>>,
+}
+
+/// An indirect jump to the current definition of a function, stored in `slot`.
+#[derive(Clone, Copy)]
+struct Trampoline {
+    code: *const u8,
+    slot: *mut *const u8,
 }
 
 /// A record of a relocation to perform.
 struct RelocRecord {
     offset: CodeOffset,
     reloc: Reloc,
-    name: ir::ExternalName,
+    target: RelocTarget,
     addend: Addend,
 }
 
+/// The target of a relocation.
+enum RelocTarget {
+    /// A function or data object.
+    External(ir::ExternalName),
+    /// An offset in the code of the function containing the relocation, such as an EBB or a jump
+    /// table.
+    Local(CodeOffset),
+}
+
 pub struct SimpleJITCompiledFunction {
-    code: *mut u8,
+    /// The address from which the code is executed.
+    code: *const u8,
+    /// The address at which the code is written, which is `code` unless it is dual-mapped.
+    writable_code: *mut u8,
     size: usize,
     relocs: Vec<RelocRecord>,
+    trampoline: Option<Trampoline>,
+    /// Whether the code is in the memory of lazily-compiled functions.
+    lazily_compiled: bool,
+}
+
+impl SimpleJITCompiledFunction {
+    /// The address through which the function is called.
+    fn entry(&self) -> *const u8 {
+        match self.trampoline {
+            Some(trampoline) => trampoline.code,
+            None => self.code,
+        }
+    }
 }
 
 pub struct SimpleJITCompiledData {
     storage: *mut u8,
     size: usize,
+    writable: bool,
     relocs: Vec<RelocRecord>,
 }
 
+/// Statistics about the memory used by a `SimpleJITBackend`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimpleJITMemoryStats {
+    /// The memory of the code of functions.
+    pub code: MemoryStats,
+    /// The memory of readonly data objects.
+    pub readonly_data: MemoryStats,
+    /// The memory of writable data objects, and of the slots of trampolines.
+    pub writable_data: MemoryStats,
+    /// The memory of the code of trampolines and lazy compilation stubs.
+    pub trampolines: MemoryStats,
+    /// The memory of the code of lazily-compiled functions.
+    pub lazily_compiled_code: MemoryStats,
+}
+
 impl SimpleJITBackend {
+    /// Return statistics about the memory used by this backend.
+    pub fn memory_stats(&self) -> SimpleJITMemoryStats {
+        SimpleJITMemoryStats {
+            code: self.code_memory.stats(),
+            readonly_data: self.readonly_memory.stats(),
+            writable_data: self.writable_memory.stats(),
+            trampolines: self.trampoline_memory.stats(),
+            lazily_compiled_code: self.lazy_code_memory.stats(),
+        }
+    }
+
+    /// Return the errors of the lazily-compiled functions which failed to compile since the
+    /// last call, along with the functions.
+    pub fn take_lazy_compilation_errors(&mut self) -> Vec<(FuncId, ModuleError)> {
+        mem::replace(&mut self.lazy_errors, Vec::new())
+    }
+
     fn lookup_symbol(&self, name: &str) -> *const u8 {
         match self.symbols.get(name) {
             Some(&ptr) => ptr,
             None => lookup_with_dlsym(name),
         }
     }
+
+    /// Get the address of the function or data object `name`.
+    fn get_definition(
+        &self,
+        namespace: &ModuleNamespace<Self>,
+        name: &ir::ExternalName,
+    ) -> *const u8 {
+        if namespace.is_function(name) {
+            let (def, name_str, _signature) = namespace.get_function_definition(&name);
+            match def {
+                Some(compiled) => compiled.entry(),
+                None => self.lookup_symbol(name_str),
+            }
+        } else {
+            let (def, name_str, _writable) = namespace.get_data_definition(&name);
+            match def {
+                Some(compiled) => compiled.storage,
+                None => self.lookup_symbol(name_str),
+            }
+        }
+    }
+
+    /// Get the trampoline of the function `name`, creating it if needed.
+    fn get_trampoline(&mut self, name: &str) -> Trampoline {
+        if let Some(&trampoline) = self.trampolines.get(name) {
+            return trampoline;
+        }
+
+        // The slot is aligned, so that it is updated atomically.
+        let slot = self
+            .writable_memory
+            .allocate(mem::size_of::<*const u8>(), mem::align_of::<*const u8>())
+            .expect("TODO: handle OOM etc.") as *mut *const u8;
+        unsafe { ptr::write(slot, ptr::null()) };
+
+        let code = match self.isa.triple().architecture {
+            Architecture::X86_64 => {
+                // movabs $slot, %r11
+                // jmp *(%r11)
+                let mut code = vec![0x49, 0xbb];
+                code.extend_from_slice(&(slot as u64).to_le_bytes());
+                code.extend_from_slice(&[0x41, 0xff, 0x23]);
+                code
+            }
+            Architecture::I386 | Architecture::I586 | Architecture::I686 => {
+                // jmp *slot
+                let mut code = vec![0xff, 0x25];
+                code.extend_from_slice(&(slot as u32).to_le_bytes());
+                code
+            }
+            arch => panic!("trampolines are not supported on {}", arch),
+        };
+        let trampoline = Trampoline {
+            code: self.emit_trampoline_code(&code),
+            slot,
+        };
+        self.trampolines.insert(name.to_string(), trampoline);
+        trampoline
+    }
+
+    /// Copy `code` to the memory of trampolines, and return its executable address.
+    fn emit_trampoline_code(&mut self, code: &[u8]) -> *const u8 {
+        let ptr = self
+            .trampoline_memory
+            .allocate(code.len(), FUNCTION_ALIGNMENT)
+            .expect("TODO: handle OOM etc.");
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), ptr, code.len()) };
+        self.trampoline_memory.executable_address(ptr)
+    }
+
+    /// Get the code shared by the stubs of lazily-compiled functions, emitting it if needed,
+    /// along with the trap it jumps to when a function fails to compile.
+    ///
+    /// It is jumped to with the `LazyFunction` to compile in `%r10`. It saves the registers
+    /// which may hold arguments, calls `compile_lazy_function`, restores them and jumps to the
+    /// compiled function, which returns to the original caller.
+    pub(crate) fn get_lazy_resolver(&mut self) -> ModuleResult<(*const u8, *const u8)> {
+        if let Some(resolver) = self.lazy_resolver {
+            return Ok(resolver);
+        }
+        let triple = self.isa.triple();
+        if triple.architecture != Architecture::X86_64
+            || self.isa.default_call_conv() != CallConv::SystemV
+        {
+            return Err(ModuleError::Backend(format!(
+                "lazy compilation is not supported on {}",
+                triple
+            )));
+        }
+
+        // push %rbp; mov %rsp, %rbp
+        let mut code = vec![0x55, 0x48, 0x89, 0xe5];
+        // push %rdi, %rsi, %rdx, %rcx, %r8, %r9, %rax
+        code.extend_from_slice(&[0x57, 0x56, 0x52, 0x51, 0x41, 0x50, 0x41, 0x51, 0x50]);
+        // sub $136, %rsp, which aligns the stack to 16 bytes.
+        code.extend_from_slice(&[0x48, 0x81, 0xec, 0x88, 0x00, 0x00, 0x00]);
+        // movups %xmmN, 16*N(%rsp)
+        for n in 0..8 {
+            code.extend_from_slice(&[0x0f, 0x11, 0x44 | (n << 3), 0x24, 16 * n]);
+        }
+        // mov %r10, %rdi
+        code.extend_from_slice(&[0x4c, 0x89, 0xd7]);
+        // movabs $compile_lazy_function, %r11; call *%r11
+        code.extend_from_slice(&[0x49, 0xbb]);
+        code.extend_from_slice(&(compile_lazy_function as usize as u64).to_le_bytes());
+        code.extend_from_slice(&[0x41, 0xff, 0xd3]);
+        // mov %rax, %r11
+        code.extend_from_slice(&[0x49, 0x89, 0xc3]);
+        // movups 16*N(%rsp), %xmmN
+        for n in 0..8 {
+            code.extend_from_slice(&[0x0f, 0x10, 0x44 | (n << 3), 0x24, 16 * n]);
+        }
+        // add $136, %rsp
+        code.extend_from_slice(&[0x48, 0x81, 0xc4, 0x88, 0x00, 0x00, 0x00]);
+        // pop %rax, %r9, %r8, %rcx, %rdx, %rsi, %rdi, %rbp
+        code.extend_from_slice(&[0x58, 0x41, 0x59, 0x41, 0x58, 0x59, 0x5a, 0x5e, 0x5f, 0x5d]);
+        // jmp *%r11
+        code.extend_from_slice(&[0x41, 0xff, 0xe3]);
+        // ud2, which `compile_lazy_function` returns when the compilation fails. It traps as if
+        // it were the first instruction of the function.
+        let trap_offset = code.len();
+        code.extend_from_slice(&[0x0f, 0x0b]);
+
+        let resolver = self.emit_trampoline_code(&code);
+        register_function(
+            resolver,
+            code.len(),
+            vec![TrapSite {
+                offset: trap_offset as CodeOffset,
+                srcloc: ir::SourceLoc::default(),
+                code: LAZY_COMPILATION_TRAP,
+            }],
+        );
+        let resolver = (resolver, unsafe { resolver.add(trap_offset) });
+        self.lazy_resolver = Some(resolver);
+        Ok(resolver)
+    }
+
+    /// Create the definition of the lazily-compiled function `name`, which is a stub compiling
+    /// it. Like the definitions of hot-swappable functions, it is called through a trampoline.
+    pub(crate) fn define_lazy_stub(
+        &mut self,
+        name: &str,
+        mut function: LazyFunction,
+    ) -> ModuleResult<SimpleJITCompiledFunction> {
+        let (resolver, trap) = self.get_lazy_resolver()?;
+        let trampoline = self.get_trampoline(name);
+        function.entry = trampoline.code;
+        function.trap = trap;
+        let mut function = Box::new(function);
+
+        // movabs $function, %r10; movabs $resolver, %r11; jmp *%r11
+        let mut code = vec![0x49, 0xba];
+        code.extend_from_slice(&(&mut *function as *mut LazyFunction as u64).to_le_bytes());
+        code.extend_from_slice(&[0x49, 0xbb]);
+        code.extend_from_slice(&(resolver as u64).to_le_bytes());
+        code.extend_from_slice(&[0x41, 0xff, 0xe3]);
+        let stub = self.emit_trampoline_code(&code);
+        self.lazy_functions.push(function);
+
+        Ok(SimpleJITCompiledFunction {
+            code: stub,
+            writable_code: ptr::null_mut(),
+            size: 0,
+            relocs: Vec::new(),
+            trampoline: Some(trampoline),
+            lazily_compiled: false,
+        })
+    }
+
+    /// The module of this backend, if it is shared with `share_module`.
+    pub(crate) fn shared_module(&self) -> Option<&Weak<Mutex<Module<Self>>>> {
+        self.shared_module.as_ref()
+    }
+
+    pub(crate) fn set_shared_module(&mut self, module: Weak<Mutex<Module<Self>>>) {
+        self.shared_module = Some(module);
+    }
+
+    /// Set the function whose definitions are placed in the memory of lazily-compiled functions.
+    pub(crate) fn set_compiling_lazily(&mut self, name: Option<String>) {
+        self.compiling_lazily = name;
+    }
+
+    pub(crate) fn add_lazy_error(&mut self, func: FuncId, err: ModuleError) {
+        self.lazy_errors.push((func, err));
+    }
 }
 
 impl<'simple_jit_backend> Backend for SimpleJITBackend {
@@ -140,18 +491,35 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
     type FinalizedFunction = *const u8;
     type FinalizedData = (*mut u8, usize);
 
-    /// SimpleJIT emits code and data into memory as it processes them, so it
-    /// doesn't need to provide anything after the `Module` is complete.
-    type Product = ();
+    /// SimpleJIT emits code and data into memory as it processes them, so
+    /// it only provides the memory holding them after the `Module` is complete.
+    type Product = SimpleJITProduct;
 
     /// Create a new `SimpleJITBackend`.
     fn new(builder: SimpleJITBuilder) -> Self {
+        let code_memory = || {
+            if builder.dual_mapping {
+                Memory::with_dual_mapping()
+            } else {
+                Memory::new()
+            }
+        };
         Self {
+            code_memory: code_memory(),
+            trampoline_memory: code_memory(),
+            lazy_code_memory: code_memory(),
             isa: builder.isa,
             symbols: builder.symbols,
-            code_memory: Memory::new(),
+            hotswap: builder.hotswap,
             readonly_memory: Memory::new(),
             writable_memory: Memory::new(),
+            trampolines: HashMap::new(),
+            pending_slots: Vec::new(),
+            lazy_functions: Vec::new(),
+            lazy_resolver: None,
+            compiling_lazily: None,
+            lazy_errors: Vec::new(),
+            shared_module: None,
         }
     }
 
@@ -175,10 +543,18 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         code_size: u32,
     ) -> ModuleResult<Self::CompiledFunction> {
         let size = code_size as usize;
-        let ptr = self
-            .code_memory
-            .allocate(size)
+        // The code of a function compiled by its lazy compilation stub is published on its own,
+        // while other definitions may still be waiting to be finalized.
+        let lazily_compiled = self.compiling_lazily.as_ref().map(String::as_str) == Some(name);
+        let memory = if lazily_compiled {
+            &mut self.lazy_code_memory
+        } else {
+            &mut self.code_memory
+        };
+        let ptr = memory
+            .allocate(size, FUNCTION_ALIGNMENT)
             .expect("TODO: handle OOM etc.");
+        let code = memory.executable_address(ptr);
 
         if cfg!(target_os = "linux") && ::std::env::var_os("PERF_BUILDID_DIR").is_some() {
             let mut map_file = ::std::fs::OpenOptions::new()
@@ -187,28 +563,37 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
                 .open(format!("/tmp/perf-{}.map", ::std::process::id()))
                 .unwrap();
 
-            let _ = writeln!(map_file, "{:x} {:x} {}", ptr as usize, code_size, name);
+            let _ = writeln!(map_file, "{:x} {:x} {}", code as usize, code_size, name);
         }
 
-        let mut reloc_sink = SimpleJITRelocSink::new();
-        // Ignore traps for now. For now, frontends should just avoid generating code
-        // that traps.
-        let mut trap_sink = NullTrapSink {};
+        let mut reloc_sink = SimpleJITRelocSink::new(&ctx.func);
+        let mut trap_sink = SimpleJITTrapSink::new();
         unsafe { ctx.emit_to_memory(&*self.isa, ptr, &mut reloc_sink, &mut trap_sink) };
+        register_function(code, size, trap_sink.traps);
+
+        // Lazily-compiled functions already have a trampoline.
+        let trampoline = if self.hotswap || self.trampolines.contains_key(name) {
+            Some(self.get_trampoline(name))
+        } else {
+            None
+        };
 
         Ok(Self::CompiledFunction {
-            code: ptr,
+            code,
+            writable_code: ptr,
             size,
             relocs: reloc_sink.relocs,
+            trampoline,
+            lazily_compiled,
         })
     }
 
     fn define_data(
         &mut self,
-        _name: &str,
+        name: &str,
         writable: bool,
         data: &DataContext,
-        _namespace: &ModuleNamespace<Self>,
+        namespace: &ModuleNamespace<Self>,
     ) -> ModuleResult<Self::CompiledData> {
         let &DataDescription {
             ref init,
@@ -216,16 +601,37 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
             ref data_decls,
             ref function_relocs,
             ref data_relocs,
+            align,
+            ref section,
         } = data.description();
 
+        if namespace.get_data_decl_by_name(name).tls {
+            return Err(ModuleError::Backend(
+                "SimpleJIT doesn't support thread-local data".to_owned(),
+            ));
+        }
+        // There are no sections in memory to place the data object in.
+        if section.is_some() {
+            return Err(ModuleError::Backend(
+                "SimpleJIT doesn't support custom sections".to_owned(),
+            ));
+        }
+        let align = cmp::max(align.unwrap_or(0) as usize, DATA_ALIGNMENT);
+        if align > region::page::size() {
+            return Err(ModuleError::Backend(format!(
+                "SimpleJIT doesn't support data alignment above the page size: {}",
+                align
+            )));
+        }
+
         let size = init.size();
         let storage = if writable {
             self.writable_memory
-                .allocate(size)
+                .allocate(size, align)
                 .expect("TODO: handle OOM etc.")
         } else {
             self.readonly_memory
-                .allocate(size)
+                .allocate(size, align)
                 .expect("TODO: handle OOM etc.")
         };
 
@@ -252,7 +658,7 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
             relocs.push(RelocRecord {
                 reloc,
                 offset,
-                name: function_decls[id].clone(),
+                target: RelocTarget::External(function_decls[id].clone()),
                 addend: 0,
             });
         }
@@ -260,7 +666,7 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
             relocs.push(RelocRecord {
                 reloc,
                 offset,
-                name: data_decls[id].clone(),
+                target: RelocTarget::External(data_decls[id].clone()),
                 addend,
             });
         }
@@ -268,6 +674,7 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         Ok(Self::CompiledData {
             storage,
             size,
+            writable,
             relocs,
         })
     }
@@ -277,8 +684,10 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         _data: &mut Self::CompiledData,
         _offset: usize,
         _what: ir::FuncRef,
-    ) {
-        unimplemented!();
+    ) -> ModuleResult<()> {
+        Err(ModuleError::Backend(
+            "SimpleJIT doesn't support writing addresses into defined data".to_owned(),
+        ))
     }
 
     fn write_data_dataaddr(
@@ -287,8 +696,10 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         _offset: usize,
         _what: ir::GlobalValue,
         _usize: Addend,
-    ) {
-        unimplemented!();
+    ) -> ModuleResult<()> {
+        Err(ModuleError::Backend(
+            "SimpleJIT doesn't support writing addresses into defined data".to_owned(),
+        ))
     }
 
     fn finalize_function(
@@ -301,24 +712,20 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         for &RelocRecord {
             reloc,
             offset,
-            ref name,
+            ref target,
             addend,
         } in &func.relocs
         {
-            let ptr = func.code;
             debug_assert!((offset as usize) < func.size);
-            let at = unsafe { ptr.offset(offset as isize) };
-            let base = if namespace.is_function(name) {
-                let (def, name_str, _signature) = namespace.get_function_definition(&name);
-                match def {
-                    Some(compiled) => compiled.code,
-                    None => self.lookup_symbol(name_str),
-                }
-            } else {
-                let (def, name_str, _writable) = namespace.get_data_definition(&name);
-                match def {
-                    Some(compiled) => compiled.storage,
-                    None => self.lookup_symbol(name_str),
+            // The relocation is written at `at` in the writable view of the code, and PC-relative
+            // relocations are computed from `pc` in the executable one.
+            let at = unsafe { func.writable_code.offset(offset as isize) };
+            let pc = unsafe { func.code.offset(offset as isize) };
+            let base = match *target {
+                RelocTarget::External(ref name) => self.get_definition(namespace, name),
+                RelocTarget::Local(code_offset) => {
+                    debug_assert!((code_offset as usize) <= func.size);
+                    unsafe { func.code.offset(code_offset as isize) }
                 }
             };
             // TODO: Handle overflow.
@@ -339,21 +746,25 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
                 }
                 Reloc::X86PCRel4 | Reloc::X86CallPCRel4 => {
                     // TODO: Handle overflow.
-                    let pcrel = ((what as isize) - (at as isize)) as i32;
+                    let pcrel = ((what as isize) - (pc as isize)) as i32;
                     #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_ptr_alignment))]
                     unsafe {
                         write_unaligned(at as *mut i32, pcrel)
                     };
                 }
                 Reloc::X86GOTPCRel4 | Reloc::X86CallPLTRel4 => panic!("unexpected PIC relocation"),
+                Reloc::X86GOTTPOff4 => panic!("unexpected thread-local relocation"),
                 _ => unimplemented!(),
             }
         }
-        func.code
+        if let Some(trampoline) = func.trampoline {
+            self.pending_slots.push((trampoline.slot, func.code));
+        }
+        func.entry()
     }
 
     fn get_finalized_function(&self, func: &Self::CompiledFunction) -> Self::FinalizedFunction {
-        func.code
+        func.entry()
     }
 
     fn finalize_data(
@@ -366,25 +777,16 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         for &RelocRecord {
             reloc,
             offset,
-            ref name,
+            ref target,
             addend,
         } in &data.relocs
         {
             let ptr = data.storage;
             debug_assert!((offset as usize) < data.size);
             let at = unsafe { ptr.offset(offset as isize) };
-            let base = if namespace.is_function(name) {
-                let (def, name_str, _signature) = namespace.get_function_definition(&name);
-                match def {
-                    Some(compiled) => compiled.code,
-                    None => self.lookup_symbol(name_str),
-                }
-            } else {
-                let (def, name_str, _writable) = namespace.get_data_definition(&name);
-                match def {
-                    Some(compiled) => compiled.storage,
-                    None => self.lookup_symbol(name_str),
-                }
+            let base = match *target {
+                RelocTarget::External(ref name) => self.get_definition(namespace, name),
+                RelocTarget::Local(_) => panic!("unexpected local relocation in data"),
             };
             // TODO: Handle overflow.
             let what = unsafe { base.offset(addend as isize) };
@@ -405,6 +807,7 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
                 Reloc::X86PCRel4
                 | Reloc::X86CallPCRel4
                 | Reloc::X86GOTPCRel4
+                | Reloc::X86GOTTPOff4
                 | Reloc::X86CallPLTRel4 => panic!("unexpected text relocation in data"),
                 _ => unimplemented!(),
             }
@@ -420,11 +823,75 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         // Now that we're done patching, prepare the memory for execution!
         self.readonly_memory.set_readonly();
         self.code_memory.set_readable_and_executable();
+        self.trampoline_memory.set_readable_and_executable();
+        self.lazy_code_memory.set_readable_and_executable();
+
+        // Switch the trampolines to the new definitions.
+        for (slot, code) in self.pending_slots.drain(..) {
+            unsafe { ptr::write_volatile(slot, code) };
+        }
+    }
+
+    fn publish_function(&mut self, func: &Self::CompiledFunction) {
+        if !func.lazily_compiled {
+            // Its code may share pages with definitions which aren't finalized yet.
+            return self.publish();
+        }
+        // The memory of lazily-compiled functions only holds finalized code, and trampolines
+        // are complete as soon as they are emitted.
+        self.lazy_code_memory.set_readable_and_executable();
+        self.trampoline_memory.set_readable_and_executable();
+        let code = func.code;
+        self.pending_slots.retain(|&(slot, pending)| {
+            if pending != code {
+                return true;
+            }
+            unsafe { ptr::write_volatile(slot, code) };
+            false
+        });
+    }
+
+    fn free_function(&mut self, func: Self::CompiledFunction) {
+        // Don't switch the trampoline to the freed code if it hasn't been published yet.
+        self.pending_slots.retain(|&(_, code)| code != func.code);
+        if func.writable_code.is_null() {
+            // Lazy compilation stubs are in the memory of trampolines, which is never freed.
+            return;
+        }
+        unregister_range(func.code, func.size);
+        let memory = if func.lazily_compiled {
+            &mut self.lazy_code_memory
+        } else {
+            &mut self.code_memory
+        };
+        if let Err(err) = memory.free(func.writable_code) {
+            panic!("unable to free function code: {}", err);
+        }
+    }
+
+    fn free_data(&mut self, data: Self::CompiledData) {
+        let memory = if data.writable {
+            &mut self.writable_memory
+        } else {
+            &mut self.readonly_memory
+        };
+        if let Err(err) = memory.free(data.storage) {
+            panic!("unable to free data object: {}", err);
+        }
     }
 
     /// SimpleJIT emits code and data into memory as it processes them, so it
-    /// doesn't need to provide anything after the `Module` is complete.
-    fn finish(self) {}
+    /// only provides the memory holding them after the `Module` is complete.
+    fn finish(self) -> SimpleJITProduct {
+        SimpleJITProduct {
+            _code_memory: self.code_memory,
+            _readonly_memory: self.readonly_memory,
+            _writable_memory: self.writable_memory,
+            _trampoline_memory: self.trampoline_memory,
+            _lazy_code_memory: self.lazy_code_memory,
+            _lazy_functions: self.lazy_functions,
+        }
+    }
 }
 
 #[cfg(not(windows))]
@@ -470,19 +937,28 @@ fn lookup_with_dlsym(name: &str) -> *const u8 {
     }
 }
 
-struct SimpleJITRelocSink {
+struct SimpleJITRelocSink<'a> {
     pub relocs: Vec<RelocRecord>,
+    jt_offsets: &'a ir::JumpTableOffsets,
 }
 
-impl SimpleJITRelocSink {
-    pub fn new() -> Self {
-        Self { relocs: Vec::new() }
+impl<'a> SimpleJITRelocSink<'a> {
+    pub fn new(func: &'a ir::Function) -> Self {
+        Self {
+            relocs: Vec::new(),
+            jt_offsets: &func.jt_offsets,
+        }
     }
 }
 
-impl RelocSink for SimpleJITRelocSink {
-    fn reloc_ebb(&mut self, _offset: CodeOffset, _reloc: Reloc, _ebb_offset: CodeOffset) {
-        unimplemented!();
+impl<'a> RelocSink for SimpleJITRelocSink<'a> {
+    fn reloc_ebb(&mut self, offset: CodeOffset, reloc: Reloc, ebb_offset: CodeOffset) {
+        self.relocs.push(RelocRecord {
+            offset,
+            reloc,
+            target: RelocTarget::Local(ebb_offset),
+            addend: 0,
+        });
     }
 
     fn reloc_external(
@@ -495,12 +971,17 @@ impl RelocSink for SimpleJITRelocSink {
         self.relocs.push(RelocRecord {
             offset,
             reloc,
-            name: name.clone(),
+            target: RelocTarget::External(name.clone()),
             addend,
         });
     }
 
-    fn reloc_jt(&mut self, _offset: CodeOffset, _reloc: Reloc, _jt: ir::JumpTable) {
-        unimplemented!();
+    fn reloc_jt(&mut self, offset: CodeOffset, reloc: Reloc, jt: ir::JumpTable) {
+        self.relocs.push(RelocRecord {
+            offset,
+            reloc,
+            target: RelocTarget::Local(self.jt_offsets[jt]),
+            addend: 0,
+        });
     }
 }
@@ -0,0 +1,189 @@
+//! Lazy compilation of functions in SimpleJIT.
+//!
+//! A lazily-compiled function is initially defined as a stub, and called through a trampoline.
+//! The stub saves the argument registers and calls `compile_lazy_function`, which locks the
+//! module shared with `share_module`, produces the body of the function with the callback given
+//! to `declare_lazy_function`, and redefines and finalizes the function with it. This switches
+//! the trampoline to the new code, which the stub then jumps to with the original arguments.
+
+use crate::backend::SimpleJITBackend;
+use cranelift_codegen::ir;
+use cranelift_codegen::Context;
+use cranelift_module::{FuncId, Linkage, Module, ModuleError, ModuleResult};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+
+/// A module shared with the stubs of its lazily-compiled functions.
+pub type SharedModule = Arc<Mutex<Module<SimpleJITBackend>>>;
+
+/// The trap raised by a call to a lazily-compiled function which failed to compile.
+///
+/// The error is returned by `SimpleJITBackend::take_lazy_compilation_errors`.
+pub const LAZY_COMPILATION_TRAP: ir::TrapCode = ir::TrapCode::User(0xffff);
+
+/// A callback producing the body of a lazily-compiled function. It is only called once.
+pub type LazyCompile =
+    Box<FnMut(&mut Module<SimpleJITBackend>, &mut Context) -> ModuleResult<()> + Send>;
+
+/// The compilation state of a lazily-compiled function.
+pub enum LazyState {
+    /// The function hasn't been called yet.
+    Pending(LazyCompile),
+    /// The function is being compiled, or has been compiled.
+    Compiled,
+    /// The function failed to compile.
+    Failed,
+}
+
+/// A lazily-compiled function.
+///
+/// It is only accessed by its stub while the shared module is locked.
+pub struct LazyFunction {
+    pub func: FuncId,
+    pub name: String,
+    pub signature: ir::Signature,
+    pub state: LazyState,
+    /// The module which the function is defined in.
+    pub module: Weak<Mutex<Module<SimpleJITBackend>>>,
+    /// The trampoline through which the function is called.
+    pub entry: *const u8,
+    /// The trap to jump to if the function fails to compile.
+    pub trap: *const u8,
+}
+
+// See the `Send` implementation of `SimpleJITBackend`.
+unsafe impl Send for LazyFunction {}
+
+/// Share `module` with the stubs of its lazily-compiled functions, which is needed to declare
+/// them.
+///
+/// The stubs lock the module to compile their function, so the lock must not be held while
+/// calling code which may call a lazily-compiled function for the first time, or the thread
+/// deadlocks.
+pub fn share_module(module: Module<SimpleJITBackend>) -> SharedModule {
+    let shared = Arc::new(Mutex::new(module));
+    shared
+        .lock()
+        .unwrap()
+        .backend_mut()
+        .set_shared_module(Arc::downgrade(&shared));
+    shared
+}
+
+/// Declare the function `name` and define it lazily.
+///
+/// The function is compiled the first time it is called, from any thread. Its body is produced
+/// by `compile`, which receives the module and a `Context` whose function already has the right
+/// name and signature. Until then, the function is defined as a stub which compiles it, and
+/// which can be called once the definitions of the module are finalized. Either way,
+/// `Module::get_finalized_function` returns the trampoline through which the function is called.
+///
+/// The module must have been shared with `share_module`. If the function fails to compile, the
+/// call raises `LAZY_COMPILATION_TRAP` and the error is kept by the backend.
+///
+/// Lazy compilation is only supported on x86-64 with the System V calling convention.
+pub fn declare_lazy_function<F>(
+    module: &mut Module<SimpleJITBackend>,
+    name: &str,
+    linkage: Linkage,
+    signature: &ir::Signature,
+    compile: F,
+) -> ModuleResult<FuncId>
+where
+    F: FnOnce(&mut Module<SimpleJITBackend>, &mut Context) -> ModuleResult<()> + Send + 'static,
+{
+    let shared = module.backend().shared_module().cloned().ok_or_else(|| {
+        ModuleError::Backend(
+            "lazily-compiled functions need a module shared with `share_module`".to_owned(),
+        )
+    })?;
+    module.backend_mut().get_lazy_resolver()?;
+
+    let func = module.declare_function(name, linkage, signature)?;
+    let mut compile = Some(compile);
+    let stub = module.backend_mut().define_lazy_stub(
+        name,
+        LazyFunction {
+            func,
+            name: name.to_owned(),
+            signature: signature.clone(),
+            state: LazyState::Pending(Box::new(move |module, ctx| {
+                (compile
+                    .take()
+                    .expect("lazily-compiled function compiled twice"))(module, ctx)
+            })),
+            module: shared,
+            entry: ptr::null(),
+            trap: ptr::null(),
+        },
+    )?;
+    module.define_function_artifact(func, stub)?;
+    Ok(func)
+}
+
+/// Compile the lazily-compiled function `function`, and return the address to jump to.
+///
+/// This is called by the lazy compilation stub.
+pub extern "C" fn compile_lazy_function(function: *mut LazyFunction) -> *const u8 {
+    let (shared, trap) = unsafe { ((*function).module.upgrade(), (*function).trap) };
+    // The module is only gone if it is dropped while its code is running.
+    let shared = match shared {
+        Some(shared) => shared,
+        None => return trap,
+    };
+    // A panic in another thread doesn't prevent compiling the function.
+    let mut module = shared.lock().unwrap_or_else(PoisonError::into_inner);
+    // Now that the module is locked, no other stub is accessing the function.
+    let function = unsafe { &mut *function };
+    let mut compile = match mem::replace(&mut function.state, LazyState::Compiled) {
+        LazyState::Pending(compile) => compile,
+        // The function was compiled by an earlier call to the stub.
+        LazyState::Compiled => return function.entry,
+        LazyState::Failed => {
+            function.state = LazyState::Failed;
+            return trap;
+        }
+    };
+
+    // Panics can't unwind through the stub and the compiled code calling it.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        compile_function(&mut module, function, &mut compile)
+    }))
+    .unwrap_or_else(|_| {
+        Err(ModuleError::Backend(format!(
+            "panic while compiling lazily-compiled function {}",
+            function.name
+        )))
+    });
+    module.backend_mut().set_compiling_lazily(None);
+    match result {
+        Ok(()) => function.entry,
+        Err(err) => {
+            function.state = LazyState::Failed;
+            module.backend_mut().add_lazy_error(function.func, err);
+            trap
+        }
+    }
+}
+
+/// Compile `function` with `compile`, and finalize it without the other pending definitions.
+fn compile_function(
+    module: &mut Module<SimpleJITBackend>,
+    function: &LazyFunction,
+    compile: &mut LazyCompile,
+) -> ModuleResult<()> {
+    let mut ctx = module.make_context();
+    ctx.func = ir::Function::with_name_signature(
+        ir::ExternalName::user(0, function.func.as_u32()),
+        function.signature.clone(),
+    );
+    compile(module, &mut ctx)?;
+    module
+        .backend_mut()
+        .set_compiling_lazily(Some(function.name.clone()));
+    module.redefine_function(function.func, &mut ctx)?;
+    module.finalize_function(function.func);
+    Ok(())
+}
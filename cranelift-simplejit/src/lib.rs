@@ -24,9 +24,20 @@
 )]
 
 mod backend;
+mod lazy;
 mod memory;
+#[cfg(unix)]
+mod signals;
+mod traps;
 
-pub use crate::backend::{SimpleJITBackend, SimpleJITBuilder};
+pub use crate::backend::{
+    SimpleJITBackend, SimpleJITBuilder, SimpleJITMemoryStats, SimpleJITProduct,
+};
+pub use crate::lazy::{declare_lazy_function, share_module, SharedModule, LAZY_COMPILATION_TRAP};
+pub use crate::memory::MemoryStats;
+#[cfg(unix)]
+pub use crate::signals::{call_guarded, install_trap_handler, Trap};
+pub use crate::traps::{lookup_trap, TrapSite};
 
 /// Version number of this crate.
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
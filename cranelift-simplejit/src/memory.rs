@@ -1,6 +1,9 @@
+use crate::traps::unregister_range;
 use errno;
 use libc;
 use region;
+use std::cmp;
+use std::collections::HashSet;
 use std::mem;
 use std::ptr;
 
@@ -13,6 +16,8 @@ fn round_up_to_page_size(size: usize, page_size: usize) -> usize {
 struct PtrLen {
     ptr: *mut u8,
     len: usize,
+    /// Whether the memory is a mapping of a file rather than allocated memory.
+    mapped: bool,
 }
 
 impl PtrLen {
@@ -21,6 +26,7 @@ impl PtrLen {
         Self {
             ptr: ptr::null_mut(),
             len: 0,
+            mapped: false,
         }
     }
 
@@ -37,6 +43,7 @@ impl PtrLen {
                 Ok(Self {
                     ptr: ptr as *mut u8,
                     len: alloc_size,
+                    mapped: false,
                 })
             } else {
                 Err(errno::Errno(err).to_string())
@@ -64,85 +71,340 @@ impl PtrLen {
             Ok(Self {
                 ptr: ptr as *mut u8,
                 len: round_up_to_page_size(size, page_size),
+                mapped: false,
             })
         } else {
             Err(errno::errno().to_string())
         }
     }
+
+    /// Create two `PtrLen`s pointing to two views of the same memory of at least `size` bytes:
+    /// the first is readable and writable, and the second is readable and executable.
+    #[cfg(target_os = "linux")]
+    fn with_dual_mapping(size: usize) -> Result<(Self, Self), String> {
+        let page_size = region::page::size();
+        let alloc_size = round_up_to_page_size(size, page_size);
+        unsafe {
+            let fd = libc::memfd_create(
+                b"cranelift-simplejit\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            );
+            if fd < 0 {
+                return Err(errno::errno().to_string());
+            }
+            // The mappings keep the memory alive once the file is closed.
+            let result = Self::map_twice(fd, alloc_size);
+            libc::close(fd);
+            result
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn map_twice(fd: libc::c_int, len: usize) -> Result<(Self, Self), String> {
+        if libc::ftruncate(fd, len as libc::off_t) != 0 {
+            return Err(errno::errno().to_string());
+        }
+        let mut writable = Self::map(fd, len, libc::PROT_READ | libc::PROT_WRITE)?;
+        match Self::map(fd, len, libc::PROT_READ | libc::PROT_EXEC) {
+            Ok(executable) => Ok((writable, executable)),
+            Err(err) => {
+                writable.free();
+                Err(err)
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn map(fd: libc::c_int, len: usize, prot: libc::c_int) -> Result<Self, String> {
+        let ptr = libc::mmap(ptr::null_mut(), len, prot, libc::MAP_SHARED, fd, 0);
+        if ptr == libc::MAP_FAILED {
+            Err(errno::errno().to_string())
+        } else {
+            Ok(Self {
+                ptr: ptr as *mut u8,
+                len,
+                mapped: true,
+            })
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn with_dual_mapping(_size: usize) -> Result<(Self, Self), String> {
+        Err("dual mapping is only supported on Linux".to_string())
+    }
+
+    /// Release the memory, which must not be in use anymore.
+    #[cfg(not(target_os = "windows"))]
+    unsafe fn free(&mut self) {
+        if self.len != 0 {
+            unregister_range(self.ptr, self.len);
+            if self.mapped {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            } else {
+                region::protect(self.ptr, self.len, region::Protection::ReadWrite)
+                    .expect("unable to make memory writable");
+                libc::free(self.ptr as *mut libc::c_void);
+            }
+        }
+        *self = Self::new();
+    }
+
+    #[cfg(target_os = "windows")]
+    unsafe fn free(&mut self) {
+        use winapi::um::memoryapi::VirtualFree;
+        use winapi::um::winnt::MEM_RELEASE;
+
+        if self.len != 0 {
+            unregister_range(self.ptr, self.len);
+            VirtualFree(self.ptr as *mut winapi::ctypes::c_void, 0, MEM_RELEASE);
+        }
+        *self = Self::new();
+    }
+
+    /// Does this memory contain the address `ptr`?
+    fn contains(&self, ptr: *const u8) -> bool {
+        let ptr = ptr as usize;
+        let start = self.ptr as usize;
+        start <= ptr && ptr < start + self.len
+    }
+}
+
+/// The minimum size of the blocks of memory reserved by a `Memory`.
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// A block of memory. Allocations are packed in it one after the other, and it is released once
+/// all of them have been freed.
+struct Block {
+    memory: PtrLen,
+    /// The executable view of `memory`, if it is dual-mapped.
+    executable: Option<PtrLen>,
+    /// Offset of the end of the last allocation.
+    position: usize,
+    /// Length of the prefix whose protection has been set. Nothing is allocated in it anymore.
+    protected: usize,
+    /// Offsets of the allocations in the block which haven't been freed.
+    live: HashSet<usize>,
+}
+
+impl Block {
+    /// Release the memory of the block, which must not be in use anymore.
+    unsafe fn free(&mut self) {
+        self.memory.free();
+        if let Some(ref mut executable) = self.executable {
+            executable.free();
+        }
+    }
+}
+
+/// Statistics about the memory managed by a `Memory`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Number of blocks of memory reserved.
+    pub blocks: usize,
+    /// Number of bytes reserved.
+    pub reserved_bytes: usize,
+    /// Number of bytes used, including alignment padding and the unused ends of protected pages.
+    pub used_bytes: usize,
+    /// Number of allocations made.
+    pub allocations: usize,
+    /// Number of allocations which haven't been freed.
+    pub live_allocations: usize,
+    /// Number of times the protection of a range of pages was changed.
+    pub protections: usize,
 }
 
 /// JIT memory manager. This manages pages of suitably aligned and
 /// accessible memory.
+///
+/// Memory is reserved in large blocks, in which allocations are packed. A block is released once
+/// all the allocations in it have been freed, and all the memory is released when the `Memory`
+/// is dropped.
+///
+/// The protection of memory is set at page granularity, and includes the last page allocated
+/// in, which may be partially used. Its unused end can't be allocated in anymore, since it can't
+/// be made writable again while the code on the page may be running. The following allocations
+/// start on a new page, so each call to `set_readable_and_executable` or `set_readonly` wastes up
+/// to a page per block. It's best to set the protection of many allocations at once.
+///
+/// A dual-mapped `Memory` maps each block twice: allocations are written through a view which is
+/// readable and writable, and executed from a view which is readable and executable. Its
+/// protection never changes, and no page is ever writable and executable at the same address.
 pub struct Memory {
-    allocations: Vec<PtrLen>,
-    executable: usize,
-    current: PtrLen,
-    position: usize,
+    /// The blocks of memory. Allocations are made in the last one.
+    blocks: Vec<Block>,
+    dual_mapped: bool,
+    allocations: usize,
+    protections: usize,
 }
 
 impl Memory {
     pub fn new() -> Self {
         Self {
-            allocations: Vec::new(),
-            executable: 0,
-            current: PtrLen::new(),
-            position: 0,
+            blocks: Vec::new(),
+            dual_mapped: false,
+            allocations: 0,
+            protections: 0,
         }
     }
 
+    /// Create a dual-mapped `Memory`. This is only supported on Linux.
+    pub fn with_dual_mapping() -> Self {
+        let mut memory = Self::new();
+        memory.dual_mapped = true;
+        memory
+    }
+
+    /// Release the last block if all the allocations in it have been freed.
     fn finish_current(&mut self) {
-        self.allocations
-            .push(mem::replace(&mut self.current, PtrLen::new()));
-        self.position = 0;
+        if self
+            .blocks
+            .last()
+            .map_or(false, |block| block.live.is_empty())
+        {
+            let mut block = self.blocks.pop().unwrap();
+            unsafe { block.free() };
+        }
     }
 
+    /// Allocate `size` bytes aligned to `align`, which must be a power of two no larger than
+    /// the page size.
+    ///
     /// TODO: Use a proper error type.
-    pub fn allocate(&mut self, size: usize) -> Result<*mut u8, String> {
-        if size <= self.current.len - self.position {
-            // TODO: Ensure overflow is not possible.
-            let ptr = unsafe { self.current.ptr.add(self.position) };
-            self.position += size;
-            return Ok(ptr);
+    pub fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, String> {
+        debug_assert!(align.is_power_of_two() && align <= region::page::size());
+        // Zero-sized allocations still take a byte, so that they can be freed like the others.
+        let size = cmp::max(size, 1);
+        self.allocations += 1;
+
+        if let Some(block) = self.blocks.last_mut() {
+            let start = (block.position + (align - 1)) & !(align - 1);
+            if start <= block.memory.len && size <= block.memory.len - start {
+                block.position = start + size;
+                block.live.insert(start);
+                return Ok(unsafe { block.memory.ptr.add(start) });
+            }
         }
 
         self.finish_current();
 
-        // TODO: Allocate more at a time.
-        self.current = PtrLen::with_size(size)?;
-        self.position = size;
-        Ok(self.current.ptr)
+        // Blocks are page-aligned, so the allocation is suitably aligned at their start.
+        let block_size = cmp::max(size, BLOCK_SIZE);
+        let (memory, executable) = if self.dual_mapped {
+            let (memory, executable) = PtrLen::with_dual_mapping(block_size)?;
+            (memory, Some(executable))
+        } else {
+            (PtrLen::with_size(block_size)?, None)
+        };
+        let ptr = memory.ptr;
+        self.blocks.push(Block {
+            memory,
+            executable,
+            position: size,
+            protected: 0,
+            live: [0].iter().cloned().collect(),
+        });
+        Ok(ptr)
     }
 
-    /// Set all memory allocated in this `Memory` up to now as readable and executable.
-    pub fn set_readable_and_executable(&mut self) {
-        self.finish_current();
+    /// Free the allocation at `ptr`, which must have been returned by `allocate` and must not be
+    /// in use anymore.
+    ///
+    /// Return an error if `ptr` isn't a live allocation of this `Memory`, for instance because
+    /// it has already been freed.
+    ///
+    /// TODO: Use a proper error type.
+    pub fn free(&mut self, ptr: *const u8) -> Result<(), String> {
+        let index = self
+            .blocks
+            .iter()
+            .position(|block| block.memory.contains(ptr))
+            .ok_or_else(|| format!("{:p} was not allocated in this memory", ptr))?;
+        let offset = ptr as usize - self.blocks[index].memory.ptr as usize;
+        if !self.blocks[index].live.remove(&offset) {
+            return Err(format!("{:p} is not a live allocation", ptr));
+        }
+        // The last block is kept, since allocations are still made in it.
+        if self.blocks[index].live.is_empty() && index + 1 != self.blocks.len() {
+            let mut block = self.blocks.remove(index);
+            unsafe { block.free() };
+        }
+        Ok(())
+    }
+
+    /// Return the address from which the allocation at `ptr`, which must have been returned by
+    /// `allocate`, is executed. This is `ptr` itself unless this `Memory` is dual-mapped.
+    pub fn executable_address(&self, ptr: *mut u8) -> *const u8 {
+        if !self.dual_mapped {
+            return ptr;
+        }
+        let block = self
+            .blocks
+            .iter()
+            .find(|block| block.memory.contains(ptr))
+            .expect("address not allocated in this memory");
+        let offset = ptr as usize - block.memory.ptr as usize;
+        unsafe { block.executable.as_ref().unwrap().ptr.add(offset) }
+    }
 
-        for &PtrLen { ptr, len } in &self.allocations[self.executable..] {
-            if len != 0 {
+    /// Set the protection of all the pages allocated up to now whose protection hasn't been set.
+    fn protect(&mut self, protection: region::Protection, error: &str) {
+        let page_size = region::page::size();
+        for block in &mut self.blocks {
+            if block.executable.is_some() {
+                // Dual-mapped blocks keep their protection.
+                continue;
+            }
+            let end = round_up_to_page_size(block.position, page_size);
+            if end > block.protected {
                 unsafe {
-                    region::protect(ptr, len, region::Protection::ReadExecute)
-                        .expect("unable to make memory readable+executable");
+                    region::protect(
+                        block.memory.ptr.add(block.protected),
+                        end - block.protected,
+                        protection,
+                    )
+                    .expect(error);
                 }
+                self.protections += 1;
+                block.protected = end;
             }
+            // The following allocations start on the next page, which isn't protected yet.
+            block.position = end;
         }
     }
 
+    /// Set all memory allocated in this `Memory` up to now as readable and executable.
+    pub fn set_readable_and_executable(&mut self) {
+        self.protect(
+            region::Protection::ReadExecute,
+            "unable to make memory readable+executable",
+        );
+    }
+
     /// Set all memory allocated in this `Memory` up to now as readonly.
     pub fn set_readonly(&mut self) {
-        self.finish_current();
+        self.protect(region::Protection::Read, "unable to make memory readonly");
+    }
 
-        for &PtrLen { ptr, len } in &self.allocations[self.executable..] {
-            if len != 0 {
-                unsafe {
-                    region::protect(ptr, len, region::Protection::Read)
-                        .expect("unable to make memory readonly");
-                }
-            }
+    /// Return statistics about the memory managed by this `Memory`.
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            blocks: self.blocks.len(),
+            reserved_bytes: self.blocks.iter().map(|block| block.memory.len).sum(),
+            used_bytes: self.blocks.iter().map(|block| block.position).sum(),
+            allocations: self.allocations,
+            live_allocations: self.blocks.iter().map(|block| block.live.len()).sum(),
+            protections: self.protections,
         }
     }
 }
 
-// TODO: Implement Drop to unprotect and deallocate the memory?
+impl Drop for Memory {
+    fn drop(&mut self) {
+        for block in &mut self.blocks {
+            unsafe { block.free() };
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -155,4 +417,104 @@ mod tests {
         assert_eq!(round_up_to_page_size(4096, 4096), 4096);
         assert_eq!(round_up_to_page_size(4097, 4096), 8192);
     }
+
+    #[test]
+    fn pack_allocations() {
+        let mut memory = Memory::new();
+        let a = memory.allocate(3, 1).unwrap();
+        let b = memory.allocate(16, 16).unwrap();
+        let c = memory.allocate(0, 8).unwrap();
+        assert_eq!(b as usize, a as usize + 16);
+        assert_eq!(c as usize, b as usize + 16);
+
+        let stats = memory.stats();
+        assert_eq!(stats.blocks, 1);
+        assert_eq!(stats.reserved_bytes, BLOCK_SIZE);
+        assert_eq!(stats.used_bytes, 33);
+        assert_eq!(stats.allocations, 3);
+        assert_eq!(stats.live_allocations, 3);
+    }
+
+    #[test]
+    fn protect_pages() {
+        let page_size = region::page::size();
+        let mut memory = Memory::new();
+        let a = memory.allocate(16, 16).unwrap();
+        memory.set_readable_and_executable();
+        assert_eq!(memory.stats().protections, 1);
+
+        // Allocations after setting the protection start on the next page, and only the new
+        // pages are protected.
+        let b = memory.allocate(16, 16).unwrap();
+        assert_eq!(b as usize, a as usize + page_size);
+        memory.set_readable_and_executable();
+        memory.set_readable_and_executable();
+        let stats = memory.stats();
+        assert_eq!(stats.blocks, 1);
+        assert_eq!(stats.used_bytes, 2 * page_size);
+        assert_eq!(stats.protections, 2);
+    }
+
+    #[test]
+    fn free_blocks() {
+        let mut memory = Memory::new();
+        let a = memory.allocate(16, 16).unwrap();
+        let b = memory.allocate(BLOCK_SIZE, 16).unwrap();
+        assert_eq!(memory.stats().blocks, 2);
+
+        // A block is released once all of its allocations are freed, unless it's the last one.
+        memory.free(a).unwrap();
+        assert_eq!(memory.stats().blocks, 1);
+        memory.free(b).unwrap();
+        assert_eq!(memory.stats().blocks, 1);
+        assert_eq!(memory.stats().live_allocations, 0);
+
+        // It is released when a new block is needed.
+        memory.allocate(BLOCK_SIZE, 16).unwrap();
+        assert_eq!(memory.stats().blocks, 1);
+
+        let local = 0u8;
+        assert!(memory.free(&local).is_err());
+    }
+
+    #[test]
+    fn double_free() {
+        let mut memory = Memory::new();
+        let a = memory.allocate(16, 16).unwrap();
+        let b = memory.allocate(16, 16).unwrap();
+        memory.free(a).unwrap();
+        assert!(memory.free(a).is_err());
+        // Interior pointers aren't allocations either.
+        assert!(memory.free(unsafe { b.add(1) }).is_err());
+        assert_eq!(memory.stats().live_allocations, 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dual_mapping() {
+        let mut memory = Memory::with_dual_mapping();
+        let a = memory.allocate(4, 4).unwrap();
+        let b = memory.allocate(4, 4).unwrap();
+        let a_exec = memory.executable_address(a);
+        assert_ne!(a as *const u8, a_exec);
+        assert_eq!(memory.executable_address(b) as usize, a_exec as usize + 4);
+
+        // Writes through the writable view are visible through the executable one, before and
+        // after publishing.
+        unsafe { ptr::write(a, 42) };
+        assert_eq!(unsafe { ptr::read(a_exec) }, 42);
+        memory.set_readable_and_executable();
+        assert_eq!(memory.stats().protections, 0);
+        unsafe { ptr::write(b, 43) };
+        assert_eq!(unsafe { ptr::read(memory.executable_address(b)) }, 43);
+
+        // Since the protection doesn't change, the following allocations fill the same page.
+        let c = memory.allocate(4, 4).unwrap();
+        assert_eq!(c as usize, b as usize + 4);
+        assert_eq!(memory.stats().used_bytes, 12);
+
+        memory.free(a).unwrap();
+        memory.free(b).unwrap();
+        memory.free(c).unwrap();
+    }
 }
@@ -0,0 +1,45 @@
+// Helpers for recovering from traps in code emitted by SimpleJIT.
+//
+// `sigsetjmp` can't be called from Rust, since it returns twice, so guarded calls are made from C.
+// The signal handler is written in Rust and calls `cranelift_simplejit_unwind` to return from the
+// innermost guarded call of the current thread. The state of the guarded calls is kept in C
+// thread-local variables, which, unlike Rust's `thread_local!`, are never lazily initialized and
+// so can be accessed from the signal handler.
+
+#include <setjmp.h>
+#include <stddef.h>
+
+static __thread sigjmp_buf *current_buf = NULL;
+static __thread void *current_trap = NULL;
+
+// Call `body(payload)`. Return 0 if it returns normally, or 1 if it is unwound by
+// `cranelift_simplejit_unwind`. `trap` is where the signal handler records the trap that unwinds
+// the call.
+int cranelift_simplejit_call_guarded(void (*body)(void *), void *payload, void *trap) {
+    sigjmp_buf buf;
+    sigjmp_buf *prev_buf = current_buf;
+    void *prev_trap = current_trap;
+    if (sigsetjmp(buf, 1) != 0) {
+        current_buf = prev_buf;
+        current_trap = prev_trap;
+        return 1;
+    }
+    current_buf = &buf;
+    current_trap = trap;
+    body(payload);
+    current_buf = prev_buf;
+    current_trap = prev_trap;
+    return 0;
+}
+
+// Return where the trap unwinding the innermost guarded call of the current thread should be
+// recorded, or NULL if the current thread is not in a guarded call.
+void *cranelift_simplejit_current_trap(void) {
+    return current_trap;
+}
+
+// Return from the innermost guarded call of the current thread, restoring the signal mask it was
+// made with.
+void cranelift_simplejit_unwind(void) {
+    siglongjmp(*current_buf, 1);
+}
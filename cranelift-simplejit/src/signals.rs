@@ -0,0 +1,212 @@
+//! Recovery from traps in code emitted by SimpleJIT.
+//!
+//! Trapping instructions raise a signal: `SIGILL` for `ud2`, `SIGFPE` for a division by zero, or
+//! `SIGSEGV`/`SIGBUS` for an out-of-bounds heap access. The signal handler installed by
+//! `install_trap_handler` looks up the faulting instruction in the trap sites recorded by the
+//! `traps` module. If the signal was raised by a trap during a `call_guarded`, it unwinds the call
+//! and the trap is returned as an error. All other signals are forwarded to the handlers that
+//! were installed before.
+
+use crate::traps::{lookup_trap, TrapSite};
+use cranelift_codegen::ir::{SourceLoc, TrapCode};
+use libc;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Once;
+use std::thread;
+
+extern "C" {
+    fn cranelift_simplejit_call_guarded(
+        body: extern "C" fn(*mut libc::c_void),
+        payload: *mut libc::c_void,
+        trap: *mut libc::c_void,
+    ) -> libc::c_int;
+    fn cranelift_simplejit_current_trap() -> *mut libc::c_void;
+    fn cranelift_simplejit_unwind() -> !;
+}
+
+/// A trap raised by compiled code during a `call_guarded`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trap {
+    /// The address of the trapping instruction.
+    pub pc: usize,
+    /// The source location of the trapping instruction.
+    pub srcloc: SourceLoc,
+    /// The reason for the trap.
+    pub code: TrapCode,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "trap {} at {:#x}", self.code, self.pc)?;
+        if !self.srcloc.is_default() {
+            write!(f, " ({})", self.srcloc)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Trap {}
+
+/// The signals raised by trapping instructions.
+const TRAP_SIGNALS: [libc::c_int; 4] = [libc::SIGILL, libc::SIGFPE, libc::SIGSEGV, libc::SIGBUS];
+
+/// The handlers of `TRAP_SIGNALS` before `install_trap_handler`.
+static mut PREV_ACTIONS: *const [libc::sigaction; 4] = ptr::null();
+
+/// Install a signal handler which turns traps in code emitted by SimpleJIT into errors returned
+/// from `call_guarded`.
+///
+/// The handler is installed once per process. Signals that are not raised by a trapping
+/// instruction during a guarded call are forwarded to the previously installed handlers.
+pub fn install_trap_handler() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        // The handler may run as soon as it is installed for one signal, so the previous handlers
+        // of all the signals must be known before installing it.
+        let mut prev_actions: [libc::sigaction; 4] = mem::zeroed();
+        for (&signal, prev_action) in TRAP_SIGNALS.iter().zip(prev_actions.iter_mut()) {
+            if libc::sigaction(signal, ptr::null(), prev_action) != 0 {
+                panic!("unable to query signal handler for signal {}", signal);
+            }
+        }
+        PREV_ACTIONS = Box::into_raw(Box::new(prev_actions));
+        for &signal in TRAP_SIGNALS.iter() {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = trap_handler as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                panic!("unable to install signal handler for signal {}", signal);
+            }
+        }
+    });
+}
+
+/// Call `f`, returning an error if compiled code called by it traps.
+///
+/// The trap is only caught if the signal handler has been installed by `install_trap_handler`.
+///
+/// # Safety
+///
+/// When a trap happens, the frames between the trapping instruction and this call are discarded
+/// without running any destructors. They should only be frames of compiled code.
+pub unsafe fn call_guarded<F, R>(f: F) -> Result<R, Trap>
+where
+    F: FnOnce() -> R,
+{
+    struct Payload<F, R> {
+        f: Option<F>,
+        result: Option<thread::Result<R>>,
+    }
+
+    extern "C" fn call<F: FnOnce() -> R, R>(payload: *mut libc::c_void) {
+        let payload = unsafe { &mut *(payload as *mut Payload<F, R>) };
+        let f = payload.f.take().unwrap();
+        // Panics must not unwind through the C helper.
+        payload.result = Some(panic::catch_unwind(AssertUnwindSafe(f)));
+    }
+
+    let mut payload = Payload {
+        f: Some(f),
+        result: None,
+    };
+    let mut trap: Option<Trap> = None;
+    let trapped = cranelift_simplejit_call_guarded(
+        call::<F, R>,
+        &mut payload as *mut Payload<F, R> as *mut libc::c_void,
+        &mut trap as *mut Option<Trap> as *mut libc::c_void,
+    );
+    if trapped != 0 {
+        // The trap was written by the signal handler, behind the compiler's back.
+        return Err(ptr::read_volatile(&trap).expect("unwound without a trap"));
+    }
+    match payload.result.take().unwrap() {
+        Ok(result) => Ok(result),
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+unsafe extern "C" fn trap_handler(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    // Only async-signal-safe code may run here: no locks, no allocation and no Rust thread-locals.
+    let trap = cranelift_simplejit_current_trap() as *mut Option<Trap>;
+    if !trap.is_null() {
+        let pc = get_pc(context);
+        if let Some(TrapSite { srcloc, code, .. }) = lookup_trap(pc) {
+            ptr::write_volatile(
+                trap,
+                Some(Trap {
+                    pc: pc as usize,
+                    srcloc,
+                    code,
+                }),
+            );
+            cranelift_simplejit_unwind();
+        }
+    }
+
+    // This is not a trap we know about, so forward the signal to the previous handler.
+    let index = TRAP_SIGNALS
+        .iter()
+        .position(|&s| s == signal)
+        .expect("unexpected signal");
+    let prev_action = &(*PREV_ACTIONS)[index];
+    if prev_action.sa_flags & libc::SA_SIGINFO != 0 {
+        let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+            mem::transmute(prev_action.sa_sigaction);
+        handler(signal, info, context);
+    } else if prev_action.sa_sigaction == libc::SIG_DFL {
+        // Restore the default disposition and return, so that the faulting instruction raises
+        // the signal again.
+        libc::sigaction(signal, prev_action, ptr::null_mut());
+    } else if prev_action.sa_sigaction == libc::SIG_IGN {
+        // Returning would re-execute the faulting instruction forever.
+        libc::abort();
+    } else {
+        let handler: extern "C" fn(libc::c_int) = mem::transmute(prev_action.sa_sigaction);
+        handler(signal);
+    }
+}
+
+/// Get the program counter from the context passed to a signal handler.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn get_pc(context: *mut libc::c_void) -> *const u8 {
+    let context = &*(context as *const libc::ucontext_t);
+    context.uc_mcontext.gregs[libc::REG_RIP as usize] as *const u8
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86"))]
+unsafe fn get_pc(context: *mut libc::c_void) -> *const u8 {
+    let context = &*(context as *const libc::ucontext_t);
+    context.uc_mcontext.gregs[libc::REG_EIP as usize] as *const u8
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+unsafe fn get_pc(context: *mut libc::c_void) -> *const u8 {
+    let context = &*(context as *const libc::ucontext_t);
+    context.uc_mcontext.pc as *const u8
+}
+
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+unsafe fn get_pc(context: *mut libc::c_void) -> *const u8 {
+    let context = &*(context as *const libc::ucontext_t);
+    (*context.uc_mcontext).__ss.__rip as *const u8
+}
+
+#[cfg(not(any(
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    all(target_os = "macos", target_arch = "x86_64")
+)))]
+unsafe fn get_pc(_context: *mut libc::c_void) -> *const u8 {
+    // Traps aren't recognized on this platform.
+    ptr::null()
+}
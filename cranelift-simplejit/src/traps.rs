@@ -0,0 +1,164 @@
+//! Trap sites in the code emitted by SimpleJIT.
+//!
+//! The trap sites of every function defined by a `SimpleJITBackend` are recorded in a
+//! process-wide table, so that the trap raised by a faulting instruction can be identified from
+//! the program counter alone. This is what the signal handler in the `signals` module relies on,
+//! so lookups must be async-signal-safe.
+
+use cranelift_codegen::binemit::{CodeOffset, TrapSink};
+use cranelift_codegen::ir::{SourceLoc, TrapCode};
+use std::collections::BTreeMap;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+
+/// A trapping instruction in a compiled function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrapSite {
+    /// The offset of the instruction from the start of the function.
+    pub offset: CodeOffset,
+    /// The source location of the instruction.
+    pub srcloc: SourceLoc,
+    /// The reason for the trap.
+    pub code: TrapCode,
+}
+
+/// A `TrapSink` recording the trap sites of a function.
+pub struct SimpleJITTrapSink {
+    pub traps: Vec<TrapSite>,
+}
+
+impl SimpleJITTrapSink {
+    pub fn new() -> Self {
+        Self { traps: Vec::new() }
+    }
+}
+
+impl TrapSink for SimpleJITTrapSink {
+    fn trap(&mut self, offset: CodeOffset, srcloc: SourceLoc, code: TrapCode) {
+        self.traps.push(TrapSite {
+            offset,
+            srcloc,
+            code,
+        });
+    }
+}
+
+/// The trap sites of a function, sorted by offset.
+struct FunctionTraps {
+    size: usize,
+    traps: Vec<TrapSite>,
+}
+
+/// The trap sites of all the functions in this process, sorted by the address of their code.
+///
+/// A table is never modified once it has been published in `TABLE`. Updates publish a new table
+/// instead, so that the signal handler can look up trap sites without taking any lock.
+struct Table {
+    functions: Vec<(usize, Arc<FunctionTraps>)>,
+}
+
+/// The current table, or null if no function with trap sites has been registered yet.
+static TABLE: AtomicPtr<Table> = AtomicPtr::new(ptr::null_mut());
+
+/// The number of `lookup_trap` calls which may be reading a table.
+static READERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The trap sites of all the functions, indexed by the address of their code. This is the master
+/// copy of the table, which is only used by updates.
+fn functions() -> &'static Mutex<BTreeMap<usize, Arc<FunctionTraps>>> {
+    static INIT: Once = Once::new();
+    static mut FUNCTIONS: *const Mutex<BTreeMap<usize, Arc<FunctionTraps>>> = ptr::null();
+    unsafe {
+        INIT.call_once(|| {
+            FUNCTIONS = Box::into_raw(Box::new(Mutex::new(BTreeMap::new())));
+        });
+        &*FUNCTIONS
+    }
+}
+
+/// Apply `f` to the master copy of the table and publish the result.
+fn update<F>(f: F)
+where
+    F: FnOnce(&mut BTreeMap<usize, Arc<FunctionTraps>>),
+{
+    let mut functions = functions().lock().unwrap();
+    f(&mut functions);
+    let table = Table {
+        functions: functions
+            .iter()
+            .map(|(&code, traps)| (code, traps.clone()))
+            .collect(),
+    };
+    let old = TABLE.swap(Box::into_raw(Box::new(table)), Ordering::SeqCst);
+    if !old.is_null() {
+        // Lookups which started before the swap may still be reading the old table. Lookups
+        // never block, so this doesn't wait for long.
+        while READERS.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
+/// Record the trap sites of the function whose code is at `code..code + size`.
+pub fn register_function(code: *const u8, size: usize, mut traps: Vec<TrapSite>) {
+    if traps.is_empty() {
+        return;
+    }
+    traps.sort_by_key(|site| site.offset);
+    update(|functions| {
+        functions.insert(code as usize, Arc::new(FunctionTraps { size, traps }));
+    });
+}
+
+/// Forget the trap sites of the functions whose code is in `start..start + len`.
+pub fn unregister_range(start: *const u8, len: usize) {
+    let start = start as usize;
+    update(|functions| {
+        let codes: Vec<usize> = functions
+            .range(start..start + len)
+            .map(|(&code, _)| code)
+            .collect();
+        for code in codes {
+            functions.remove(&code);
+        }
+    });
+}
+
+/// Look up the trap site at the address `pc`, in any of the functions compiled by SimpleJIT.
+///
+/// Return `None` if `pc` isn't the address of an instruction that can trap. This doesn't take
+/// any lock or allocate memory, so it can be called from a signal handler.
+pub fn lookup_trap(pc: *const u8) -> Option<TrapSite> {
+    READERS.fetch_add(1, Ordering::SeqCst);
+    let table = TABLE.load(Ordering::SeqCst);
+    let result = if table.is_null() {
+        None
+    } else {
+        unsafe { (*table).lookup(pc as usize) }
+    };
+    READERS.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+impl Table {
+    fn lookup(&self, pc: usize) -> Option<TrapSite> {
+        let index = match self.functions.binary_search_by_key(&pc, |&(code, _)| code) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let (start, ref function) = self.functions[index];
+        if pc - start >= function.size {
+            return None;
+        }
+        let offset = (pc - start) as CodeOffset;
+        function
+            .traps
+            .binary_search_by_key(&offset, |site| site.offset)
+            .ok()
+            .map(|index| function.traps[index])
+    }
+}
@@ -5,6 +5,9 @@ use cranelift_entity::EntityRef;
 use cranelift_frontend::*;
 use cranelift_module::*;
 use cranelift_simplejit::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 #[test]
 fn error_on_incompatible_sig_in_declare_function() {
@@ -144,3 +147,476 @@ fn switch_error() {
         }
     }
 }
+
+#[cfg(unix)]
+#[test]
+fn trap_handling() {
+    install_trap_handler();
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+
+    // fn(a, b) { trapz a, user7; return a / b }
+    let sig = Signature {
+        params: vec![AbiParam::new(types::I32), AbiParam::new(types::I32)],
+        returns: vec![AbiParam::new(types::I32)],
+        call_conv: CallConv::SystemV,
+    };
+    let func_id = module
+        .declare_function("divide", Linkage::Local, &sig)
+        .unwrap();
+
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, func_id.as_u32()), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        bcx.append_ebb_params_for_function_params(ebb);
+        let a = bcx.ebb_params(ebb)[0];
+        let b = bcx.ebb_params(ebb)[1];
+        bcx.set_srcloc(SourceLoc::new(1));
+        bcx.ins().trapz(a, TrapCode::User(7));
+        bcx.set_srcloc(SourceLoc::new(2));
+        let quotient = bcx.ins().udiv(a, b);
+        bcx.ins().return_(&[quotient]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(func_id, &mut ctx).unwrap();
+    module.finalize_definitions();
+
+    let code = module.get_finalized_function(func_id);
+    let divide = unsafe { std::mem::transmute::<_, extern "C" fn(u32, u32) -> u32>(code) };
+    assert_eq!(unsafe { call_guarded(|| divide(6, 3)) }, Ok(2));
+
+    let trap = unsafe { call_guarded(|| divide(0, 3)) }.unwrap_err();
+    assert_eq!(trap.code, TrapCode::User(7));
+    assert_eq!(trap.srcloc, SourceLoc::new(1));
+    let site = lookup_trap(trap.pc as *const u8).unwrap();
+    assert_eq!(trap.pc - code as usize, site.offset as usize);
+
+    let trap = unsafe { call_guarded(|| divide(6, 0)) }.unwrap_err();
+    assert_eq!(trap.code, TrapCode::IntegerDivisionByZero);
+    assert_eq!(trap.srcloc, SourceLoc::new(2));
+
+    // The handler keeps working after a trap.
+    assert_eq!(unsafe { call_guarded(|| divide(9, 3)) }, Ok(3));
+}
+
+/// Define the function `func_id` as returning `value`.
+fn define_constant(
+    module: &mut Module<SimpleJITBackend>,
+    func_id: FuncId,
+    value: i32,
+    redefine: bool,
+) {
+    let sig = constant_signature();
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, func_id.as_u32()), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        let value = bcx.ins().iconst(types::I32, i64::from(value));
+        bcx.ins().return_(&[value]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    if redefine {
+        module.redefine_function(func_id, &mut ctx).unwrap();
+    } else {
+        module.define_function(func_id, &mut ctx).unwrap();
+    }
+}
+
+fn constant_signature() -> Signature {
+    Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I32)],
+        call_conv: CallConv::SystemV,
+    }
+}
+
+#[test]
+fn free_and_define_again() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let func_id = module
+        .declare_function("constant", Linkage::Local, &constant_signature())
+        .unwrap();
+
+    define_constant(&mut module, func_id, 1, false);
+    module.finalize_definitions();
+    let code = module.get_finalized_function(func_id);
+    let constant = unsafe { std::mem::transmute::<_, extern "C" fn() -> i32>(code) };
+    assert_eq!(constant(), 1);
+
+    module.free_function(func_id);
+    define_constant(&mut module, func_id, 2, false);
+    module.finalize_definitions();
+    let code = module.get_finalized_function(func_id);
+    let constant = unsafe { std::mem::transmute::<_, extern "C" fn() -> i32>(code) };
+    assert_eq!(constant(), 2);
+}
+
+/// Define the function `caller` as returning the result of `callee`.
+fn define_caller(module: &mut Module<SimpleJITBackend>, caller: FuncId, callee: FuncId) {
+    let mut ctx = Context::new();
+    ctx.func =
+        Function::with_name_signature(ExternalName::user(0, caller.as_u32()), constant_signature());
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let callee_ref = module.declare_func_in_func(callee, &mut bcx.func);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        let call = bcx.ins().call(callee_ref, &[]);
+        let value = bcx.inst_results(call)[0];
+        bcx.ins().return_(&[value]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(caller, &mut ctx).unwrap();
+}
+
+#[test]
+fn hotswap() {
+    let mut builder = SimpleJITBuilder::new();
+    builder.hotswap(true);
+    let mut module: Module<SimpleJITBackend> = Module::new(builder);
+    let sig = constant_signature();
+    let callee = module
+        .declare_function("callee", Linkage::Local, &sig)
+        .unwrap();
+    let caller = module
+        .declare_function("caller", Linkage::Local, &sig)
+        .unwrap();
+
+    define_constant(&mut module, callee, 1, false);
+    define_caller(&mut module, caller, callee);
+    module.finalize_definitions();
+
+    let callee_code = module.get_finalized_function(callee);
+    let callee_fn = unsafe { std::mem::transmute::<_, extern "C" fn() -> i32>(callee_code) };
+    let caller_code = module.get_finalized_function(caller);
+    let caller_fn = unsafe { std::mem::transmute::<_, extern "C" fn() -> i32>(caller_code) };
+    assert_eq!(callee_fn(), 1);
+    assert_eq!(caller_fn(), 1);
+
+    // Both the caller and the address obtained before use the new definition.
+    define_constant(&mut module, callee, 2, true);
+    module.finalize_definitions();
+    assert_eq!(module.get_finalized_function(callee), callee_code);
+    assert_eq!(callee_fn(), 2);
+    assert_eq!(caller_fn(), 2);
+}
+
+#[test]
+fn call_before_finalizing_redefinition() {
+    let mut builder = SimpleJITBuilder::new();
+    builder.hotswap(true);
+    let mut module: Module<SimpleJITBackend> = Module::new(builder);
+    let sig = constant_signature();
+    let callee = module
+        .declare_function("callee", Linkage::Local, &sig)
+        .unwrap();
+    let caller = module
+        .declare_function("caller", Linkage::Local, &sig)
+        .unwrap();
+
+    define_constant(&mut module, callee, 1, false);
+    define_caller(&mut module, caller, callee);
+    module.finalize_definitions();
+
+    let callee_code = module.get_finalized_function(callee);
+    let callee_fn = unsafe { std::mem::transmute::<_, extern "C" fn() -> i32>(callee_code) };
+    let caller_fn = unsafe {
+        std::mem::transmute::<_, extern "C" fn() -> i32>(module.get_finalized_function(caller))
+    };
+
+    // Until the new definition is finalized, the old one is still used and not freed.
+    define_constant(&mut module, callee, 2, true);
+    assert_eq!(module.backend().memory_stats().code.live_allocations, 3);
+    assert_eq!(callee_fn(), 1);
+    assert_eq!(caller_fn(), 1);
+
+    module.finalize_definitions();
+    assert_eq!(module.backend().memory_stats().code.live_allocations, 2);
+    assert_eq!(callee_fn(), 2);
+    assert_eq!(caller_fn(), 2);
+}
+
+#[test]
+fn many_small_functions() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let funcs: Vec<FuncId> = (0..1000)
+        .map(|i| {
+            let func_id = module
+                .declare_function(
+                    &format!("constant{}", i),
+                    Linkage::Local,
+                    &constant_signature(),
+                )
+                .unwrap();
+            define_constant(&mut module, func_id, i, false);
+            func_id
+        })
+        .collect();
+    module.finalize_definitions();
+
+    // The functions are packed in a single block, which is protected at once.
+    let stats = module.backend().memory_stats().code;
+    assert_eq!(stats.blocks, 1);
+    assert_eq!(stats.allocations, 1000);
+    assert_eq!(stats.live_allocations, 1000);
+    assert_eq!(stats.protections, 1);
+    assert!(stats.used_bytes < stats.reserved_bytes);
+
+    for (i, &func_id) in funcs.iter().enumerate() {
+        let code = module.get_finalized_function(func_id);
+        assert_eq!(code as usize % 16, 0);
+        let constant = unsafe { std::mem::transmute::<_, extern "C" fn() -> i32>(code) };
+        assert_eq!(constant(), i as i32);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[test]
+fn dual_mapping_unsupported() {
+    let mut builder = SimpleJITBuilder::new();
+    assert!(builder.dual_mapping(true).is_err());
+    assert!(builder.dual_mapping(false).is_ok());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn dual_mapping() {
+    let mut builder = SimpleJITBuilder::new();
+    builder.dual_mapping(true).unwrap();
+    builder.hotswap(true);
+    let mut module: Module<SimpleJITBackend> = Module::new(builder);
+    let sig = constant_signature();
+    let callee = module
+        .declare_function("callee", Linkage::Local, &sig)
+        .unwrap();
+    let caller = module
+        .declare_function("caller", Linkage::Local, &sig)
+        .unwrap();
+    define_constant(&mut module, callee, 1, false);
+    define_caller(&mut module, caller, callee);
+    module.finalize_definitions();
+
+    let caller_code = module.get_finalized_function(caller);
+    let caller_fn = unsafe { std::mem::transmute::<_, extern "C" fn() -> i32>(caller_code) };
+    assert_eq!(caller_fn(), 1);
+
+    // The code is never made executable through the view it is written to.
+    define_constant(&mut module, callee, 2, true);
+    module.finalize_definitions();
+    assert_eq!(caller_fn(), 2);
+    let stats = module.backend().memory_stats();
+    assert_eq!(stats.code.protections, 0);
+    assert_eq!(stats.trampolines.protections, 0);
+}
+
+#[test]
+fn lazy_compilation() {
+    let shared = share_module(Module::new(SimpleJITBuilder::new()));
+    let mut module = shared.lock().unwrap();
+
+    // fn(a, b, c, d, e, f, g, h, x) { return a - b + c - d + e - f + g - h + fcvt_to_sint(x) }
+    let mut params = vec![AbiParam::new(types::I64); 8];
+    params.push(AbiParam::new(types::F64));
+    let sig = Signature {
+        params,
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+    let compiled = Arc::new(AtomicUsize::new(0));
+    let compiled_in_callback = compiled.clone();
+    let callee = declare_lazy_function(
+        &mut module,
+        "callee",
+        Linkage::Local,
+        &sig,
+        move |_module, ctx| {
+            compiled_in_callback.fetch_add(1, Ordering::SeqCst);
+            let mut func_ctx = FunctionBuilderContext::new();
+            let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+            let ebb = bcx.create_ebb();
+            bcx.switch_to_block(ebb);
+            bcx.append_ebb_params_for_function_params(ebb);
+            let params = bcx.ebb_params(ebb).to_vec();
+            let mut sum = bcx.ins().fcvt_to_sint(types::I64, params[8]);
+            for (i, &param) in params[..8].iter().enumerate() {
+                sum = if i % 2 == 0 {
+                    bcx.ins().iadd(sum, param)
+                } else {
+                    bcx.ins().isub(sum, param)
+                };
+            }
+            bcx.ins().return_(&[sum]);
+            bcx.seal_all_blocks();
+            bcx.finalize();
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    // fn caller() { return callee(1, 2, 3, 4, 5, 6, 7, 8, 100.0) }
+    let caller_sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+    let caller = module
+        .declare_function("caller", Linkage::Local, &caller_sig)
+        .unwrap();
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, caller.as_u32()), caller_sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let callee_ref = module.declare_func_in_func(callee, &mut bcx.func);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        let mut args: Vec<Value> = (1..=8).map(|i| bcx.ins().iconst(types::I64, i)).collect();
+        args.push(bcx.ins().f64const(100.0));
+        let call = bcx.ins().call(callee_ref, &args);
+        let value = bcx.inst_results(call)[0];
+        bcx.ins().return_(&[value]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(caller, &mut ctx).unwrap();
+    module.finalize_definitions();
+    let callee_code = module.get_finalized_function(callee);
+    assert_eq!(compiled.load(Ordering::SeqCst), 0);
+
+    let code = module.get_finalized_function(caller);
+    let caller_fn = unsafe { std::mem::transmute::<_, extern "C" fn() -> i64>(code) };
+
+    // The function is compiled by whichever thread calls it first, which must not hold the lock
+    // of the module. A pending definition isn't finalized along with it.
+    let pending = module
+        .declare_function("pending", Linkage::Local, &constant_signature())
+        .unwrap();
+    define_constant(&mut module, pending, 0, false);
+    drop(module);
+    assert_eq!(thread::spawn(move || caller_fn()).join().unwrap(), 96);
+    assert_eq!(compiled.load(Ordering::SeqCst), 1);
+
+    // The function is compiled once, and keeps its address.
+    let mut module = shared.lock().unwrap();
+    let code = module.get_finalized_function(callee);
+    assert_eq!(code, callee_code);
+    drop(module);
+    let callee_fn = unsafe {
+        std::mem::transmute::<_, extern "C" fn(i64, i64, i64, i64, i64, i64, i64, i64, f64) -> i64>(
+            code,
+        )
+    };
+    assert_eq!(caller_fn(), 96);
+    assert_eq!(callee_fn(8, 7, 6, 5, 4, 3, 2, 1, -0.5), 4);
+    assert_eq!(compiled.load(Ordering::SeqCst), 1);
+
+    let mut module = shared.lock().unwrap();
+    assert_eq!(module.backend().memory_stats().code.protections, 1);
+    module.finalize_definitions();
+    let code = module.get_finalized_function(pending);
+    let pending_fn = unsafe { std::mem::transmute::<_, extern "C" fn() -> i32>(code) };
+    assert_eq!(pending_fn(), 0);
+}
+
+#[test]
+fn lazy_compilation_error() {
+    install_trap_handler();
+    let shared = share_module(Module::new(SimpleJITBuilder::new()));
+    let mut module = shared.lock().unwrap();
+    let sig = Signature {
+        params: vec![],
+        returns: vec![],
+        call_conv: CallConv::SystemV,
+    };
+    let func = declare_lazy_function(&mut module, "invalid", Linkage::Local, &sig, |_, _| {
+        Err(ModuleError::Backend("invalid function".to_owned()))
+    })
+    .unwrap();
+    module.finalize_definitions();
+    let code = module.get_finalized_function(func);
+    drop(module);
+
+    // The call traps, every time, and the error is reported once.
+    let func_fn = unsafe { std::mem::transmute::<_, extern "C" fn()>(code) };
+    for _ in 0..2 {
+        let trap = unsafe { call_guarded(|| func_fn()) }.unwrap_err();
+        assert_eq!(trap.code, LAZY_COMPILATION_TRAP);
+    }
+    let errors = shared
+        .lock()
+        .unwrap()
+        .backend_mut()
+        .take_lazy_compilation_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, func);
+    assert_eq!(errors[0].1.to_string(), "Backend error: invalid function");
+}
+
+#[test]
+fn lazy_compilation_requires_shared_module() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let sig = module.make_signature();
+    let result = declare_lazy_function(&mut module, "lazy", Linkage::Local, &sig, |_, _| Ok(()));
+    assert!(result.is_err());
+    assert!(module.get_name("lazy").is_none());
+}
+
+#[test]
+fn data_alignment() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let mut data_ids = Vec::new();
+    for &align in &[1, 256, 4096] {
+        let data_id = module
+            .declare_data(&format!("aligned{}", align), Linkage::Local, true)
+            .unwrap();
+        let mut data_ctx = DataContext::new();
+        data_ctx.define(vec![1, 2, 3].into_boxed_slice());
+        data_ctx.set_align(align);
+        module.define_data(data_id, &data_ctx).unwrap();
+        data_ids.push((data_id, align));
+    }
+    module.finalize_definitions();
+
+    for &(data_id, align) in &data_ids {
+        let (ptr, size) = module.get_finalized_data(data_id);
+        assert_eq!(ptr as usize % align as usize, 0);
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, size) }, &[1, 2, 3]);
+    }
+}
+
+#[test]
+fn custom_section_unsupported() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let data_id = module.declare_data("ctors", Linkage::Local, false).unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(8);
+    data_ctx.set_section(".init_array");
+    match module.define_data(data_id, &data_ctx) {
+        Err(ModuleError::Backend(_)) => {}
+        _ => panic!("expected a backend error"),
+    }
+}
+
+#[test]
+fn tls_unsupported() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let data_id = module
+        .declare_tls_data("counter", Linkage::Local, true)
+        .unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(8);
+    match module.define_data(data_id, &data_ctx) {
+        Err(ModuleError::Backend(_)) => {}
+        _ => panic!("expected a backend error"),
+    }
+}
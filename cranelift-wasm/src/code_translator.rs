@@ -23,10 +23,14 @@
 //! That is why `translate_function_body` takes an object having the `WasmRuntime` trait as
 //! argument.
 use super::{hash_map, HashMap};
-use crate::environ::{FuncEnvironment, GlobalVariable, ReturnMode, WasmError, WasmResult};
+use crate::environ::{FuncEnvironment, GlobalVariable, ReturnMode, WasmResult};
 use crate::state::{ControlStackFrame, TranslationState};
-use crate::translation_utils::{f32_translation, f64_translation, num_return_values, type_to_type};
-use crate::translation_utils::{FuncIndex, MemoryIndex, SignatureIndex, TableIndex};
+use crate::translation_utils::{
+    blocktype_params_results, f32_translation, f64_translation, type_to_type,
+};
+use crate::translation_utils::{
+    DataIndex, ElemIndex, FuncIndex, MemoryIndex, SignatureIndex, TableIndex,
+};
 use core::{i32, u32};
 use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
 use cranelift_codegen::ir::types::*;
@@ -122,37 +126,37 @@ pub fn translate_operator<FE: FuncEnvironment + ?Sized>(
          *  possible `Ebb`'s arguments values.
          ***********************************************************************************/
         Operator::Block { ty } => {
-            let next = builder.create_ebb();
-            if let Ok(ty_cre) = type_to_type(ty) {
-                builder.append_ebb_param(next, ty_cre);
-            }
-            state.push_block(next, num_return_values(ty));
+            let offset = builder.srcloc().bits() as usize;
+            let (params, results) = blocktype_params_results(ty, offset)?;
+            let next = ebb_with_params(builder, results, offset, environ)?;
+            state.push_block(next, params.len(), results.len());
         }
         Operator::Loop { ty } => {
-            let loop_body = builder.create_ebb();
-            let next = builder.create_ebb();
-            if let Ok(ty_cre) = type_to_type(ty) {
-                builder.append_ebb_param(next, ty_cre);
-            }
-            builder.ins().jump(loop_body, &[]);
-            state.push_loop(loop_body, next, num_return_values(ty));
+            let offset = builder.srcloc().bits() as usize;
+            let (params, results) = blocktype_params_results(ty, offset)?;
+            let loop_body = ebb_with_params(builder, params, offset, environ)?;
+            let next = ebb_with_params(builder, results, offset, environ)?;
+            builder.ins().jump(loop_body, state.peekn(params.len()));
+            state.push_loop(loop_body, next, params.len(), results.len());
+            // The loop parameters are now the parameters of the loop header.
+            state.popn(params.len());
+            state.pushn(builder.ebb_params(loop_body));
             builder.switch_to_block(loop_body);
             environ.translate_loop_header(builder.cursor());
         }
         Operator::If { ty } => {
+            let offset = builder.srcloc().bits() as usize;
+            let (params, results) = blocktype_params_results(ty, offset)?;
             let val = state.pop1();
-            let if_not = builder.create_ebb();
-            let jump_inst = builder.ins().brz(val, if_not, &[]);
-            // Here we append an argument to an Ebb targeted by an argumentless jump instruction
-            // But in fact there are two cases:
-            // - either the If does not have a Else clause, in that case ty = EmptyBlock
-            //   and we add nothing;
+            // The `if` parameters are passed along to the code after the `if`, which expects
+            // the `if` results. There are two cases:
+            // - either the If does not have a Else clause, in that case the parameter and result
+            //   types must be the same;
             // - either the If have an Else clause, in that case the destination of this jump
             //   instruction will be changed later when we translate the Else operator.
-            if let Ok(ty_cre) = type_to_type(ty) {
-                builder.append_ebb_param(if_not, ty_cre);
-            }
-            state.push_if(jump_inst, if_not, num_return_values(ty));
+            let if_not = ebb_with_params(builder, results, offset, environ)?;
+            let jump_inst = builder.ins().brz(val, if_not, state.peekn(params.len()));
+            state.push_if(jump_inst, if_not, params.len(), results.len());
         }
         Operator::Else => {
             // We take the control frame pushed by the if, use its ebb as the else body
@@ -180,10 +184,9 @@ pub fn translate_operator<FE: FuncEnvironment + ?Sized>(
             builder.ins().jump(destination, state.peekn(return_count));
             state.popn(return_count);
             // We change the target of the branch instruction
-            let else_ebb = builder.create_ebb();
-            builder.change_jump_destination(branch_inst, else_ebb);
-            builder.seal_block(else_ebb);
+            let else_ebb = translate_else_ebb(builder, branch_inst);
             builder.switch_to_block(else_ebb);
+            state.pushn(builder.ebb_params(else_ebb));
         }
         Operator::End => {
             let frame = state.control_stack.pop().unwrap();
@@ -231,12 +234,7 @@ pub fn translate_operator<FE: FuncEnvironment + ?Sized>(
                 let frame = &mut state.control_stack[i];
                 // We signal that all the code that follows until the next End is unreachable
                 frame.set_branched_to_exit();
-                let return_count = if frame.is_loop() {
-                    0
-                } else {
-                    frame.num_return_values()
-                };
-                (return_count, frame.br_destination())
+                (frame.num_branch_args(), frame.br_destination())
             };
             builder
                 .ins()
@@ -255,12 +253,7 @@ pub fn translate_operator<FE: FuncEnvironment + ?Sized>(
             }
             let jump_args_count = {
                 let i = state.control_stack.len() - 1 - (min_depth as usize);
-                let min_depth_frame = &state.control_stack[i];
-                if min_depth_frame.is_loop() {
-                    0
-                } else {
-                    min_depth_frame.num_return_values()
-                }
+                state.control_stack[i].num_branch_args()
             };
             let val = state.pop1();
             let mut data = JumpTableData::with_capacity(depths.len());
@@ -815,231 +808,752 @@ pub fn translate_operator<FE: FuncEnvironment + ?Sized>(
         Operator::F32Le | Operator::F64Le => {
             translate_fcmp(FloatCC::LessThanOrEqual, builder, state)
         }
-        Operator::Wake { .. }
-        | Operator::I32Wait { .. }
-        | Operator::I64Wait { .. }
-        | Operator::I32AtomicLoad { .. }
-        | Operator::I64AtomicLoad { .. }
-        | Operator::I32AtomicLoad8U { .. }
-        | Operator::I32AtomicLoad16U { .. }
-        | Operator::I64AtomicLoad8U { .. }
-        | Operator::I64AtomicLoad16U { .. }
-        | Operator::I64AtomicLoad32U { .. }
-        | Operator::I32AtomicStore { .. }
-        | Operator::I64AtomicStore { .. }
-        | Operator::I32AtomicStore8 { .. }
-        | Operator::I32AtomicStore16 { .. }
-        | Operator::I64AtomicStore8 { .. }
-        | Operator::I64AtomicStore16 { .. }
-        | Operator::I64AtomicStore32 { .. }
-        | Operator::I32AtomicRmwAdd { .. }
-        | Operator::I64AtomicRmwAdd { .. }
-        | Operator::I32AtomicRmw8UAdd { .. }
-        | Operator::I32AtomicRmw16UAdd { .. }
-        | Operator::I64AtomicRmw8UAdd { .. }
-        | Operator::I64AtomicRmw16UAdd { .. }
-        | Operator::I64AtomicRmw32UAdd { .. }
-        | Operator::I32AtomicRmwSub { .. }
-        | Operator::I64AtomicRmwSub { .. }
-        | Operator::I32AtomicRmw8USub { .. }
-        | Operator::I32AtomicRmw16USub { .. }
-        | Operator::I64AtomicRmw8USub { .. }
-        | Operator::I64AtomicRmw16USub { .. }
-        | Operator::I64AtomicRmw32USub { .. }
-        | Operator::I32AtomicRmwAnd { .. }
-        | Operator::I64AtomicRmwAnd { .. }
-        | Operator::I32AtomicRmw8UAnd { .. }
-        | Operator::I32AtomicRmw16UAnd { .. }
-        | Operator::I64AtomicRmw8UAnd { .. }
-        | Operator::I64AtomicRmw16UAnd { .. }
-        | Operator::I64AtomicRmw32UAnd { .. }
-        | Operator::I32AtomicRmwOr { .. }
-        | Operator::I64AtomicRmwOr { .. }
-        | Operator::I32AtomicRmw8UOr { .. }
-        | Operator::I32AtomicRmw16UOr { .. }
-        | Operator::I64AtomicRmw8UOr { .. }
-        | Operator::I64AtomicRmw16UOr { .. }
-        | Operator::I64AtomicRmw32UOr { .. }
-        | Operator::I32AtomicRmwXor { .. }
-        | Operator::I64AtomicRmwXor { .. }
-        | Operator::I32AtomicRmw8UXor { .. }
-        | Operator::I32AtomicRmw16UXor { .. }
-        | Operator::I64AtomicRmw8UXor { .. }
-        | Operator::I64AtomicRmw16UXor { .. }
-        | Operator::I64AtomicRmw32UXor { .. }
-        | Operator::I32AtomicRmwXchg { .. }
-        | Operator::I64AtomicRmwXchg { .. }
-        | Operator::I32AtomicRmw8UXchg { .. }
-        | Operator::I32AtomicRmw16UXchg { .. }
-        | Operator::I64AtomicRmw8UXchg { .. }
-        | Operator::I64AtomicRmw16UXchg { .. }
-        | Operator::I64AtomicRmw32UXchg { .. }
-        | Operator::I32AtomicRmwCmpxchg { .. }
-        | Operator::I64AtomicRmwCmpxchg { .. }
-        | Operator::I32AtomicRmw8UCmpxchg { .. }
-        | Operator::I32AtomicRmw16UCmpxchg { .. }
-        | Operator::I64AtomicRmw8UCmpxchg { .. }
-        | Operator::I64AtomicRmw16UCmpxchg { .. }
-        | Operator::I64AtomicRmw32UCmpxchg { .. } => {
-            return Err(WasmError::Unsupported("proposed thread operators"));
-        }
-        Operator::RefNull | Operator::RefIsNull { .. } => {
-            return Err(WasmError::Unsupported("proposed reference-type operators"));
-        }
-        Operator::MemoryInit { .. }
-        | Operator::DataDrop { .. }
-        | Operator::MemoryCopy
-        | Operator::MemoryFill
-        | Operator::TableInit { .. }
-        | Operator::ElemDrop { .. }
-        | Operator::TableCopy
-        | Operator::TableGet { .. }
-        | Operator::TableSet { .. }
-        | Operator::TableGrow { .. }
-        | Operator::TableSize { .. } => {
-            return Err(WasmError::Unsupported("proposed bulk memory operators"));
-        }
-        Operator::V128Load { .. }
-        | Operator::V128Store { .. }
-        | Operator::V128Const { .. }
-        | Operator::V8x16Shuffle { .. }
-        | Operator::I8x16Splat
-        | Operator::I8x16ExtractLaneS { .. }
-        | Operator::I8x16ExtractLaneU { .. }
-        | Operator::I8x16ReplaceLane { .. }
-        | Operator::I16x8Splat
-        | Operator::I16x8ExtractLaneS { .. }
-        | Operator::I16x8ExtractLaneU { .. }
-        | Operator::I16x8ReplaceLane { .. }
-        | Operator::I32x4Splat
-        | Operator::I32x4ExtractLane { .. }
-        | Operator::I32x4ReplaceLane { .. }
-        | Operator::I64x2Splat
-        | Operator::I64x2ExtractLane { .. }
-        | Operator::I64x2ReplaceLane { .. }
-        | Operator::F32x4Splat
-        | Operator::F32x4ExtractLane { .. }
-        | Operator::F32x4ReplaceLane { .. }
-        | Operator::F64x2Splat
-        | Operator::F64x2ExtractLane { .. }
-        | Operator::F64x2ReplaceLane { .. }
-        | Operator::I8x16Eq
-        | Operator::I8x16Ne
-        | Operator::I8x16LtS
-        | Operator::I8x16LtU
-        | Operator::I8x16GtS
-        | Operator::I8x16GtU
-        | Operator::I8x16LeS
-        | Operator::I8x16LeU
-        | Operator::I8x16GeS
-        | Operator::I8x16GeU
-        | Operator::I16x8Eq
-        | Operator::I16x8Ne
-        | Operator::I16x8LtS
-        | Operator::I16x8LtU
-        | Operator::I16x8GtS
-        | Operator::I16x8GtU
-        | Operator::I16x8LeS
-        | Operator::I16x8LeU
-        | Operator::I16x8GeS
-        | Operator::I16x8GeU
-        | Operator::I32x4Eq
-        | Operator::I32x4Ne
-        | Operator::I32x4LtS
-        | Operator::I32x4LtU
-        | Operator::I32x4GtS
-        | Operator::I32x4GtU
-        | Operator::I32x4LeS
-        | Operator::I32x4LeU
-        | Operator::I32x4GeS
-        | Operator::I32x4GeU
-        | Operator::F32x4Eq
-        | Operator::F32x4Ne
-        | Operator::F32x4Lt
-        | Operator::F32x4Gt
-        | Operator::F32x4Le
-        | Operator::F32x4Ge
-        | Operator::F64x2Eq
-        | Operator::F64x2Ne
-        | Operator::F64x2Lt
-        | Operator::F64x2Gt
-        | Operator::F64x2Le
-        | Operator::F64x2Ge
-        | Operator::V128Not
-        | Operator::V128And
-        | Operator::V128Or
-        | Operator::V128Xor
-        | Operator::V128Bitselect
-        | Operator::I8x16Neg
-        | Operator::I8x16AnyTrue
-        | Operator::I8x16AllTrue
-        | Operator::I8x16Shl
-        | Operator::I8x16ShrS
-        | Operator::I8x16ShrU
-        | Operator::I8x16Add
-        | Operator::I8x16AddSaturateS
-        | Operator::I8x16AddSaturateU
-        | Operator::I8x16Sub
-        | Operator::I8x16SubSaturateS
-        | Operator::I8x16SubSaturateU
-        | Operator::I8x16Mul
-        | Operator::I16x8Neg
+        /******************************* Atomic instructions *********************************
+         * Atomic accesses must be naturally aligned, so unlike ordinary loads and stores we
+         * check the effective address explicitly and trap when it is misaligned. Narrow
+         * accesses truncate their operands and zero-extend their results.
+         ************************************************************************************/
+        Operator::Wake {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            let count = state.pop1();
+            let addr = translate_atomic_addr(offset, I32, builder, state, environ);
+            let heap = state.get_heap(builder.func, 0, environ);
+            let heap_index = MemoryIndex::from_u32(0);
+            state.push1(environ.translate_atomic_notify(
+                builder.cursor(),
+                heap_index,
+                heap,
+                addr,
+                count,
+            )?);
+        }
+        Operator::I32Wait {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64Wait {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            let (expected, timeout) = state.pop2();
+            let expected_ty = builder.func.dfg.value_type(expected);
+            let addr = translate_atomic_addr(offset, expected_ty, builder, state, environ);
+            let heap = state.get_heap(builder.func, 0, environ);
+            let heap_index = MemoryIndex::from_u32(0);
+            state.push1(environ.translate_atomic_wait(
+                builder.cursor(),
+                heap_index,
+                heap,
+                addr,
+                expected,
+                timeout,
+            )?);
+        }
+        Operator::I32AtomicLoad {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicLoad {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicLoad8U {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicLoad16U {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicLoad8U {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicLoad16U {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicLoad32U {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_load(offset, atomic_types(&op), builder, state, environ);
+        }
+        Operator::I32AtomicStore {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicStore {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicStore8 {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicStore16 {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicStore8 {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicStore16 {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicStore32 {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_store(offset, atomic_types(&op), builder, state, environ);
+        }
+        Operator::I32AtomicRmwAdd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmwAdd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw8UAdd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw16UAdd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw8UAdd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw16UAdd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw32UAdd {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_rmw(
+                offset,
+                ir::AtomicRmwOp::Add,
+                atomic_types(&op),
+                builder,
+                state,
+                environ,
+            );
+        }
+        Operator::I32AtomicRmwSub {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmwSub {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw8USub {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw16USub {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw8USub {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw16USub {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw32USub {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_rmw(
+                offset,
+                ir::AtomicRmwOp::Sub,
+                atomic_types(&op),
+                builder,
+                state,
+                environ,
+            );
+        }
+        Operator::I32AtomicRmwAnd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmwAnd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw8UAnd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw16UAnd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw8UAnd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw16UAnd {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw32UAnd {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_rmw(
+                offset,
+                ir::AtomicRmwOp::And,
+                atomic_types(&op),
+                builder,
+                state,
+                environ,
+            );
+        }
+        Operator::I32AtomicRmwOr {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmwOr {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw8UOr {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw16UOr {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw8UOr {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw16UOr {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw32UOr {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_rmw(
+                offset,
+                ir::AtomicRmwOp::Or,
+                atomic_types(&op),
+                builder,
+                state,
+                environ,
+            );
+        }
+        Operator::I32AtomicRmwXor {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmwXor {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw8UXor {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw16UXor {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw8UXor {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw16UXor {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw32UXor {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_rmw(
+                offset,
+                ir::AtomicRmwOp::Xor,
+                atomic_types(&op),
+                builder,
+                state,
+                environ,
+            );
+        }
+        Operator::I32AtomicRmwXchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmwXchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw8UXchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw16UXchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw8UXchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw16UXchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw32UXchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_rmw(
+                offset,
+                ir::AtomicRmwOp::Xchg,
+                atomic_types(&op),
+                builder,
+                state,
+                environ,
+            );
+        }
+        Operator::I32AtomicRmwCmpxchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmwCmpxchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw8UCmpxchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I32AtomicRmw16UCmpxchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw8UCmpxchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw16UCmpxchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        }
+        | Operator::I64AtomicRmw32UCmpxchg {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_atomic_cas(offset, atomic_types(&op), builder, state, environ);
+        }
+        /****************************** Reference type operators *****************************
+         * References are opaque values of the environment's `reference_type`, and the tables
+         * holding them are managed by the environment.
+         ************************************************************************************/
+        Operator::RefNull => state.push1(environ.translate_ref_null(builder.cursor())?),
+        Operator::RefIsNull => {
+            let value = state.pop1();
+            state.push1(environ.translate_ref_is_null(builder.cursor(), value)?);
+        }
+        Operator::TableGet { table } => {
+            let table_index = TableIndex::from_u32(table);
+            let ir_table = state.get_table(builder.func, table, environ);
+            let index = state.pop1();
+            state.push1(environ.translate_table_get(
+                builder.cursor(),
+                table_index,
+                ir_table,
+                index,
+            )?);
+        }
+        Operator::TableSet { table } => {
+            let table_index = TableIndex::from_u32(table);
+            let ir_table = state.get_table(builder.func, table, environ);
+            let (index, value) = state.pop2();
+            environ.translate_table_set(builder.cursor(), table_index, ir_table, index, value)?;
+        }
+        Operator::TableGrow { table } => {
+            let table_index = TableIndex::from_u32(table);
+            let ir_table = state.get_table(builder.func, table, environ);
+            let delta = state.pop1();
+            state.push1(environ.translate_table_grow(
+                builder.cursor(),
+                table_index,
+                ir_table,
+                delta,
+            )?);
+        }
+        Operator::TableSize { table } => {
+            let table_index = TableIndex::from_u32(table);
+            let ir_table = state.get_table(builder.func, table, environ);
+            state.push1(environ.translate_table_size(builder.cursor(), table_index, ir_table)?);
+        }
+        /******************************* Bulk memory operators *******************************
+         * These need access to the current memory and table sizes and to the passive segments
+         * kept by the runtime, so they are all handled by the environment.
+         ************************************************************************************/
+        Operator::MemoryCopy => {
+            // The WebAssembly MVP only supports one linear memory and wasmparser doesn't yet
+            // decode a memory index for the bulk memory operators.
+            let heap_index = MemoryIndex::from_u32(0);
+            let heap = state.get_heap(builder.func, 0, environ);
+            let (dst, src, len) = state.pop3();
+            environ.translate_memory_copy(builder.cursor(), heap_index, heap, dst, src, len)?;
+        }
+        Operator::MemoryFill => {
+            let heap_index = MemoryIndex::from_u32(0);
+            let heap = state.get_heap(builder.func, 0, environ);
+            let (dst, val, len) = state.pop3();
+            environ.translate_memory_fill(builder.cursor(), heap_index, heap, dst, val, len)?;
+        }
+        Operator::MemoryInit { segment } => {
+            let heap_index = MemoryIndex::from_u32(0);
+            let heap = state.get_heap(builder.func, 0, environ);
+            let (dst, src, len) = state.pop3();
+            environ.translate_memory_init(
+                builder.cursor(),
+                heap_index,
+                heap,
+                DataIndex::from_u32(segment),
+                dst,
+                src,
+                len,
+            )?;
+        }
+        Operator::DataDrop { segment } => {
+            environ.translate_data_drop(builder.cursor(), DataIndex::from_u32(segment))?;
+        }
+        Operator::TableCopy => {
+            // Like the memory operators, wasmparser doesn't decode a table index yet.
+            let table_index = TableIndex::from_u32(0);
+            let table = state.get_table(builder.func, 0, environ);
+            let (dst, src, len) = state.pop3();
+            environ.translate_table_copy(builder.cursor(), table_index, table, dst, src, len)?;
+        }
+        Operator::TableInit { segment } => {
+            let table_index = TableIndex::from_u32(0);
+            let table = state.get_table(builder.func, 0, environ);
+            let (dst, src, len) = state.pop3();
+            environ.translate_table_init(
+                builder.cursor(),
+                table_index,
+                table,
+                ElemIndex::from_u32(segment),
+                dst,
+                src,
+                len,
+            )?;
+        }
+        Operator::ElemDrop { segment } => {
+            environ.translate_elem_drop(builder.cursor(), ElemIndex::from_u32(segment))?;
+        }
+        /******************************* SIMD operators **************************************
+         * The `v128` type is represented as `I8X16` in locals, block parameters and function
+         * signatures. Operators working on other lane types reinterpret their operands with
+         * `bitcast` and convert their result back to `I8X16`.
+         ************************************************************************************/
+        Operator::V128Load {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_load(offset, ir::Opcode::Load, I8X16, builder, state, environ);
+        }
+        Operator::V128Store {
+            memarg: MemoryImmediate { flags: _, offset },
+        } => {
+            translate_store(offset, ir::Opcode::Store, builder, state, environ);
+        }
+        Operator::V128Const { value } => {
+            // Build the constant from its two 64-bit halves.
+            let bytes = value.bytes();
+            let half = |bytes: &[u8]| {
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, &b| acc << 8 | u64::from(b))
+            };
+            let (low_bits, high_bits) = (half(&bytes[..8]), half(&bytes[8..]));
+            let low = builder.ins().iconst(I64, low_bits as i64);
+            let mut vector = builder.ins().splat(I64X2, low);
+            if high_bits != low_bits {
+                let high = builder.ins().iconst(I64, high_bits as i64);
+                vector = builder.ins().insertlane(vector, 1, high);
+            }
+            push_vector(vector, builder, state);
+        }
+        Operator::V8x16Shuffle { lines } => {
+            let (a, b) = state.pop2();
+            let lane = |line: u8, builder: &mut FunctionBuilder| {
+                let source = if line < 16 { a } else { b };
+                builder.ins().extractlane(source, line % 16)
+            };
+            let first = lane(lines[0], builder);
+            let mut vector = builder.ins().splat(I8X16, first);
+            for (i, &line) in lines.iter().enumerate().skip(1) {
+                let value = lane(line, builder);
+                vector = builder.ins().insertlane(vector, i as u8, value);
+            }
+            state.push1(vector);
+        }
+        Operator::I8x16Splat => translate_splat(I8X16, builder, state),
+        Operator::I16x8Splat => translate_splat(I16X8, builder, state),
+        Operator::I32x4Splat => translate_splat(I32X4, builder, state),
+        Operator::I64x2Splat => translate_splat(I64X2, builder, state),
+        Operator::F32x4Splat => translate_splat(F32X4, builder, state),
+        Operator::F64x2Splat => translate_splat(F64X2, builder, state),
+        Operator::I8x16ExtractLaneS { line } => {
+            let lane = translate_extract_lane(I8X16, line, builder, state);
+            state.push1(builder.ins().sextend(I32, lane));
+        }
+        Operator::I8x16ExtractLaneU { line } => {
+            let lane = translate_extract_lane(I8X16, line, builder, state);
+            state.push1(builder.ins().uextend(I32, lane));
+        }
+        Operator::I16x8ExtractLaneS { line } => {
+            let lane = translate_extract_lane(I16X8, line, builder, state);
+            state.push1(builder.ins().sextend(I32, lane));
+        }
+        Operator::I16x8ExtractLaneU { line } => {
+            let lane = translate_extract_lane(I16X8, line, builder, state);
+            state.push1(builder.ins().uextend(I32, lane));
+        }
+        Operator::I32x4ExtractLane { line } => {
+            let lane = translate_extract_lane(I32X4, line, builder, state);
+            state.push1(lane);
+        }
+        Operator::I64x2ExtractLane { line } => {
+            let lane = translate_extract_lane(I64X2, line, builder, state);
+            state.push1(lane);
+        }
+        Operator::F32x4ExtractLane { line } => {
+            let lane = translate_extract_lane(F32X4, line, builder, state);
+            state.push1(lane);
+        }
+        Operator::F64x2ExtractLane { line } => {
+            let lane = translate_extract_lane(F64X2, line, builder, state);
+            state.push1(lane);
+        }
+        Operator::I8x16ReplaceLane { line } => translate_replace_lane(I8X16, line, builder, state),
+        Operator::I16x8ReplaceLane { line } => translate_replace_lane(I16X8, line, builder, state),
+        Operator::I32x4ReplaceLane { line } => translate_replace_lane(I32X4, line, builder, state),
+        Operator::I64x2ReplaceLane { line } => translate_replace_lane(I64X2, line, builder, state),
+        Operator::F32x4ReplaceLane { line } => translate_replace_lane(F32X4, line, builder, state),
+        Operator::F64x2ReplaceLane { line } => translate_replace_lane(F64X2, line, builder, state),
+        Operator::I8x16Eq => translate_vector_icmp(IntCC::Equal, I8X16, builder, state),
+        Operator::I8x16Ne => translate_vector_icmp(IntCC::NotEqual, I8X16, builder, state),
+        Operator::I8x16LtS => translate_vector_icmp(IntCC::SignedLessThan, I8X16, builder, state),
+        Operator::I8x16LtU => translate_vector_icmp(IntCC::UnsignedLessThan, I8X16, builder, state),
+        Operator::I8x16GtS => {
+            translate_vector_icmp(IntCC::SignedGreaterThan, I8X16, builder, state)
+        }
+        Operator::I8x16GtU => {
+            translate_vector_icmp(IntCC::UnsignedGreaterThan, I8X16, builder, state)
+        }
+        Operator::I8x16LeS => {
+            translate_vector_icmp(IntCC::SignedLessThanOrEqual, I8X16, builder, state)
+        }
+        Operator::I8x16LeU => {
+            translate_vector_icmp(IntCC::UnsignedLessThanOrEqual, I8X16, builder, state)
+        }
+        Operator::I8x16GeS => {
+            translate_vector_icmp(IntCC::SignedGreaterThanOrEqual, I8X16, builder, state)
+        }
+        Operator::I8x16GeU => {
+            translate_vector_icmp(IntCC::UnsignedGreaterThanOrEqual, I8X16, builder, state)
+        }
+        Operator::I16x8Eq => translate_vector_icmp(IntCC::Equal, I16X8, builder, state),
+        Operator::I16x8Ne => translate_vector_icmp(IntCC::NotEqual, I16X8, builder, state),
+        Operator::I16x8LtS => translate_vector_icmp(IntCC::SignedLessThan, I16X8, builder, state),
+        Operator::I16x8LtU => translate_vector_icmp(IntCC::UnsignedLessThan, I16X8, builder, state),
+        Operator::I16x8GtS => {
+            translate_vector_icmp(IntCC::SignedGreaterThan, I16X8, builder, state)
+        }
+        Operator::I16x8GtU => {
+            translate_vector_icmp(IntCC::UnsignedGreaterThan, I16X8, builder, state)
+        }
+        Operator::I16x8LeS => {
+            translate_vector_icmp(IntCC::SignedLessThanOrEqual, I16X8, builder, state)
+        }
+        Operator::I16x8LeU => {
+            translate_vector_icmp(IntCC::UnsignedLessThanOrEqual, I16X8, builder, state)
+        }
+        Operator::I16x8GeS => {
+            translate_vector_icmp(IntCC::SignedGreaterThanOrEqual, I16X8, builder, state)
+        }
+        Operator::I16x8GeU => {
+            translate_vector_icmp(IntCC::UnsignedGreaterThanOrEqual, I16X8, builder, state)
+        }
+        Operator::I32x4Eq => translate_vector_icmp(IntCC::Equal, I32X4, builder, state),
+        Operator::I32x4Ne => translate_vector_icmp(IntCC::NotEqual, I32X4, builder, state),
+        Operator::I32x4LtS => translate_vector_icmp(IntCC::SignedLessThan, I32X4, builder, state),
+        Operator::I32x4LtU => translate_vector_icmp(IntCC::UnsignedLessThan, I32X4, builder, state),
+        Operator::I32x4GtS => {
+            translate_vector_icmp(IntCC::SignedGreaterThan, I32X4, builder, state)
+        }
+        Operator::I32x4GtU => {
+            translate_vector_icmp(IntCC::UnsignedGreaterThan, I32X4, builder, state)
+        }
+        Operator::I32x4LeS => {
+            translate_vector_icmp(IntCC::SignedLessThanOrEqual, I32X4, builder, state)
+        }
+        Operator::I32x4LeU => {
+            translate_vector_icmp(IntCC::UnsignedLessThanOrEqual, I32X4, builder, state)
+        }
+        Operator::I32x4GeS => {
+            translate_vector_icmp(IntCC::SignedGreaterThanOrEqual, I32X4, builder, state)
+        }
+        Operator::I32x4GeU => {
+            translate_vector_icmp(IntCC::UnsignedGreaterThanOrEqual, I32X4, builder, state)
+        }
+        Operator::F32x4Eq => translate_vector_fcmp(FloatCC::Equal, F32X4, builder, state),
+        Operator::F32x4Ne => translate_vector_fcmp(FloatCC::NotEqual, F32X4, builder, state),
+        Operator::F32x4Lt => translate_vector_fcmp(FloatCC::LessThan, F32X4, builder, state),
+        Operator::F32x4Gt => translate_vector_fcmp(FloatCC::GreaterThan, F32X4, builder, state),
+        Operator::F32x4Le => translate_vector_fcmp(FloatCC::LessThanOrEqual, F32X4, builder, state),
+        Operator::F32x4Ge => {
+            translate_vector_fcmp(FloatCC::GreaterThanOrEqual, F32X4, builder, state)
+        }
+        Operator::F64x2Eq => translate_vector_fcmp(FloatCC::Equal, F64X2, builder, state),
+        Operator::F64x2Ne => translate_vector_fcmp(FloatCC::NotEqual, F64X2, builder, state),
+        Operator::F64x2Lt => translate_vector_fcmp(FloatCC::LessThan, F64X2, builder, state),
+        Operator::F64x2Gt => translate_vector_fcmp(FloatCC::GreaterThan, F64X2, builder, state),
+        Operator::F64x2Le => translate_vector_fcmp(FloatCC::LessThanOrEqual, F64X2, builder, state),
+        Operator::F64x2Ge => {
+            translate_vector_fcmp(FloatCC::GreaterThanOrEqual, F64X2, builder, state)
+        }
+        Operator::V128Not => {
+            let a = state.pop1();
+            state.push1(builder.ins().bnot(a));
+        }
+        Operator::V128And => {
+            let (a, b) = state.pop2();
+            state.push1(builder.ins().band(a, b));
+        }
+        Operator::V128Or => {
+            let (a, b) = state.pop2();
+            state.push1(builder.ins().bor(a, b));
+        }
+        Operator::V128Xor => {
+            let (a, b) = state.pop2();
+            state.push1(builder.ins().bxor(a, b));
+        }
+        Operator::V128Bitselect => {
+            // Take the bits of `a` where `c` is set, and the bits of `b` elsewhere.
+            let (a, b, c) = state.pop3();
+            let selected_a = builder.ins().band(a, c);
+            let selected_b = builder.ins().band_not(b, c);
+            state.push1(builder.ins().bor(selected_a, selected_b));
+        }
+        Operator::I8x16Neg => translate_vector_ineg(I8X16, builder, state),
+        Operator::I16x8Neg => translate_vector_ineg(I16X8, builder, state),
+        Operator::I32x4Neg => translate_vector_ineg(I32X4, builder, state),
+        Operator::I64x2Neg => translate_vector_ineg(I64X2, builder, state),
+        Operator::I8x16AnyTrue
         | Operator::I16x8AnyTrue
-        | Operator::I16x8AllTrue
-        | Operator::I16x8Shl
-        | Operator::I16x8ShrS
-        | Operator::I16x8ShrU
-        | Operator::I16x8Add
-        | Operator::I16x8AddSaturateS
-        | Operator::I16x8AddSaturateU
-        | Operator::I16x8Sub
-        | Operator::I16x8SubSaturateS
-        | Operator::I16x8SubSaturateU
-        | Operator::I16x8Mul
-        | Operator::I32x4Neg
         | Operator::I32x4AnyTrue
-        | Operator::I32x4AllTrue
-        | Operator::I32x4Shl
-        | Operator::I32x4ShrS
-        | Operator::I32x4ShrU
-        | Operator::I32x4Add
-        | Operator::I32x4Sub
-        | Operator::I32x4Mul
-        | Operator::I64x2Neg
-        | Operator::I64x2AnyTrue
-        | Operator::I64x2AllTrue
-        | Operator::I64x2Shl
-        | Operator::I64x2ShrS
-        | Operator::I64x2ShrU
-        | Operator::I64x2Add
-        | Operator::I64x2Sub
-        | Operator::F32x4Abs
-        | Operator::F32x4Neg
-        | Operator::F32x4Sqrt
-        | Operator::F32x4Add
-        | Operator::F32x4Sub
-        | Operator::F32x4Mul
-        | Operator::F32x4Div
-        | Operator::F32x4Min
-        | Operator::F32x4Max
-        | Operator::F64x2Abs
-        | Operator::F64x2Neg
-        | Operator::F64x2Sqrt
-        | Operator::F64x2Add
-        | Operator::F64x2Sub
-        | Operator::F64x2Mul
-        | Operator::F64x2Div
-        | Operator::F64x2Min
-        | Operator::F64x2Max
-        | Operator::I32x4TruncSF32x4Sat
-        | Operator::I32x4TruncUF32x4Sat
-        | Operator::I64x2TruncSF64x2Sat
-        | Operator::I64x2TruncUF64x2Sat
-        | Operator::F32x4ConvertSI32x4
-        | Operator::F32x4ConvertUI32x4
-        | Operator::F64x2ConvertSI64x2
-        | Operator::F64x2ConvertUI64x2 => {
-            return Err(WasmError::Unsupported("proposed SIMD operators"));
+        | Operator::I64x2AnyTrue => {
+            // A lane is non-zero if and only if some of its bits are set, so the lane type
+            // doesn't matter.
+            let a = pop1_vector(I64X2, builder, state);
+            let any_set = translate_vector_any_set(a, builder);
+            let val = builder.ins().icmp_imm(IntCC::NotEqual, any_set, 0);
+            state.push1(builder.ins().bint(I32, val));
+        }
+        Operator::I8x16AllTrue => translate_vector_all_true(I8X16, builder, state),
+        Operator::I16x8AllTrue => translate_vector_all_true(I16X8, builder, state),
+        Operator::I32x4AllTrue => translate_vector_all_true(I32X4, builder, state),
+        Operator::I64x2AllTrue => translate_vector_all_true(I64X2, builder, state),
+        Operator::I8x16Shl | Operator::I16x8Shl | Operator::I32x4Shl | Operator::I64x2Shl => {
+            let (a, b) = state.pop2();
+            let a = optionally_bitcast_vector(a, vector_type(&op), builder);
+            let val = builder.ins().ishl(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::I8x16ShrS | Operator::I16x8ShrS | Operator::I32x4ShrS | Operator::I64x2ShrS => {
+            let (a, b) = state.pop2();
+            let a = optionally_bitcast_vector(a, vector_type(&op), builder);
+            let val = builder.ins().sshr(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::I8x16ShrU | Operator::I16x8ShrU | Operator::I32x4ShrU | Operator::I64x2ShrU => {
+            let (a, b) = state.pop2();
+            let a = optionally_bitcast_vector(a, vector_type(&op), builder);
+            let val = builder.ins().ushr(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::I8x16Add | Operator::I16x8Add | Operator::I32x4Add | Operator::I64x2Add => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().iadd(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::I8x16Sub | Operator::I16x8Sub | Operator::I32x4Sub | Operator::I64x2Sub => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().isub(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::I8x16Mul | Operator::I16x8Mul | Operator::I32x4Mul => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().imul(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::I8x16AddSaturateS | Operator::I16x8AddSaturateS => {
+            translate_vector_signed_sat(true, vector_type(&op), builder, state);
+        }
+        Operator::I8x16SubSaturateS | Operator::I16x8SubSaturateS => {
+            translate_vector_signed_sat(false, vector_type(&op), builder, state);
+        }
+        Operator::I8x16AddSaturateU | Operator::I16x8AddSaturateU => {
+            // The sum wrapped around if it is smaller than an operand; saturate it to all ones.
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let sum = builder.ins().iadd(a, b);
+            let wrapped = builder.ins().icmp(IntCC::UnsignedLessThan, sum, a);
+            let wrapped = builder.ins().bmask(vector_type(&op), wrapped);
+            let val = builder.ins().bor(sum, wrapped);
+            push_vector(val, builder, state);
+        }
+        Operator::I8x16SubSaturateU | Operator::I16x8SubSaturateU => {
+            // The difference wrapped around if `b > a`; saturate it to zero.
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let diff = builder.ins().isub(a, b);
+            let in_range = builder.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, a, b);
+            let in_range = builder.ins().bmask(vector_type(&op), in_range);
+            let val = builder.ins().band(diff, in_range);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Abs | Operator::F64x2Abs => {
+            let a = pop1_vector(vector_type(&op), builder, state);
+            let val = builder.ins().fabs(a);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Neg | Operator::F64x2Neg => {
+            let a = pop1_vector(vector_type(&op), builder, state);
+            let val = builder.ins().fneg(a);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Sqrt | Operator::F64x2Sqrt => {
+            let a = pop1_vector(vector_type(&op), builder, state);
+            let val = builder.ins().sqrt(a);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Add | Operator::F64x2Add => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().fadd(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Sub | Operator::F64x2Sub => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().fsub(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Mul | Operator::F64x2Mul => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().fmul(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Div | Operator::F64x2Div => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().fdiv(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Min | Operator::F64x2Min => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().fmin(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4Max | Operator::F64x2Max => {
+            let (a, b) = pop2_vector(vector_type(&op), builder, state);
+            let val = builder.ins().fmax(a, b);
+            push_vector(val, builder, state);
+        }
+        Operator::I32x4TruncSF32x4Sat => {
+            let a = pop1_vector(F32X4, builder, state);
+            let val = builder.ins().fcvt_to_sint_sat(I32X4, a);
+            push_vector(val, builder, state);
+        }
+        Operator::I32x4TruncUF32x4Sat => {
+            let a = pop1_vector(F32X4, builder, state);
+            let val = builder.ins().fcvt_to_uint_sat(I32X4, a);
+            push_vector(val, builder, state);
+        }
+        Operator::I64x2TruncSF64x2Sat => {
+            let a = pop1_vector(F64X2, builder, state);
+            let val = builder.ins().fcvt_to_sint_sat(I64X2, a);
+            push_vector(val, builder, state);
+        }
+        Operator::I64x2TruncUF64x2Sat => {
+            let a = pop1_vector(F64X2, builder, state);
+            let val = builder.ins().fcvt_to_uint_sat(I64X2, a);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4ConvertSI32x4 => {
+            let a = pop1_vector(I32X4, builder, state);
+            let val = builder.ins().fcvt_from_sint(F32X4, a);
+            push_vector(val, builder, state);
+        }
+        Operator::F32x4ConvertUI32x4 => {
+            let a = pop1_vector(I32X4, builder, state);
+            let val = builder.ins().fcvt_from_uint(F32X4, a);
+            push_vector(val, builder, state);
+        }
+        Operator::F64x2ConvertSI64x2 => {
+            let a = pop1_vector(I64X2, builder, state);
+            let val = builder.ins().fcvt_from_sint(F64X2, a);
+            push_vector(val, builder, state);
+        }
+        Operator::F64x2ConvertUI64x2 => {
+            let a = pop1_vector(I64X2, builder, state);
+            let val = builder.ins().fcvt_from_uint(F64X2, a);
+            push_vector(val, builder, state);
         }
     };
     Ok(())
@@ -1059,15 +1573,16 @@ fn translate_unreachable_operator(
         Operator::If { ty: _ } => {
             // Push a placeholder control stack entry. The if isn't reachable,
             // so we don't have any branches anywhere.
-            state.push_if(ir::Inst::reserved_value(), ir::Ebb::reserved_value(), 0);
+            state.push_if(ir::Inst::reserved_value(), ir::Ebb::reserved_value(), 0, 0);
         }
         Operator::Loop { ty: _ } | Operator::Block { ty: _ } => {
-            state.push_block(ir::Ebb::reserved_value(), 0);
+            state.push_block(ir::Ebb::reserved_value(), 0, 0);
         }
         Operator::Else => {
             let i = state.control_stack.len() - 1;
             if let ControlStackFrame::If {
                 branch_inst,
+                original_stack_size,
                 ref mut reachable_from_top,
                 ..
             } = state.control_stack[i]
@@ -1080,10 +1595,10 @@ fn translate_unreachable_operator(
                     *reachable_from_top = false;
 
                     // We change the target of the branch instruction
-                    let else_ebb = builder.create_ebb();
-                    builder.change_jump_destination(branch_inst, else_ebb);
-                    builder.seal_block(else_ebb);
+                    let else_ebb = translate_else_ebb(builder, branch_inst);
                     builder.switch_to_block(else_ebb);
+                    state.stack.truncate(original_stack_size);
+                    state.pushn(builder.ebb_params(else_ebb));
                 }
             }
         }
@@ -1130,6 +1645,36 @@ fn translate_unreachable_operator(
     }
 }
 
+/// Create an `Ebb` whose parameters have the given WebAssembly types.
+fn ebb_with_params<FE: FuncEnvironment + ?Sized>(
+    builder: &mut FunctionBuilder,
+    params: &[wasmparser::Type],
+    offset: usize,
+    environ: &FE,
+) -> WasmResult<ir::Ebb> {
+    let ebb = builder.create_ebb();
+    for &ty in params {
+        let ty = type_to_type(ty, offset, environ)?;
+        builder.append_ebb_param(ebb, ty);
+    }
+    Ok(ebb)
+}
+
+/// Create the `Ebb` for the `else` arm of an `if` and redirect the `brz` at the top of the `if`
+/// to it. The `else` arm receives the `if` parameters, which are the arguments of that `brz`.
+fn translate_else_ebb(builder: &mut FunctionBuilder, branch_inst: ir::Inst) -> ir::Ebb {
+    let else_ebb = builder.create_ebb();
+    let num_args = builder.func.dfg.inst_variable_args(branch_inst).len();
+    for i in 0..num_args {
+        let arg = builder.func.dfg.inst_variable_args(branch_inst)[i];
+        let ty = builder.func.dfg.value_type(arg);
+        builder.append_ebb_param(else_ebb, ty);
+    }
+    builder.change_jump_destination(branch_inst, else_ebb);
+    builder.seal_block(else_ebb);
+    else_ebb
+}
+
 /// Get the address+offset to use for a heap access.
 fn get_heap_addr(
     heap: ir::Heap,
@@ -1211,6 +1756,185 @@ fn translate_store<FE: FuncEnvironment + ?Sized>(
         .Store(opcode, val_ty, flags, offset.into(), val, base);
 }
 
+/// Get the memory access type and the WebAssembly value type of an atomic operator.
+fn atomic_types(op: &Operator) -> (Type, Type) {
+    match *op {
+        Operator::I32AtomicLoad8U { .. }
+        | Operator::I32AtomicStore8 { .. }
+        | Operator::I32AtomicRmw8UAdd { .. }
+        | Operator::I32AtomicRmw8USub { .. }
+        | Operator::I32AtomicRmw8UAnd { .. }
+        | Operator::I32AtomicRmw8UOr { .. }
+        | Operator::I32AtomicRmw8UXor { .. }
+        | Operator::I32AtomicRmw8UXchg { .. }
+        | Operator::I32AtomicRmw8UCmpxchg { .. } => (I8, I32),
+        Operator::I32AtomicLoad16U { .. }
+        | Operator::I32AtomicStore16 { .. }
+        | Operator::I32AtomicRmw16UAdd { .. }
+        | Operator::I32AtomicRmw16USub { .. }
+        | Operator::I32AtomicRmw16UAnd { .. }
+        | Operator::I32AtomicRmw16UOr { .. }
+        | Operator::I32AtomicRmw16UXor { .. }
+        | Operator::I32AtomicRmw16UXchg { .. }
+        | Operator::I32AtomicRmw16UCmpxchg { .. } => (I16, I32),
+        Operator::I32AtomicLoad { .. }
+        | Operator::I32AtomicStore { .. }
+        | Operator::I32AtomicRmwAdd { .. }
+        | Operator::I32AtomicRmwSub { .. }
+        | Operator::I32AtomicRmwAnd { .. }
+        | Operator::I32AtomicRmwOr { .. }
+        | Operator::I32AtomicRmwXor { .. }
+        | Operator::I32AtomicRmwXchg { .. }
+        | Operator::I32AtomicRmwCmpxchg { .. } => (I32, I32),
+        Operator::I64AtomicLoad8U { .. }
+        | Operator::I64AtomicStore8 { .. }
+        | Operator::I64AtomicRmw8UAdd { .. }
+        | Operator::I64AtomicRmw8USub { .. }
+        | Operator::I64AtomicRmw8UAnd { .. }
+        | Operator::I64AtomicRmw8UOr { .. }
+        | Operator::I64AtomicRmw8UXor { .. }
+        | Operator::I64AtomicRmw8UXchg { .. }
+        | Operator::I64AtomicRmw8UCmpxchg { .. } => (I8, I64),
+        Operator::I64AtomicLoad16U { .. }
+        | Operator::I64AtomicStore16 { .. }
+        | Operator::I64AtomicRmw16UAdd { .. }
+        | Operator::I64AtomicRmw16USub { .. }
+        | Operator::I64AtomicRmw16UAnd { .. }
+        | Operator::I64AtomicRmw16UOr { .. }
+        | Operator::I64AtomicRmw16UXor { .. }
+        | Operator::I64AtomicRmw16UXchg { .. }
+        | Operator::I64AtomicRmw16UCmpxchg { .. } => (I16, I64),
+        Operator::I64AtomicLoad32U { .. }
+        | Operator::I64AtomicStore32 { .. }
+        | Operator::I64AtomicRmw32UAdd { .. }
+        | Operator::I64AtomicRmw32USub { .. }
+        | Operator::I64AtomicRmw32UAnd { .. }
+        | Operator::I64AtomicRmw32UOr { .. }
+        | Operator::I64AtomicRmw32UXor { .. }
+        | Operator::I64AtomicRmw32UXchg { .. }
+        | Operator::I64AtomicRmw32UCmpxchg { .. } => (I32, I64),
+        Operator::I64AtomicLoad { .. }
+        | Operator::I64AtomicStore { .. }
+        | Operator::I64AtomicRmwAdd { .. }
+        | Operator::I64AtomicRmwSub { .. }
+        | Operator::I64AtomicRmwAnd { .. }
+        | Operator::I64AtomicRmwOr { .. }
+        | Operator::I64AtomicRmwXor { .. }
+        | Operator::I64AtomicRmwXchg { .. }
+        | Operator::I64AtomicRmwCmpxchg { .. } => (I64, I64),
+        _ => panic!("not an atomic memory operator: {:?}", op),
+    }
+}
+
+/// Pop the WebAssembly address of an atomic access of type `access_ty` and compute its native
+/// address, trapping if it is out of bounds or not naturally aligned.
+fn translate_atomic_addr<FE: FuncEnvironment + ?Sized>(
+    offset: u32,
+    access_ty: Type,
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+    environ: &mut FE,
+) -> ir::Value {
+    let addr32 = state.pop1();
+    // We don't yet support multiple linear memories.
+    let heap = state.get_heap(builder.func, 0, environ);
+    let (base, offset) = get_heap_addr(heap, addr32, offset, environ.pointer_type(), builder);
+    let addr = if offset == 0 {
+        base
+    } else {
+        builder.ins().iadd_imm(base, i64::from(offset))
+    };
+
+    // The heap base is at least page aligned, so checking the native address is equivalent to
+    // checking the WebAssembly effective address.
+    let access_size = access_ty.bytes();
+    if access_size > 1 {
+        let misalignment = builder.ins().band_imm(addr, i64::from(access_size - 1));
+        builder
+            .ins()
+            .trapnz(misalignment, ir::TrapCode::HeapMisaligned);
+    }
+    addr
+}
+
+/// Translate an atomic load instruction.
+fn translate_atomic_load<FE: FuncEnvironment + ?Sized>(
+    offset: u32,
+    (access_ty, result_ty): (Type, Type),
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+    environ: &mut FE,
+) {
+    let addr = translate_atomic_addr(offset, access_ty, builder, state, environ);
+    let flags = MemFlags::new();
+    let mut val = builder.ins().atomic_load(access_ty, flags, addr, 0);
+    if access_ty != result_ty {
+        val = builder.ins().uextend(result_ty, val);
+    }
+    state.push1(val);
+}
+
+/// Translate an atomic store instruction.
+fn translate_atomic_store<FE: FuncEnvironment + ?Sized>(
+    offset: u32,
+    (access_ty, _): (Type, Type),
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+    environ: &mut FE,
+) {
+    let mut val = state.pop1();
+    let addr = translate_atomic_addr(offset, access_ty, builder, state, environ);
+    if builder.func.dfg.value_type(val) != access_ty {
+        val = builder.ins().ireduce(access_ty, val);
+    }
+    let flags = MemFlags::new();
+    builder.ins().atomic_store(flags, val, addr, 0);
+}
+
+/// Translate an atomic read-modify-write instruction.
+fn translate_atomic_rmw<FE: FuncEnvironment + ?Sized>(
+    offset: u32,
+    op: ir::AtomicRmwOp,
+    (access_ty, result_ty): (Type, Type),
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+    environ: &mut FE,
+) {
+    let mut arg = state.pop1();
+    let addr = translate_atomic_addr(offset, access_ty, builder, state, environ);
+    if access_ty != result_ty {
+        arg = builder.ins().ireduce(access_ty, arg);
+    }
+    let flags = MemFlags::new();
+    let mut val = builder.ins().atomic_rmw(flags, op, addr, arg);
+    if access_ty != result_ty {
+        val = builder.ins().uextend(result_ty, val);
+    }
+    state.push1(val);
+}
+
+/// Translate an atomic compare-and-exchange instruction.
+fn translate_atomic_cas<FE: FuncEnvironment + ?Sized>(
+    offset: u32,
+    (access_ty, result_ty): (Type, Type),
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+    environ: &mut FE,
+) {
+    let (mut expected, mut replacement) = state.pop2();
+    let addr = translate_atomic_addr(offset, access_ty, builder, state, environ);
+    if access_ty != result_ty {
+        expected = builder.ins().ireduce(access_ty, expected);
+        replacement = builder.ins().ireduce(access_ty, replacement);
+    }
+    let flags = MemFlags::new();
+    let mut val = builder.ins().atomic_cas(flags, addr, expected, replacement);
+    if access_ty != result_ty {
+        val = builder.ins().uextend(result_ty, val);
+    }
+    state.push1(val);
+}
+
 fn translate_icmp(cc: IntCC, builder: &mut FunctionBuilder, state: &mut TranslationState) {
     let (arg0, arg1) = state.pop2();
     let val = builder.ins().icmp(cc, arg0, arg1);
@@ -1223,6 +1947,237 @@ fn translate_fcmp(cc: FloatCC, builder: &mut FunctionBuilder, state: &mut Transl
     state.push1(builder.ins().bint(I32, val));
 }
 
+/// Get the Cranelift vector type that a SIMD arithmetic operator works on.
+fn vector_type(op: &Operator) -> Type {
+    match *op {
+        Operator::I8x16Shl
+        | Operator::I8x16ShrS
+        | Operator::I8x16ShrU
+        | Operator::I8x16Add
+        | Operator::I8x16AddSaturateS
+        | Operator::I8x16AddSaturateU
+        | Operator::I8x16Sub
+        | Operator::I8x16SubSaturateS
+        | Operator::I8x16SubSaturateU
+        | Operator::I8x16Mul => I8X16,
+        Operator::I16x8Shl
+        | Operator::I16x8ShrS
+        | Operator::I16x8ShrU
+        | Operator::I16x8Add
+        | Operator::I16x8AddSaturateS
+        | Operator::I16x8AddSaturateU
+        | Operator::I16x8Sub
+        | Operator::I16x8SubSaturateS
+        | Operator::I16x8SubSaturateU
+        | Operator::I16x8Mul => I16X8,
+        Operator::I32x4Shl
+        | Operator::I32x4ShrS
+        | Operator::I32x4ShrU
+        | Operator::I32x4Add
+        | Operator::I32x4Sub
+        | Operator::I32x4Mul => I32X4,
+        Operator::I64x2Shl
+        | Operator::I64x2ShrS
+        | Operator::I64x2ShrU
+        | Operator::I64x2Add
+        | Operator::I64x2Sub => I64X2,
+        Operator::F32x4Abs
+        | Operator::F32x4Neg
+        | Operator::F32x4Sqrt
+        | Operator::F32x4Add
+        | Operator::F32x4Sub
+        | Operator::F32x4Mul
+        | Operator::F32x4Div
+        | Operator::F32x4Min
+        | Operator::F32x4Max => F32X4,
+        Operator::F64x2Abs
+        | Operator::F64x2Neg
+        | Operator::F64x2Sqrt
+        | Operator::F64x2Add
+        | Operator::F64x2Sub
+        | Operator::F64x2Mul
+        | Operator::F64x2Div
+        | Operator::F64x2Min
+        | Operator::F64x2Max => F64X2,
+        _ => panic!("not a SIMD arithmetic operator: {:?}", op),
+    }
+}
+
+/// Reinterpret `value` as the vector type `ty`, if it isn't one already.
+fn optionally_bitcast_vector(
+    value: ir::Value,
+    ty: Type,
+    builder: &mut FunctionBuilder,
+) -> ir::Value {
+    if builder.func.dfg.value_type(value) == ty {
+        value
+    } else {
+        builder.ins().bitcast(ty, value)
+    }
+}
+
+/// Pop a `v128` value and reinterpret it as the vector type `ty`.
+fn pop1_vector(ty: Type, builder: &mut FunctionBuilder, state: &mut TranslationState) -> ir::Value {
+    let a = state.pop1();
+    optionally_bitcast_vector(a, ty, builder)
+}
+
+/// Pop two `v128` values and reinterpret them as the vector type `ty`.
+fn pop2_vector(
+    ty: Type,
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+) -> (ir::Value, ir::Value) {
+    let (a, b) = state.pop2();
+    (
+        optionally_bitcast_vector(a, ty, builder),
+        optionally_bitcast_vector(b, ty, builder),
+    )
+}
+
+/// Push a vector as a `v128` value, in its `I8X16` representation.
+fn push_vector(value: ir::Value, builder: &mut FunctionBuilder, state: &mut TranslationState) {
+    state.push1(optionally_bitcast_vector(value, I8X16, builder));
+}
+
+/// Get a vector of type `ty` with all lanes set to `imm`.
+fn vector_iconst(ty: Type, imm: i64, builder: &mut FunctionBuilder) -> ir::Value {
+    let lane = builder.ins().iconst(ty.lane_type(), imm);
+    builder.ins().splat(ty, lane)
+}
+
+fn translate_splat(ty: Type, builder: &mut FunctionBuilder, state: &mut TranslationState) {
+    let mut lane = state.pop1();
+    // Wasm passes `i8` and `i16` lanes as `i32`.
+    if ty.lane_bits() < 32 {
+        lane = builder.ins().ireduce(ty.lane_type(), lane);
+    }
+    let val = builder.ins().splat(ty, lane);
+    push_vector(val, builder, state);
+}
+
+fn translate_extract_lane(
+    ty: Type,
+    line: u8,
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+) -> ir::Value {
+    let a = pop1_vector(ty, builder, state);
+    builder.ins().extractlane(a, line)
+}
+
+fn translate_replace_lane(
+    ty: Type,
+    line: u8,
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+) {
+    let (a, mut lane) = state.pop2();
+    let a = optionally_bitcast_vector(a, ty, builder);
+    // Wasm passes `i8` and `i16` lanes as `i32`.
+    if ty.lane_bits() < 32 {
+        lane = builder.ins().ireduce(ty.lane_type(), lane);
+    }
+    let val = builder.ins().insertlane(a, line, lane);
+    push_vector(val, builder, state);
+}
+
+fn translate_vector_icmp(
+    cc: IntCC,
+    ty: Type,
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+) {
+    let (a, b) = pop2_vector(ty, builder, state);
+    let val = builder.ins().icmp(cc, a, b);
+    let mask = builder.ins().bmask(ty, val);
+    push_vector(mask, builder, state);
+}
+
+fn translate_vector_fcmp(
+    cc: FloatCC,
+    ty: Type,
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+) {
+    let (a, b) = pop2_vector(ty, builder, state);
+    let val = builder.ins().fcmp(cc, a, b);
+    let int_ty = Type::int(ty.lane_bits() as u16)
+        .and_then(|lane| lane.by(ty.lane_count()))
+        .expect("vector of floats has a matching integer type");
+    let mask = builder.ins().bmask(int_ty, val);
+    push_vector(mask, builder, state);
+}
+
+fn translate_vector_ineg(ty: Type, builder: &mut FunctionBuilder, state: &mut TranslationState) {
+    let a = pop1_vector(ty, builder, state);
+    let zero = vector_iconst(ty, 0, builder);
+    let val = builder.ins().isub(zero, a);
+    push_vector(val, builder, state);
+}
+
+/// Compute the bitwise or of the two halves of the `I64X2` vector `a`.
+fn translate_vector_any_set(a: ir::Value, builder: &mut FunctionBuilder) -> ir::Value {
+    let low = builder.ins().extractlane(a, 0);
+    let high = builder.ins().extractlane(a, 1);
+    builder.ins().bor(low, high)
+}
+
+fn translate_vector_all_true(
+    ty: Type,
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+) {
+    // Compute a mask of the zero lanes, and check that it is empty.
+    let a = pop1_vector(ty, builder, state);
+    let zero = vector_iconst(ty, 0, builder);
+    let is_zero = builder.ins().icmp(IntCC::Equal, a, zero);
+    let zero_lanes = builder.ins().bmask(ty, is_zero);
+    let zero_lanes = builder.ins().bitcast(I64X2, zero_lanes);
+    let any_zero = translate_vector_any_set(zero_lanes, builder);
+    let val = builder.ins().icmp_imm(IntCC::Equal, any_zero, 0);
+    state.push1(builder.ins().bint(I32, val));
+}
+
+/// Translate a signed saturating addition if `add` is true, or a subtraction otherwise.
+fn translate_vector_signed_sat(
+    add: bool,
+    ty: Type,
+    builder: &mut FunctionBuilder,
+    state: &mut TranslationState,
+) {
+    let (a, b) = pop2_vector(ty, builder, state);
+    let sign_shift = i64::from(ty.lane_bits() - 1);
+
+    // The result overflowed if its sign differs from the sign `a` and `b` share for an addition,
+    // or from the sign of `a` when `a` and `b` have different signs for a subtraction. Get a mask
+    // of those lanes from the sign bits.
+    let wrapped = if add {
+        builder.ins().iadd(a, b)
+    } else {
+        builder.ins().isub(a, b)
+    };
+    let wrapped_xor_a = builder.ins().bxor(wrapped, a);
+    let overflow = if add {
+        let wrapped_xor_b = builder.ins().bxor(wrapped, b);
+        builder.ins().band(wrapped_xor_a, wrapped_xor_b)
+    } else {
+        let a_xor_b = builder.ins().bxor(a, b);
+        builder.ins().band(a_xor_b, wrapped_xor_a)
+    };
+    let overflow = builder.ins().sshr_imm(overflow, sign_shift);
+
+    // Overflowing lanes saturate to the minimum when `a` is negative and the maximum otherwise.
+    let max = vector_iconst(ty, (1 << sign_shift) - 1, builder);
+    let a_sign = builder.ins().sshr_imm(a, sign_shift);
+    let saturated = builder.ins().bxor(a_sign, max);
+
+    let saturated = builder.ins().band(saturated, overflow);
+    let wrapped = builder.ins().band_not(wrapped, overflow);
+    let val = builder.ins().bor(saturated, wrapped);
+    push_vector(val, builder, state);
+}
+
 fn translate_br_if(
     relative_depth: u32,
     builder: &mut FunctionBuilder,
@@ -1243,12 +2198,7 @@ fn translate_br_if_args(
         // The values returned by the branch are still available for the reachable
         // code that comes after it
         frame.set_branched_to_exit();
-        let return_count = if frame.is_loop() {
-            0
-        } else {
-            frame.num_return_values()
-        };
-        (return_count, frame.br_destination())
+        (frame.num_branch_args(), frame.br_destination())
     };
     let inputs = state.peekn(return_count);
     (br_destination, inputs)
@@ -0,0 +1,275 @@
+//! Compile function bodies concurrently.
+//!
+//! This module provides the `ConcurrentCompiler` struct which uses a pool of threads to translate
+//! and compile the function bodies of a module while the rest of the module is being parsed.
+//!
+//! A `ModuleEnvironment` would typically create the compiler in `reserve_function_bodies`, when
+//! all the declarations needed to translate function bodies are known, hand each body received
+//! by `define_function_body` to `ConcurrentCompiler::put`, and collect the results in
+//! `finish_function_bodies`. `DummyEnvironment::set_translation_threads` does this.
+
+use crate::environ::WasmResult;
+use crate::translation_utils::DefinedFuncIndex;
+use crate::FuncTranslator;
+use cranelift_codegen::timing;
+use cranelift_codegen::Context;
+use cranelift_entity::{EntityRef, PrimaryMap};
+use std::panic::resume_unwind;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::vec::Vec;
+
+/// Request sent to worker threads: a function body and its offset in the module.
+struct Request(DefinedFuncIndex, Vec<u8>, usize);
+
+/// Reply from worker threads.
+struct Reply<T>(DefinedFuncIndex, WasmResult<T>);
+
+/// Manage threads that compile function bodies concurrently.
+///
+/// Each worker thread owns a `FuncTranslator` and a `Context`, which are reused for all the
+/// functions it compiles. The results are collected in function index order by `finish`.
+pub struct ConcurrentCompiler<T> {
+    /// Channel for sending requests to the worker threads.
+    /// The workers are sharing the receiver with an `Arc<Mutex<Receiver>>`.
+    request_tx: Sender<Request>,
+
+    /// Channel for receiving replies from the workers.
+    /// Workers have their own `Sender`.
+    reply_rx: Receiver<Reply<T>>,
+
+    handles: Vec<thread::JoinHandle<timing::PassTimes>>,
+
+    /// Number of function bodies sent to the workers so far.
+    num_bodies: usize,
+}
+
+impl<T: Send + 'static> ConcurrentCompiler<T> {
+    /// Create a new `ConcurrentCompiler` with one thread per CPU.
+    ///
+    /// The `compile` function is called by the worker threads for every function body. It is
+    /// given the worker's translator and a cleared context, followed by the index of the function
+    /// and the body bytes and offset as passed to `ModuleEnvironment::define_function_body`.
+    pub fn new<F>(compile: F) -> Self
+    where
+        F: Fn(&mut FuncTranslator, &mut Context, DefinedFuncIndex, &[u8], usize) -> WasmResult<T>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self::with_threads(num_cpus::get(), compile)
+    }
+
+    /// Create a new `ConcurrentCompiler` with `num_threads` threads.
+    pub fn with_threads<F>(num_threads: usize, compile: F) -> Self
+    where
+        F: Fn(&mut FuncTranslator, &mut Context, DefinedFuncIndex, &[u8], usize) -> WasmResult<T>
+            + Send
+            + Sync
+            + 'static,
+    {
+        assert!(num_threads > 0, "need at least one worker thread");
+        let (request_tx, request_rx) = channel();
+        let request_mutex = Arc::new(Mutex::new(request_rx));
+        let (reply_tx, reply_rx) = channel();
+        let compile: Arc<CompileFn<T>> = Arc::new(compile);
+
+        let handles = (0..num_threads)
+            .map(|num| {
+                worker_thread(
+                    num,
+                    request_mutex.clone(),
+                    reply_tx.clone(),
+                    compile.clone(),
+                )
+            })
+            .collect();
+
+        Self {
+            request_tx,
+            reply_rx,
+            handles,
+            num_bodies: 0,
+        }
+    }
+
+    /// Queue the next function body for compilation.
+    ///
+    /// The body is copied, so the worker threads don't borrow the module data.
+    pub fn put(&mut self, body_bytes: &[u8], body_offset: usize) {
+        let index = DefinedFuncIndex::new(self.num_bodies);
+        self.num_bodies += 1;
+        // The send only fails when all the workers have panicked. Drop the body in that case and
+        // let `finish` propagate the panic.
+        let _ = self
+            .request_tx
+            .send(Request(index, body_bytes.to_vec(), body_offset));
+    }
+
+    /// Wait for all the queued function bodies to be compiled and return the results.
+    ///
+    /// If any function failed to compile, return the error of the first one in index order.
+    /// Pass timings from the worker threads are added to the current thread. A panic in a worker
+    /// thread is propagated to the current thread.
+    pub fn finish(self) -> WasmResult<PrimaryMap<DefinedFuncIndex, T>> {
+        let Self {
+            request_tx,
+            reply_rx,
+            handles,
+            num_bodies,
+        } = self;
+
+        // Shut down the workers orderly. They will finish any queued jobs first.
+        drop(request_tx);
+
+        let mut results: Vec<Option<WasmResult<T>>> = (0..num_bodies).map(|_| None).collect();
+        for Reply(index, result) in reply_rx {
+            results[index.index()] = Some(result);
+        }
+
+        for handle in handles {
+            match handle.join() {
+                Ok(times) => timing::add_to_current(&times),
+                Err(panic) => resume_unwind(panic),
+            }
+        }
+
+        let mut funcs = PrimaryMap::with_capacity(num_bodies);
+        for result in results {
+            funcs.push(result.expect("worker thread dropped a function body")?);
+        }
+        Ok(funcs)
+    }
+}
+
+/// The function called by worker threads to compile a function body.
+type CompileFn<T> = Fn(&mut FuncTranslator, &mut Context, DefinedFuncIndex, &[u8], usize) -> WasmResult<T>
+    + Send
+    + Sync;
+
+/// Spawn a worker thread compiling function bodies.
+fn worker_thread<T: Send + 'static>(
+    thread_num: usize,
+    requests: Arc<Mutex<Receiver<Request>>>,
+    replies: Sender<Reply<T>>,
+    compile: Arc<CompileFn<T>>,
+) -> thread::JoinHandle<timing::PassTimes> {
+    thread::Builder::new()
+        .name(format!("wasm worker #{}", thread_num))
+        .spawn(move || {
+            let mut trans = FuncTranslator::new();
+            let mut context = Context::new();
+            loop {
+                // Lock the mutex only long enough to extract a request. The receiver is still
+                // usable if another worker panicked while holding the lock.
+                let request = requests
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .recv();
+                let Request(index, body, offset) = match request {
+                    Err(..) => break, // TX end shut down. exit thread.
+                    Ok(req) => req,
+                };
+                context.clear();
+                let result = compile(&mut trans, &mut context, index, &body, offset);
+                if result.is_err() {
+                    // A failed translation can leave the translator in an unusable state.
+                    trans = FuncTranslator::new();
+                }
+                if replies.send(Reply(index, result)).is_err() {
+                    break;
+                }
+            }
+
+            // Timing is accumulated independently per thread.
+            // Timings from this worker thread will be aggregated by `finish()`.
+            timing::take_current()
+        })
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConcurrentCompiler;
+    use crate::environ::{DummyEnvironment, ReturnMode, WasmError};
+    use cranelift_codegen::ir::types::I32;
+    use cranelift_codegen::{ir, isa, settings};
+    use std::panic;
+    use std::string::{String, ToString};
+    use std::thread;
+    use std::time::Duration;
+    use std::vec::Vec;
+    use target_lexicon::PointerWidth;
+
+    /// Compile `(func (param i32) (result i32) (i32.add (get_local 0) (i32.const n)))` for
+    /// a range of `n`, optionally with an invalid body in the middle.
+    fn compile(count: u8, invalid: Option<u8>) -> Result<Vec<String>, WasmError> {
+        let config = isa::TargetFrontendConfig {
+            default_call_conv: isa::CallConv::Fast,
+            pointer_width: PointerWidth::U64,
+        };
+        let mut compiler =
+            ConcurrentCompiler::with_threads(4, move |trans, ctx, index, body, offset| {
+                // The environment isn't shared between the threads, since it holds its own
+                // compiler.
+                let runtime = DummyEnvironment::new(config, ReturnMode::NormalReturns);
+                let flags = settings::Flags::new(settings::builder());
+                ctx.func.name = ir::ExternalName::user(0, index.as_u32());
+                ctx.func.signature.params.push(ir::AbiParam::new(I32));
+                ctx.func.signature.returns.push(ir::AbiParam::new(I32));
+                trans.translate(body, offset, &mut ctx.func, &mut runtime.func_env())?;
+                ctx.verify(&flags).unwrap();
+                Ok(ctx.func.display(None).to_string())
+            });
+        for n in 0..count {
+            let body = if invalid == Some(n) {
+                vec![0x00, 0xff, 0x0b] // Invalid opcode.
+            } else {
+                vec![0x00, 0x20, 0x00, 0x41, n, 0x6a, 0x0b]
+            };
+            compiler.put(&body, usize::from(n) * 16);
+        }
+        compiler
+            .finish()
+            .map(|funcs| funcs.values().cloned().collect())
+    }
+
+    #[test]
+    fn results_in_order() {
+        let funcs = compile(64, None).unwrap();
+        assert_eq!(funcs.len(), 64);
+        for (n, func) in funcs.iter().enumerate() {
+            assert!(func.starts_with(&format!("function u0:{}(", n)));
+            assert!(func.contains(&format!("iconst.i32 {}\n", n)));
+        }
+    }
+
+    #[test]
+    fn first_error() {
+        match compile(64, Some(17)) {
+            Err(WasmError::InvalidWebAssembly { offset, .. }) => {
+                assert!(offset >= 17 * 16 && offset < 18 * 16)
+            }
+            result => panic!("unexpected result: {:?}", result.map(|funcs| funcs.len())),
+        }
+    }
+
+    #[test]
+    fn worker_panic() {
+        let result = panic::catch_unwind(|| {
+            let mut compiler =
+                ConcurrentCompiler::with_threads(1, |_, _, _, _, _| -> Result<(), WasmError> {
+                    panic!("worker panic")
+                });
+            compiler.put(&[0x00, 0x0b], 0);
+            // Give the worker time to panic, so the following bodies are sent to a closed
+            // channel.
+            thread::sleep(Duration::from_millis(100));
+            compiler.put(&[0x00, 0x0b], 16);
+            compiler.finish()
+        });
+        let payload = result.err().expect("the worker panic wasn't propagated");
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"worker panic"));
+    }
+}
@@ -0,0 +1,1088 @@
+//! Translation of the DWARF debug information of a WebAssembly module to native code.
+//!
+//! Compilers such as clang and rustc describe the WebAssembly code they generate with DWARF
+//! sections stored in `.debug_*` custom sections. The code addresses in these sections are
+//! offsets into the code section of the module. The translator records the bytecode offset of
+//! every instruction as its `SourceLoc`, so once a function has been compiled, the native code of
+//! each instruction can be mapped back to these addresses. This module uses that mapping to
+//! rewrite the DWARF sections so that they describe the native code instead, which lets a
+//! debugger step through JIT-compiled WebAssembly at the source level.
+//!
+//! Only code addresses are rewritten. Location expressions are copied unchanged, so variable
+//! locations still refer to WebAssembly locals and operand stack slots.
+
+use crate::environ::{WasmError, WasmResult};
+use crate::HashMap;
+use core::cmp;
+use cranelift_codegen::ir;
+use cranelift_codegen::isa::TargetIsa;
+use gimli::read::{self, AttributeValue as ReadValue};
+use gimli::write::{self, Address, AttributeValue as WriteValue};
+use gimli::{constants, EndianSlice, LittleEndian, UnitSectionOffset};
+use std::format;
+use std::vec::Vec;
+
+type Reader<'data> = EndianSlice<'data, LittleEndian>;
+
+/// The DWARF sections of a WebAssembly module.
+///
+/// A `ModuleEnvironment` can collect them with `declare_custom_section` and
+/// `reserve_function_bodies`.
+#[derive(Default)]
+pub struct DebugSections<'data> {
+    /// Offset of the contents of the code section in the module. Code addresses in the DWARF
+    /// sections are relative to it.
+    code_section_offset: u64,
+    /// The contents of the `.debug_*` custom sections, indexed by name.
+    sections: HashMap<&'data str, &'data [u8]>,
+}
+
+impl<'data> DebugSections<'data> {
+    /// Create an empty set of DWARF sections.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the custom section `name` if it is a DWARF section.
+    ///
+    /// Return `true` if the section was recorded.
+    pub fn declare_section(&mut self, name: &'data str, data: &'data [u8]) -> bool {
+        if name.starts_with(".debug_") {
+            self.sections.insert(name, data);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Set the offset of the contents of the code section in the module.
+    pub fn set_code_section_offset(&mut self, offset: u64) {
+        self.code_section_offset = offset;
+    }
+
+    /// Is there any DWARF section?
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    fn load(&self) -> WasmResult<read::Dwarf<Reader<'data>>> {
+        let section = |id: gimli::SectionId| -> Result<Reader<'data>, read::Error> {
+            let data = self.sections.get(id.name()).cloned().unwrap_or(&[]);
+            Ok(EndianSlice::new(data, LittleEndian))
+        };
+        let sup = |_| Ok(EndianSlice::new(&[], LittleEndian));
+        read::Dwarf::load(section, sup).map_err(read_error)
+    }
+}
+
+/// The native code of an instruction and the WebAssembly bytecode offset it was translated from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionAddressMap {
+    /// The bytecode offset recorded by the translator.
+    pub srcloc: ir::SourceLoc,
+    /// The offset of the instruction from the start of the function's native code.
+    pub code_offset: usize,
+    /// The size of the instruction's native code in bytes.
+    pub code_len: usize,
+}
+
+/// Mapping from the native code of a compiled function to WebAssembly bytecode offsets.
+#[derive(Debug, Clone)]
+pub struct FunctionAddressMap {
+    /// The instructions which have a source location, in code order.
+    pub instructions: Vec<InstructionAddressMap>,
+    /// The bytecode offset of the start of the function body.
+    pub start_srcloc: ir::SourceLoc,
+    /// The bytecode offset of the end of the function body.
+    pub end_srcloc: ir::SourceLoc,
+    /// The size of the function's native code in bytes.
+    pub code_len: usize,
+}
+
+impl FunctionAddressMap {
+    /// Build the address map of `func`, which has been compiled for `isa`.
+    ///
+    /// The `body_offset` and `body_len` describe the function body that was translated to
+    /// `func`, as passed to `ModuleEnvironment::define_function_body`.
+    pub fn new(func: &ir::Function, isa: &TargetIsa, body_offset: usize, body_len: usize) -> Self {
+        let encinfo = isa.encoding_info();
+        let mut instructions = Vec::new();
+        let mut code_len = 0;
+        for ebb in func.layout.ebbs() {
+            for (offset, inst, size) in func.inst_offsets(ebb, &encinfo) {
+                let srcloc = func.srclocs[inst];
+                if !srcloc.is_default() && size > 0 {
+                    instructions.push(InstructionAddressMap {
+                        srcloc,
+                        code_offset: offset as usize,
+                        code_len: size as usize,
+                    });
+                }
+                code_len = cmp::max(code_len, (offset + size) as usize);
+            }
+        }
+        Self {
+            instructions,
+            start_srcloc: ir::SourceLoc::new(body_offset as u32),
+            end_srcloc: ir::SourceLoc::new((body_offset + body_len) as u32),
+            code_len,
+        }
+    }
+}
+
+/// Rewrite the DWARF sections of a module to describe its compiled code.
+///
+/// Each compiled function is given by the native address of its code and its address map. The
+/// returned sections are named by their ELF section names, and use the pointer size of `isa`.
+/// Functions which are not described by the DWARF sections are ignored, and so are the entries
+/// describing code which wasn't compiled.
+///
+/// The native code of the functions in a compilation unit needn't be contiguous, so units are
+/// described by a `DW_AT_ranges` list, with a base address of 0.
+pub fn transform_dwarf(
+    isa: &TargetIsa,
+    debug_sections: &DebugSections,
+    functions: &[(u64, &FunctionAddressMap)],
+) -> WasmResult<Vec<(&'static str, Vec<u8>)>> {
+    let dwarf = debug_sections.load()?;
+    let transform = AddressTransform::new(debug_sections.code_section_offset, functions)?;
+    let mut out = write::Dwarf::new();
+
+    // Create the entries of all units first, so that references to any of them can be converted.
+    let mut units = Vec::new();
+    let mut entry_ids = HashMap::new();
+    let mut headers = dwarf.units();
+    while let Some(header) = headers.next().map_err(read_error)? {
+        let unit = dwarf.unit(header).map_err(read_error)?;
+        let encoding = gimli::Encoding {
+            address_size: isa.pointer_bytes(),
+            ..unit.encoding()
+        };
+        let (line_program, files) = transform_line_program(&dwarf, &unit, encoding, &transform)?;
+        let unit_id = out.units.add(write::Unit::new(encoding, line_program));
+
+        let mut entries = Vec::new();
+        {
+            let out_unit = out.units.get_mut(unit_id);
+            let root_id = out_unit.root();
+            let mut tree = unit.entries_tree(None).map_err(read_error)?;
+            let root = tree.root().map_err(read_error)?;
+            let keep = |entry: &read::DebuggingInformationEntry<Reader<'_>>| {
+                Ok(match entry_low_pc(&dwarf, &unit, entry)? {
+                    Some(low_pc) => transform.function_from(low_pc).is_some(),
+                    None => true,
+                })
+            };
+            add_entries(root, root_id, out_unit, &keep, &mut entries)?;
+        }
+        for &(offset, id) in &entries {
+            entry_ids.insert(offset.to_unit_section_offset(&unit), (unit_id, id));
+        }
+        units.push((unit, unit_id, entries, files));
+    }
+
+    for (unit, unit_id, entries, files) in &units {
+        let mut context = UnitContext {
+            dwarf: &dwarf,
+            unit,
+            transform: &transform,
+            entry_ids: &entry_ids,
+            files,
+            strings: &mut out.strings,
+        };
+        let out_unit = out.units.get_mut(*unit_id);
+        let root_id = out_unit.root();
+        for &(offset, id) in entries {
+            let entry = unit.entry(offset).map_err(read_error)?;
+            let low_pc = context.low_pc(&entry)?;
+            let is_root_with_code = id == root_id && context.has_code(&entry)?;
+            if is_root_with_code {
+                let ranges = context.unit_ranges(out_unit)?;
+                let out_entry = out_unit.get_mut(id);
+                out_entry.set(
+                    constants::DW_AT_low_pc,
+                    WriteValue::Address(Address::Constant(0)),
+                );
+                out_entry.set(constants::DW_AT_ranges, ranges);
+            }
+            let mut attrs = entry.attrs();
+            while let Some(attr) = attrs.next().map_err(read_error)? {
+                match attr.name() {
+                    constants::DW_AT_sibling => continue,
+                    constants::DW_AT_low_pc
+                    | constants::DW_AT_high_pc
+                    | constants::DW_AT_ranges
+                        if is_root_with_code =>
+                    {
+                        continue
+                    }
+                    _ => {}
+                }
+                if let Some(value) = context.convert_attribute(&attr, low_pc, out_unit)? {
+                    out_unit.get_mut(id).set(attr.name(), value);
+                }
+            }
+        }
+    }
+
+    let mut sections = write::Sections::new(write::EndianVec::new(LittleEndian));
+    out.write(&mut sections).map_err(write_error)?;
+    let mut result = Vec::new();
+    sections
+        .for_each(|id, data| -> Result<(), ()> {
+            if !data.slice().is_empty() {
+                result.push((id.name(), data.slice().to_vec()));
+            }
+            Ok(())
+        })
+        .expect("collecting sections can't fail");
+    Ok(result)
+}
+
+fn read_error(e: read::Error) -> WasmError {
+    WasmError::InvalidDebugInfo(e.description().into())
+}
+
+fn write_error(e: write::Error) -> WasmError {
+    WasmError::InvalidDebugInfo(format!("{:?}", e))
+}
+
+/// Add the children of the read entry `node` to the entry `id` of `out_unit`, recursively.
+///
+/// Children for which `keep` returns `false` are left out along with their own children. Record
+/// the offset of every read entry along with the corresponding id in `entries`.
+fn add_entries<'data>(
+    node: read::EntriesTreeNode<Reader<'data>>,
+    id: write::UnitEntryId,
+    out_unit: &mut write::Unit,
+    keep: &Fn(&read::DebuggingInformationEntry<Reader<'data>>) -> WasmResult<bool>,
+    entries: &mut Vec<(read::UnitOffset, write::UnitEntryId)>,
+) -> WasmResult<()> {
+    entries.push((node.entry().offset(), id));
+    let mut children = node.children();
+    while let Some(child) = children.next().map_err(read_error)? {
+        if !keep(child.entry())? {
+            continue;
+        }
+        let child_id = out_unit.add(id, child.entry().tag());
+        add_entries(child, child_id, out_unit, keep, entries)?;
+    }
+    Ok(())
+}
+
+/// Get the code address of the `DW_AT_low_pc` attribute of `entry`.
+fn entry_low_pc<'data>(
+    dwarf: &read::Dwarf<Reader<'data>>,
+    unit: &read::Unit<Reader<'data>>,
+    entry: &read::DebuggingInformationEntry<Reader<'data>>,
+) -> WasmResult<Option<u64>> {
+    match entry
+        .attr_value(constants::DW_AT_low_pc)
+        .map_err(read_error)?
+    {
+        Some(value) => attr_address(dwarf, unit, value),
+        None => Ok(None),
+    }
+}
+
+/// Get the code address of an address attribute value.
+fn attr_address<'data>(
+    dwarf: &read::Dwarf<Reader<'data>>,
+    unit: &read::Unit<Reader<'data>>,
+    value: ReadValue<Reader<'data>>,
+) -> WasmResult<Option<u64>> {
+    match value {
+        ReadValue::Addr(addr) => Ok(Some(addr)),
+        ReadValue::DebugAddrIndex(index) => {
+            dwarf.address(unit, index).map(Some).map_err(read_error)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Get the code address of the bytecode offset `srcloc`.
+fn code_address(srcloc: ir::SourceLoc, code_section_offset: u64) -> WasmResult<u64> {
+    u64::from(srcloc.bits())
+        .checked_sub(code_section_offset)
+        .ok_or_else(|| {
+            WasmError::InvalidDebugInfo(format!(
+                "function body at offset {} precedes the code section at offset {}",
+                srcloc.bits(),
+                code_section_offset
+            ))
+        })
+}
+
+/// A compiled function, as seen from the DWARF sections.
+struct FunctionRange<'a> {
+    /// The code address range of the function body.
+    wasm_start: u64,
+    wasm_end: u64,
+    /// The native address of the function's code.
+    native_start: u64,
+    /// Code addresses of instructions and their native code offsets, sorted by code address.
+    positions: Vec<(u64, u64)>,
+    map: &'a FunctionAddressMap,
+}
+
+/// Translation of code addresses to native addresses.
+struct AddressTransform<'a> {
+    code_section_offset: u64,
+    /// The compiled functions, sorted by code address.
+    functions: Vec<FunctionRange<'a>>,
+}
+
+impl<'a> FunctionRange<'a> {
+    /// Translate the code address `addr` of this function to a native address.
+    ///
+    /// An address inside the function is mapped to the native code of the closest preceding
+    /// instruction, and the end of the function body is mapped to the end of its native code.
+    fn translate(&self, addr: u64) -> u64 {
+        let offset = if addr >= self.wasm_end {
+            self.map.code_len as u64
+        } else {
+            match self
+                .positions
+                .binary_search_by_key(&addr, |&(wasm, _)| wasm)
+            {
+                Ok(index) => self.positions[index].1,
+                Err(0) => 0,
+                Err(index) => self.positions[index - 1].1,
+            }
+        };
+        self.native_start + offset
+    }
+}
+
+impl<'a> AddressTransform<'a> {
+    fn new(
+        code_section_offset: u64,
+        functions: &[(u64, &'a FunctionAddressMap)],
+    ) -> WasmResult<Self> {
+        let mut ranges = Vec::with_capacity(functions.len());
+        for &(native_start, map) in functions {
+            let mut positions: Vec<_> = map
+                .instructions
+                .iter()
+                .filter_map(|inst| {
+                    let addr = u64::from(inst.srcloc.bits()).checked_sub(code_section_offset)?;
+                    Some((addr, inst.code_offset as u64))
+                })
+                .collect();
+            positions.sort();
+            positions.dedup_by_key(|&mut (addr, _)| addr);
+            ranges.push(FunctionRange {
+                wasm_start: code_address(map.start_srcloc, code_section_offset)?,
+                wasm_end: code_address(map.end_srcloc, code_section_offset)?,
+                native_start,
+                positions,
+                map,
+            });
+        }
+        ranges.sort_by_key(|range| range.wasm_start);
+        Ok(Self {
+            code_section_offset,
+            functions: ranges,
+        })
+    }
+
+    /// Get the function containing the code address `addr`. The end of a function body is
+    /// considered part of the function.
+    fn function(&self, addr: u64) -> Option<&FunctionRange<'a>> {
+        let index = match self
+            .functions
+            .binary_search_by_key(&addr, |range| range.wasm_start)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let range = &self.functions[index];
+        if addr <= range.wasm_end {
+            Some(range)
+        } else {
+            None
+        }
+    }
+
+    /// Get the function whose code starts at or covers the code address `addr`. Unlike
+    /// `function`, the end of a function body is not part of it, since it may be the start of
+    /// the next function.
+    fn function_from(&self, addr: u64) -> Option<&FunctionRange<'a>> {
+        self.function(addr).filter(|range| addr < range.wasm_end)
+    }
+
+    /// Translate the code address `addr` to a native address, if it is part of a function.
+    fn translate(&self, addr: u64) -> Option<u64> {
+        Some(self.function(addr)?.translate(addr))
+    }
+
+    /// Translate the code address range `begin..end` to native address ranges, one for each
+    /// function overlapping it. Empty ranges are left out.
+    fn translate_ranges(&self, begin: u64, end: u64) -> Vec<(Address, Address)> {
+        self.functions
+            .iter()
+            .filter(|range| begin < range.wasm_end && range.wasm_start < end)
+            .filter_map(|range| {
+                let native_begin = range.translate(cmp::max(begin, range.wasm_start));
+                let native_end = range.translate(cmp::min(end, range.wasm_end));
+                if native_begin < native_end {
+                    Some((
+                        Address::Constant(native_begin),
+                        Address::Constant(native_end),
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// A row of a line number program which applies to the code addresses `begin..end`.
+struct LineRow {
+    begin: u64,
+    end: u64,
+    file: u64,
+    line: u64,
+    column: u64,
+    is_statement: bool,
+}
+
+/// Build a line number program for the native code of the functions described by the line
+/// number program of `unit`.
+///
+/// Return the program and the ids of the files in it, indexed by their file index in `unit`.
+fn transform_line_program<'data>(
+    dwarf: &read::Dwarf<Reader<'data>>,
+    unit: &read::Unit<Reader<'data>>,
+    encoding: gimli::Encoding,
+    transform: &AddressTransform,
+) -> WasmResult<(write::LineProgram, Vec<Option<write::FileId>>)> {
+    let program = match unit.line_program {
+        Some(ref program) => program.clone(),
+        None => return Ok((write::LineProgram::none(), Vec::new())),
+    };
+    let header = program.header();
+    let line_string = |value| -> WasmResult<write::LineString> {
+        let name = dwarf.attr_string(unit, value).map_err(read_error)?;
+        Ok(write::LineString::String(name.slice().to_vec()))
+    };
+    let comp_dir = match header.directory(0) {
+        Some(dir) => line_string(dir)?,
+        None => write::LineString::String(b".".to_vec()),
+    };
+    let (comp_file, comp_file_info) = match header.file(0) {
+        Some(file) => (
+            line_string(file.path_name())?,
+            Some(write::FileInfo {
+                timestamp: file.timestamp(),
+                size: file.size(),
+                md5: *file.md5(),
+            }),
+        ),
+        None => (write::LineString::String(b"<unknown>".to_vec()), None),
+    };
+    let mut out = write::LineProgram::new(
+        encoding,
+        header.line_encoding(),
+        comp_dir,
+        comp_file,
+        comp_file_info,
+    );
+
+    // Directory and file indices are 1-based before DWARF 5, with an implicit entry 0 for the
+    // compilation directory and no file 0.
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    if header.version() <= 4 {
+        dirs.push(out.default_directory());
+        files.push(None);
+    }
+    for dir in header.include_directories() {
+        let dir = line_string(*dir)?;
+        dirs.push(out.add_directory(dir));
+    }
+    for file in header.file_names() {
+        let dir = match dirs.get(file.directory_index() as usize) {
+            Some(&dir) => dir,
+            None => out.default_directory(),
+        };
+        let info = write::FileInfo {
+            timestamp: file.timestamp(),
+            size: file.size(),
+            md5: *file.md5(),
+        };
+        files.push(Some(out.add_file(
+            line_string(file.path_name())?,
+            dir,
+            Some(info),
+        )));
+    }
+
+    // Collect the rows, along with the range of code addresses each of them applies to.
+    let mut rows = Vec::new();
+    let mut sequence_start = rows.len();
+    let mut program_rows = program.rows();
+    while let Some((_, row)) = program_rows.next_row().map_err(read_error)? {
+        let address = row.address();
+        if rows.len() > sequence_start {
+            let previous: &mut LineRow = rows.last_mut().unwrap();
+            previous.end = address;
+        }
+        if row.end_sequence() {
+            sequence_start = rows.len();
+            continue;
+        }
+        rows.push(LineRow {
+            begin: address,
+            end: address,
+            file: row.file_index(),
+            line: row.line().unwrap_or(0),
+            column: match row.column() {
+                read::ColumnType::LeftEdge => 0,
+                read::ColumnType::Column(column) => column,
+            },
+            is_statement: row.is_stmt(),
+        });
+    }
+    rows.sort_by_key(|row| row.begin);
+    let find_row = |addr: u64| -> Option<&LineRow> {
+        let index = match rows.binary_search_by_key(&addr, |row| row.begin) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let row = &rows[index];
+        if addr < row.end {
+            Some(row)
+        } else {
+            None
+        }
+    };
+
+    // Generate a sequence for every function with rows in this unit.
+    for function in &transform.functions {
+        let mut in_sequence = false;
+        let mut previous = None;
+        for inst in &function.map.instructions {
+            let addr =
+                match u64::from(inst.srcloc.bits()).checked_sub(transform.code_section_offset) {
+                    Some(addr) => addr,
+                    None => continue,
+                };
+            let row = match find_row(addr) {
+                Some(row) => row,
+                None => continue,
+            };
+            let file = match files.get(row.file as usize) {
+                Some(&Some(file)) => file,
+                _ => continue,
+            };
+            let key = (file, row.line, row.column);
+            if previous == Some(key) {
+                continue;
+            }
+            previous = Some(key);
+            if !in_sequence {
+                out.begin_sequence(Some(Address::Constant(function.native_start)));
+                in_sequence = true;
+            }
+            let out_row = out.row();
+            out_row.address_offset = inst.code_offset as u64;
+            out_row.file = file;
+            out_row.line = row.line;
+            out_row.column = row.column;
+            out_row.is_statement = row.is_statement;
+            out.generate_row();
+        }
+        if in_sequence {
+            out.end_sequence(function.map.code_len as u64);
+        }
+    }
+
+    Ok((out, files))
+}
+
+/// State for converting the attributes of a unit.
+struct UnitContext<'a, 'data> {
+    dwarf: &'a read::Dwarf<Reader<'data>>,
+    unit: &'a read::Unit<Reader<'data>>,
+    transform: &'a AddressTransform<'a>,
+    entry_ids: &'a HashMap<UnitSectionOffset, (write::UnitId, write::UnitEntryId)>,
+    files: &'a [Option<write::FileId>],
+    strings: &'a mut write::StringTable,
+}
+
+impl<'a, 'data> UnitContext<'a, 'data> {
+    /// Get the code address of the `DW_AT_low_pc` attribute of `entry`.
+    fn low_pc(
+        &self,
+        entry: &read::DebuggingInformationEntry<Reader<'data>>,
+    ) -> WasmResult<Option<u64>> {
+        entry_low_pc(self.dwarf, self.unit, entry)
+    }
+
+    /// Get the code address of an address attribute value.
+    fn address(&self, value: ReadValue<Reader<'data>>) -> WasmResult<Option<u64>> {
+        attr_address(self.dwarf, self.unit, value)
+    }
+
+    /// Does `entry` describe code with `DW_AT_low_pc` or `DW_AT_ranges`?
+    fn has_code(&self, entry: &read::DebuggingInformationEntry<Reader<'data>>) -> WasmResult<bool> {
+        Ok(entry
+            .attr(constants::DW_AT_low_pc)
+            .map_err(read_error)?
+            .is_some()
+            || entry
+                .attr(constants::DW_AT_ranges)
+                .map_err(read_error)?
+                .is_some())
+    }
+
+    /// Build the range list of the native code of the unit.
+    fn unit_ranges(&self, out_unit: &mut write::Unit) -> WasmResult<WriteValue> {
+        let mut ranges = self.dwarf.unit_ranges(self.unit).map_err(read_error)?;
+        let mut list = Vec::new();
+        while let Some(range) = ranges.next().map_err(read_error)? {
+            for (begin, end) in self.transform.translate_ranges(range.begin, range.end) {
+                list.push(write::Range::StartEnd { begin, end });
+            }
+        }
+        Ok(WriteValue::RangeListRef(
+            out_unit.ranges.add(write::RangeList(list)),
+        ))
+    }
+
+    /// Convert an attribute of an entry whose `DW_AT_low_pc` is `low_pc`.
+    ///
+    /// Return `None` for attributes which can't be converted.
+    fn convert_attribute(
+        &mut self,
+        attr: &read::Attribute<Reader<'data>>,
+        low_pc: Option<u64>,
+        out_unit: &mut write::Unit,
+    ) -> WasmResult<Option<WriteValue>> {
+        let value = attr.value();
+
+        // `DW_AT_high_pc` may be the size of the code rather than an address. Either way, it is
+        // translated within the function containing `DW_AT_low_pc`, since it may also be the
+        // start of the next function.
+        if attr.name() == constants::DW_AT_high_pc {
+            let size = attr.udata_value();
+            let high_pc = match size {
+                Some(size) => low_pc.map(|low_pc| low_pc + size),
+                None => self.address(value)?,
+            };
+            let (low_pc, high_pc) = match (low_pc, high_pc) {
+                (Some(low_pc), Some(high_pc)) => (low_pc, high_pc),
+                _ => return Ok(None),
+            };
+            let range = match self.transform.function_from(low_pc) {
+                Some(range) => range,
+                None => return Ok(None),
+            };
+            let native_low = range.translate(low_pc);
+            let native_high = cmp::max(
+                native_low,
+                range.translate(cmp::min(high_pc, range.wasm_end)),
+            );
+            return Ok(Some(match size {
+                Some(_) => WriteValue::Udata(native_high - native_low),
+                None => WriteValue::Address(Address::Constant(native_high)),
+            }));
+        }
+
+        Ok(Some(match value {
+            ReadValue::Addr(_) | ReadValue::DebugAddrIndex(_) => {
+                // Addresses outside the compiled functions are dropped.
+                let addr = self.address(value)?.expect("address attribute");
+                match self.transform.translate(addr) {
+                    Some(addr) => WriteValue::Address(Address::Constant(addr)),
+                    None => return Ok(None),
+                }
+            }
+            ReadValue::Block(data) => WriteValue::Block(data.slice().to_vec()),
+            ReadValue::Data1(data) => WriteValue::Data1(data),
+            ReadValue::Data2(data) => WriteValue::Data2(data),
+            ReadValue::Data4(data) => WriteValue::Data4(data),
+            ReadValue::Data8(data) => WriteValue::Data8(data),
+            ReadValue::Sdata(data) => WriteValue::Sdata(data),
+            ReadValue::Udata(data) => WriteValue::Udata(data),
+            ReadValue::Flag(flag) => WriteValue::Flag(flag),
+            ReadValue::Exprloc(expr) => {
+                WriteValue::Exprloc(write::Expression::raw(expr.0.slice().to_vec()))
+            }
+            ReadValue::UnitRef(offset) => {
+                match self
+                    .entry_ids
+                    .get(&offset.to_unit_section_offset(self.unit))
+                {
+                    Some(&(_, id)) => WriteValue::UnitRef(id),
+                    None => return Ok(None),
+                }
+            }
+            ReadValue::DebugInfoRef(offset) => {
+                match self
+                    .entry_ids
+                    .get(&UnitSectionOffset::DebugInfoOffset(offset))
+                {
+                    Some(&(unit_id, id)) => {
+                        WriteValue::DebugInfoRef(write::Reference::Entry(unit_id, id))
+                    }
+                    None => return Ok(None),
+                }
+            }
+            ReadValue::DebugLineRef(_) => WriteValue::LineProgramRef,
+            ReadValue::RangeListsRef(_) | ReadValue::DebugRngListsIndex(_) => {
+                let mut ranges = match self
+                    .dwarf
+                    .attr_ranges(self.unit, value)
+                    .map_err(read_error)?
+                {
+                    Some(ranges) => ranges,
+                    None => return Ok(None),
+                };
+                let mut list = Vec::new();
+                while let Some(range) = ranges.next().map_err(read_error)? {
+                    for (begin, end) in self.transform.translate_ranges(range.begin, range.end) {
+                        list.push(write::Range::StartEnd { begin, end });
+                    }
+                }
+                WriteValue::RangeListRef(out_unit.ranges.add(write::RangeList(list)))
+            }
+            ReadValue::LocationListsRef(_) | ReadValue::DebugLocListsIndex(_) => {
+                let mut locations = match self
+                    .dwarf
+                    .attr_locations(self.unit, value)
+                    .map_err(read_error)?
+                {
+                    Some(locations) => locations,
+                    None => return Ok(None),
+                };
+                let mut list = Vec::new();
+                while let Some(location) = locations.next().map_err(read_error)? {
+                    let range = location.range;
+                    for (begin, end) in self.transform.translate_ranges(range.begin, range.end) {
+                        list.push(write::Location::StartEnd {
+                            begin,
+                            end,
+                            data: write::Expression::raw(location.data.0.slice().to_vec()),
+                        });
+                    }
+                }
+                WriteValue::LocationListRef(out_unit.locations.add(write::LocationList(list)))
+            }
+            ReadValue::String(_)
+            | ReadValue::DebugStrRef(_)
+            | ReadValue::DebugStrOffsetsIndex(_)
+            | ReadValue::DebugLineStrRef(_) => {
+                let string = self
+                    .dwarf
+                    .attr_string(self.unit, value)
+                    .map_err(read_error)?;
+                WriteValue::StringRef(self.strings.add(string.slice()))
+            }
+            ReadValue::FileIndex(index) => match self.files.get(index as usize) {
+                Some(&Some(file)) => WriteValue::FileIndex(Some(file)),
+                _ => return Ok(None),
+            },
+            ReadValue::Encoding(value) => WriteValue::Encoding(value),
+            ReadValue::DecimalSign(value) => WriteValue::DecimalSign(value),
+            ReadValue::Endianity(value) => WriteValue::Endianity(value),
+            ReadValue::Accessibility(value) => WriteValue::Accessibility(value),
+            ReadValue::Visibility(value) => WriteValue::Visibility(value),
+            ReadValue::Virtuality(value) => WriteValue::Virtuality(value),
+            ReadValue::Language(value) => WriteValue::Language(value),
+            ReadValue::AddressClass(value) => WriteValue::AddressClass(value),
+            ReadValue::IdentifierCase(value) => WriteValue::IdentifierCase(value),
+            ReadValue::CallingConvention(value) => WriteValue::CallingConvention(value),
+            ReadValue::Inline(value) => WriteValue::Inline(value),
+            ReadValue::Ordering(value) => WriteValue::Ordering(value),
+            // Section offsets and bases, type signatures and macro information are dropped.
+            _ => return Ok(None),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cranelift_codegen::isa;
+    use cranelift_codegen::settings;
+    use std::str::FromStr;
+    use std::string::String;
+    use target_lexicon::triple;
+
+    type Sections = HashMap<&'static str, Vec<u8>>;
+
+    /// Build the DWARF sections of a module with a single compilation unit containing the
+    /// function bodies at the code address ranges `functions`, with a line row at every 4 bytes.
+    fn wasm_dwarf(functions: &[(&str, u64, u64)]) -> Sections {
+        let encoding = gimli::Encoding {
+            format: gimli::Format::Dwarf32,
+            version: 4,
+            address_size: 4,
+        };
+        let mut program = write::LineProgram::new(
+            encoding,
+            gimli::LineEncoding::default(),
+            write::LineString::String(b"/src".to_vec()),
+            write::LineString::String(b"a.c".to_vec()),
+            None,
+        );
+        let dir = program.default_directory();
+        let file = program.add_file(write::LineString::String(b"a.c".to_vec()), dir, None);
+        let mut line = 1;
+        for &(_, begin, end) in functions {
+            program.begin_sequence(Some(Address::Constant(begin)));
+            for address_offset in (0..end - begin).step_by(4) {
+                let row = program.row();
+                row.address_offset = address_offset;
+                row.file = file;
+                row.line = line;
+                program.generate_row();
+                line += 1;
+            }
+            program.end_sequence(end - begin);
+        }
+
+        let mut dwarf = write::Dwarf::new();
+        let unit_id = dwarf.units.add(write::Unit::new(encoding, program));
+        let unit = dwarf.units.get_mut(unit_id);
+        let root = unit.root();
+        let unit_begin = functions[0].1;
+        let unit_end = functions[functions.len() - 1].2;
+        let entry = unit.get_mut(root);
+        entry.set(
+            constants::DW_AT_low_pc,
+            WriteValue::Address(Address::Constant(unit_begin)),
+        );
+        entry.set(
+            constants::DW_AT_high_pc,
+            WriteValue::Udata(unit_end - unit_begin),
+        );
+        entry.set(constants::DW_AT_stmt_list, WriteValue::LineProgramRef);
+        for &(name, begin, end) in functions {
+            let subprogram = unit.add(root, constants::DW_TAG_subprogram);
+            let entry = unit.get_mut(subprogram);
+            entry.set(
+                constants::DW_AT_low_pc,
+                WriteValue::Address(Address::Constant(begin)),
+            );
+            entry.set(constants::DW_AT_high_pc, WriteValue::Udata(end - begin));
+            entry.set(
+                constants::DW_AT_name,
+                WriteValue::String(name.as_bytes().to_vec()),
+            );
+        }
+
+        let mut sections = write::Sections::new(write::EndianVec::new(LittleEndian));
+        dwarf.write(&mut sections).unwrap();
+        let mut result = HashMap::new();
+        sections
+            .for_each(|id, data| -> Result<(), ()> {
+                if !data.slice().is_empty() {
+                    result.insert(id.name(), data.slice().to_vec());
+                }
+                Ok(())
+            })
+            .unwrap();
+        result
+    }
+
+    /// Transform the sections built by `wasm_dwarf` for a code section at module offset `0x20`.
+    fn transform(
+        wasm_sections: &Sections,
+        functions: &[(u64, &FunctionAddressMap)],
+    ) -> WasmResult<Sections> {
+        let mut debug_sections = DebugSections::new();
+        for (name, data) in wasm_sections {
+            assert!(debug_sections.declare_section(name, data));
+        }
+        debug_sections.set_code_section_offset(0x20);
+        let isa = isa::lookup(triple!("riscv64"))
+            .unwrap()
+            .finish(settings::Flags::new(settings::builder()));
+        Ok(transform_dwarf(&*isa, &debug_sections, functions)?
+            .into_iter()
+            .collect())
+    }
+
+    fn load(sections: &Sections) -> read::Dwarf<Reader> {
+        let section = |id: gimli::SectionId| -> Result<_, read::Error> {
+            let data = sections.get(id.name()).map_or(&[][..], |data| &data[..]);
+            Ok(EndianSlice::new(data, LittleEndian))
+        };
+        read::Dwarf::load(section, |_| Ok(EndianSlice::new(&[], LittleEndian))).unwrap()
+    }
+
+    fn inst(srcloc: u32, code_offset: usize, code_len: usize) -> InstructionAddressMap {
+        InstructionAddressMap {
+            srcloc: ir::SourceLoc::new(srcloc),
+            code_offset,
+            code_len,
+        }
+    }
+
+    /// Get the line rows of `unit` as `(address, line, end_sequence)`.
+    fn line_rows(unit: &read::Unit<Reader>) -> Vec<(u64, Option<u64>, bool)> {
+        let mut rows = Vec::new();
+        let mut program = unit.line_program.clone().unwrap().rows();
+        while let Some((_, row)) = program.next_row().unwrap() {
+            rows.push((row.address(), row.line(), row.end_sequence()));
+        }
+        rows
+    }
+
+    /// Get the name, low PC and size of the subprograms of `unit`.
+    fn subprograms(
+        dwarf: &read::Dwarf<Reader>,
+        unit: &read::Unit<Reader>,
+    ) -> Vec<(String, u64, u64)> {
+        let mut entries = unit.entries();
+        let mut result = Vec::new();
+        while let Some((_, entry)) = entries.next_dfs().unwrap() {
+            if entry.tag() != constants::DW_TAG_subprogram {
+                continue;
+            }
+            let name = entry.attr_value(constants::DW_AT_name).unwrap().unwrap();
+            let name = dwarf.attr_string(unit, name).unwrap();
+            let low_pc = match entry.attr_value(constants::DW_AT_low_pc).unwrap() {
+                Some(ReadValue::Addr(addr)) => addr,
+                value => panic!("unexpected low PC: {:?}", value),
+            };
+            let size = entry
+                .attr(constants::DW_AT_high_pc)
+                .unwrap()
+                .unwrap()
+                .udata_value()
+                .unwrap();
+            result.push((
+                String::from_utf8_lossy(name.slice()).into_owned(),
+                low_pc,
+                size,
+            ));
+        }
+        result
+    }
+
+    /// Get the native address ranges of `unit`, and check that its base address is 0.
+    fn unit_ranges(dwarf: &read::Dwarf<Reader>, unit: &read::Unit<Reader>) -> Vec<(u64, u64)> {
+        let mut entries = unit.entries();
+        let (_, root) = entries.next_dfs().unwrap().unwrap();
+        assert_eq!(
+            root.attr_value(constants::DW_AT_low_pc).unwrap(),
+            Some(ReadValue::Addr(0))
+        );
+        assert!(root.attr(constants::DW_AT_high_pc).unwrap().is_none());
+        let mut ranges = dwarf.unit_ranges(unit).unwrap();
+        let mut result = Vec::new();
+        while let Some(range) = ranges.next().unwrap() {
+            result.push((range.begin, range.end));
+        }
+        result
+    }
+
+    #[test]
+    fn line_rows_and_ranges() {
+        let wasm_sections = wasm_dwarf(&[("f", 0x10, 0x20)]);
+        let mut debug_sections = DebugSections::new();
+        assert!(!debug_sections.declare_section("name", &[]));
+
+        // The function body is at module offsets `0x30..0x40`. The second and third
+        // instructions are on the same line.
+        let map = FunctionAddressMap {
+            instructions: vec![
+                inst(0x30, 0, 4),
+                inst(0x34, 4, 6),
+                inst(0x36, 10, 2),
+                inst(0x38, 12, 8),
+            ],
+            start_srcloc: ir::SourceLoc::new(0x30),
+            end_srcloc: ir::SourceLoc::new(0x40),
+            code_len: 20,
+        };
+        let native_sections = transform(&wasm_sections, &[(0x1000, &map)]).unwrap();
+        let dwarf = load(&native_sections);
+        let header = dwarf.units().next().unwrap().unwrap();
+        let unit = dwarf.unit(header).unwrap();
+        assert_eq!(unit.encoding().address_size, 8);
+
+        assert_eq!(
+            line_rows(&unit),
+            [
+                (0x1000, Some(1), false),
+                (0x1004, Some(2), false),
+                (0x100c, Some(3), false),
+                (0x1014, Some(3), true),
+            ]
+        );
+        assert_eq!(unit_ranges(&dwarf, &unit), [(0x1000, 0x1014)]);
+        assert_eq!(
+            subprograms(&dwarf, &unit),
+            [(String::from("f"), 0x1000, 20)]
+        );
+    }
+
+    #[test]
+    fn multiple_functions() {
+        // Three adjacent functions, `f`, `g` and `h`, the last of which isn't compiled. The
+        // native code of `g` precedes the native code of `f`.
+        let wasm_sections = wasm_dwarf(&[("f", 0x10, 0x18), ("g", 0x18, 0x20), ("h", 0x20, 0x28)]);
+        let f = FunctionAddressMap {
+            instructions: vec![inst(0x30, 0, 4), inst(0x34, 4, 8)],
+            start_srcloc: ir::SourceLoc::new(0x30),
+            end_srcloc: ir::SourceLoc::new(0x38),
+            code_len: 12,
+        };
+        let g = FunctionAddressMap {
+            instructions: vec![inst(0x38, 0, 2), inst(0x3c, 2, 2)],
+            start_srcloc: ir::SourceLoc::new(0x38),
+            end_srcloc: ir::SourceLoc::new(0x40),
+            code_len: 4,
+        };
+        let native_sections = transform(&wasm_sections, &[(0x2000, &f), (0x1000, &g)]).unwrap();
+        let dwarf = load(&native_sections);
+        let header = dwarf.units().next().unwrap().unwrap();
+        let unit = dwarf.unit(header).unwrap();
+
+        assert_eq!(
+            line_rows(&unit),
+            [
+                (0x2000, Some(1), false),
+                (0x2004, Some(2), false),
+                (0x200c, Some(2), true),
+                (0x1000, Some(3), false),
+                (0x1002, Some(4), false),
+                (0x1004, Some(4), true),
+            ]
+        );
+        assert_eq!(
+            unit_ranges(&dwarf, &unit),
+            [(0x2000, 0x200c), (0x1000, 0x1004)]
+        );
+        assert_eq!(
+            subprograms(&dwarf, &unit),
+            [
+                (String::from("f"), 0x2000, 12),
+                (String::from("g"), 0x1000, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn function_before_code_section() {
+        let wasm_sections = wasm_dwarf(&[("f", 0x10, 0x20)]);
+        let map = FunctionAddressMap {
+            instructions: Vec::new(),
+            start_srcloc: ir::SourceLoc::new(0x10),
+            end_srcloc: ir::SourceLoc::new(0x20),
+            code_len: 0,
+        };
+        match transform(&wasm_sections, &[(0x1000, &map)]) {
+            Err(WasmError::InvalidDebugInfo(_)) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+}
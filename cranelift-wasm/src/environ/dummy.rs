@@ -5,29 +5,52 @@
 //! [wasmtime-environ]: https://crates.io/crates/wasmtime-environ
 //! [Wasmtime]: https://github.com/CraneStation/wasmtime
 
-use crate::environ::{FuncEnvironment, GlobalVariable, ModuleEnvironment, ReturnMode, WasmResult};
+use crate::environ::{
+    FuncEnvironment, GlobalVariable, ModuleEnvironment, ReturnMode, TargetEnvironment, WasmResult,
+};
 use crate::func_translator::FuncTranslator;
 use crate::translation_utils::{
-    DefinedFuncIndex, FuncIndex, Global, GlobalIndex, Memory, MemoryIndex, SignatureIndex, Table,
-    TableIndex,
+    DataIndex, DefinedFuncIndex, ElemIndex, FuncIndex, Global, GlobalIndex, Memory, MemoryIndex,
+    SignatureIndex, Table, TableIndex,
 };
+#[cfg(feature = "std")]
+use crate::ConcurrentCompiler;
+use crate::HashMap;
 use cast;
+use core::mem;
 use cranelift_codegen::cursor::FuncCursor;
-use cranelift_codegen::ir::immediates::{Offset32, Uimm64};
+use cranelift_codegen::ir::immediates::{Imm64, Offset32, Uimm64};
 use cranelift_codegen::ir::types::*;
 use cranelift_codegen::ir::{self, InstBuilder};
 use cranelift_codegen::isa::TargetFrontendConfig;
-use cranelift_entity::{EntityRef, PrimaryMap};
+use cranelift_entity::{EntityRef, PrimaryMap, SecondaryMap};
 use std::boxed::Box;
-use std::string::String;
+use std::string::{String, ToString};
 use std::vec::Vec;
 
+/// The symbol of the fuel counter.
+const FUEL_NAME: &str = "fuel";
+
+/// The function called when a function runs out of fuel.
+const OUT_OF_FUEL_NAME: &str = "out_of_fuel";
+
 /// Compute a `ir::ExternalName` for a given wasm function index.
-fn get_func_name(func_index: FuncIndex) -> ir::ExternalName {
+///
+/// Functions named by the "name" section get a test case name, which is truncated to 16 bytes.
+/// Other functions get a user name in namespace 0, as do the functions whose test case name
+/// would be the same as another one's.
+fn get_func_name(info: &DummyModuleInfo, func_index: FuncIndex) -> ir::ExternalName {
+    if let Some(name) = info.function_names.get(func_index) {
+        let name = ir::ExternalName::testcase(name);
+        if info.testcase_names.get(&name.to_string()) == Some(&func_index) {
+            return name;
+        }
+    }
     ir::ExternalName::user(0, func_index.as_u32())
 }
 
 /// A collection of names under which a given entity is exported.
+#[derive(Clone)]
 pub struct Exportable<T> {
     /// A wasm entity.
     pub entity: T,
@@ -48,6 +71,7 @@ impl<T> Exportable<T> {
 /// The main state belonging to a `DummyEnvironment`. This is split out from
 /// `DummyEnvironment` to allow it to be borrowed separately from the
 /// `FuncTranslator` field.
+#[derive(Clone)]
 pub struct DummyModuleInfo {
     /// Target description relevant to frontends producing Cranelift IR.
     config: TargetFrontendConfig,
@@ -84,6 +108,15 @@ pub struct DummyModuleInfo {
 
     /// The start function.
     pub start_func: Option<FuncIndex>,
+
+    /// Function names as provided by `declare_func_name`.
+    pub function_names: SecondaryMap<FuncIndex, String>,
+
+    /// Local names of each function as provided by `declare_local_name`.
+    pub local_names: SecondaryMap<FuncIndex, HashMap<u32, String>>,
+
+    /// The functions which have a test case name, indexed by the name as displayed.
+    testcase_names: HashMap<String, FuncIndex>,
 }
 
 impl DummyModuleInfo {
@@ -102,6 +135,23 @@ impl DummyModuleInfo {
             memories: PrimaryMap::new(),
             globals: PrimaryMap::new(),
             start_func: None,
+            function_names: SecondaryMap::new(),
+            local_names: SecondaryMap::new(),
+            testcase_names: HashMap::new(),
+        }
+    }
+
+    /// Get the index of the function with the name `name`, as given by `get_func_name`.
+    fn func_index_by_name(&self, name: &ir::ExternalName) -> Option<FuncIndex> {
+        match *name {
+            ir::ExternalName::User {
+                namespace: 0,
+                index,
+            } => Some(FuncIndex::from_u32(index)),
+            ir::ExternalName::TestCase { .. } => {
+                self.testcase_names.get(&name.to_string()).cloned()
+            }
+            _ => None,
         }
     }
 }
@@ -121,6 +171,20 @@ pub struct DummyEnvironment {
 
     /// How to return from functions.
     return_mode: ReturnMode,
+
+    /// The translated functions referring to each function by name: the index of the function
+    /// body, and the function reference, or `None` for the name of the body itself.
+    func_name_refs: SecondaryMap<FuncIndex, Vec<(DefinedFuncIndex, Option<ir::FuncRef>)>>,
+
+    /// Number of worker threads translating function bodies, or 0 to translate them on the
+    /// current thread.
+    #[cfg(feature = "std")]
+    translation_threads: usize,
+
+    /// The compiler translating the function bodies of the code section, when using worker
+    /// threads.
+    #[cfg(feature = "std")]
+    compiler: Option<ConcurrentCompiler<ir::Function>>,
 }
 
 impl DummyEnvironment {
@@ -131,9 +195,24 @@ impl DummyEnvironment {
             trans: FuncTranslator::new(),
             func_bytecode_sizes: Vec::new(),
             return_mode,
+            func_name_refs: SecondaryMap::new(),
+            #[cfg(feature = "std")]
+            translation_threads: 0,
+            #[cfg(feature = "std")]
+            compiler: None,
         }
     }
 
+    /// Translate function bodies on `num_threads` worker threads with a `ConcurrentCompiler`,
+    /// or on the current thread if `num_threads` is 0, which is the default.
+    ///
+    /// The translated functions are the same either way, and are available once the code section
+    /// has been translated.
+    #[cfg(feature = "std")]
+    pub fn set_translation_threads(&mut self, num_threads: usize) {
+        self.translation_threads = num_threads;
+    }
+
     /// Return a `DummyFuncEnvironment` for translating functions within this
     /// `DummyEnvironment`.
     pub fn func_env(&self) -> DummyFuncEnvironment {
@@ -144,10 +223,33 @@ impl DummyEnvironment {
         self.info.functions[func_index].entity
     }
 
+    /// Record the names in the translated function body `def_index`, so that `declare_func_name`
+    /// can rename the function and its references without scanning all the bodies.
+    fn index_func_names(&mut self, def_index: DefinedFuncIndex) {
+        let func_index = FuncIndex::new(self.get_num_func_imports() + def_index.index());
+        self.func_name_refs[func_index].push((def_index, None));
+        let func = &self.info.function_bodies[def_index];
+        for (func_ref, ext_func) in func.dfg.ext_funcs.iter() {
+            if let Some(callee) = self.info.func_index_by_name(&ext_func.name) {
+                self.func_name_refs[callee].push((def_index, Some(func_ref)));
+            }
+        }
+    }
+
     /// Return the number of imported functions within this `DummyEnvironment`.
     pub fn get_num_func_imports(&self) -> usize {
         self.info.imported_funcs.len()
     }
+
+    /// Return the name of the function, if a name for the function with
+    /// the corresponding index exists.
+    pub fn get_func_name(&self, func_index: FuncIndex) -> Option<&str> {
+        self.info
+            .function_names
+            .get(func_index)
+            .map(String::as_str)
+            .filter(|name| !name.is_empty())
+    }
 }
 
 /// The `FuncEnvironment` implementation for use by the `DummyEnvironment`.
@@ -177,11 +279,13 @@ impl<'dummy_environment> DummyFuncEnvironment<'dummy_environment> {
     }
 }
 
-impl<'dummy_environment> FuncEnvironment for DummyFuncEnvironment<'dummy_environment> {
+impl<'dummy_environment> TargetEnvironment for DummyFuncEnvironment<'dummy_environment> {
     fn target_config(&self) -> TargetFrontendConfig {
         self.mod_info.config
     }
+}
 
+impl<'dummy_environment> FuncEnvironment for DummyFuncEnvironment<'dummy_environment> {
     fn make_global(&mut self, func: &mut ir::Function, index: GlobalIndex) -> GlobalVariable {
         // Just create a dummy `vmctx` global.
         let offset = cast::i32((index.index() * 8) + 8).unwrap().into();
@@ -250,7 +354,7 @@ impl<'dummy_environment> FuncEnvironment for DummyFuncEnvironment<'dummy_environ
         // A real implementation would probably add a `vmctx` argument.
         // And maybe attempt some signature de-duplication.
         let signature = func.import_signature(self.vmctx_sig(sigidx));
-        let name = get_func_name(index);
+        let name = get_func_name(self.mod_info, index);
         func.import_function(ir::ExtFuncData {
             name,
             signature,
@@ -341,16 +445,187 @@ impl<'dummy_environment> FuncEnvironment for DummyFuncEnvironment<'dummy_environ
         Ok(pos.ins().iconst(I32, -1))
     }
 
+    fn translate_table_get(
+        &mut self,
+        mut pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _index: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        Ok(pos.ins().iconst(self.reference_type(), 0))
+    }
+
+    fn translate_table_set(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _index: ir::Value,
+        _value: ir::Value,
+    ) -> WasmResult<()> {
+        Ok(())
+    }
+
+    fn translate_table_grow(
+        &mut self,
+        mut pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _delta: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        Ok(pos.ins().iconst(I32, -1))
+    }
+
+    fn translate_table_size(
+        &mut self,
+        mut pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+    ) -> WasmResult<ir::Value> {
+        Ok(pos.ins().iconst(I32, -1))
+    }
+
+    fn translate_memory_copy(
+        &mut self,
+        _pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _dst: ir::Value,
+        _src: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Ok(())
+    }
+
+    fn translate_memory_fill(
+        &mut self,
+        _pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _dst: ir::Value,
+        _val: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Ok(())
+    }
+
+    fn translate_memory_init(
+        &mut self,
+        _pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _seg_index: DataIndex,
+        _dst: ir::Value,
+        _src: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Ok(())
+    }
+
+    fn translate_data_drop(&mut self, _pos: FuncCursor, _seg_index: DataIndex) -> WasmResult<()> {
+        Ok(())
+    }
+
+    fn translate_table_copy(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _dst: ir::Value,
+        _src: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Ok(())
+    }
+
+    fn translate_table_init(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _seg_index: ElemIndex,
+        _dst: ir::Value,
+        _src: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Ok(())
+    }
+
+    fn translate_elem_drop(&mut self, _pos: FuncCursor, _seg_index: ElemIndex) -> WasmResult<()> {
+        Ok(())
+    }
+
+    fn translate_atomic_wait(
+        &mut self,
+        mut pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _addr: ir::Value,
+        _expected: ir::Value,
+        _timeout: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        Ok(pos.ins().iconst(I32, 1))
+    }
+
+    fn translate_atomic_notify(
+        &mut self,
+        mut pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _addr: ir::Value,
+        _count: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        Ok(pos.ins().iconst(I32, 0))
+    }
+
+    fn make_fuel_counter(
+        &mut self,
+        func: &mut ir::Function,
+    ) -> WasmResult<(ir::GlobalValue, Offset32)> {
+        // The fuel counter is a separate `fuel` symbol, so it doesn't overlap the globals in
+        // `vmctx`.
+        let gv = func.create_global_value(ir::GlobalValueData::Symbol {
+            name: ir::ExternalName::testcase(FUEL_NAME),
+            offset: Imm64::new(0),
+            colocated: false,
+            tls: false,
+        });
+        Ok((gv, Offset32::new(0)))
+    }
+
+    fn translate_out_of_fuel(&mut self, mut pos: FuncCursor) -> WasmResult<()> {
+        // Call a handler which takes the current function's vmctx parameter.
+        let vmctx = pos
+            .func
+            .special_param(ir::ArgumentPurpose::VMContext)
+            .expect("Missing vmctx parameter");
+        let mut sig = ir::Signature::new(self.target_config().default_call_conv);
+        sig.params.push(ir::AbiParam::special(
+            self.pointer_type(),
+            ir::ArgumentPurpose::VMContext,
+        ));
+        let signature = pos.func.import_signature(sig);
+        let handler = pos.func.import_function(ir::ExtFuncData {
+            name: ir::ExternalName::testcase(OUT_OF_FUEL_NAME),
+            signature,
+            colocated: false,
+        });
+        pos.ins().call(handler, &[vmctx]);
+        Ok(())
+    }
+
     fn return_mode(&self) -> ReturnMode {
         self.return_mode
     }
 }
 
-impl<'data> ModuleEnvironment<'data> for DummyEnvironment {
+impl TargetEnvironment for DummyEnvironment {
     fn target_config(&self) -> TargetFrontendConfig {
         self.info.config
     }
+}
 
+impl<'data> ModuleEnvironment<'data> for DummyEnvironment {
     fn declare_signature(&mut self, sig: ir::Signature) {
         self.info.signatures.push(sig);
     }
@@ -408,6 +683,10 @@ impl<'data> ModuleEnvironment<'data> for DummyEnvironment {
         // We do nothing
     }
 
+    fn declare_passive_element(&mut self, _elem_index: ElemIndex, _elements: Box<[FuncIndex]>) {
+        // We do nothing
+    }
+
     fn declare_memory(&mut self, memory: Memory) {
         self.info.memories.push(Exportable::new(memory));
     }
@@ -429,6 +708,10 @@ impl<'data> ModuleEnvironment<'data> for DummyEnvironment {
         // We do nothing
     }
 
+    fn declare_passive_data(&mut self, _data_index: DataIndex, _data: &'data [u8]) {
+        // We do nothing
+    }
+
     fn declare_func_export(&mut self, func_index: FuncIndex, name: &'data str) {
         self.info.functions[func_index]
             .export_names
@@ -458,16 +741,46 @@ impl<'data> ModuleEnvironment<'data> for DummyEnvironment {
         self.info.start_func = Some(func_index);
     }
 
+    #[cfg(feature = "std")]
+    fn reserve_function_bodies(&mut self, _bodies: u32, _code_section_offset: u64) {
+        if self.translation_threads == 0 {
+            return;
+        }
+        // The workers translate with a copy of the module information, which is complete up to
+        // the function bodies.
+        let info = self.info.clone();
+        let return_mode = self.return_mode;
+        self.compiler = Some(ConcurrentCompiler::with_threads(
+            self.translation_threads,
+            move |trans, ctx, def_index, body_bytes, body_offset| {
+                let mut func_environ = DummyFuncEnvironment::new(&info, return_mode);
+                let func_index = FuncIndex::new(info.imported_funcs.len() + def_index.index());
+                ctx.func.name = get_func_name(&info, func_index);
+                ctx.func.signature = func_environ.vmctx_sig(info.functions[func_index].entity);
+                trans.translate(body_bytes, body_offset, &mut ctx.func, &mut func_environ)?;
+                Ok(mem::replace(&mut ctx.func, ir::Function::new()))
+            },
+        ));
+    }
+
     fn define_function_body(
         &mut self,
         body_bytes: &'data [u8],
         body_offset: usize,
     ) -> WasmResult<()> {
+        #[cfg(feature = "std")]
+        {
+            if let Some(ref mut compiler) = self.compiler {
+                compiler.put(body_bytes, body_offset);
+                self.func_bytecode_sizes.push(body_bytes.len());
+                return Ok(());
+            }
+        }
         let func = {
             let mut func_environ = DummyFuncEnvironment::new(&self.info, self.return_mode);
             let func_index =
                 FuncIndex::new(self.get_num_func_imports() + self.info.function_bodies.len());
-            let name = get_func_name(func_index);
+            let name = get_func_name(&self.info, func_index);
             let sig = func_environ.vmctx_sig(self.get_func_type(func_index));
             let mut func = ir::Function::with_name_signature(name, sig);
             self.trans
@@ -475,7 +788,62 @@ impl<'data> ModuleEnvironment<'data> for DummyEnvironment {
             func
         };
         self.func_bytecode_sizes.push(body_bytes.len());
-        self.info.function_bodies.push(func);
+        let def_index = self.info.function_bodies.push(func);
+        self.index_func_names(def_index);
         Ok(())
     }
+
+    #[cfg(feature = "std")]
+    fn finish_function_bodies(&mut self) -> WasmResult<()> {
+        if let Some(compiler) = self.compiler.take() {
+            debug_assert!(self.info.function_bodies.is_empty());
+            self.info.function_bodies = compiler.finish()?;
+            for def_index in self.info.function_bodies.keys() {
+                self.index_func_names(def_index);
+            }
+        }
+        Ok(())
+    }
+
+    fn declare_func_name(&mut self, func_index: FuncIndex, name: &'data str) {
+        // The name section isn't validated, so ignore the names of functions that don't exist.
+        if func_index.index() >= self.info.functions.len() {
+            return;
+        }
+        let old_name = get_func_name(&self.info, func_index);
+        if let ir::ExternalName::TestCase { .. } = old_name {
+            self.info.testcase_names.remove(&old_name.to_string());
+        }
+        self.info.function_names[func_index] = String::from(name);
+
+        // Keep the test case names unique, including the ones of the fuel symbols.
+        let testcase_name = ir::ExternalName::testcase(name).to_string();
+        let reserved = [FUEL_NAME, OUT_OF_FUEL_NAME]
+            .iter()
+            .any(|&reserved| ir::ExternalName::testcase(reserved).to_string() == testcase_name);
+        if !name.is_empty() && !reserved && !self.info.testcase_names.contains_key(&testcase_name) {
+            self.info.testcase_names.insert(testcase_name, func_index);
+        }
+
+        // The name section usually follows the code section, so rename the functions that have
+        // already been translated, and their references to the named function.
+        let new_name = get_func_name(&self.info, func_index);
+        if new_name == old_name {
+            return;
+        }
+        for &(def_index, func_ref) in &self.func_name_refs[func_index] {
+            let func = &mut self.info.function_bodies[def_index];
+            match func_ref {
+                Some(func_ref) => func.dfg.ext_funcs[func_ref].name = new_name.clone(),
+                None => func.name = new_name.clone(),
+            }
+        }
+    }
+
+    fn declare_local_name(&mut self, func_index: FuncIndex, local_index: u32, name: &'data str) {
+        if func_index.index() >= self.info.functions.len() {
+            return;
+        }
+        self.info.local_names[func_index].insert(local_index, String::from(name));
+    }
 }
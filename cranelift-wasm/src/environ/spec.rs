@@ -7,15 +7,18 @@
 //! [Wasmtime]: https://github.com/CraneStation/wasmtime
 
 use crate::translation_utils::{
-    FuncIndex, Global, GlobalIndex, Memory, MemoryIndex, SignatureIndex, Table, TableIndex,
+    DataIndex, ElemIndex, FuncIndex, Global, GlobalIndex, Memory, MemoryIndex, SignatureIndex,
+    Table, TableIndex,
 };
 use core::convert::From;
 use cranelift_codegen::cursor::FuncCursor;
+use cranelift_codegen::ir::condcodes::IntCC;
 use cranelift_codegen::ir::immediates::Offset32;
 use cranelift_codegen::ir::{self, InstBuilder};
 use cranelift_codegen::isa::TargetFrontendConfig;
 use failure_derive::Fail;
 use std::boxed::Box;
+use std::string::String;
 use wasmparser::BinaryReaderError;
 
 /// The value of a WebAssembly global variable.
@@ -67,6 +70,10 @@ pub enum WasmError {
     /// [limits]: https://cranelift.readthedocs.io/en/latest/ir.html#implementation-limits
     #[fail(display = "Implementation limit exceeded")]
     ImplLimitExceeded,
+
+    /// The DWARF debug information of the module could not be translated.
+    #[fail(display = "Invalid DWARF debug information: {}", _0)]
+    InvalidDebugInfo(String),
 }
 
 impl From<BinaryReaderError> for WasmError {
@@ -89,12 +96,12 @@ pub enum ReturnMode {
     FallthroughReturn,
 }
 
-/// Environment affecting the translation of a single WebAssembly function.
+/// Environment describing the compilation target, shared by the module and function
+/// environments.
 ///
-/// A `FuncEnvironment` trait object is required to translate a WebAssembly function to Cranelift
-/// IR. The function environment provides information about the WebAssembly module as well as the
-/// runtime environment.
-pub trait FuncEnvironment {
+/// Both `FuncEnvironment` and `ModuleEnvironment` require this trait, so an implementation of
+/// either one provides `target_config` here instead of in the environment itself.
+pub trait TargetEnvironment {
     /// Get the information needed to produce Cranelift IR for the given target.
     fn target_config(&self) -> TargetFrontendConfig;
 
@@ -110,6 +117,20 @@ pub trait FuncEnvironment {
         self.target_config().pointer_bytes()
     }
 
+    /// Get the Cranelift type used to represent WebAssembly `anyref` and `anyfunc` values.
+    ///
+    /// By default references are native pointers.
+    fn reference_type(&self) -> ir::Type {
+        self.pointer_type()
+    }
+}
+
+/// Environment affecting the translation of a single WebAssembly function.
+///
+/// A `FuncEnvironment` trait object is required to translate a WebAssembly function to Cranelift
+/// IR. The function environment provides information about the WebAssembly module as well as the
+/// runtime environment.
+pub trait FuncEnvironment: TargetEnvironment {
     /// Set up the necessary preamble definitions in `func` to access the global variable
     /// identified by `index`.
     ///
@@ -222,6 +243,277 @@ pub trait FuncEnvironment {
         heap: ir::Heap,
     ) -> WasmResult<ir::Value>;
 
+    /// Translate a `ref.null` WebAssembly instruction.
+    ///
+    /// By default a null reference is the zero value of `reference_type`.
+    fn translate_ref_null(&mut self, mut pos: FuncCursor) -> WasmResult<ir::Value> {
+        Ok(pos.ins().iconst(self.reference_type(), 0))
+    }
+
+    /// Translate a `ref.is_null` WebAssembly instruction.
+    ///
+    /// Returns an `i32` which is 1 if `value` is a null reference and 0 otherwise. By default
+    /// this compares `value` against the zero value of `reference_type`.
+    fn translate_ref_is_null(
+        &mut self,
+        mut pos: FuncCursor,
+        value: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        let is_null = pos.ins().icmp_imm(IntCC::Equal, value, 0);
+        Ok(pos.ins().bint(ir::types::I32, is_null))
+    }
+
+    /// Translate a `table.get` WebAssembly instruction.
+    ///
+    /// The `table_index` provided identifies the table to read, and `table` is the table
+    /// reference returned by `make_table` for the same index.
+    ///
+    /// The `index` value is the `i32` index of the element to read. Returns the element as a
+    /// value of `reference_type`.
+    fn translate_table_get(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _index: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        Err(WasmError::Unsupported("table.get"))
+    }
+
+    /// Translate a `table.set` WebAssembly instruction.
+    ///
+    /// The `table_index` provided identifies the table to write, and `table` is the table
+    /// reference returned by `make_table` for the same index.
+    ///
+    /// The `index` value is the `i32` index of the element to write and `value` is the
+    /// reference to store.
+    fn translate_table_set(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _index: ir::Value,
+        _value: ir::Value,
+    ) -> WasmResult<()> {
+        Err(WasmError::Unsupported("table.set"))
+    }
+
+    /// Translate a `table.grow` WebAssembly instruction.
+    ///
+    /// The `table_index` provided identifies the table to grow, and `table` is the table
+    /// reference returned by `make_table` for the same index.
+    ///
+    /// The `delta` value is the `i32` number of elements to add, which are initialized to null
+    /// references. Returns the old number of elements, or -1 if the table could not be grown.
+    fn translate_table_grow(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _delta: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        Err(WasmError::Unsupported("table.grow"))
+    }
+
+    /// Translate a `table.size` WebAssembly instruction.
+    ///
+    /// The `table_index` provided identifies the table to query, and `table` is the table
+    /// reference returned by `make_table` for the same index.
+    ///
+    /// Returns the number of elements in the table as an `i32`.
+    fn translate_table_size(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+    ) -> WasmResult<ir::Value> {
+        Err(WasmError::Unsupported("table.size"))
+    }
+
+    /// Translate a `memory.copy` WebAssembly instruction.
+    ///
+    /// The `index` provided identifies the linear memory to copy within, and `heap` is the heap
+    /// reference returned by `make_heap` for the same index.
+    ///
+    /// The `dst`, `src` and `len` values are the `i32` destination address, source address and
+    /// number of bytes to copy. The source and destination ranges may overlap, and the
+    /// implementation must trap without copying anything if either range is out of bounds.
+    fn translate_memory_copy(
+        &mut self,
+        _pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _dst: ir::Value,
+        _src: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Err(WasmError::Unsupported("memory.copy"))
+    }
+
+    /// Translate a `memory.fill` WebAssembly instruction.
+    ///
+    /// The `index` provided identifies the linear memory to fill, and `heap` is the heap
+    /// reference returned by `make_heap` for the same index.
+    ///
+    /// The `dst`, `val` and `len` values are the `i32` destination address, the byte value to
+    /// store in its low 8 bits and the number of bytes to fill. The implementation must trap
+    /// without storing anything if the destination range is out of bounds.
+    fn translate_memory_fill(
+        &mut self,
+        _pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _dst: ir::Value,
+        _val: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Err(WasmError::Unsupported("memory.fill"))
+    }
+
+    /// Translate a `memory.init` WebAssembly instruction.
+    ///
+    /// The `index` provided identifies the linear memory to initialize, and `heap` is the heap
+    /// reference returned by `make_heap` for the same index. The `seg_index` identifies the
+    /// passive data segment declared by `declare_passive_data` to copy from.
+    ///
+    /// The `dst`, `src` and `len` values are the `i32` destination address, offset into the
+    /// segment and number of bytes to copy.
+    #[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
+    fn translate_memory_init(
+        &mut self,
+        _pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _seg_index: DataIndex,
+        _dst: ir::Value,
+        _src: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Err(WasmError::Unsupported("memory.init"))
+    }
+
+    /// Translate a `data.drop` WebAssembly instruction.
+    ///
+    /// The `seg_index` identifies the passive data segment to drop.
+    fn translate_data_drop(&mut self, _pos: FuncCursor, _seg_index: DataIndex) -> WasmResult<()> {
+        Err(WasmError::Unsupported("data.drop"))
+    }
+
+    /// Translate a `table.copy` WebAssembly instruction.
+    ///
+    /// The `table_index` provided identifies the table to copy within, and `table` is the table
+    /// reference returned by `make_table` for the same index.
+    ///
+    /// The `dst`, `src` and `len` values are the `i32` destination index, source index and
+    /// number of elements to copy. The ranges may overlap.
+    fn translate_table_copy(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _dst: ir::Value,
+        _src: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Err(WasmError::Unsupported("table.copy"))
+    }
+
+    /// Translate a `table.init` WebAssembly instruction.
+    ///
+    /// The `table_index` provided identifies the table to initialize, and `table` is the table
+    /// reference returned by `make_table` for the same index. The `seg_index` identifies the
+    /// passive element segment declared by `declare_passive_element` to copy from.
+    ///
+    /// The `dst`, `src` and `len` values are the `i32` destination index, offset into the
+    /// segment and number of elements to copy.
+    #[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
+    fn translate_table_init(
+        &mut self,
+        _pos: FuncCursor,
+        _table_index: TableIndex,
+        _table: ir::Table,
+        _seg_index: ElemIndex,
+        _dst: ir::Value,
+        _src: ir::Value,
+        _len: ir::Value,
+    ) -> WasmResult<()> {
+        Err(WasmError::Unsupported("table.init"))
+    }
+
+    /// Translate an `elem.drop` WebAssembly instruction.
+    ///
+    /// The `seg_index` identifies the passive element segment to drop.
+    fn translate_elem_drop(&mut self, _pos: FuncCursor, _seg_index: ElemIndex) -> WasmResult<()> {
+        Err(WasmError::Unsupported("elem.drop"))
+    }
+
+    /// Translate an `i32.atomic.wait` or `i64.atomic.wait` WebAssembly instruction.
+    ///
+    /// The `index` provided identifies the linear memory containing the value to wait on, and
+    /// `heap` is the heap reference returned by `make_heap` for the same index.
+    ///
+    /// The `addr` value is the native address of the value to wait on. It has already been
+    /// bounds-checked and checked for natural alignment. The `expected` value is an `i32` or an
+    /// `i64` depending on the instruction, and `timeout` is a relative timeout in nanoseconds as
+    /// an `i64`, where a negative value means to wait forever.
+    ///
+    /// Returns an `i32` which is 0 if the thread was woken, 1 if the value at `addr` was not
+    /// `expected`, and 2 if the wait timed out.
+    fn translate_atomic_wait(
+        &mut self,
+        _pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _addr: ir::Value,
+        _expected: ir::Value,
+        _timeout: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        Err(WasmError::Unsupported("atomic.wait"))
+    }
+
+    /// Translate an `atomic.notify` WebAssembly instruction.
+    ///
+    /// The `index` provided identifies the linear memory containing the waited-on value, and
+    /// `heap` is the heap reference returned by `make_heap` for the same index.
+    ///
+    /// The `addr` value is the native address of the waited-on value. It has already been
+    /// bounds-checked and checked for natural alignment. The `count` value is the maximum number
+    /// of waiters to wake as an `i32`.
+    ///
+    /// Returns the number of waiters that were woken as an `i32`.
+    fn translate_atomic_notify(
+        &mut self,
+        _pos: FuncCursor,
+        _index: MemoryIndex,
+        _heap: ir::Heap,
+        _addr: ir::Value,
+        _count: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        Err(WasmError::Unsupported("atomic.notify"))
+    }
+
+    /// Get the location of the fuel counter used when fuel metering is enabled in the
+    /// `FuncTranslator`.
+    ///
+    /// The counter is an `i64` stored at `offset` from the address computed by `gv`, typically
+    /// a `VMContext`-relative address. The translated code subtracts the cost of each block of
+    /// code from the counter before running the block.
+    fn make_fuel_counter(
+        &mut self,
+        _func: &mut ir::Function,
+    ) -> WasmResult<(ir::GlobalValue, Offset32)> {
+        Err(WasmError::Unsupported("fuel metering"))
+    }
+
+    /// Translate a call to the out-of-fuel handler at `pos`.
+    ///
+    /// This code runs when the fuel counter has gone negative after being decremented. The
+    /// handler can refill the counter or abort execution. If it returns, execution resumes with
+    /// the block that ran out of fuel.
+    fn translate_out_of_fuel(&mut self, _pos: FuncCursor) -> WasmResult<()> {
+        Err(WasmError::Unsupported("fuel metering"))
+    }
+
     /// Emit code at the beginning of every wasm loop.
     ///
     /// This can be used to insert explicit interrupt or safepoint checking at
@@ -241,10 +533,7 @@ pub trait FuncEnvironment {
 /// An object satisfying the `ModuleEnvironment` trait can be passed as argument to the
 /// [`translate_module`](fn.translate_module.html) function. These methods should not be called
 /// by the user, they are only for `cranelift-wasm` internal use.
-pub trait ModuleEnvironment<'data> {
-    /// Get the information needed to produce Cranelift IR for the current target.
-    fn target_config(&self) -> TargetFrontendConfig;
-
+pub trait ModuleEnvironment<'data>: TargetEnvironment {
     /// Provides the number of signatures up front. By default this does nothing, but
     /// implementations can use this to preallocate memory if desired.
     fn reserve_signatures(&mut self, _num: u32) {}
@@ -339,16 +628,34 @@ pub trait ModuleEnvironment<'data> {
         elements: Box<[FuncIndex]>,
     );
 
-    /// Provides the contents of a function body.
+    /// Declares a passive element segment, which is only copied into a table by `table.init`. By
+    /// default this does nothing.
     ///
-    /// Note there's no `reserve_function_bodies` function because the number of
-    /// functions is already provided by `reserve_func_types`.
+    /// The `elem_index` counts both active and passive segments in the element section.
+    fn declare_passive_element(&mut self, _elem_index: ElemIndex, _elements: Box<[FuncIndex]>) {}
+
+    /// Provides the number of function bodies up front, along with the offset of the contents of
+    /// the code section in the module. By default this does nothing.
+    ///
+    /// Code addresses in the DWARF debug information of a module are relative to the code section
+    /// offset.
+    fn reserve_function_bodies(&mut self, _bodies: u32, _code_section_offset: u64) {}
+
+    /// Provides the contents of a function body.
     fn define_function_body(
         &mut self,
         body_bytes: &'data [u8],
         body_offset: usize,
     ) -> WasmResult<()>;
 
+    /// Indicates that all the function bodies have been provided. By default this does nothing.
+    ///
+    /// An implementation compiling function bodies in the background, for example with a
+    /// `ConcurrentCompiler`, can wait for them to be compiled here.
+    fn finish_function_bodies(&mut self) -> WasmResult<()> {
+        Ok(())
+    }
+
     /// Provides the number of data initializers up front. By default this does nothing, but
     /// implementations can use this to preallocate memory if desired.
     fn reserve_data_initializers(&mut self, _num: u32) {}
@@ -361,4 +668,25 @@ pub trait ModuleEnvironment<'data> {
         offset: usize,
         data: &'data [u8],
     );
+
+    /// Declares a passive data segment, which is only copied into a memory by `memory.init`. By
+    /// default this does nothing.
+    ///
+    /// The `data_index` counts both active and passive segments in the data section.
+    fn declare_passive_data(&mut self, _data_index: DataIndex, _data: &'data [u8]) {}
+
+    /// Declares the name of a function, as provided by the custom "name" section. By default this
+    /// does nothing, but implementations can use this to give functions readable names.
+    fn declare_func_name(&mut self, _func_index: FuncIndex, _name: &'data str) {}
+
+    /// Declares the name of a function's local, as provided by the custom "name" section. By
+    /// default this does nothing.
+    ///
+    /// The `local_index` counts the function parameters before the locals declared in its body.
+    fn declare_local_name(&mut self, _func_index: FuncIndex, _local_index: u32, _name: &'data str) {
+    }
+
+    /// Provides the contents of a custom section other than the "name" section, such as the
+    /// `.debug_*` sections holding DWARF debug information. By default this does nothing.
+    fn declare_custom_section(&mut self, _name: &'data str, _data: &'data [u8]) {}
 }
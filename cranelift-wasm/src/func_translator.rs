@@ -5,14 +5,16 @@
 //! WebAssembly module and the runtime environment.
 
 use crate::code_translator::translate_operator;
-use crate::environ::{FuncEnvironment, ReturnMode, WasmResult};
+use crate::environ::{FuncEnvironment, ReturnMode, WasmError, WasmResult};
 use crate::state::TranslationState;
 use cranelift_codegen::entity::EntityRef;
-use cranelift_codegen::ir::{self, Ebb, InstBuilder};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::immediates::Offset32;
+use cranelift_codegen::ir::{self, Ebb, InstBuilder, MemFlags};
 use cranelift_codegen::timing;
 use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
 use log::info;
-use wasmparser::{self, BinaryReader};
+use wasmparser::{self, BinaryReader, Operator};
 
 /// WebAssembly to Cranelift IR function translator.
 ///
@@ -22,6 +24,7 @@ use wasmparser::{self, BinaryReader};
 pub struct FuncTranslator {
     func_ctx: FunctionBuilderContext,
     state: TranslationState,
+    fuel_metering: bool,
 }
 
 impl FuncTranslator {
@@ -30,9 +33,22 @@ impl FuncTranslator {
         Self {
             func_ctx: FunctionBuilderContext::new(),
             state: TranslationState::new(),
+            fuel_metering: false,
         }
     }
 
+    /// Enable or disable fuel metering in the functions translated from now on.
+    ///
+    /// When fuel metering is enabled, the translated code subtracts the cost of each block of
+    /// WebAssembly code from the counter given by `FuncEnvironment::make_fuel_counter` before
+    /// running the block, and calls the handler from `FuncEnvironment::translate_out_of_fuel`
+    /// when the counter goes negative. Each operator costs one unit of fuel, except `block`,
+    /// `loop`, `else` and `end` which are free. Blocks end at control flow operators and at
+    /// calls, so the code following a call is only charged once the callee has returned.
+    pub fn set_fuel_metering(&mut self, enable: bool) {
+        self.fuel_metering = enable;
+    }
+
     /// Translate a binary WebAssembly function.
     ///
     /// The `code` slice contains the binary WebAssembly *function code* as it appears in the code
@@ -94,14 +110,21 @@ impl FuncTranslator {
 
         let num_params = declare_wasm_parameters(&mut builder, entry_block);
 
+        // The address of the fuel counter is computed once in the entry block.
+        let mut meter = if self.fuel_metering {
+            Some(FuelMeter::new(&mut builder, environ)?)
+        } else {
+            None
+        };
+
         // Set up the translation state with a single pushed control block representing the whole
         // function and its return values.
         let exit_block = builder.create_ebb();
         builder.append_ebb_params_for_function_returns(exit_block);
         self.state.initialize(&builder.func.signature, exit_block);
 
-        parse_local_decls(&mut reader, &mut builder, num_params)?;
-        parse_function_body(reader, &mut builder, &mut self.state, environ)?;
+        parse_local_decls(&mut reader, &mut builder, num_params, environ)?;
+        parse_function_body(reader, &mut builder, &mut self.state, &mut meter, environ)?;
 
         builder.finalize();
         Ok(())
@@ -135,10 +158,11 @@ fn declare_wasm_parameters(builder: &mut FunctionBuilder, entry_block: Ebb) -> u
 /// Parse the local variable declarations that precede the function body.
 ///
 /// Declare local variables, starting from `num_params`.
-fn parse_local_decls(
+fn parse_local_decls<FE: FuncEnvironment + ?Sized>(
     reader: &mut BinaryReader,
     builder: &mut FunctionBuilder,
     num_params: usize,
+    environ: &mut FE,
 ) -> WasmResult<()> {
     let mut next_local = num_params;
     let local_count = reader.read_local_count()?;
@@ -146,8 +170,9 @@ fn parse_local_decls(
     let mut locals_total = 0;
     for _ in 0..local_count {
         builder.set_srcloc(cur_srcloc(reader));
+        let offset = reader.original_position();
         let (count, ty) = reader.read_local_decl(&mut locals_total)?;
-        declare_locals(builder, count, ty, &mut next_local);
+        declare_locals(builder, count, ty, offset, &mut next_local, environ)?;
     }
 
     Ok(())
@@ -156,20 +181,33 @@ fn parse_local_decls(
 /// Declare `count` local variables of the same type, starting from `next_local`.
 ///
 /// Fail of too many locals are declared in the function, or if the type is not valid for a local.
-fn declare_locals(
+/// The `offset` of the local declaration is reported in the latter case.
+fn declare_locals<FE: FuncEnvironment + ?Sized>(
     builder: &mut FunctionBuilder,
     count: u32,
     wasm_type: wasmparser::Type,
+    offset: usize,
     next_local: &mut usize,
-) {
-    // All locals are initialized to 0.
+    environ: &mut FE,
+) -> WasmResult<()> {
+    // All locals are initialized to 0 or to a null reference.
     use wasmparser::Type::*;
     let zeroval = match wasm_type {
         I32 => builder.ins().iconst(ir::types::I32, 0),
         I64 => builder.ins().iconst(ir::types::I64, 0),
         F32 => builder.ins().f32const(ir::immediates::Ieee32::with_bits(0)),
         F64 => builder.ins().f64const(ir::immediates::Ieee64::with_bits(0)),
-        _ => panic!("invalid local type"),
+        V128 => {
+            let zero = builder.ins().iconst(ir::types::I8, 0);
+            builder.ins().splat(ir::types::I8X16, zero)
+        }
+        AnyRef | AnyFunc => environ.translate_ref_null(builder.cursor())?,
+        _ => {
+            return Err(WasmError::InvalidWebAssembly {
+                message: "invalid local type",
+                offset,
+            })
+        }
     };
 
     let ty = builder.func.dfg.value_type(zeroval);
@@ -179,6 +217,7 @@ fn declare_locals(
         builder.def_var(local, zeroval);
         *next_local += 1;
     }
+    Ok(())
 }
 
 /// Parse the function body in `reader`.
@@ -189,6 +228,7 @@ fn parse_function_body<FE: FuncEnvironment + ?Sized>(
     mut reader: BinaryReader,
     builder: &mut FunctionBuilder,
     state: &mut TranslationState,
+    meter: &mut Option<FuelMeter>,
     environ: &mut FE,
 ) -> WasmResult<()> {
     // The control stack is initialized with a single block representing the whole function.
@@ -198,7 +238,18 @@ fn parse_function_body<FE: FuncEnvironment + ?Sized>(
     while !state.control_stack.is_empty() {
         builder.set_srcloc(cur_srcloc(&reader));
         let op = reader.read_operator()?;
+        let ends_block = ends_metered_block(&op);
+        if let Some(meter) = meter {
+            if state.reachable {
+                meter.charge(operator_cost(&op), builder, environ)?;
+            }
+        }
         translate_operator(op, builder, state, environ)?;
+        if ends_block {
+            if let Some(meter) = meter {
+                meter.finish_block(builder);
+            }
+        }
     }
 
     // The final `End` operator left us in the exit block where we need to manually add a return
@@ -225,6 +276,126 @@ fn parse_function_body<FE: FuncEnvironment + ?Sized>(
     Ok(())
 }
 
+/// State for metering the fuel consumed by the function being translated.
+///
+/// The code is divided into blocks which end at control flow operators and calls. The fuel
+/// counter is decremented by the cost of a block on entry to it, but that cost is only known once
+/// the whole block has been translated. The subtracted amount is an `iconst` placeholder which is
+/// patched when the block ends.
+struct FuelMeter {
+    /// The address of the fuel counter, computed in the entry block.
+    addr: ir::Value,
+    /// The offset of the fuel counter from `addr`.
+    offset: Offset32,
+    /// The placeholder for the cost of the current block, if a block is being metered.
+    cost_inst: Option<ir::Inst>,
+    /// The cost of the operators translated so far in the current block.
+    cost: i64,
+}
+
+impl FuelMeter {
+    fn new<FE: FuncEnvironment + ?Sized>(
+        builder: &mut FunctionBuilder,
+        environ: &mut FE,
+    ) -> WasmResult<Self> {
+        let (gv, offset) = environ.make_fuel_counter(builder.func)?;
+        let addr = builder.ins().global_value(environ.pointer_type(), gv);
+        Ok(Self {
+            addr,
+            offset,
+            cost_inst: None,
+            cost: 0,
+        })
+    }
+
+    /// Add `cost` to the cost of the current block, starting a new block if needed.
+    fn charge<FE: FuncEnvironment + ?Sized>(
+        &mut self,
+        cost: i64,
+        builder: &mut FunctionBuilder,
+        environ: &mut FE,
+    ) -> WasmResult<()> {
+        if cost == 0 {
+            return Ok(());
+        }
+        if self.cost_inst.is_none() {
+            self.start_block(builder, environ)?;
+        }
+        self.cost += cost;
+        Ok(())
+    }
+
+    /// Emit the fuel check at the start of a new block.
+    fn start_block<FE: FuncEnvironment + ?Sized>(
+        &mut self,
+        builder: &mut FunctionBuilder,
+        environ: &mut FE,
+    ) -> WasmResult<()> {
+        let flags = MemFlags::trusted();
+        let cost = builder.ins().iconst(ir::types::I64, 0);
+        self.cost_inst = Some(builder.func.dfg.value_def(cost).unwrap_inst());
+        self.cost = 0;
+
+        let fuel = builder
+            .ins()
+            .load(ir::types::I64, flags, self.addr, self.offset);
+        let fuel = builder.ins().isub(fuel, cost);
+        builder.ins().store(flags, fuel, self.addr, self.offset);
+        let exhausted = builder.ins().icmp_imm(IntCC::SignedLessThan, fuel, 0);
+
+        let out_of_fuel = builder.create_ebb();
+        let resume = builder.create_ebb();
+        builder.ins().brnz(exhausted, out_of_fuel, &[]);
+        builder.ins().jump(resume, &[]);
+
+        builder.switch_to_block(out_of_fuel);
+        builder.seal_block(out_of_fuel);
+        environ.translate_out_of_fuel(builder.cursor())?;
+        builder.ins().jump(resume, &[]);
+
+        builder.switch_to_block(resume);
+        builder.seal_block(resume);
+        Ok(())
+    }
+
+    /// Patch the cost of the current block, if any, now that all of it has been translated.
+    fn finish_block(&mut self, builder: &mut FunctionBuilder) {
+        if let Some(inst) = self.cost_inst.take() {
+            builder
+                .func
+                .dfg
+                .replace(inst)
+                .iconst(ir::types::I64, self.cost);
+        }
+    }
+}
+
+/// Get the fuel cost of a WebAssembly operator.
+fn operator_cost(op: &Operator) -> i64 {
+    match *op {
+        Operator::Block { .. } | Operator::Loop { .. } | Operator::Else | Operator::End => 0,
+        _ => 1,
+    }
+}
+
+/// Does `op` end a block of straight-line code for fuel metering purposes?
+fn ends_metered_block(op: &Operator) -> bool {
+    match *op {
+        Operator::Loop { .. }
+        | Operator::If { .. }
+        | Operator::Else
+        | Operator::End
+        | Operator::Br { .. }
+        | Operator::BrIf { .. }
+        | Operator::BrTable { .. }
+        | Operator::Return
+        | Operator::Unreachable
+        | Operator::Call { .. }
+        | Operator::CallIndirect { .. } => true,
+        _ => false,
+    }
+}
+
 /// Get the current source location from a reader.
 fn cur_srcloc(reader: &BinaryReader) -> ir::SourceLoc {
     // We record source locations as byte code offsets relative to the beginning of the file.
@@ -235,10 +406,13 @@ fn cur_srcloc(reader: &BinaryReader) -> ir::SourceLoc {
 #[cfg(test)]
 mod tests {
     use super::{FuncTranslator, ReturnMode};
-    use crate::environ::DummyEnvironment;
-    use cranelift_codegen::ir::types::I32;
+    use crate::environ::{DummyEnvironment, ModuleEnvironment};
+    use crate::translation_utils::SignatureIndex;
+    use cranelift_codegen::entity::EntityRef;
+    use cranelift_codegen::ir::types::{I32, I64};
     use cranelift_codegen::{ir, isa, settings, Context};
     use log::debug;
+    use std::vec::Vec;
     use target_lexicon::PointerWidth;
 
     #[test]
@@ -317,6 +491,49 @@ mod tests {
         ctx.verify(&flags).unwrap();
     }
 
+    #[test]
+    fn multi_value() {
+        // Multiple return values, the last one produced by an `if` with a result.
+        //
+        // (func $multi_value (param i32) (result i32 i64)
+        //     (get_local 0)
+        //     (if (result i64) (get_local 0) (then (i64.const 1)) (else (i64.const 2)))
+        // )
+        const BODY: [u8; 14] = [
+            0x00, // local decl count
+            0x20, 0x00, // get_local 0
+            0x20, 0x00, // get_local 0
+            0x04, 0x7e, // if i64
+            0x42, 0x01, // i64.const 1
+            0x05, // else
+            0x42, 0x02, // i64.const 2
+            0x0b, // end
+            0x0b, // end
+        ];
+
+        let mut trans = FuncTranslator::new();
+        let flags = settings::Flags::new(settings::builder());
+        let runtime = DummyEnvironment::new(
+            isa::TargetFrontendConfig {
+                default_call_conv: isa::CallConv::Fast,
+                pointer_width: PointerWidth::U64,
+            },
+            ReturnMode::NormalReturns,
+        );
+        let mut ctx = Context::new();
+
+        ctx.func.name = ir::ExternalName::testcase("multi_value");
+        ctx.func.signature.params.push(ir::AbiParam::new(I32));
+        ctx.func.signature.returns.push(ir::AbiParam::new(I32));
+        ctx.func.signature.returns.push(ir::AbiParam::new(I64));
+
+        trans
+            .translate(&BODY, 0, &mut ctx.func, &mut runtime.func_env())
+            .unwrap();
+        debug!("{}", ctx.func.display(None));
+        ctx.verify(&flags).unwrap();
+    }
+
     #[test]
     fn infloop() {
         // An infinite loop, no return instructions.
@@ -362,4 +579,109 @@ mod tests {
         debug!("{}", ctx.func.display(None));
         ctx.verify(&flags).unwrap();
     }
+
+    #[test]
+    fn fuel_metering() {
+        // A loop calling a function, with fuel metering enabled.
+        //
+        // (func $fuel_metering (param i32) (result i32)
+        //     (local i32)
+        //     (block
+        //         (loop
+        //             (br_if 1 (i32.eqz (get_local 0)))
+        //             (set_local 1 (call 0 (get_local 1)))
+        //             (set_local 0 (i32.sub (get_local 0) (i32.const 1)))
+        //             (br 0)
+        //         )
+        //     )
+        //     (get_local 1)
+        // )
+        const BODY: [u8; 32] = [
+            0x01, // 1 local decl.
+            0x01, 0x7f, // 1 i32 local.
+            0x02, 0x40, // block
+            0x03, 0x40, // loop
+            0x20, 0x00, // get_local 0
+            0x45, // i32.eqz
+            0x0d, 0x01, // br_if 1
+            0x20, 0x01, // get_local 1
+            0x10, 0x00, // call 0
+            0x21, 0x01, // set_local 1
+            0x20, 0x00, // get_local 0
+            0x41, 0x01, // i32.const 1
+            0x6b, // i32.sub
+            0x21, 0x00, // set_local 0
+            0x0c, 0x00, // br 0
+            0x0b, // end
+            0x0b, // end
+            0x20, 0x01, // get_local 1
+            0x0b, // end
+        ];
+
+        let mut trans = FuncTranslator::new();
+        trans.set_fuel_metering(true);
+        let flags = settings::Flags::new(settings::builder());
+        let mut runtime = DummyEnvironment::new(
+            isa::TargetFrontendConfig {
+                default_call_conv: isa::CallConv::Fast,
+                pointer_width: PointerWidth::U64,
+            },
+            ReturnMode::NormalReturns,
+        );
+        let mut sig = ir::Signature::new(isa::CallConv::Fast);
+        sig.params.push(ir::AbiParam::new(I32));
+        sig.returns.push(ir::AbiParam::new(I32));
+        runtime.declare_signature(sig);
+        runtime.declare_func_type(SignatureIndex::new(0));
+        let mut ctx = Context::new();
+
+        ctx.func.name = ir::ExternalName::testcase("fuel_metering");
+        ctx.func.signature.params.push(ir::AbiParam::new(I32));
+        ctx.func
+            .signature
+            .params
+            .push(ir::AbiParam::special(I64, ir::ArgumentPurpose::VMContext));
+        ctx.func.signature.returns.push(ir::AbiParam::new(I32));
+
+        trans
+            .translate(&BODY, 0, &mut ctx.func, &mut runtime.func_env())
+            .unwrap();
+        debug!("{}", ctx.func.display(None));
+        ctx.verify(&flags).unwrap();
+
+        // List the block charges and the call to function 0 in layout order. The out-of-fuel
+        // handler calls are left out.
+        let func = &ctx.func;
+        let mut events = Vec::new();
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                match func.dfg[inst] {
+                    ir::InstructionData::UnaryImm {
+                        opcode: ir::Opcode::Iconst,
+                        imm,
+                    } if func.dfg.ctrl_typevar(inst) == I64 => {
+                        events.push(Some(imm.into()));
+                    }
+                    ir::InstructionData::Call { func_ref, .. }
+                        if func.dfg.ext_funcs[func_ref].name == ir::ExternalName::user(0, 0) =>
+                    {
+                        events.push(None);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // The loop body is split by the `br_if` and the call, and the code following the
+        // `block` is charged separately. `block`, `loop` and `end` are free.
+        assert_eq!(events, [Some(3), Some(2), None, Some(6), Some(1)]);
+
+        // The first block is charged at the loop header, so it is charged on every iteration.
+        let entry = func.layout.entry_block().unwrap();
+        let entry_jump = func.layout.last_inst(entry).unwrap();
+        let loop_header = func.dfg[entry_jump].branch_destination().unwrap();
+        let first = func.layout.first_inst(loop_header).unwrap();
+        assert_eq!(func.dfg[first].opcode(), ir::Opcode::Iconst);
+        assert_eq!(func.dfg.ctrl_typevar(first), I64);
+    }
 }
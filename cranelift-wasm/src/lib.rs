@@ -43,6 +43,10 @@ use hashmap_core::{map as hash_map, HashMap};
 use std::collections::{hash_map, HashMap};
 
 mod code_translator;
+#[cfg(feature = "std")]
+mod concurrent;
+#[cfg(feature = "std")]
+mod debug;
 mod environ;
 mod func_translator;
 mod module_translator;
@@ -50,16 +54,20 @@ mod sections_translator;
 mod state;
 mod translation_utils;
 
+#[cfg(feature = "std")]
+pub use crate::concurrent::ConcurrentCompiler;
+#[cfg(feature = "std")]
+pub use crate::debug::{transform_dwarf, DebugSections, FunctionAddressMap, InstructionAddressMap};
 pub use crate::environ::{
-    DummyEnvironment, FuncEnvironment, GlobalVariable, ModuleEnvironment, ReturnMode, WasmError,
-    WasmResult,
+    DummyEnvironment, FuncEnvironment, GlobalVariable, ModuleEnvironment, ReturnMode,
+    TargetEnvironment, WasmError, WasmResult,
 };
 pub use crate::func_translator::FuncTranslator;
 pub use crate::module_translator::translate_module;
 pub use crate::translation_utils::{
-    DefinedFuncIndex, DefinedGlobalIndex, DefinedMemoryIndex, DefinedTableIndex, FuncIndex, Global,
-    GlobalIndex, GlobalInit, Memory, MemoryIndex, SignatureIndex, Table, TableElementType,
-    TableIndex,
+    DataIndex, DefinedFuncIndex, DefinedGlobalIndex, DefinedMemoryIndex, DefinedTableIndex,
+    ElemIndex, FuncIndex, Global, GlobalIndex, GlobalInit, Memory, MemoryIndex, SignatureIndex,
+    Table, TableElementType, TableIndex,
 };
 
 /// Version number of this crate.
@@ -1,13 +1,13 @@
 //! Translation skeleton that traverses the whole WebAssembly module and call helper functions
 //! to deal with each part of it.
-use crate::environ::{ModuleEnvironment, WasmResult};
+use crate::environ::{ModuleEnvironment, WasmError, WasmResult};
 use crate::sections_translator::{
     parse_code_section, parse_data_section, parse_element_section, parse_export_section,
     parse_function_section, parse_global_section, parse_import_section, parse_memory_section,
-    parse_start_section, parse_table_section, parse_type_section,
+    parse_name_section, parse_start_section, parse_table_section, parse_type_section,
 };
 use cranelift_codegen::timing;
-use wasmparser::{ModuleReader, SectionCode};
+use wasmparser::{CustomSectionKind, ModuleReader, SectionCode};
 
 /// Translate a sequence of bytes forming a valid Wasm binary into a list of valid Cranelift IR
 /// [`Function`](../codegen/ir/function/struct.Function.html).
@@ -17,127 +17,123 @@ pub fn translate_module<'data>(
 ) -> WasmResult<()> {
     let _tt = timing::wasm_translate_module();
     let mut reader = ModuleReader::new(data)?;
-
-    reader.skip_custom_sections()?;
-    if reader.eof() {
-        return Ok(());
-    }
-    let mut section = reader.read()?;
-
-    if let SectionCode::Type = section.code {
-        let types = section.get_type_section_reader()?;
-        parse_type_section(types, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
+    let mut last_order = 0;
+
+    while !reader.eof() {
+        let offset = reader.current_position();
+        let section = reader.read()?;
+
+        // Known sections must appear at most once and in order, while custom sections may
+        // appear anywhere.
+        if let Some(order) = section_order(&section.code) {
+            if order <= last_order {
+                return Err(WasmError::InvalidWebAssembly {
+                    message: "section out of order or duplicated",
+                    offset,
+                });
+            }
+            last_order = order;
         }
-        section = reader.read()?;
-    }
-
-    if let SectionCode::Import = section.code {
-        let imports = section.get_import_section_reader()?;
-        parse_import_section(imports, environ)?;
 
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
+        match section.code {
+            SectionCode::Type => {
+                let types = section.get_type_section_reader()?;
+                parse_type_section(types, environ)?;
+            }
+
+            SectionCode::Import => {
+                let imports = section.get_import_section_reader()?;
+                parse_import_section(imports, environ)?;
+            }
+
+            SectionCode::Function => {
+                let functions = section.get_function_section_reader()?;
+                parse_function_section(functions, environ)?;
+            }
+
+            SectionCode::Table => {
+                let tables = section.get_table_section_reader()?;
+                parse_table_section(tables, environ)?;
+            }
+
+            SectionCode::Memory => {
+                let memories = section.get_memory_section_reader()?;
+                parse_memory_section(memories, environ)?;
+            }
+
+            SectionCode::Global => {
+                let globals = section.get_global_section_reader()?;
+                parse_global_section(globals, environ)?;
+            }
+
+            SectionCode::Export => {
+                let exports = section.get_export_section_reader()?;
+                parse_export_section(exports, environ)?;
+            }
+
+            SectionCode::Start => {
+                let start = section.get_start_section_content()?;
+                parse_start_section(start, environ)?;
+            }
+
+            SectionCode::Element => {
+                let elements = section.get_element_section_reader()?;
+                parse_element_section(elements, environ)?;
+            }
+
+            SectionCode::DataCount => {
+                // The number of data segments is only needed to validate the bulk memory
+                // operators, which wasmparser has already done.
+            }
+
+            SectionCode::Code => {
+                let code = section.get_code_section_reader()?;
+                environ.reserve_function_bodies(code.get_count(), section.range().start as u64);
+                parse_code_section(code, environ)?;
+                environ.finish_function_bodies()?;
+            }
+
+            SectionCode::Data => {
+                let data = section.get_data_section_reader()?;
+                parse_data_section(data, environ)?;
+            }
+
+            SectionCode::Custom {
+                kind: CustomSectionKind::Name,
+                ..
+            } => {
+                let names = section.get_name_section_reader()?;
+                parse_name_section(names, environ)?;
+            }
+
+            SectionCode::Custom { name, .. } => {
+                let mut reader = section.get_binary_reader();
+                let size = reader.bytes_remaining();
+                environ.declare_custom_section(name, reader.read_bytes(size)?);
+            }
         }
-        section = reader.read()?;
     }
 
-    if let SectionCode::Function = section.code {
-        let functions = section.get_function_section_reader()?;
-        parse_function_section(functions, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
-        }
-        section = reader.read()?;
-    }
-
-    if let SectionCode::Table = section.code {
-        let tables = section.get_table_section_reader()?;
-        parse_table_section(tables, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
-        }
-        section = reader.read()?;
-    }
-
-    if let SectionCode::Memory = section.code {
-        let memories = section.get_memory_section_reader()?;
-        parse_memory_section(memories, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
-        }
-        section = reader.read()?;
-    }
-
-    if let SectionCode::Global = section.code {
-        let globals = section.get_global_section_reader()?;
-        parse_global_section(globals, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
-        }
-        section = reader.read()?;
-    }
-
-    if let SectionCode::Export = section.code {
-        let exports = section.get_export_section_reader()?;
-        parse_export_section(exports, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
-        }
-        section = reader.read()?;
-    }
-
-    if let SectionCode::Start = section.code {
-        let start = section.get_start_section_content()?;
-        parse_start_section(start, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
-        }
-        section = reader.read()?;
-    }
-
-    if let SectionCode::Element = section.code {
-        let elements = section.get_element_section_reader()?;
-        parse_element_section(elements, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
-        }
-        section = reader.read()?;
-    }
-
-    if let SectionCode::Code = section.code {
-        let code = section.get_code_section_reader()?;
-        parse_code_section(code, environ)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(());
-        }
-        section = reader.read()?;
-    }
+    Ok(())
+}
 
-    if let SectionCode::Data = section.code {
-        let data = section.get_data_section_reader()?;
-        parse_data_section(data, environ)?;
+/// Get the position of a known section in the order required by the binary format, or `None`
+/// for custom sections.
+fn section_order(code: &SectionCode) -> Option<u8> {
+    match *code {
+        SectionCode::Custom { .. } => None,
+        SectionCode::Type => Some(1),
+        SectionCode::Import => Some(2),
+        SectionCode::Function => Some(3),
+        SectionCode::Table => Some(4),
+        SectionCode::Memory => Some(5),
+        SectionCode::Global => Some(6),
+        SectionCode::Export => Some(7),
+        SectionCode::Start => Some(8),
+        SectionCode::Element => Some(9),
+        // The data count section is placed between the element and code sections.
+        SectionCode::DataCount => Some(10),
+        SectionCode::Code => Some(11),
+        SectionCode::Data => Some(12),
     }
-
-    Ok(())
 }
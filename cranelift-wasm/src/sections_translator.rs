@@ -7,10 +7,10 @@
 //! The special case of the initialize expressions for table elements offsets or global variables
 //! is handled, according to the semantics of WebAssembly, to only specific expressions that are
 //! interpreted on the fly.
-use crate::environ::{ModuleEnvironment, WasmResult};
+use crate::environ::{ModuleEnvironment, WasmError, WasmResult};
 use crate::translation_utils::{
-    type_to_type, FuncIndex, Global, GlobalIndex, GlobalInit, Memory, MemoryIndex, SignatureIndex,
-    Table, TableElementType, TableIndex,
+    type_to_type, DataIndex, ElemIndex, FuncIndex, Global, GlobalIndex, GlobalInit, Memory,
+    MemoryIndex, SignatureIndex, Table, TableElementType, TableIndex,
 };
 use cranelift_codegen::ir::{self, AbiParam, Signature};
 use cranelift_entity::EntityRef;
@@ -19,38 +19,43 @@ use wasmparser::{
     self, CodeSectionReader, Data, DataKind, DataSectionReader, Element, ElementKind,
     ElementSectionReader, Export, ExportSectionReader, ExternalKind, FuncType,
     FunctionSectionReader, GlobalSectionReader, GlobalType, ImportSectionEntryType,
-    ImportSectionReader, MemorySectionReader, MemoryType, Operator, TableSectionReader,
-    TypeSectionReader,
+    ImportSectionReader, MemorySectionReader, MemoryType, Name, NameSectionReader, Operator,
+    TableSectionReader, TypeSectionReader,
 };
 
 /// Parses the Type section of the wasm module.
 pub fn parse_type_section(
-    types: TypeSectionReader,
+    mut types: TypeSectionReader,
     environ: &mut ModuleEnvironment,
 ) -> WasmResult<()> {
-    environ.reserve_signatures(types.get_count());
+    let count = types.get_count();
+    environ.reserve_signatures(count);
 
-    for entry in types {
-        match entry? {
+    for _ in 0..count {
+        let offset = types.original_position();
+        match types.read()? {
             FuncType {
                 form: wasmparser::Type::Func,
                 ref params,
                 ref returns,
             } => {
                 let mut sig = Signature::new(environ.target_config().default_call_conv);
-                sig.params.extend(params.iter().map(|ty| {
-                    let cret_arg: ir::Type = type_to_type(*ty)
-                        .expect("only numeric types are supported in function signatures");
-                    AbiParam::new(cret_arg)
-                }));
-                sig.returns.extend(returns.iter().map(|ty| {
-                    let cret_arg: ir::Type = type_to_type(*ty)
-                        .expect("only numeric types are supported in function signatures");
-                    AbiParam::new(cret_arg)
-                }));
+                for ty in params.iter() {
+                    let cret_arg: ir::Type = type_to_type(*ty, offset, environ)?;
+                    sig.params.push(AbiParam::new(cret_arg));
+                }
+                for ty in returns.iter() {
+                    let cret_arg: ir::Type = type_to_type(*ty, offset, environ)?;
+                    sig.returns.push(AbiParam::new(cret_arg));
+                }
                 environ.declare_signature(sig);
             }
-            ref s => panic!("unsupported type: {:?}", s),
+            _ => {
+                return Err(WasmError::InvalidWebAssembly {
+                    message: "type section entry is not a function type",
+                    offset,
+                })
+            }
         }
     }
     Ok(())
@@ -58,13 +63,15 @@ pub fn parse_type_section(
 
 /// Parses the Import section of the wasm module.
 pub fn parse_import_section<'data>(
-    imports: ImportSectionReader<'data>,
+    mut imports: ImportSectionReader<'data>,
     environ: &mut ModuleEnvironment<'data>,
 ) -> WasmResult<()> {
-    environ.reserve_imports(imports.get_count());
+    let count = imports.get_count();
+    environ.reserve_imports(count);
 
-    for entry in imports {
-        let import = entry?;
+    for _ in 0..count {
+        let offset = imports.original_position();
+        let import = imports.read()?;
         let module_name = import.module;
         let field_name = import.field;
 
@@ -89,7 +96,7 @@ pub fn parse_import_section<'data>(
             ImportSectionEntryType::Global(ref ty) => {
                 environ.declare_global_import(
                     Global {
-                        ty: type_to_type(ty.content_type).unwrap(),
+                        ty: type_to_type(ty.content_type, offset, environ)?,
                         mutability: ty.mutable,
                         initializer: GlobalInit::Import,
                     },
@@ -100,10 +107,7 @@ pub fn parse_import_section<'data>(
             ImportSectionEntryType::Table(ref tab) => {
                 environ.declare_table_import(
                     Table {
-                        ty: match type_to_type(tab.element_type) {
-                            Ok(t) => TableElementType::Val(t),
-                            Err(()) => TableElementType::Func,
-                        },
+                        ty: table_element_type(tab.element_type, offset, environ)?,
                         minimum: tab.limits.initial,
                         maximum: tab.limits.maximum,
                     },
@@ -135,18 +139,17 @@ pub fn parse_function_section(
 
 /// Parses the Table section of the wasm module.
 pub fn parse_table_section(
-    tables: TableSectionReader,
+    mut tables: TableSectionReader,
     environ: &mut ModuleEnvironment,
 ) -> WasmResult<()> {
-    environ.reserve_tables(tables.get_count());
+    let count = tables.get_count();
+    environ.reserve_tables(count);
 
-    for entry in tables {
-        let table = entry?;
+    for _ in 0..count {
+        let offset = tables.original_position();
+        let table = tables.read()?;
         environ.declare_table(Table {
-            ty: match type_to_type(table.element_type) {
-                Ok(t) => TableElementType::Val(t),
-                Err(()) => TableElementType::Func,
-            },
+            ty: table_element_type(table.element_type, offset, environ)?,
             minimum: table.limits.initial,
             maximum: table.limits.maximum,
         });
@@ -176,32 +179,41 @@ pub fn parse_memory_section(
 
 /// Parses the Global section of the wasm module.
 pub fn parse_global_section(
-    globals: GlobalSectionReader,
+    mut globals: GlobalSectionReader,
     environ: &mut ModuleEnvironment,
 ) -> WasmResult<()> {
-    environ.reserve_globals(globals.get_count());
+    let count = globals.get_count();
+    environ.reserve_globals(count);
 
-    for entry in globals {
+    for _ in 0..count {
+        let offset = globals.original_position();
         let wasmparser::Global {
             ty: GlobalType {
                 content_type,
                 mutable,
             },
             init_expr,
-        } = entry?;
+        } = globals.read()?;
         let mut init_expr_reader = init_expr.get_binary_reader();
+        let init_offset = init_expr_reader.original_position();
         let initializer = match init_expr_reader.read_operator()? {
             Operator::I32Const { value } => GlobalInit::I32Const(value),
             Operator::I64Const { value } => GlobalInit::I64Const(value),
             Operator::F32Const { value } => GlobalInit::F32Const(value.bits()),
             Operator::F64Const { value } => GlobalInit::F64Const(value.bits()),
+            Operator::RefNull => GlobalInit::RefNullConst,
             Operator::GetGlobal { global_index } => {
                 GlobalInit::GetGlobal(GlobalIndex::from_u32(global_index))
             }
-            ref s => panic!("unsupported init expr in global section: {:?}", s),
+            _ => {
+                return Err(WasmError::InvalidWebAssembly {
+                    message: "unsupported init expr in global section",
+                    offset: init_offset,
+                })
+            }
         };
         let global = Global {
-            ty: type_to_type(content_type).unwrap(),
+            ty: type_to_type(content_type, offset, environ)?,
             mutability: mutable,
             initializer,
         };
@@ -254,35 +266,43 @@ pub fn parse_element_section<'data>(
 ) -> WasmResult<()> {
     environ.reserve_table_elements(elements.get_count());
 
-    for entry in elements {
+    for (index, entry) in elements.into_iter().enumerate() {
         let Element { kind, items } = entry?;
-        if let ElementKind::Active {
-            table_index,
-            init_expr,
-        } = kind
-        {
-            let mut init_expr_reader = init_expr.get_binary_reader();
-            let (base, offset) = match init_expr_reader.read_operator()? {
-                Operator::I32Const { value } => (None, value as u32 as usize),
-                Operator::GetGlobal { global_index } => {
-                    (Some(GlobalIndex::from_u32(global_index)), 0)
-                }
-                ref s => panic!("unsupported init expr in element section: {:?}", s),
-            };
-            let items_reader = items.get_items_reader()?;
-            let mut elems = Vec::with_capacity(cast::usize(items_reader.get_count()));
-            for item in items_reader {
-                let x = item?;
-                elems.push(FuncIndex::from_u32(x));
+        let items_reader = items.get_items_reader()?;
+        let mut elems = Vec::with_capacity(cast::usize(items_reader.get_count()));
+        for item in items_reader {
+            let x = item?;
+            elems.push(FuncIndex::from_u32(x));
+        }
+        match kind {
+            ElementKind::Active {
+                table_index,
+                init_expr,
+            } => {
+                let mut init_expr_reader = init_expr.get_binary_reader();
+                let init_offset = init_expr_reader.original_position();
+                let (base, offset) = match init_expr_reader.read_operator()? {
+                    Operator::I32Const { value } => (None, value as u32 as usize),
+                    Operator::GetGlobal { global_index } => {
+                        (Some(GlobalIndex::from_u32(global_index)), 0)
+                    }
+                    _ => {
+                        return Err(WasmError::InvalidWebAssembly {
+                            message: "unsupported init expr in element section",
+                            offset: init_offset,
+                        })
+                    }
+                };
+                environ.declare_table_elements(
+                    TableIndex::from_u32(table_index),
+                    base,
+                    offset,
+                    elems.into_boxed_slice(),
+                )
+            }
+            ElementKind::Passive(_) => {
+                environ.declare_passive_element(ElemIndex::new(index), elems.into_boxed_slice())
             }
-            environ.declare_table_elements(
-                TableIndex::from_u32(table_index),
-                base,
-                offset,
-                elems.into_boxed_slice(),
-            )
-        } else {
-            panic!("unsupported passive elements section");
         }
     }
     Ok(())
@@ -309,31 +329,90 @@ pub fn parse_data_section<'data>(
 ) -> WasmResult<()> {
     environ.reserve_data_initializers(data.get_count());
 
-    for entry in data {
+    for (index, entry) in data.into_iter().enumerate() {
         let Data { kind, data } = entry?;
-        if let DataKind::Active {
-            memory_index,
-            init_expr,
-        } = kind
-        {
-            let mut init_expr_reader = init_expr.get_binary_reader();
-            let (base, offset) = match init_expr_reader.read_operator()? {
-                Operator::I32Const { value } => (None, value as u32 as usize),
-                Operator::GetGlobal { global_index } => {
-                    (Some(GlobalIndex::from_u32(global_index)), 0)
-                }
-                ref s => panic!("unsupported init expr in data section: {:?}", s),
-            };
-            environ.declare_data_initialization(
-                MemoryIndex::from_u32(memory_index),
-                base,
-                offset,
-                data,
-            );
-        } else {
-            panic!("unsupported passive data section");
+        match kind {
+            DataKind::Active {
+                memory_index,
+                init_expr,
+            } => {
+                let mut init_expr_reader = init_expr.get_binary_reader();
+                let init_offset = init_expr_reader.original_position();
+                let (base, offset) = match init_expr_reader.read_operator()? {
+                    Operator::I32Const { value } => (None, value as u32 as usize),
+                    Operator::GetGlobal { global_index } => {
+                        (Some(GlobalIndex::from_u32(global_index)), 0)
+                    }
+                    _ => {
+                        return Err(WasmError::InvalidWebAssembly {
+                            message: "unsupported init expr in data section",
+                            offset: init_offset,
+                        })
+                    }
+                };
+                environ.declare_data_initialization(
+                    MemoryIndex::from_u32(memory_index),
+                    base,
+                    offset,
+                    data,
+                );
+            }
+            DataKind::Passive => environ.declare_passive_data(DataIndex::new(index), data),
         }
     }
 
     Ok(())
 }
+
+/// Parses the Name section of the wasm module.
+pub fn parse_name_section<'data>(
+    mut names: NameSectionReader<'data>,
+    environ: &mut ModuleEnvironment<'data>,
+) -> WasmResult<()> {
+    while !names.eof() {
+        match names.read()? {
+            Name::Module(_) => {
+                // The module name isn't needed by any environment.
+            }
+            Name::Function(function_names) => {
+                let mut map = function_names.get_map()?;
+                for _ in 0..map.get_count() {
+                    let naming = map.read()?;
+                    // The name section isn't validated, and `u32::MAX` isn't a valid index.
+                    if naming.index == u32::max_value() {
+                        continue;
+                    }
+                    environ.declare_func_name(FuncIndex::from_u32(naming.index), naming.name);
+                }
+            }
+            Name::Local(local_names) => {
+                let mut reader = local_names.get_function_local_reader()?;
+                for _ in 0..reader.get_count() {
+                    let function_local_names = reader.read()?;
+                    if function_local_names.func_index == u32::max_value() {
+                        continue;
+                    }
+                    let func_index = FuncIndex::from_u32(function_local_names.func_index);
+                    let mut map = function_local_names.get_map()?;
+                    for _ in 0..map.get_count() {
+                        let naming = map.read()?;
+                        environ.declare_local_name(func_index, naming.index, naming.name);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Translate the element type of a table declared at `offset`.
+fn table_element_type(
+    ty: wasmparser::Type,
+    offset: usize,
+    environ: &ModuleEnvironment,
+) -> WasmResult<TableElementType> {
+    match ty {
+        wasmparser::Type::AnyFunc => Ok(TableElementType::Func),
+        ty => Ok(TableElementType::Val(type_to_type(ty, offset, environ)?)),
+    }
+}
@@ -13,17 +13,21 @@ use std::vec::Vec;
 /// fields:
 ///
 /// - `destination`: reference to the `Ebb` that will hold the code after the control block;
+/// - `num_param_values`: number of values taken from the value stack by the control block;
 /// - `num_return_values`: number of values returned by the control block;
-/// - `original_stack_size`: size of the value stack at the beginning of the control block.
+/// - `original_stack_size`: size of the value stack at the beginning of the control block, not
+///   counting its parameters.
 ///
 /// Moreover, the `if` frame has the `branch_inst` field that points to the `brz` instruction
 /// separating the `true` and `false` branch. The `loop` frame has a `header` field that references
-/// the `Ebb` that contains the beginning of the body of the loop.
+/// the `Ebb` that contains the beginning of the body of the loop. Branches to a loop pass the loop
+/// parameters to its header, while branches to any other block pass the block results.
 #[derive(Debug)]
 pub enum ControlStackFrame {
     If {
         destination: Ebb,
         branch_inst: Inst,
+        num_param_values: usize,
         num_return_values: usize,
         original_stack_size: usize,
         exit_is_branched_to: bool,
@@ -31,6 +35,7 @@ pub enum ControlStackFrame {
     },
     Block {
         destination: Ebb,
+        num_param_values: usize,
         num_return_values: usize,
         original_stack_size: usize,
         exit_is_branched_to: bool,
@@ -38,6 +43,7 @@ pub enum ControlStackFrame {
     Loop {
         destination: Ebb,
         header: Ebb,
+        num_param_values: usize,
         num_return_values: usize,
         original_stack_size: usize,
     },
@@ -45,6 +51,19 @@ pub enum ControlStackFrame {
 
 /// Helper methods for the control stack objects.
 impl ControlStackFrame {
+    pub fn num_param_values(&self) -> usize {
+        match *self {
+            ControlStackFrame::If {
+                num_param_values, ..
+            }
+            | ControlStackFrame::Block {
+                num_param_values, ..
+            }
+            | ControlStackFrame::Loop {
+                num_param_values, ..
+            } => num_param_values,
+        }
+    }
     pub fn num_return_values(&self) -> usize {
         match *self {
             ControlStackFrame::If {
@@ -88,6 +107,14 @@ impl ControlStackFrame {
             } => original_stack_size,
         }
     }
+    /// Number of values passed along by a branch to this frame's `br_destination`.
+    pub fn num_branch_args(&self) -> usize {
+        if self.is_loop() {
+            self.num_param_values()
+        } else {
+            self.num_return_values()
+        }
+    }
     pub fn is_loop(&self) -> bool {
         match *self {
             ControlStackFrame::If { .. } | ControlStackFrame::Block { .. } => false,
@@ -187,6 +214,7 @@ impl TranslationState {
         self.clear();
         self.push_block(
             exit_block,
+            0,
             sig.returns
                 .iter()
                 .filter(|arg| arg.purpose == ir::ArgumentPurpose::Normal)
@@ -243,31 +271,51 @@ impl TranslationState {
     }
 
     // Push a block on the control stack.
-    pub fn push_block(&mut self, following_code: Ebb, num_result_types: usize) {
+    pub fn push_block(
+        &mut self,
+        following_code: Ebb,
+        num_param_types: usize,
+        num_result_types: usize,
+    ) {
         self.control_stack.push(ControlStackFrame::Block {
             destination: following_code,
-            original_stack_size: self.stack.len(),
+            original_stack_size: self.stack.len() - num_param_types,
+            num_param_values: num_param_types,
             num_return_values: num_result_types,
             exit_is_branched_to: false,
         });
     }
 
     // Push a loop on the control stack.
-    pub fn push_loop(&mut self, header: Ebb, following_code: Ebb, num_result_types: usize) {
+    pub fn push_loop(
+        &mut self,
+        header: Ebb,
+        following_code: Ebb,
+        num_param_types: usize,
+        num_result_types: usize,
+    ) {
         self.control_stack.push(ControlStackFrame::Loop {
             header,
             destination: following_code,
-            original_stack_size: self.stack.len(),
+            original_stack_size: self.stack.len() - num_param_types,
+            num_param_values: num_param_types,
             num_return_values: num_result_types,
         });
     }
 
     // Push an if on the control stack.
-    pub fn push_if(&mut self, branch_inst: Inst, following_code: Ebb, num_result_types: usize) {
+    pub fn push_if(
+        &mut self,
+        branch_inst: Inst,
+        following_code: Ebb,
+        num_param_types: usize,
+        num_result_types: usize,
+    ) {
         self.control_stack.push(ControlStackFrame::If {
             branch_inst,
             destination: following_code,
-            original_stack_size: self.stack.len(),
+            original_stack_size: self.stack.len() - num_param_types,
+            num_param_values: num_param_types,
             num_return_values: num_result_types,
             exit_is_branched_to: false,
             reachable_from_top: self.reachable,
@@ -1,4 +1,5 @@
 //! Helper functions and structures for the translation.
+use crate::environ::{TargetEnvironment, WasmError, WasmResult};
 use core::u32;
 use cranelift_codegen::entity::entity_impl;
 use cranelift_codegen::ir;
@@ -49,6 +50,16 @@ entity_impl!(MemoryIndex);
 pub struct SignatureIndex(u32);
 entity_impl!(SignatureIndex);
 
+/// Index type of a data segment (active or passive) inside the WebAssembly module.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct DataIndex(u32);
+entity_impl!(DataIndex);
+
+/// Index type of an element segment (active or passive) inside the WebAssembly module.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct ElemIndex(u32);
+entity_impl!(ElemIndex);
+
 /// WebAssembly global.
 #[derive(Debug, Clone, Copy)]
 pub struct Global {
@@ -73,6 +84,8 @@ pub enum GlobalInit {
     F64Const(u64),
     /// A `get_global` of another global.
     GetGlobal(GlobalIndex),
+    /// A `ref.null`.
+    RefNullConst,
     ///< The global is imported from, and thus initialized by, a different module.
     Import,
 }
@@ -109,13 +122,27 @@ pub struct Memory {
 }
 
 /// Helper function translating wasmparser types to Cranelift types when possible.
-pub fn type_to_type(ty: wasmparser::Type) -> Result<ir::Type, ()> {
+///
+/// Reference types are represented by the environment's `reference_type`. Other types are rejected
+/// with an error reporting the byte `offset` of the construct being translated.
+pub fn type_to_type<PE: TargetEnvironment + ?Sized>(
+    ty: wasmparser::Type,
+    offset: usize,
+    environ: &PE,
+) -> WasmResult<ir::Type> {
     Ok(match ty {
         wasmparser::Type::I32 => ir::types::I32,
         wasmparser::Type::I64 => ir::types::I64,
         wasmparser::Type::F32 => ir::types::F32,
         wasmparser::Type::F64 => ir::types::F64,
-        _ => return Err(()),
+        wasmparser::Type::V128 => ir::types::I8X16,
+        wasmparser::Type::AnyRef | wasmparser::Type::AnyFunc => environ.reference_type(),
+        _ => {
+            return Err(WasmError::InvalidWebAssembly {
+                message: "invalid value type",
+                offset,
+            })
+        }
     })
 }
 
@@ -129,14 +156,30 @@ pub fn f64_translation(x: wasmparser::Ieee64) -> ir::immediates::Ieee64 {
     ir::immediates::Ieee64::with_bits(x.bits())
 }
 
-/// Translate a `wasmparser` type into its `Cranelift` equivalent, when possible
-pub fn num_return_values(ty: wasmparser::Type) -> usize {
-    match ty {
-        wasmparser::Type::EmptyBlockType => 0,
-        wasmparser::Type::I32
-        | wasmparser::Type::F32
-        | wasmparser::Type::I64
-        | wasmparser::Type::F64 => 1,
-        _ => panic!("unsupported return value type"),
-    }
+/// Get the parameter and result types of a block type.
+///
+/// A block without parameters or results is `EmptyBlockType`, and a block with a single result is
+/// encoded as that result's value type. Any other type is rejected with an error reporting the
+/// byte `offset` of the block.
+pub fn blocktype_params_results(
+    ty: wasmparser::Type,
+    offset: usize,
+) -> WasmResult<(&'static [wasmparser::Type], &'static [wasmparser::Type])> {
+    use wasmparser::Type;
+    Ok(match ty {
+        Type::EmptyBlockType => (&[], &[]),
+        Type::I32 => (&[], &[Type::I32]),
+        Type::I64 => (&[], &[Type::I64]),
+        Type::F32 => (&[], &[Type::F32]),
+        Type::F64 => (&[], &[Type::F64]),
+        Type::V128 => (&[], &[Type::V128]),
+        Type::AnyRef => (&[], &[Type::AnyRef]),
+        Type::AnyFunc => (&[], &[Type::AnyFunc]),
+        _ => {
+            return Err(WasmError::InvalidWebAssembly {
+                message: "invalid block type",
+                offset,
+            })
+        }
+    })
 }
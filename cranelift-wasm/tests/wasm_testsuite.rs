@@ -1,40 +1,55 @@
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::ir::ExternalName;
 use cranelift_codegen::isa;
-use cranelift_codegen::print_errors::pretty_verifier_error;
+use cranelift_codegen::print_errors::{pretty_error, pretty_verifier_error};
 use cranelift_codegen::settings::{self, Flags};
-use cranelift_codegen::verifier;
-use cranelift_wasm::{translate_module, DummyEnvironment, ReturnMode};
+use cranelift_codegen::{verifier, Context};
+use cranelift_wasm::{
+    translate_module, DefinedFuncIndex, DummyEnvironment, FuncIndex, ModuleEnvironment, ReturnMode,
+    WasmError,
+};
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use target_lexicon::triple;
 use wabt::wat2wasm;
 
 #[test]
 fn testsuite() {
-    let mut paths: Vec<_> = fs::read_dir("../wasmtests")
-        .unwrap()
-        .map(|r| r.unwrap())
-        .filter(|p| {
-            // Ignore files starting with `.`, which could be editor temporary files
-            if let Some(stem) = p.path().file_stem() {
-                if let Some(stemstr) = stem.to_str() {
-                    return !stemstr.starts_with('.');
-                }
-            }
-            false
-        })
-        .collect();
-    paths.sort_by_key(|dir| dir.path());
     let flags = Flags::new(settings::builder());
-    for path in paths {
-        let path = path.path();
+    for path in testsuite_paths() {
         handle_module(&path, &flags, ReturnMode::NormalReturns);
     }
 }
 
+#[test]
+fn concurrent_translation() {
+    // Translating function bodies on worker threads gives the same functions as translating them
+    // on the current thread.
+    let flags = Flags::new(settings::builder());
+    let isa = isa::lookup(triple!("riscv64")).unwrap().finish(flags);
+    let translate = |data: &[u8], num_threads| {
+        let mut dummy_environ =
+            DummyEnvironment::new(isa.frontend_config(), ReturnMode::NormalReturns);
+        dummy_environ.set_translation_threads(num_threads);
+        translate_module(data, &mut dummy_environ).unwrap();
+        dummy_environ
+            .info
+            .function_bodies
+            .values()
+            .map(|func| func.display(None).to_string())
+            .collect::<Vec<_>>()
+    };
+    for path in testsuite_paths() {
+        let data = read_module(&path);
+        let funcs = translate(&data, 0);
+        assert_eq!(translate(&data, 4), funcs, "{:?}", path);
+    }
+}
+
 #[test]
 fn use_fallthrough_return() {
     let flags = Flags::new(settings::builder());
@@ -45,6 +60,166 @@ fn use_fallthrough_return() {
     );
 }
 
+#[test]
+fn compile_atomics() {
+    // Atomic operators need encodings or legalizations all the way down to machine code.
+    let flags = Flags::new(settings::builder());
+    let isa = isa::lookup(triple!("x86_64")).unwrap().finish(flags);
+    let data = read_file(Path::new("../wasmtests/atomics.wasm")).unwrap();
+    let mut dummy_environ = DummyEnvironment::new(isa.frontend_config(), ReturnMode::NormalReturns);
+    translate_module(&data, &mut dummy_environ).unwrap();
+
+    for func in dummy_environ.info.function_bodies.values() {
+        let mut ctx = Context::for_function(func.clone());
+        if let Err(err) = ctx.compile(&*isa) {
+            panic!(pretty_error(&ctx.func, Some(&*isa), err));
+        }
+    }
+}
+
+#[test]
+fn invalid_modules_report_offsets() {
+    let flags = Flags::new(settings::builder());
+    let triple = triple!("riscv64");
+    let isa = isa::lookup(triple).unwrap().finish(flags);
+
+    // A type section entry which is not a function type.
+    let wasm = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x7f, 0x00, 0x00,
+    ];
+    let mut dummy_environ = DummyEnvironment::new(isa.frontend_config(), ReturnMode::NormalReturns);
+    assert_eq!(
+        translate_module(&wasm, &mut dummy_environ).unwrap_err(),
+        WasmError::InvalidWebAssembly {
+            message: "type section entry is not a function type",
+            offset: 11,
+        }
+    );
+
+    // A global initialized with a `nop`.
+    let wasm = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x06, 0x05, 0x01, 0x7f, 0x00, 0x01, 0x0b,
+    ];
+    let mut dummy_environ = DummyEnvironment::new(isa.frontend_config(), ReturnMode::NormalReturns);
+    assert_eq!(
+        translate_module(&wasm, &mut dummy_environ).unwrap_err(),
+        WasmError::InvalidWebAssembly {
+            message: "unsupported init expr in global section",
+            offset: 13,
+        }
+    );
+
+    // A type section following a function section.
+    let wasm = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x03, 0x01, 0x00, 0x01, 0x01, 0x00,
+    ];
+    let mut dummy_environ = DummyEnvironment::new(isa.frontend_config(), ReturnMode::NormalReturns);
+    assert_eq!(
+        translate_module(&wasm, &mut dummy_environ).unwrap_err(),
+        WasmError::InvalidWebAssembly {
+            message: "section out of order or duplicated",
+            offset: 11,
+        }
+    );
+}
+
+#[test]
+fn names_are_applied() {
+    // The name section follows the code section, naming both functions and the locals of the
+    // first one.
+    let flags = Flags::new(settings::builder());
+    let isa = isa::lookup(triple!("riscv64")).unwrap().finish(flags);
+    let data = read_file(Path::new("../wasmtests/names.wasm")).unwrap();
+    let mut dummy_environ = DummyEnvironment::new(isa.frontend_config(), ReturnMode::NormalReturns);
+    translate_module(&data, &mut dummy_environ).unwrap();
+
+    let identity = FuncIndex::new(0);
+    let call_identity = FuncIndex::new(1);
+    assert_eq!(dummy_environ.get_func_name(identity), Some("identity"));
+    assert_eq!(
+        dummy_environ.get_func_name(call_identity),
+        Some("call_identity")
+    );
+    assert_eq!(dummy_environ.info.local_names[identity][&0], "param");
+    assert_eq!(dummy_environ.info.local_names[identity][&1], "local");
+
+    let bodies = &dummy_environ.info.function_bodies;
+    assert_eq!(
+        bodies[DefinedFuncIndex::new(0)].name,
+        ExternalName::testcase("identity")
+    );
+    let caller = &bodies[DefinedFuncIndex::new(1)];
+    assert_eq!(caller.name, ExternalName::testcase("call_identity"));
+    let callees: Vec<_> = caller.dfg.ext_funcs.values().map(|f| &f.name).collect();
+    assert_eq!(callees, [&ExternalName::testcase("identity")]);
+}
+
+#[test]
+fn names_are_unique() {
+    // Three functions of type `() -> ()`, the first one calling the other two.
+    let wasm = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03,
+        0x04, 0x03, 0x00, 0x00, 0x00, 0x0a, 0x0e, 0x03, 0x06, 0x00, 0x10, 0x01, 0x10, 0x02, 0x0b,
+        0x02, 0x00, 0x0b, 0x02, 0x00, 0x0b,
+    ];
+    let flags = Flags::new(settings::builder());
+    let isa = isa::lookup(triple!("riscv64")).unwrap().finish(flags);
+    let mut dummy_environ = DummyEnvironment::new(isa.frontend_config(), ReturnMode::NormalReturns);
+    translate_module(&wasm, &mut dummy_environ).unwrap();
+
+    // Names which are reserved, or the same as another one once truncated, aren't applied.
+    // Names of functions which don't exist are ignored.
+    dummy_environ.declare_func_name(FuncIndex::new(0), "fuel");
+    dummy_environ.declare_func_name(FuncIndex::new(1), "a_very_long_name_1");
+    dummy_environ.declare_func_name(FuncIndex::new(2), "a_very_long_name_2");
+    dummy_environ.declare_func_name(FuncIndex::from_u32(0xffff_fffe), "missing");
+    dummy_environ.declare_local_name(FuncIndex::from_u32(0xffff_fffe), 0, "missing");
+    assert_eq!(dummy_environ.get_func_name(FuncIndex::new(0)), Some("fuel"));
+    assert_eq!(
+        dummy_environ.get_func_name(FuncIndex::from_u32(0xffff_fffe)),
+        None
+    );
+
+    let bodies = &dummy_environ.info.function_bodies;
+    let caller = &bodies[DefinedFuncIndex::new(0)];
+    assert_eq!(caller.name, ExternalName::user(0, 0));
+    let callees: Vec<_> = caller.dfg.ext_funcs.values().map(|f| &f.name).collect();
+    assert_eq!(
+        callees,
+        [
+            &ExternalName::testcase("a_very_long_name"),
+            &ExternalName::user(0, 2)
+        ]
+    );
+    assert_eq!(
+        bodies[DefinedFuncIndex::new(1)].name,
+        ExternalName::testcase("a_very_long_name")
+    );
+    assert_eq!(
+        bodies[DefinedFuncIndex::new(2)].name,
+        ExternalName::user(0, 2)
+    );
+}
+
+/// Get the modules in the `wasmtests` directory, sorted by name.
+fn testsuite_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<_> = fs::read_dir("../wasmtests")
+        .unwrap()
+        .map(|r| r.unwrap().path())
+        .filter(|p| {
+            // Ignore files starting with `.`, which could be editor temporary files
+            if let Some(stem) = p.file_stem() {
+                if let Some(stemstr) = stem.to_str() {
+                    return !stemstr.starts_with('.');
+                }
+            }
+            false
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
 fn read_file(path: &Path) -> io::Result<Vec<u8>> {
     let mut buf: Vec<u8> = Vec::new();
     let mut file = File::open(path)?;
@@ -52,8 +227,9 @@ fn read_file(path: &Path) -> io::Result<Vec<u8>> {
     Ok(buf)
 }
 
-fn handle_module(path: &Path, flags: &Flags, return_mode: ReturnMode) {
-    let data = match path.extension() {
+/// Read a `.wasm` module, or convert a `.wat` module to binary.
+fn read_module(path: &Path) -> Vec<u8> {
+    match path.extension() {
         None => {
             panic!("the file extension is not wasm or wat");
         }
@@ -70,7 +246,11 @@ fn handle_module(path: &Path, flags: &Flags, return_mode: ReturnMode) {
             }
             None | Some(&_) => panic!("the file extension for {:?} is not wasm or wat", path),
         },
-    };
+    }
+}
+
+fn handle_module(path: &Path, flags: &Flags, return_mode: ReturnMode) {
+    let data = read_module(path);
     let triple = triple!("riscv64");
     let isa = isa::lookup(triple).unwrap().finish(flags.clone());
     let mut dummy_environ = DummyEnvironment::new(isa.frontend_config(), return_mode);
@@ -21,5 +21,6 @@ fuzz_target!(|data: &[u8]| {
     let triple = triple!("x86_64");
     let isa = isa::lookup(triple).unwrap().finish(flags);
     let mut dummy_environ = DummyEnvironment::new(isa.frontend_config(), ReturnMode::NormalReturns);
-    translate_module(&wasm, &mut dummy_environ).unwrap();
+    // Translation may reject the module, but it must do so with an error rather than a panic.
+    let _ = translate_module(&wasm, &mut dummy_environ);
 });
@@ -41,7 +41,7 @@ for crate in \
     entity bforest codegen/meta codegen frontend native \
     preopt \
     reader wasm module \
-    faerie umbrella simplejit
+    faerie coff umbrella simplejit
 do
     echo cargo publish --manifest-path "cranelift-$crate/Cargo.toml"
 
@@ -0,0 +1,182 @@
+//! Jump tables compiled by the SimpleJIT and Faerie backends.
+//!
+//! 64-bit x86 code addresses its jump tables relative to the program counter, which doesn't need
+//! any relocation. 32-bit x86 code addresses them absolutely, which needs a jump table relocation
+//! to be resolved by the backend, except in PIC where it computes the program counter with a call.
+//! SimpleJIT only supports non-PIC code, and Faerie only PIC code.
+
+use cranelift_codegen::binemit::CodeOffset;
+use cranelift_codegen::ir::*;
+use cranelift_codegen::isa::{self, CallConv, TargetIsa};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_faerie::{FaerieBackend, FaerieBuilder, FaerieTrapCollection};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{Backend, FuncId, Linkage, Module};
+use cranelift_simplejit::{SimpleJITBackend, SimpleJITBuilder};
+use goblin::elf::Elf;
+use std::str::FromStr;
+use target_lexicon::{triple, Triple};
+
+fn isa(triple: Triple, is_pic: bool) -> Box<TargetIsa> {
+    let mut flag_builder = settings::builder();
+    flag_builder
+        .set("is_pic", if is_pic { "true" } else { "false" })
+        .unwrap();
+    isa::lookup(triple)
+        .unwrap()
+        .finish(settings::Flags::new(flag_builder))
+}
+
+/// Define `switch` as `fn(x) { match x { 0 => 10, 1 => 20, 2 => 30, _ => -1 } }`, which is
+/// compiled to a jump table.
+///
+/// Return the size of its code and the context it was compiled in.
+fn define_switch<B: Backend>(
+    module: &mut Module<B>,
+    linkage: Linkage,
+) -> (FuncId, CodeOffset, Context) {
+    let sig = Signature {
+        params: vec![AbiParam::new(types::I32)],
+        returns: vec![AbiParam::new(types::I32)],
+        call_conv: CallConv::SystemV,
+    };
+    let func_id = module.declare_function("switch", linkage, &sig).unwrap();
+
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, func_id.as_u32()), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        let cases: Vec<Ebb> = (0..3).map(|_| bcx.create_ebb()).collect();
+        let default = bcx.create_ebb();
+        let mut jt_data = JumpTableData::new();
+        for &case in &cases {
+            jt_data.push_entry(case);
+        }
+        let jt = bcx.create_jump_table(jt_data);
+
+        bcx.switch_to_block(ebb);
+        bcx.append_ebb_params_for_function_params(ebb);
+        let x = bcx.ebb_params(ebb)[0];
+        bcx.ins().br_table(x, default, jt);
+        for (i, &case) in cases.iter().enumerate() {
+            bcx.switch_to_block(case);
+            let value = bcx.ins().iconst(types::I32, 10 * (i as i64 + 1));
+            bcx.ins().return_(&[value]);
+        }
+        bcx.switch_to_block(default);
+        let value = bcx.ins().iconst(types::I32, -1);
+        bcx.ins().return_(&[value]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    let code_size = module.define_function(func_id, &mut ctx).unwrap();
+    (func_id, code_size, ctx)
+}
+
+/// Return the offset of the jump table of `switch`, and the offsets of the start and the end of
+/// the instruction which addresses it, whose last 4 bytes are a displacement.
+fn jump_table_base(ctx: &Context, isa: &TargetIsa) -> (CodeOffset, CodeOffset, CodeOffset) {
+    let func = &ctx.func;
+    let (jt, _) = func.jump_tables.iter().next().unwrap();
+    let encinfo = isa.encoding_info();
+    for ebb in func.layout.ebbs() {
+        for (offset, inst, size) in func.inst_offsets(ebb, &encinfo) {
+            if func.dfg[inst].opcode() == Opcode::JumpTableBase {
+                return (func.jt_offsets[jt], offset, offset + size);
+            }
+        }
+    }
+    panic!("no jump table base in switch");
+}
+
+fn simplejit_module(isa: Box<TargetIsa>) -> Module<SimpleJITBackend> {
+    Module::new(SimpleJITBuilder::with_isa(isa))
+}
+
+fn faerie_module(isa: Box<TargetIsa>) -> Module<FaerieBackend> {
+    let builder = FaerieBuilder::new(
+        isa,
+        "test.o".to_string(),
+        FaerieTrapCollection::Disabled,
+        FaerieBuilder::default_libcall_names(),
+    )
+    .unwrap();
+    Module::new(builder)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn simplejit_x86_64() {
+    let mut module = simplejit_module(isa(triple!("x86_64"), false));
+    let (func_id, _, _) = define_switch(&mut module, Linkage::Local);
+    module.finalize_definitions();
+
+    let code = module.get_finalized_function(func_id);
+    let switch = unsafe { std::mem::transmute::<_, extern "C" fn(u32) -> i32>(code) };
+    assert_eq!(switch(0), 10);
+    assert_eq!(switch(1), 20);
+    assert_eq!(switch(2), 30);
+    assert_eq!(switch(3), -1);
+    assert_eq!(switch(100), -1);
+}
+
+#[test]
+fn simplejit_i686() {
+    let mut module = simplejit_module(isa(triple!("i686"), false));
+    let (func_id, _, ctx) = define_switch(&mut module, Linkage::Local);
+    let (jt_offset, _, end) = jump_table_base(&ctx, module.isa());
+    module.finalize_definitions();
+
+    // The code can't be run on a 64-bit host, so only check the address of the jump table,
+    // which is truncated to 32 bits like all `Abs4` relocations.
+    let code = module.get_finalized_function(func_id);
+    let disp = unsafe { std::ptr::read_unaligned(code.add(end as usize - 4) as *const u32) };
+    assert_eq!(disp, (code as usize + jt_offset as usize) as u32);
+}
+
+#[test]
+fn faerie_x86_64() {
+    let mut module = faerie_module(isa(triple!("x86_64-unknown-linux-gnu"), true));
+    let (_, code_size, _) = define_switch(&mut module, Linkage::Export);
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit().unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    let switch = elf
+        .syms
+        .iter()
+        .find(|sym| elf.strtab.get(sym.st_name).and_then(|name| name.ok()) == Some("switch"))
+        .expect("missing symbol for the function");
+    assert!(switch.is_function());
+    assert_eq!(switch.st_size, u64::from(code_size));
+    assert!(elf.shdr_relocs.iter().all(|(_, relocs)| relocs.len() == 0));
+}
+
+#[test]
+fn faerie_i686() {
+    let mut module = faerie_module(isa(triple!("i686-unknown-linux-gnu"), true));
+    let (_, _, ctx) = define_switch(&mut module, Linkage::Export);
+    let (jt_offset, start, end) = jump_table_base(&ctx, module.isa());
+    module.finalize_definitions();
+
+    let bytes = module.finish().emit().unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    assert!(elf.shdr_relocs.iter().all(|(_, relocs)| relocs.len() == 0));
+    let switch = elf
+        .syms
+        .iter()
+        .find(|sym| elf.strtab.get(sym.st_name).and_then(|name| name.ok()) == Some("switch"))
+        .expect("missing symbol for the function");
+
+    // The jump table is addressed relative to the return address of the `call` starting the
+    // instruction, which is popped into the register offset by the displacement.
+    let text = &elf.section_headers[switch.st_shndx];
+    let at = (text.sh_offset + switch.st_value) as usize;
+    let code = &bytes[at..];
+    assert_eq!(code[start as usize], 0xe8);
+    let disp = &code[end as usize - 4..end as usize];
+    assert_eq!(disp, &(jt_offset - (start + 5)).to_le_bytes());
+}